@@ -0,0 +1,81 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ed25519_dalek::SigningKey;
+use storyteller::patch::Patch;
+
+/// Compares batch and sequential signature verification.
+fn verify(c: &mut Criterion) {
+    let key = SigningKey::generate(&mut rand::rngs::OsRng);
+    let patches: Vec<Patch> = (0..1000)
+        .map(|i| Patch::new(&key, [], &i).unwrap())
+        .collect();
+    let refs: Vec<&Patch> = patches.iter().collect();
+
+    let mut group = c.benchmark_group("verify 1000 patches");
+    group.bench_function("sequential", |b| {
+        b.iter(|| {
+            for patch in refs.iter() {
+                patch.verify().unwrap();
+            }
+        })
+    });
+    group.bench_function("batch", |b| b.iter(|| Patch::verify_batch(&refs).unwrap()));
+    group.finish();
+}
+
+fn full_eq(a: &Patch, b: &Patch) -> bool {
+    a.id() == b.id()
+        && a.deps() == b.deps()
+        && a.author() == b.author()
+        && a.sign() == b.sign()
+        && a.data() == b.data()
+        && a.content_type() == b.content_type()
+}
+
+/// Compares patch equality with a field by field comparison of large patches.
+fn eq(c: &mut Criterion) {
+    let key = SigningKey::generate(&mut rand::rngs::OsRng);
+    let patches: Vec<Patch> = (0..10u8)
+        .map(|i| Patch::new_raw(&key, [], vec![i; 1024 * 1024]))
+        .collect();
+    // equal content read back into separate buffers
+    let copies: Vec<Patch> = patches
+        .iter()
+        .map(|p| {
+            let mut bytes = Vec::new();
+            p.write(&mut bytes).unwrap();
+            Patch::read(&mut bytes.as_slice()).unwrap()
+        })
+        .collect();
+    let clones = patches.clone();
+    let distinct: Vec<Patch> = patches.iter().rev().cloned().collect();
+
+    let mut group = c.benchmark_group("eq 10 patches of 1MiB");
+    for (name, others) in [
+        ("copies", &copies),
+        ("clones", &clones),
+        ("distinct", &distinct),
+    ] {
+        group.bench_function(format!("field by field/{name}"), |b| {
+            b.iter(|| {
+                black_box(&patches)
+                    .iter()
+                    .zip(others)
+                    .filter(|(a, b)| full_eq(a, b))
+                    .count()
+            })
+        });
+        group.bench_function(format!("eq/{name}"), |b| {
+            b.iter(|| {
+                black_box(&patches)
+                    .iter()
+                    .zip(others)
+                    .filter(|(a, b)| a == b)
+                    .count()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, verify, eq);
+criterion_main!(benches);
@@ -0,0 +1,37 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ed25519_dalek::SigningKey;
+use storyteller::patch::{Patch, ID};
+use storyteller::store::sqlite::SqliteStore;
+use storyteller::store::ObjectStore;
+
+/// Compares a single batched lookup of 10k patches with looking them up one ID at a time.
+fn patches(c: &mut Criterion) {
+    let key = SigningKey::generate(&mut rand::rngs::OsRng);
+    let store = SqliteStore::open_in_memory().unwrap();
+    let mut chain: Vec<Patch> = Vec::with_capacity(10_000);
+    for i in 0..10_000 {
+        let deps: Vec<ID> = chain.last().map(|p| *p.id()).into_iter().collect();
+        chain.push(Patch::new(&key, deps, &i).unwrap());
+    }
+    store.commit_batch(&chain).unwrap();
+    let ids: Vec<ID> = chain.iter().map(|p| *p.id()).collect();
+
+    let mut group = c.benchmark_group("patches 10k of sqlite");
+    group.sample_size(20);
+    group.bench_function("per id", |b| {
+        b.iter(|| {
+            let mut found = 0;
+            for id in black_box(&ids) {
+                found += store.patches(std::slice::from_ref(id)).unwrap().len();
+            }
+            found
+        })
+    });
+    group.bench_function("batch", |b| {
+        b.iter(|| store.patches(black_box(&ids)).unwrap().len())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, patches);
+criterion_main!(benches);
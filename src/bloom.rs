@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+
+use crate::patch::ID;
+
+/// Probabilistic set of patch IDs. A negative answer of [BloomFilter::contains] is always correct,
+/// while a positive one only means that an ID is *probably* a member of the set.
+///
+/// Since IDs are already uniformly distributed blake3 hashes, filter indexes are derived directly
+/// from ID bytes using double hashing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Creates an empty filter sized to hold `capacity` IDs with a given false-positive rate.
+    pub fn new(capacity: usize, false_positive_rate: f64) -> Self {
+        let n = capacity.max(1) as f64;
+        let p = false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-n * p.ln() / (ln2 * ln2)).ceil().max(64.0) as usize;
+        let hashes = ((bits as f64 / n) * ln2).round().clamp(1.0, 32.0) as u32;
+        BloomFilter {
+            bits: vec![0; bits.div_ceil(64)],
+            hashes,
+        }
+    }
+
+    /// Inserts a given ID into the filter.
+    pub fn insert(&mut self, id: &ID) {
+        let len = self.len_bits();
+        for bit in Self::indexes(id, self.hashes, len) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Returns false if ID was never inserted into the filter, true if it probably was.
+    pub fn contains(&self, id: &ID) -> bool {
+        let len = self.len_bits();
+        if len == 0 {
+            return false;
+        }
+        Self::indexes(id, self.hashes, len).all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn len_bits(&self) -> usize {
+        self.bits.len() * 64
+    }
+
+    fn indexes(id: &ID, hashes: u32, len: usize) -> impl Iterator<Item = usize> {
+        let h1 = u64::from_le_bytes(id[0..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(id[8..16].try_into().unwrap()) | 1;
+        (0..hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len as u64) as usize)
+    }
+}
+
+impl<'a> Extend<&'a ID> for BloomFilter {
+    fn extend<T: IntoIterator<Item = &'a ID>>(&mut self, iter: T) {
+        for id in iter {
+            self.insert(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bloom::BloomFilter;
+    use crate::patch::ID;
+
+    #[test]
+    fn no_false_negatives() {
+        let ids: Vec<ID> = (0..1000u32)
+            .map(|i| blake3::hash(&i.to_le_bytes()).into())
+            .collect();
+        let mut filter = BloomFilter::new(ids.len(), 0.01);
+        filter.extend(ids.iter());
+        assert!(ids.iter().all(|id| filter.contains(id)));
+
+        let false_positives = (1000..11000u32)
+            .map(|i| ID::from(blake3::hash(&i.to_le_bytes())))
+            .filter(|id| filter.contains(id))
+            .count();
+        assert!(
+            false_positives < 300,
+            "false positives: {}",
+            false_positives
+        );
+    }
+}
@@ -0,0 +1,50 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Source of time used by features recording timestamps, like stash eviction (see
+/// [crate::store::ObjectStore::prune_stash]).
+pub trait Clock: Debug + Send + Sync {
+    /// Current time in milliseconds since the Unix epoch.
+    fn now(&self) -> u64;
+}
+
+/// Default [Clock], reading the system time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default()
+    }
+}
+
+/// [Clock] which only moves when told to, so that time-dependent behavior can be tested without
+/// sleeping. Clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct MockClock(Arc<AtomicU64>);
+
+impl MockClock {
+    /// Creates a clock showing a given time (in milliseconds since the Unix epoch).
+    pub fn new(now: u64) -> Self {
+        MockClock(Arc::new(AtomicU64::new(now)))
+    }
+
+    pub fn set(&self, now: u64) {
+        self.0.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.0.fetch_add(by.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,161 @@
+use ed25519::Signature;
+use ed25519_dalek::{SignatureError, Signer, SigningKey, Verifier, VerifyingKey};
+
+use crate::patch::ID;
+use crate::PeerID;
+
+/// Hash function and signature scheme used to identify and authenticate patches.
+///
+/// Suites are free to choose their algorithms, as long as they fit the wire format of a patch:
+/// hashes are 32 bytes long, public keys (used as [PeerID]) take 32 bytes and signatures take 64
+/// bytes. Peers and stores working on the same DAG must use the same suite, otherwise IDs
+/// computed by one of them are rejected by the other with [crate::Error::IdMismatch].
+pub trait CryptoSuite {
+    type SigningKey;
+
+    /// Hashes a concatenation of given byte slices.
+    fn hash<'a, I>(parts: I) -> ID
+    where
+        I: IntoIterator<Item = &'a [u8]>;
+
+    /// Returns a public key corresponding to a given signing key.
+    fn peer_id(key: &Self::SigningKey) -> PeerID;
+
+    fn sign(key: &Self::SigningKey, msg: &[u8]) -> Signature;
+
+    fn verify(author: &PeerID, msg: &[u8], sign: &Signature) -> Result<(), SignatureError>;
+
+    /// Verifies many signatures at once. Fails if any of them is invalid, without telling which
+    /// one. Suites supporting batch verification can override it with a faster implementation.
+    fn verify_batch(
+        authors: &[&PeerID],
+        msgs: &[&[u8]],
+        signs: &[Signature],
+    ) -> Result<(), SignatureError> {
+        for ((author, msg), sign) in authors.iter().zip(msgs).zip(signs) {
+            Self::verify(author, msg, sign)?;
+        }
+        Ok(())
+    }
+}
+
+/// Default [CryptoSuite]: blake3 for hashing and ed25519 for signatures.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ed25519Blake3;
+
+impl CryptoSuite for Ed25519Blake3 {
+    type SigningKey = SigningKey;
+
+    #[inline]
+    fn hash<'a, I>(parts: I) -> ID
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let mut h = blake3::Hasher::new();
+        for part in parts {
+            h.update(part);
+        }
+        h.finalize().into()
+    }
+
+    #[inline]
+    fn peer_id(key: &SigningKey) -> PeerID {
+        key.verifying_key().into()
+    }
+
+    #[inline]
+    fn sign(key: &SigningKey, msg: &[u8]) -> Signature {
+        key.sign(msg)
+    }
+
+    #[inline]
+    fn verify(author: &PeerID, msg: &[u8], sign: &Signature) -> Result<(), SignatureError> {
+        VerifyingKey::from_bytes(author.as_bytes())?.verify(msg, sign)
+    }
+
+    fn verify_batch(
+        authors: &[&PeerID],
+        msgs: &[&[u8]],
+        signs: &[Signature],
+    ) -> Result<(), SignatureError> {
+        let keys = authors
+            .iter()
+            .map(|author| VerifyingKey::from_bytes(author.as_bytes()))
+            .collect::<Result<Vec<_>, _>>()?;
+        ed25519_dalek::verify_batch(msgs, signs, &keys)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ed25519::Signature;
+    use ed25519_dalek::{SignatureError, SigningKey};
+
+    use crate::crypto::{CryptoSuite, Ed25519Blake3};
+    use crate::patch::{Patch, ID};
+    use crate::peer::Peer;
+    use crate::store::memory::MemoryStore;
+    use crate::store::{for_each_store, ObjectStore};
+    use crate::{Error, PeerID};
+
+    /// Suite using a keyed blake3 hash, so that its IDs differ from the default suite.
+    struct KeyedBlake3;
+
+    impl CryptoSuite for KeyedBlake3 {
+        type SigningKey = SigningKey;
+
+        fn hash<'a, I>(parts: I) -> ID
+        where
+            I: IntoIterator<Item = &'a [u8]>,
+        {
+            let mut h = blake3::Hasher::new_derive_key("storyteller test suite");
+            for part in parts {
+                h.update(part);
+            }
+            h.finalize().into()
+        }
+
+        fn peer_id(key: &SigningKey) -> PeerID {
+            Ed25519Blake3::peer_id(key)
+        }
+
+        fn sign(key: &SigningKey, msg: &[u8]) -> Signature {
+            Ed25519Blake3::sign(key, msg)
+        }
+
+        fn verify(author: &PeerID, msg: &[u8], sign: &Signature) -> Result<(), SignatureError> {
+            Ed25519Blake3::verify(author, msg, sign)
+        }
+    }
+
+    fn check_custom_suite<S: ObjectStore>(store: S) {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let mut p1: Peer<_, KeyedBlake3> = Peer::with_suite(key.clone(), store).unwrap();
+        let a = p1.commit(&"A").unwrap();
+        let b = p1.commit(&"B").unwrap();
+        assert_ne!(a.id(), Patch::new(&key, [], &"A").unwrap().id());
+
+        let mut bytes = Vec::new();
+        Patch::write_many(&[a.clone(), b.clone()], &mut bytes).unwrap();
+        let received = Patch::read_many_with::<KeyedBlake3, _>(&mut bytes.as_slice()).unwrap();
+        assert_eq!(received, vec![a.clone(), b.clone()]);
+        assert!(received
+            .iter()
+            .all(|p| p.verify_id_with::<KeyedBlake3>().is_ok()));
+
+        let mut p2: Peer<_, KeyedBlake3> =
+            Peer::with_suite(key, MemoryStore::<KeyedBlake3>::with_suite()).unwrap();
+        let report = p2.integrate(received).unwrap();
+        assert_eq!(report.committed, vec![*a.id(), *b.id()]);
+        assert_eq!(p2.heads(), &[*b.id()]);
+
+        // store using a different suite rejects these patches
+        let res = MemoryStore::new().commit(&a);
+        assert!(matches!(res, Err(Error::IdMismatch(id)) if id == *a.id()));
+    }
+
+    #[test]
+    fn custom_suite() {
+        for_each_store!(KeyedBlake3, |store| check_custom_suite(store));
+    }
+}
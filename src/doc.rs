@@ -0,0 +1,975 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::op::{Op, Value};
+use crate::patch::{Patch, ID};
+use crate::permissions::Permissions;
+use crate::{Error, Result};
+
+/// Materialized state of a document, produced by folding patches carrying [Op]s.
+///
+/// Only data operations affect the document state: [Op::UpdateEntry] updates the map part, while
+/// [Op::InsertRange] and [Op::RemoveRange] update the sequence part. Permission operations are
+/// ignored. Patches tagged with [ContentType::Opaque] are skipped, even if their data happens to
+/// be an [Op].
+///
+/// [ContentType::Opaque]: crate::patch::ContentType::Opaque
+///
+/// Updating an entry with [Value::Null] removes it. Such entry is kept as a tombstone, so that it
+/// still takes part in conflict resolution described below. Removal only affects the writes its
+/// author has seen: a causally later update resurrects a removed entry, a causally later removal
+/// removes it again, while an update concurrent to a removal always wins over it (add-wins),
+/// regardless of their stamps.
+///
+/// Map values are merged recursively: updating an entry with [Value::Map] updates every leaf path
+/// of that map separately, leaving other paths intact, so that concurrent updates of different
+/// fields of the same nested map are all preserved. Any other value (including [Value::List]) is
+/// treated as an opaque leaf. When a leaf and a map are written at the same path concurrently,
+/// the write with the greater stamp wins: a leaf overrides the whole subtree, while a map hides the
+/// leaf and exposes only the nested writes with stamps greater than it.
+///
+/// Other concurrent updates of the same map entry are resolved using last-writer-wins: every patch is
+/// stamped with its causal depth (0 for roots, 1 + max depth of its dependencies otherwise) and its
+/// ID. Update with the greater stamp wins, which means that causally later updates always override
+/// earlier ones, while concurrent updates are ordered by their ID bytes. This way all peers converge
+/// to the same state regardless of the order in which patches were integrated.
+///
+/// Sequence part is a replicated growable array (RGA). Indexes carried by [Op::InsertRange] and
+/// [Op::RemoveRange] are interpreted against the sequence as it was seen by the patch author, i.e.
+/// containing only elements inserted and not removed by patch ancestors. Every inserted element is
+/// identified by the ID of its patch and its offset within the inserted range. Concurrent inserts at
+/// the same position are ordered by descending stamps of their patches, while removals only affect
+/// the elements the author could see. Removed elements are kept as tombstones, so an element
+/// inserted concurrently into a removed range survives the removal (inserts win over concurrent
+/// removals) and stays anchored right after the element its author inserted it after, even if that
+/// one has been removed.
+///
+/// Malformed ranges are not rejected, but have well defined outcome, so that all peers agree on it:
+/// - [Op::RemoveRange] removes elements in a half-open range `from..to`. Range with `from >= to`
+///   is a no-op.
+/// - Indexes past the end of a sequence (seen by the patch author) are clamped to its length, so
+///   inserts past the end append elements, while removals past the end only affect existing ones.
+/// - [Op::InsertRange] with no values is a no-op.
+///
+/// [Op::Prune] issued by the document owner (see [Permissions]) seals the document state: it's
+/// rebuilt from the causal past of the prune patch alone and becomes an immutable base. From then
+/// on, only patches which have the prune patch in their causal past are applied. Patches, which were
+/// created concurrently to the prune (including ones racing it from moderators, whose rights have
+/// been revoked by it), are ignored even if they arrive later.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Document {
+    entries: BTreeMap<String, Node>,
+    /// Resolved values of `entries`, skipping removed ones.
+    view: BTreeMap<String, Value>,
+    /// All sequence elements, including removed ones, in RGA order.
+    sequence: Vec<Element>,
+    causality: Causality,
+    /// Permissions used to validate [Op::Prune] patches.
+    permissions: Permissions,
+    /// ID of the most recent prune patch, which sealed the document state.
+    base: Option<ID>,
+    /// Applied patches, which have `base` in their causal past, including `base` itself.
+    after_base: HashSet<ID>,
+    /// State at the moment of the most recent prune.
+    sealed: (BTreeMap<String, Node>, Vec<Element>),
+    /// Operations applied since the most recent prune.
+    history: Vec<(Stamp, Op)>,
+}
+
+impl Document {
+    /// Materializes document state from a topologically ordered list of patches. Fails with
+    /// [Error::InvalidOp] on the first patch tagged with [ContentType::Op], which data doesn't
+    /// deserialize to [Op].
+    ///
+    /// [ContentType::Op]: crate::patch::ContentType::Op
+    pub fn from_patches(patches: &[Patch]) -> Result<Self> {
+        let mut doc = Document::default();
+        for patch in patches {
+            doc.apply(patch)?;
+        }
+        Ok(doc)
+    }
+
+    /// Applies operation stored in a given patch on top of the current document state.
+    ///
+    /// Opaque patches (including ones created before content types were introduced) are only
+    /// recorded as a part of the causal history. Fails with [Error::InvalidOp] if data of a patch
+    /// tagged with [ContentType::Op] doesn't deserialize to [Op]. Such patch is still recorded as
+    /// part of the causal history, so the caller can skip it and keep applying patches which
+    /// depend on it.
+    ///
+    /// [ContentType::Op]: crate::patch::ContentType::Op
+    pub fn apply(&mut self, patch: &Patch) -> Result<()> {
+        let stamp = self.stamp(patch);
+        // kept for all patches, as patches which are not applied still carry causality
+        let after_base =
+            self.base.is_none() || patch.deps().iter().any(|dep| self.after_base.contains(dep));
+        if self.base.is_some() && after_base {
+            self.after_base.insert(stamp.id);
+        }
+        let op: Op = match patch.op() {
+            Some(Ok(op)) => op,
+            None => {
+                self.permissions.apply(patch);
+                return Ok(());
+            }
+            Some(Err(source)) => {
+                return Err(Error::InvalidOp {
+                    patch: *patch.id(),
+                    source,
+                })
+            }
+        };
+        let authorized = self.permissions.apply(patch);
+        if !after_base {
+            return Ok(()); // concurrent to the prune, state is already sealed
+        }
+        if let Op::Prune = op {
+            if authorized {
+                self.seal(stamp.id);
+            }
+            return Ok(());
+        }
+        self.apply_op(stamp, op.clone());
+        self.history.push((stamp, op));
+        Ok(())
+    }
+
+    /// ID of the most recent [Op::Prune] patch, which sealed the document state.
+    pub fn base(&self) -> Option<&ID> {
+        self.base.as_ref()
+    }
+
+    fn apply_op(&mut self, stamp: Stamp, op: Op) {
+        match op {
+            Op::UpdateEntry(key, value) => {
+                let node = self.entries.entry(key.clone()).or_default();
+                node.write(value, stamp, &self.causality);
+                match node.resolve(None) {
+                    Some(value) => self.view.insert(key, value),
+                    None => self.view.remove(&key),
+                };
+            }
+            Op::InsertRange(index, values) => self.insert_range(stamp, index, values),
+            Op::RemoveRange(from, to) => self.remove_range(stamp, from, to),
+            Op::Prune
+            | Op::TransferOwnership(_)
+            | Op::Revoke(_)
+            | Op::Grant(_)
+            | Op::RotateKey(_) => {}
+        }
+    }
+
+    /// Rebuilds the state from the previously sealed one and operations from the causal past of
+    /// a given prune patch, then seals it as a new base.
+    fn seal(&mut self, prune: ID) {
+        let (entries, sequence) = self.sealed.clone();
+        self.entries = entries;
+        self.sequence = sequence;
+        let history = std::mem::take(&mut self.history);
+        let min_depth = history.iter().map(|(stamp, _)| stamp.depth).min();
+        let past = self.causality.past(&prune, min_depth.unwrap_or_default());
+        for (stamp, op) in history {
+            if past.contains(&stamp.id) {
+                self.apply_op(stamp, op);
+            }
+        }
+        self.view = self
+            .entries
+            .iter()
+            .filter_map(|(key, node)| Some((key.clone(), node.resolve(None)?)))
+            .collect();
+        self.sealed = (self.entries.clone(), self.sequence.clone());
+        self.base = Some(prune);
+        // patches are applied in topological order, so none of the applied ones descend from it
+        self.after_base = HashSet::from([prune]);
+    }
+
+    /// Returns value stored under a given key of the map part.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.view.get(key)
+    }
+
+    /// Returns key-value entries of the map part, ordered by key. Removed entries are skipped.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.view.iter().map(|(k, v)| (k.as_str(), v))
+    }
+
+    /// Returns elements of the sequence part.
+    pub fn sequence(&self) -> Vec<Value> {
+        self.sequence
+            .iter()
+            .filter(|e| e.removed_by.is_empty())
+            .map(|e| e.value.clone())
+            .collect()
+    }
+
+    /// Computes a stamp of a given patch, recording its depth and dependencies for the sake of its
+    /// descendants. Dependencies which were never applied to this document are ignored.
+    fn stamp(&mut self, patch: &Patch) -> Stamp {
+        let mut depth = None;
+        let mut parents = Vec::new();
+        for dep in patch.deps().iter() {
+            if let Some(&dep_depth) = self.causality.depths.get(dep) {
+                depth = depth.max(Some(dep_depth));
+                parents.push(*dep);
+            }
+        }
+        let depth = depth.map_or(0, |depth| depth + 1);
+        self.causality.depths.insert(*patch.id(), depth);
+        if !parents.is_empty() {
+            self.causality.parents.insert(*patch.id(), parents);
+        }
+        Stamp {
+            depth,
+            id: *patch.id(),
+        }
+    }
+
+    /// Returns positions (within `self.sequence`) of elements visible to the author of a patch
+    /// with a given stamp.
+    fn visible_to(&self, stamp: &Stamp) -> Vec<usize> {
+        // patches removing an element always descend from the one inserting it
+        let min_depth = self.sequence.iter().map(|e| e.key.stamp.depth).min();
+        let ancestors = self
+            .causality
+            .past(&stamp.id, min_depth.unwrap_or_default());
+        self.sequence
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| {
+                ancestors.contains(&e.key.stamp.id)
+                    && !e.removed_by.iter().any(|id| ancestors.contains(id))
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    fn insert_range(&mut self, stamp: Stamp, index: u64, values: Vec<Value>) {
+        if values.is_empty() {
+            return;
+        }
+        let visible = self.visible_to(&stamp);
+        let index = clamp_index(index, visible.len());
+        // position right after the visible element preceding the insertion point
+        let mut pos = match index {
+            0 => 0,
+            i => visible[i - 1] + 1,
+        };
+        let first = ElementKey { stamp, offset: 0 };
+        // skip over concurrently inserted elements, which take precedence over the new ones
+        while pos < self.sequence.len() && self.sequence[pos].key > first {
+            pos += 1;
+        }
+        let elements = values
+            .into_iter()
+            .enumerate()
+            .map(|(offset, value)| Element {
+                key: ElementKey {
+                    stamp,
+                    offset: offset as u32,
+                },
+                value,
+                removed_by: Vec::new(),
+            });
+        self.sequence.splice(pos..pos, elements);
+    }
+
+    fn remove_range(&mut self, stamp: Stamp, from: u64, to: u64) {
+        if from >= to {
+            return;
+        }
+        let visible = self.visible_to(&stamp);
+        let to = clamp_index(to, visible.len());
+        let from = clamp_index(from, to);
+        for &pos in &visible[from..to] {
+            self.sequence[pos].removed_by.push(stamp.id);
+        }
+    }
+}
+
+/// Causal relation between applied patches. Only direct dependencies of every patch are kept, so
+/// that memory grows linearly with history, and causal order is recovered by walking the DAG.
+/// Walks never go below the depth of the patch they look for, as ancestors are always shallower
+/// than their descendants.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct Causality {
+    /// Causal depths of all applied patches.
+    #[serde(with = "id_map")]
+    depths: HashMap<ID, u64>,
+    /// Applied dependencies of applied patches. Root patches have no entry.
+    #[serde(with = "id_map")]
+    parents: HashMap<ID, Vec<ID>>,
+}
+
+impl Causality {
+    /// Returns true if `ancestor` is in the causal past of `descendant` (excluding itself).
+    fn precedes(&self, ancestor: &ID, descendant: &ID) -> bool {
+        let Some(&min_depth) = self.depths.get(ancestor) else {
+            return false;
+        };
+        let mut visited = HashSet::new();
+        let mut stack = self.parents_of(descendant, min_depth);
+        while let Some(id) = stack.pop() {
+            if id == *ancestor {
+                return true;
+            }
+            if visited.insert(id) {
+                stack.extend(self.parents_of(&id, min_depth));
+            }
+        }
+        false
+    }
+
+    /// Returns the causal past of a given patch (excluding itself), limited to patches with depth
+    /// of at least `min_depth`.
+    fn past(&self, id: &ID, min_depth: u64) -> HashSet<ID> {
+        let mut past = HashSet::new();
+        let mut stack = self.parents_of(id, min_depth);
+        while let Some(id) = stack.pop() {
+            if past.insert(id) {
+                stack.extend(self.parents_of(&id, min_depth));
+            }
+        }
+        past
+    }
+
+    fn parents_of(&self, id: &ID, min_depth: u64) -> Vec<ID> {
+        let parents = self.parents.get(id).map(Vec::as_slice).unwrap_or_default();
+        parents
+            .iter()
+            .filter(|parent| self.depths[*parent] >= min_depth)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Converts an index carried by an operation into a sequence position not greater than `len`.
+/// Unlike a plain cast, it gives the same result on platforms with 32-bit `usize`.
+fn clamp_index(index: u64, len: usize) -> usize {
+    usize::try_from(index).map_or(len, |index| index.min(len))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Element {
+    key: ElementKey,
+    value: Value,
+    /// IDs of patches which removed this element. Empty if element was never removed.
+    removed_by: Vec<ID>,
+}
+
+/// Unique identifier of a sequence element: a stamp of the patch, which inserted it, and its
+/// offset within the inserted range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct ElementKey {
+    stamp: Stamp,
+    offset: u32,
+}
+
+/// Entry of the map part. Keeps the most recent writes of each kind made at its path, which
+/// doesn't depend on the order in which they were applied.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct Node {
+    /// Writes of non-map values (including tombstones), which were not overridden by causally
+    /// later ones. There's more than one only if they were written concurrently.
+    leaves: Vec<(Value, Stamp)>,
+    /// Stamp of the most recent write of a map value.
+    map: Option<Stamp>,
+    /// Nested entries written by all map values ever written at this path.
+    children: BTreeMap<String, Node>,
+}
+
+impl Node {
+    /// Records a write made by a patch with a given stamp.
+    fn write(&mut self, value: Value, stamp: Stamp, causality: &Causality) {
+        match value {
+            Value::Map(entries) => {
+                self.map = self.map.max(Some(stamp));
+                for (key, value) in entries {
+                    let child = self.children.entry(key).or_default();
+                    child.write(value, stamp, causality);
+                }
+            }
+            value => {
+                self.leaves
+                    .retain(|(_, s)| !causality.precedes(&s.id, &stamp.id));
+                self.leaves.push((value, stamp));
+            }
+        }
+    }
+
+    /// Returns the winning non-map write: the one with the greatest stamp, unless it's a tombstone
+    /// concurrent to a non-null value, in which case the greatest of such values wins instead.
+    fn leaf(&self) -> Option<&(Value, Stamp)> {
+        let by_stamp = |(_, a): &&(Value, Stamp), (_, b): &&(Value, Stamp)| a.cmp(b);
+        let live = self.leaves.iter().filter(|(v, _)| !v.is_null());
+        live.max_by(by_stamp)
+            .or_else(|| self.leaves.iter().max_by(by_stamp))
+    }
+
+    /// Resolves a value of this entry, ignoring writes with stamps not greater than `floor`, which
+    /// have been overridden by a leaf written at one of the parent paths. Returns `None` if
+    /// entry has been removed or was never visibly written.
+    fn resolve(&self, floor: Option<Stamp>) -> Option<Value> {
+        let leaf = self.leaf().filter(|(_, s)| Some(*s) > floor);
+        let map = self.map.filter(|s| Some(*s) > floor);
+        match (leaf, map) {
+            (Some((value, stamp)), map) if map < Some(*stamp) => {
+                Some(value.clone()).filter(|v| !v.is_null())
+            }
+            (_, Some(_)) => {
+                let floor = floor.max(self.leaf().map(|(_, s)| *s));
+                let entries = self
+                    .children
+                    .iter()
+                    .filter_map(|(key, node)| Some((key.clone(), node.resolve(floor)?)))
+                    .collect();
+                Some(Value::Map(entries))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Total order over applied patches, consistent with their causal order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Stamp {
+    depth: u64,
+    id: ID,
+}
+
+impl PartialOrd for Stamp {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Stamp {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.depth
+            .cmp(&other.depth)
+            .then_with(|| self.id.cmp(&other.id))
+    }
+}
+
+/// (De)serializes maps keyed by [ID] as sequences of key-value pairs, since formats like JSON
+/// only allow string map keys.
+mod id_map {
+    use std::collections::HashMap;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::patch::ID;
+
+    pub fn serialize<V, S>(map: &HashMap<ID, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, V, D>(deserializer: D) -> Result<HashMap<ID, V>, D::Error>
+    where
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let pairs: Vec<(ID, V)> = Vec::deserialize(deserializer)?;
+        Ok(pairs.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ed25519_dalek::SigningKey;
+
+    use crate::doc::Document;
+    use crate::op::{Op, Value};
+    use crate::patch::{ContentType, Patch};
+    use crate::peer::Peer;
+    use crate::store::memory::MemoryStore;
+    use crate::store::ObjectStore;
+    use crate::{Error, PeerID};
+
+    fn create_peer() -> Peer<MemoryStore> {
+        let key_pair = SigningKey::generate(&mut rand::rngs::OsRng);
+        Peer::new(key_pair, MemoryStore::new()).unwrap()
+    }
+
+    #[test]
+    fn materialize() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let ops = [
+            Op::UpdateEntry("title".into(), Value::String("draft".into())),
+            Op::InsertRange(0, vec![Value::Int(1), Value::Int(4)]),
+            Op::Grant(PeerID::new([1; 32])),
+            Op::InsertRange(1, vec![Value::Int(2), Value::Int(3)]),
+            Op::UpdateEntry("title".into(), Value::String("final".into())),
+            Op::UpdateEntry("done".into(), Value::Bool(true)),
+            Op::RemoveRange(0, 1),
+        ];
+        let mut patches: Vec<Patch> = Vec::new();
+        for op in ops.iter() {
+            let deps = patches.last().map(|p| *p.id());
+            patches.push(Patch::new(&key, deps, op).unwrap());
+        }
+
+        let doc = Document::from_patches(&patches).unwrap();
+        assert_eq!(doc.get("title"), Some(&Value::String("final".into())));
+        assert_eq!(doc.get("done"), Some(&Value::Bool(true)));
+        assert_eq!(doc.entries().count(), 2);
+        assert_eq!(
+            doc.sequence(),
+            vec![Value::Int(2), Value::Int(3), Value::Int(4)]
+        );
+    }
+
+    #[test]
+    fn malformed_ranges() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let ints = |values: &[i64]| values.iter().map(|&i| Value::Int(i)).collect::<Vec<_>>();
+        let ops = [
+            Op::InsertRange(0, ints(&[1, 2, 3])),
+            // insert past the end appends
+            Op::InsertRange(10, ints(&[4])),
+            Op::InsertRange(u64::MAX, ints(&[5])),
+            // empty insert
+            Op::InsertRange(1, vec![]),
+            // reversed and empty ranges
+            Op::RemoveRange(3, 1),
+            Op::RemoveRange(2, 2),
+            // range starting past the end
+            Op::RemoveRange(10, 20),
+        ];
+        let mut patches: Vec<Patch> = Vec::new();
+        for op in ops.iter() {
+            let deps = patches.last().map(|p| *p.id());
+            patches.push(Patch::new(&key, deps, op).unwrap());
+        }
+        let doc = Document::from_patches(&patches).unwrap();
+        assert_eq!(doc.sequence(), ints(&[1, 2, 3, 4, 5]));
+
+        // range ending past the end is clamped
+        let last = *patches.last().unwrap().id();
+        patches.push(Patch::new(&key, [last], &Op::RemoveRange(3, u64::MAX)).unwrap());
+        let doc = Document::from_patches(&patches).unwrap();
+        assert_eq!(doc.sequence(), ints(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn non_op_patch() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &Op::UpdateEntry("a".into(), Value::Int(1))).unwrap();
+        let opaque = Patch::new(&key, [*a.id()], &"hello").unwrap();
+        assert_eq!(a.content_type(), ContentType::Op);
+        assert_eq!(opaque.content_type(), ContentType::Opaque);
+        let mut invalid = Patch::new(&key, [*a.id()], &"invalid").unwrap();
+        invalid.content_type = ContentType::Op;
+
+        let doc = Document::from_patches(&[a.clone(), opaque.clone()]).unwrap();
+        assert_eq!(doc.entries().count(), 1);
+
+        // opaque patches are skipped, even if their data is an operation
+        let tagged = Patch::builder()
+            .deps([*a.id()])
+            .data(&Op::UpdateEntry("b".into(), Value::Int(2)))
+            .content_type(ContentType::Opaque)
+            .sign(&key)
+            .unwrap();
+        let doc = Document::from_patches(&[a.clone(), tagged]).unwrap();
+        assert_eq!(doc.entries().count(), 1);
+        let res = Document::from_patches(&[a.clone(), invalid.clone()]);
+        assert!(matches!(res, Err(Error::InvalidOp { patch: id, .. }) if id == *invalid.id()));
+
+        // invalid patch can be skipped without breaking its dependents
+        let b = Patch::new(
+            &key,
+            [*opaque.id(), *invalid.id()],
+            &Op::InsertRange(0, vec![Value::Int(2)]),
+        )
+        .unwrap();
+        let mut doc = Document::default();
+        doc.apply(&a).unwrap();
+        doc.apply(&opaque).unwrap();
+        assert!(doc.apply(&invalid).is_err());
+        doc.apply(&b).unwrap();
+        assert_eq!(doc.get("a"), Some(&Value::Int(1)));
+        assert_eq!(doc.sequence(), vec![Value::Int(2)]);
+    }
+
+    #[test]
+    fn concurrent_update_entry() {
+        let mut p1 = create_peer();
+        let mut p2 = create_peer();
+        let base = p1
+            .commit(&Op::UpdateEntry(
+                "title".into(),
+                Value::String("base".into()),
+            ))
+            .unwrap();
+        p2.integrate([base.clone()]).unwrap();
+
+        let a = p1
+            .commit(&Op::UpdateEntry("title".into(), Value::String("A".into())))
+            .unwrap();
+        let b = p2
+            .commit(&Op::UpdateEntry("title".into(), Value::String("B".into())))
+            .unwrap();
+        p1.integrate([b.clone()]).unwrap();
+        p2.integrate([a.clone()]).unwrap();
+
+        let ids1 = [*base.id(), *a.id(), *b.id()];
+        let ids2 = [*base.id(), *b.id(), *a.id()];
+        let doc1 = Document::from_patches(&p1.patches(&ids1).unwrap()).unwrap();
+        let doc2 = Document::from_patches(&p2.patches(&ids2).unwrap()).unwrap();
+        assert_eq!(doc1.get("title"), doc2.get("title"));
+
+        let order = p2.store().topo_order().unwrap();
+        let doc3 = Document::from_patches(&p2.patches(&order).unwrap()).unwrap();
+        assert_eq!(doc1.get("title"), doc3.get("title"));
+        let expected = if a.id().as_ref() > b.id().as_ref() {
+            "A"
+        } else {
+            "B"
+        };
+        assert_eq!(doc1.get("title"), Some(&Value::String(expected.into())));
+
+        // causally later update always wins
+        let c = p1
+            .commit(&Op::UpdateEntry("title".into(), Value::String("C".into())))
+            .unwrap();
+        let mut doc1 = doc1;
+        doc1.apply(&c).unwrap();
+        assert_eq!(doc1.get("title"), Some(&Value::String("C".into())));
+    }
+
+    #[test]
+    fn concurrent_sequence_edits() {
+        let mut p1 = create_peer();
+        let mut p2 = create_peer();
+        let base = p1
+            .commit(&Op::InsertRange(
+                0,
+                vec![Value::Int(1), Value::Int(2), Value::Int(3)],
+            ))
+            .unwrap();
+        p2.integrate([base.clone()]).unwrap();
+
+        // both peers insert at the same position, p2 also removes an element after it
+        let a = p1
+            .commit(&Op::InsertRange(1, vec![Value::String("a".into())]))
+            .unwrap();
+        let b1 = p2
+            .commit(&Op::InsertRange(
+                1,
+                vec![Value::String("b".into()), Value::String("c".into())],
+            ))
+            .unwrap();
+        let b2 = p2.commit(&Op::RemoveRange(3, 4)).unwrap();
+        p1.integrate([b1.clone(), b2.clone()]).unwrap();
+        p2.integrate([a.clone()]).unwrap();
+
+        let ids1 = [*base.id(), *a.id(), *b1.id(), *b2.id()];
+        let ids2 = [*base.id(), *b1.id(), *b2.id(), *a.id()];
+        let doc1 = Document::from_patches(&p1.patches(&ids1).unwrap()).unwrap();
+        let doc2 = Document::from_patches(&p2.patches(&ids2).unwrap()).unwrap();
+        assert_eq!(doc1.sequence(), doc2.sequence());
+
+        let a = Value::String("a".into());
+        let (b, c) = (Value::String("b".into()), Value::String("c".into()));
+        let expected = if doc1.sequence()[1] == a {
+            vec![Value::Int(1), a, b, c, Value::Int(3)]
+        } else {
+            vec![Value::Int(1), b, c, a, Value::Int(3)]
+        };
+        assert_eq!(doc1.sequence(), expected);
+    }
+
+    #[test]
+    fn insert_into_concurrently_removed_range() {
+        let mut a = create_peer();
+        let mut b = create_peer();
+        let base = a
+            .commit(&Op::InsertRange(0, (0..7).map(Value::Int).collect()))
+            .unwrap();
+        b.integrate([base]).unwrap();
+
+        let removed = a.commit(&Op::RemoveRange(2, 5)).unwrap();
+        let x = Value::String("x".into());
+        let inserted = b.commit(&Op::InsertRange(3, vec![x.clone()])).unwrap();
+        a.integrate([inserted]).unwrap();
+        b.integrate([removed]).unwrap();
+
+        // x was inserted between 2 and 3, which are gone, but it remains between 1 and 5
+        let expected = vec![
+            Value::Int(0),
+            Value::Int(1),
+            x,
+            Value::Int(5),
+            Value::Int(6),
+        ];
+        for peer in [&a, &b] {
+            let ids = peer.store().topo_order().unwrap();
+            let doc = Document::from_patches(&peer.patches(&ids).unwrap()).unwrap();
+            assert_eq!(doc.sequence(), expected);
+        }
+    }
+
+    #[test]
+    fn null_removes_entry() {
+        let mut p1 = create_peer();
+        let mut p2 = create_peer();
+        let a = p1
+            .commit(&Op::UpdateEntry("key".into(), Value::Bytes(vec![1, 2])))
+            .unwrap();
+        let b = p1
+            .commit(&Op::UpdateEntry("other".into(), Value::Int(1)))
+            .unwrap();
+        p2.integrate([a.clone(), b.clone()]).unwrap();
+
+        let doc = Document::from_patches(&[a.clone(), b.clone()]).unwrap();
+        assert_eq!(doc.get("key"), Some(&Value::Bytes(vec![1, 2])));
+
+        // removal wins over causally earlier updates, but not over concurrent later ones
+        let removed = p1
+            .commit(&Op::UpdateEntry("key".into(), Value::Null))
+            .unwrap();
+        let doc = Document::from_patches(&[a.clone(), b.clone(), removed.clone()]).unwrap();
+        assert_eq!(doc.get("key"), None);
+        assert_eq!(doc.entries().count(), 1);
+
+        let c = p2
+            .commit(&Op::UpdateEntry("other".into(), Value::Int(2)))
+            .unwrap();
+        let d = p2
+            .commit(&Op::UpdateEntry("key".into(), Value::Int(3)))
+            .unwrap();
+        let doc = Document::from_patches(&[a, b, removed, c, d]).unwrap();
+        assert_eq!(doc.get("key"), Some(&Value::Int(3)));
+    }
+
+    #[test]
+    fn remove_and_update_orderings() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let patch = |deps: &[&Patch], value: Value| {
+            let deps = deps.iter().map(|p| *p.id());
+            let op = Op::UpdateEntry("key".into(), value);
+            Patch::new(&key, deps, &op).unwrap()
+        };
+        let other = |dep: &Patch| {
+            let op = Op::UpdateEntry("other".into(), Value::Int(0));
+            Patch::new(&key, [*dep.id()], &op).unwrap()
+        };
+        let get = |patches: &[&Patch]| {
+            let patches: Vec<Patch> = patches.iter().map(|&p| p.clone()).collect();
+            Document::from_patches(&patches)
+                .unwrap()
+                .get("key")
+                .cloned()
+        };
+        let set = patch(&[], Value::Int(1));
+
+        // set then remove
+        let removed = patch(&[&set], Value::Null);
+        assert_eq!(get(&[&set, &removed]), None);
+
+        // remove then set
+        let resurrected = patch(&[&removed], Value::Int(2));
+        assert_eq!(get(&[&set, &removed, &resurrected]), Some(Value::Int(2)));
+
+        // concurrent, removal has a greater stamp
+        let x = other(&set);
+        let removed = patch(&[&x], Value::Null);
+        let updated = patch(&[&set], Value::Int(2));
+        assert_eq!(get(&[&set, &x, &removed, &updated]), Some(Value::Int(2)));
+        assert_eq!(get(&[&set, &updated, &x, &removed]), Some(Value::Int(2)));
+
+        // concurrent, update has a greater stamp
+        let removed = patch(&[&set], Value::Null);
+        let updated = patch(&[&x], Value::Int(2));
+        assert_eq!(get(&[&set, &x, &removed, &updated]), Some(Value::Int(2)));
+        assert_eq!(get(&[&set, &updated, &x, &removed]), Some(Value::Int(2)));
+
+        // removal which has seen both concurrent writes wins
+        let merged = patch(&[&removed, &updated], Value::Null);
+        assert_eq!(get(&[&set, &x, &removed, &updated, &merged]), None);
+    }
+
+    fn map<const N: usize>(entries: [(&str, Value); N]) -> Value {
+        Value::Map(entries.map(|(k, v)| (k.to_string(), v)).into())
+    }
+
+    #[test]
+    fn deep_merge() {
+        let mut p1 = create_peer();
+        let mut p2 = create_peer();
+        let base = p1
+            .commit(&Op::UpdateEntry(
+                "style".into(),
+                map([("font", map([("size", Value::Int(10))]))]),
+            ))
+            .unwrap();
+        p2.integrate([base.clone()]).unwrap();
+
+        // concurrent updates of different leaves of the same nested map
+        let a = p1
+            .commit(&Op::UpdateEntry(
+                "style".into(),
+                map([("font", map([("family", Value::String("mono".into()))]))]),
+            ))
+            .unwrap();
+        let b = p2
+            .commit(&Op::UpdateEntry(
+                "style".into(),
+                map([
+                    ("font", map([("size", Value::Int(12))])),
+                    ("color", Value::String("red".into())),
+                ]),
+            ))
+            .unwrap();
+
+        let expected = map([
+            ("color", Value::String("red".into())),
+            (
+                "font",
+                map([
+                    ("family", Value::String("mono".into())),
+                    ("size", Value::Int(12)),
+                ]),
+            ),
+        ]);
+        for order in [[&base, &a, &b], [&base, &b, &a]] {
+            let patches: Vec<_> = order.into_iter().cloned().collect();
+            let doc = Document::from_patches(&patches).unwrap();
+            assert_eq!(doc.get("style"), Some(&expected));
+        }
+
+        // removing a nested leaf
+        let c = p1
+            .commit(&Op::UpdateEntry(
+                "style".into(),
+                map([("font", map([("family", Value::Null)]))]),
+            ))
+            .unwrap();
+        let doc = Document::from_patches(&[base, a, b, c]).unwrap();
+        assert_eq!(
+            doc.get("style"),
+            Some(&map([
+                ("color", Value::String("red".into())),
+                ("font", map([("size", Value::Int(12))])),
+            ]))
+        );
+    }
+
+    #[test]
+    fn leaf_and_map_conflict() {
+        let mut p1 = create_peer();
+        let mut p2 = create_peer();
+        let base = p1
+            .commit(&Op::UpdateEntry("key".into(), map([("a", Value::Int(1))])))
+            .unwrap();
+        p2.integrate([base.clone()]).unwrap();
+        let leaf = p1
+            .commit(&Op::UpdateEntry("key".into(), Value::Int(0)))
+            .unwrap();
+        let nested = p2
+            .commit(&Op::UpdateEntry("key".into(), map([("b", Value::Int(2))])))
+            .unwrap();
+
+        // both writes have the same depth, so their order is decided by their IDs
+        let leaf_wins = leaf.id().as_ref() > nested.id().as_ref();
+        let expected = if leaf_wins {
+            Value::Int(0)
+        } else {
+            // base write is older than the leaf, which overrode it
+            map([("b", Value::Int(2))])
+        };
+        for order in [[&base, &leaf, &nested], [&base, &nested, &leaf]] {
+            let patches: Vec<_> = order.into_iter().cloned().collect();
+            let doc = Document::from_patches(&patches).unwrap();
+            assert_eq!(doc.get("key"), Some(&expected));
+        }
+
+        // map written on top of a leaf starts over from an empty map
+        let c = p1
+            .commit(&Op::UpdateEntry("key".into(), map([("c", Value::Int(3))])))
+            .unwrap();
+        let doc = Document::from_patches(&[base, leaf, c]).unwrap();
+        assert_eq!(doc.get("key"), Some(&map([("c", Value::Int(3))])));
+    }
+
+    #[test]
+    fn prune_seals_state() {
+        let mut owner = create_peer();
+        let moderator_key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let mut moderator = Peer::new(moderator_key.clone(), MemoryStore::new()).unwrap();
+        let genesis = owner
+            .commit(&Op::UpdateEntry("key".into(), Value::Int(1)))
+            .unwrap();
+        let grant = owner.commit(&Op::Grant(moderator.peer_id())).unwrap();
+        moderator
+            .integrate([genesis.clone(), grant.clone()])
+            .unwrap();
+
+        // moderator update races the prune
+        let racing = moderator
+            .commit(&Op::UpdateEntry("key".into(), Value::Int(2)))
+            .unwrap();
+        let prune = owner.prune().unwrap();
+        let after = owner
+            .commit(&Op::InsertRange(0, vec![Value::Bool(true)]))
+            .unwrap();
+
+        for order in [
+            [&genesis, &grant, &racing, &prune, &after],
+            [&genesis, &grant, &prune, &after, &racing],
+        ] {
+            let patches: Vec<_> = order.into_iter().cloned().collect();
+            let doc = Document::from_patches(&patches).unwrap();
+            assert_eq!(doc.base(), Some(prune.id()));
+            assert_eq!(doc.get("key"), Some(&Value::Int(1)));
+            assert_eq!(doc.sequence(), vec![Value::Bool(true)]);
+        }
+
+        // patches which don't carry ops still link their descendants to the prune
+        owner.commit(&"opaque").unwrap();
+        let last = owner
+            .commit(&Op::UpdateEntry("key".into(), Value::Int(3)))
+            .unwrap();
+        let ids = owner.store().topo_order().unwrap();
+        let doc = Document::from_patches(&owner.patches(&ids).unwrap()).unwrap();
+        assert_eq!(doc.get("key"), Some(&Value::Int(3)));
+        assert!(doc.after_base.contains(last.id()));
+
+        // prune from a non-owner is ignored
+        let fake = Patch::new(&moderator_key, [*racing.id()], &Op::Prune).unwrap();
+        let doc = Document::from_patches(&[genesis, grant, racing, fake]).unwrap();
+        assert_eq!(doc.base(), None);
+        assert_eq!(doc.get("key"), Some(&Value::Int(2)));
+    }
+
+    #[test]
+    fn causality_grows_linearly() {
+        const PATCHES: usize = 1000;
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let mut patches: Vec<Patch> = Vec::new();
+        for i in 0..PATCHES {
+            let deps = patches.last().map(|p| *p.id());
+            let op = if i % 2 == 0 {
+                Op::UpdateEntry("key".into(), Value::Int(i as i64))
+            } else {
+                Op::InsertRange(0, vec![Value::Int(i as i64)])
+            };
+            patches.push(Patch::new(&key, deps, &op).unwrap());
+        }
+        let doc = Document::from_patches(&patches).unwrap();
+        assert_eq!(doc.get("key"), Some(&Value::Int(PATCHES as i64 - 2)));
+        assert_eq!(doc.sequence().len(), PATCHES / 2);
+        // only direct dependencies are kept
+        let edges: usize = doc.causality.parents.values().map(Vec::len).sum();
+        assert_eq!(edges, PATCHES - 1);
+        assert_eq!(doc.causality.depths.len(), PATCHES);
+    }
+}
@@ -1,21 +1,239 @@
+extern crate alloc;
+
+#[macro_use]
+mod trace;
+
+pub mod bloom;
+pub mod clock;
+pub mod crypto;
+pub mod doc;
 pub mod op;
 pub mod patch;
 pub mod peer;
+pub mod permissions;
 pub mod store;
 
-pub type PeerID = [u8; ed25519_dalek::PUBLIC_KEY_LENGTH];
+use std::fmt::{Debug, Display, Formatter};
+use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+
+use ed25519_dalek::{VerifyingKey, PUBLIC_KEY_LENGTH};
+use hex::FromHexError;
+#[cfg(feature = "sqlite")]
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::de::{Error as _, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Public key of a peer, identifying authors of patches.
+///
+/// It's displayed and serialized (in human-readable formats) as a 64-character hex string.
+/// Deserialization also accepts an array of bytes, which is how operations stored before this
+/// type was introduced encoded peer keys.
+#[repr(transparent)]
+#[derive(Copy, Clone, Default, Eq, PartialEq, Hash)]
+pub struct PeerID([u8; PUBLIC_KEY_LENGTH]);
+
+impl PeerID {
+    pub const fn new(bytes: [u8; PUBLIC_KEY_LENGTH]) -> Self {
+        PeerID(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; PUBLIC_KEY_LENGTH] {
+        &self.0
+    }
+
+    /// Parses a peer ID from its 64-character hex representation (the same one used by `Display`).
+    pub fn from_hex<T: AsRef<[u8]>>(hex: T) -> std::result::Result<Self, FromHexError> {
+        let mut id = PeerID::default();
+        hex::decode_to_slice(hex, &mut id.0)?;
+        Ok(id)
+    }
+}
+
+impl FromStr for PeerID {
+    type Err = FromHexError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        PeerID::from_hex(s)
+    }
+}
+
+impl Deref for PeerID {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_slice()
+    }
+}
+
+impl DerefMut for PeerID {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.as_mut_slice()
+    }
+}
+
+impl From<[u8; PUBLIC_KEY_LENGTH]> for PeerID {
+    fn from(value: [u8; PUBLIC_KEY_LENGTH]) -> Self {
+        PeerID(value)
+    }
+}
+
+impl From<VerifyingKey> for PeerID {
+    fn from(value: VerifyingKey) -> Self {
+        PeerID(value.to_bytes())
+    }
+}
+
+impl From<&VerifyingKey> for PeerID {
+    fn from(value: &VerifyingKey) -> Self {
+        PeerID(value.to_bytes())
+    }
+}
+
+impl TryFrom<&[u8]> for PeerID {
+    type Error = std::array::TryFromSliceError;
+
+    fn try_from(value: &[u8]) -> std::result::Result<Self, Self::Error> {
+        Ok(PeerID(value.try_into()?))
+    }
+}
+
+impl TryFrom<PeerID> for VerifyingKey {
+    type Error = ed25519_dalek::SignatureError;
+
+    fn try_from(value: PeerID) -> std::result::Result<Self, Self::Error> {
+        VerifyingKey::from_bytes(&value.0)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl FromSql for PeerID {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        if let ValueRef::Blob(blob) = value {
+            if let Ok(id) = PeerID::try_from(blob) {
+                return Ok(id);
+            }
+        }
+        Err(FromSqlError::InvalidType)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl ToSql for PeerID {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::Borrowed(ValueRef::Blob(self.as_ref())))
+    }
+}
+
+impl Debug for PeerID {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl Display for PeerID {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl Serialize for PeerID {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&hex::encode(self.0))
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PeerID {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct PeerIDVisitor;
+
+        impl<'de> Visitor<'de> for PeerIDVisitor {
+            type Value = PeerID;
+
+            fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+                write!(f, "a hex string or an array of {PUBLIC_KEY_LENGTH} bytes")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> std::result::Result<PeerID, E> {
+                PeerID::from_hex(v).map_err(E::custom)
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> std::result::Result<PeerID, E> {
+                PeerID::try_from(v).map_err(|_| E::invalid_length(v.len(), &self))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> std::result::Result<PeerID, A::Error> {
+                let mut id = PeerID::default();
+                for (i, byte) in id.0.iter_mut().enumerate() {
+                    *byte = seq
+                        .next_element()?
+                        .ok_or_else(|| A::Error::invalid_length(i, &self))?;
+                }
+                Ok(id)
+            }
+        }
+
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(PeerIDVisitor)
+        } else {
+            deserializer.deserialize_tuple(PUBLIC_KEY_LENGTH, PeerIDVisitor)
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("I/O error: {0}")]
     IO(#[from] std::io::Error),
+    #[cfg(feature = "sqlite")]
     #[error("sqlite error: {0}")]
     Sqlite(#[from] rusqlite::Error),
+    #[cfg(feature = "sqlite")]
+    #[error("database schema version {found} is newer than the supported version {supported}")]
+    UnsupportedSchema { found: u32, supported: u32 },
+    #[cfg(feature = "sqlite")]
+    #[error("stored patches are signed with signature scheme version {found}, while only version {supported} is supported")]
+    UnsupportedSignatureVersion { found: u32, supported: u32 },
     #[error("patch verification failed: {0}")]
     VerificationFailed(#[from] ed25519_dalek::SignatureError),
-    #[error("operation unauthorized")]
-    Unauthorized,
+    #[error("patch {0} author is not authorized to perform its operation")]
+    Unauthorized(patch::ID),
+    #[error("patch {0} signature is invalid")]
+    InvalidSignature(patch::ID),
+    #[error("patch {0} content doesn't match its ID")]
+    IdMismatch(patch::ID),
+    #[error("dependency {0} has not been integrated")]
+    MissingDependency(patch::ID),
+    #[error("patch {0} data exceeds maximum patch size")]
+    PatchTooLarge(patch::ID),
+    #[error("patch {0} is not a part of a linear run of patches, which can be squashed")]
+    NonLinearRun(patch::ID),
+    #[error("patch {0} is not one of the current heads")]
+    NotHead(patch::ID),
+    #[error("invalid bundle: {0}")]
+    InvalidBundle(&'static str),
+    #[error("invalid patch at line {line}: {source}")]
+    InvalidLine { line: usize, source: Box<Error> },
+    #[error("patch {0} is part of a dependency cycle")]
+    CyclicDependency(patch::ID),
+    #[error("patch {0} operation can't be undone")]
+    NotInvertible(patch::ID),
+    #[error("patch {0} is pinned and can't be removed")]
+    Pinned(patch::ID),
+    #[error("patch {patch} data is not a valid operation: {source}")]
+    InvalidOp {
+        patch: patch::ID,
+        source: serde_json::Error,
+    },
     #[error("serialization error: {0}")]
     Serde(#[from] serde_json::Error),
 }
@@ -1,12 +1,74 @@
 use crate::PeerID;
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 
+/// New variants must be appended at the end, so that serialized form of existing ones stays intact.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Value {
     String(String),
     Int(i64),
     Float(f64),
     Bool(bool),
+    Bytes(Vec<u8>),
+    /// Explicit absence of a value. [Op::UpdateEntry] with a null value removes the entry.
+    Null,
+    List(Vec<Value>),
+    /// Nested map. [Op::UpdateEntry] with a map value is merged with an existing one instead of
+    /// replacing it, see [crate::doc::Document].
+    Map(BTreeMap<String, Value>),
+}
+
+impl Value {
+    /// Total order over values, which can be used to deterministically break ties between them.
+    /// Values of different types are ordered by type: null, bool, int, float, string, bytes, list,
+    /// map. Floats are ordered according to [f64::total_cmp], while lists and maps are ordered
+    /// lexicographically.
+    pub fn total_cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            (Value::Int(a), Value::Int(b)) => a.cmp(b),
+            (Value::Float(a), Value::Float(b)) => a.total_cmp(b),
+            (Value::String(a), Value::String(b)) => a.cmp(b),
+            (Value::Bytes(a), Value::Bytes(b)) => a.cmp(b),
+            (Value::List(a), Value::List(b)) => {
+                for (a, b) in a.iter().zip(b.iter()) {
+                    match a.total_cmp(b) {
+                        Ordering::Equal => {}
+                        other => return other,
+                    }
+                }
+                a.len().cmp(&b.len())
+            }
+            (Value::Map(a), Value::Map(b)) => {
+                for ((ka, va), (kb, vb)) in a.iter().zip(b.iter()) {
+                    match ka.cmp(kb).then_with(|| va.total_cmp(vb)) {
+                        Ordering::Equal => {}
+                        other => return other,
+                    }
+                }
+                a.len().cmp(&b.len())
+            }
+            (a, b) => a.rank().cmp(&b.rank()),
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Int(_) => 2,
+            Value::Float(_) => 3,
+            Value::String(_) => 4,
+            Value::Bytes(_) => 5,
+            Value::List(_) => 6,
+            Value::Map(_) => 7,
+        }
+    }
 }
 
 /// Enabled operations, defined in order from highest to lowest precedence.
@@ -29,4 +91,222 @@ pub enum Op {
     InsertRange(u64, Vec<Value>),
     /// Remove a range of array elements.
     RemoveRange(u64, u64),
+    /// Bind a new key to the identity of the patch author. Since the patch is signed with the
+    /// author's current key, it attests that the new key belongs to the same logical author, who
+    /// keeps all of its rights. See [crate::permissions::Permissions].
+    RotateKey(PeerID),
+}
+
+/// Names of [Op] variants, which tag their serialized form.
+pub(crate) const VARIANTS: &[&str] = &[
+    "Prune",
+    "TransferOwnership",
+    "Revoke",
+    "Grant",
+    "UpdateEntry",
+    "InsertRange",
+    "RemoveRange",
+    "RotateKey",
+];
+
+impl Op {
+    /// Returns true for operations which affect permissions (see [crate::permissions::Permissions])
+    /// rather than the document content.
+    pub fn is_permission(&self) -> bool {
+        match self {
+            Op::Prune
+            | Op::TransferOwnership(_)
+            | Op::Revoke(_)
+            | Op::Grant(_)
+            | Op::RotateKey(_) => true,
+            Op::UpdateEntry(..) | Op::InsertRange(..) | Op::RemoveRange(..) => false,
+        }
+    }
+
+    /// Returns a compensating operation, which undoes the effects of this one when applied on top
+    /// of it. `prior_state` is the part of the document state, which this operation has changed,
+    /// as seen right before it:
+    /// - for [Op::UpdateEntry] it's the previous value of the entry ([Value::Null] if there was
+    ///   none). Map values are restored only at the paths written by this operation.
+    /// - for [Op::RemoveRange] it's the previous sequence as a [Value::List]. Removed elements are
+    ///   inserted back (as new elements) at the same position.
+    /// - other operations don't need it.
+    ///
+    /// [Op::InsertRange] is undone by removing inserted elements, while [Op::Grant] and
+    /// [Op::Revoke] undo each other. Returns `None` for operations which can't be undone:
+    /// [Op::Prune], [Op::TransferOwnership] and [Op::RotateKey].
+    pub fn invert(&self, prior_state: &Value) -> Option<Op> {
+        match self {
+            Op::UpdateEntry(key, value) => {
+                Some(Op::UpdateEntry(key.clone(), restore(value, prior_state)))
+            }
+            Op::InsertRange(index, values) => {
+                let len = values.len() as u64;
+                Some(Op::RemoveRange(*index, index.saturating_add(len)))
+            }
+            Op::RemoveRange(from, to) => {
+                let Value::List(sequence) = prior_state else {
+                    return None;
+                };
+                let to = (*to).min(sequence.len() as u64);
+                let from = (*from).min(to);
+                let removed = sequence[from as usize..to as usize].to_vec();
+                Some(Op::InsertRange(from, removed))
+            }
+            Op::Grant(peer) => Some(Op::Revoke(*peer)),
+            Op::Revoke(peer) => Some(Op::Grant(*peer)),
+            Op::Prune | Op::TransferOwnership(_) | Op::RotateKey(_) => None,
+        }
+    }
+}
+
+/// Returns a value, which written over `written` restores `prior` value. Map writes are merged
+/// into existing maps, so only their own paths need to be restored. Any other write overrides the
+/// whole entry, so the prior value is written back as a whole.
+fn restore(written: &Value, prior: &Value) -> Value {
+    match (written, prior) {
+        (Value::Map(written), Value::Map(prior)) => Value::Map(
+            written
+                .iter()
+                .map(|(key, value)| {
+                    let prior = prior.get(key).unwrap_or(&Value::Null);
+                    (key.clone(), restore(value, prior))
+                })
+                .collect(),
+        ),
+        (_, prior) => prior.clone(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::op::{Op, Value};
+    use crate::PeerID;
+    use std::cmp::Ordering;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn value_serde() {
+        let op = Op::InsertRange(0, vec![Value::String("a".into()), Value::Int(1)]);
+        let json = serde_json::to_string(&op).unwrap();
+        assert_eq!(json, r#"{"InsertRange":[0,[{"String":"a"},{"Int":1}]]}"#);
+
+        let values = vec![Value::Bytes(vec![0, 255]), Value::Null];
+        let json = serde_json::to_string(&values).unwrap();
+        assert_eq!(json, r#"[{"Bytes":[0,255]},"Null"]"#);
+        let deserialized: Vec<Value> = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized, values);
+    }
+
+    #[test]
+    fn peer_id_serde() {
+        let peer = PeerID::new([0xab; 32]);
+        let hex = "ab".repeat(32);
+        assert_eq!(peer.to_string(), hex);
+        assert_eq!(hex.parse::<PeerID>().unwrap(), peer);
+
+        let op = Op::Grant(peer);
+        let json = serde_json::to_string(&op).unwrap();
+        assert_eq!(json, format!(r#"{{"Grant":"{hex}"}}"#));
+        assert_eq!(serde_json::from_str::<Op>(&json).unwrap(), op);
+
+        // operations written before PeerID became a newtype encode it as an array of bytes
+        let legacy = format!(r#"{{"Grant":{:?}}}"#, [0xabu8; 32]);
+        assert_eq!(serde_json::from_str::<Op>(&legacy).unwrap(), op);
+        assert!(serde_json::from_str::<Op>(r#"{"Grant":[1,2,3]}"#).is_err());
+    }
+
+    #[test]
+    fn nested_value_serde() {
+        let value = Value::Map(BTreeMap::from([
+            (
+                "tags".to_string(),
+                Value::List(vec![Value::String("a".into())]),
+            ),
+            (
+                "size".to_string(),
+                Value::Map(BTreeMap::from([("width".to_string(), Value::Int(1))])),
+            ),
+        ]));
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(
+            json,
+            r#"{"Map":{"size":{"Map":{"width":{"Int":1}}},"tags":{"List":[{"String":"a"}]}}}"#
+        );
+        let deserialized: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized, value);
+    }
+
+    #[test]
+    fn invert() {
+        let map = |entries: &[(&str, Value)]| {
+            Value::Map(
+                entries
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.clone()))
+                    .collect(),
+            )
+        };
+        let op = Op::UpdateEntry("k".into(), Value::Int(2));
+        assert_eq!(
+            op.invert(&Value::Int(1)),
+            Some(Op::UpdateEntry("k".into(), Value::Int(1)))
+        );
+        assert_eq!(
+            op.invert(&Value::Null),
+            Some(Op::UpdateEntry("k".into(), Value::Null))
+        );
+
+        // only paths written by a map update are restored
+        let op = Op::UpdateEntry(
+            "k".into(),
+            map(&[("a", Value::Int(2)), ("b", map(&[("c", Value::Int(3))]))]),
+        );
+        let prior = map(&[("a", Value::Int(1)), ("x", Value::Int(0))]);
+        assert_eq!(
+            op.invert(&prior),
+            Some(Op::UpdateEntry(
+                "k".into(),
+                map(&[("a", Value::Int(1)), ("b", Value::Null)])
+            ))
+        );
+
+        let op = Op::InsertRange(1, vec![Value::Int(1), Value::Int(2)]);
+        assert_eq!(op.invert(&Value::Null), Some(Op::RemoveRange(1, 3)));
+        let prior = Value::List(vec![Value::Int(0), Value::Int(1), Value::Int(2)]);
+        assert_eq!(
+            Op::RemoveRange(1, 10).invert(&prior),
+            Some(Op::InsertRange(1, vec![Value::Int(1), Value::Int(2)]))
+        );
+        assert_eq!(Op::RemoveRange(1, 2).invert(&Value::Null), None);
+
+        let peer = PeerID::new([1; 32]);
+        assert_eq!(Op::Grant(peer).invert(&Value::Null), Some(Op::Revoke(peer)));
+        assert_eq!(Op::Revoke(peer).invert(&Value::Null), Some(Op::Grant(peer)));
+        assert_eq!(Op::Prune.invert(&Value::Null), None);
+        assert_eq!(Op::RotateKey(peer).invert(&Value::Null), None);
+    }
+
+    #[test]
+    fn value_total_order() {
+        let mut values = [
+            Value::Bytes(vec![1]),
+            Value::String("a".into()),
+            Value::Float(f64::NAN),
+            Value::Float(-1.0),
+            Value::Int(2),
+            Value::Int(-3),
+            Value::Bool(true),
+            Value::Null,
+        ];
+        values.sort_by(|a, b| a.total_cmp(b));
+        assert_eq!(values[0], Value::Null);
+        assert_eq!(values[2], Value::Int(-3));
+        assert_eq!(values[4], Value::Float(-1.0));
+        assert_eq!(values[7], Value::Bytes(vec![1]));
+        assert_eq!(
+            Value::Float(f64::NAN).total_cmp(&Value::Float(f64::NAN)),
+            Ordering::Equal
+        );
+    }
 }
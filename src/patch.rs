@@ -1,24 +1,83 @@
 use std::array::TryFromSliceError;
+use std::borrow::Borrow;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt::{Debug, Display, Formatter};
 use std::io::{Read, Write};
 use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
 
 use blake3::Hash;
 use bytes::Bytes;
 use ed25519::{ComponentBytes, Signature};
-use ed25519_dalek::{SignatureError, Signer, SigningKey, Verifier, VerifyingKey};
+use ed25519_dalek::{SignatureError, SigningKey};
+use hex::FromHexError;
+#[cfg(feature = "sqlite")]
 use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
+#[cfg(feature = "sqlite")]
 use rusqlite::{Row, ToSql};
 use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 use varint_rs::{VarintReader, VarintWriter};
 
-use crate::{PeerID, Result};
+use crate::crypto::{CryptoSuite, Ed25519Blake3};
+use crate::patch::codec::DecodeError;
+use crate::{Error, PeerID, Result};
 
+pub mod codec;
+pub mod ndjson;
+
+/// Version of the patch hashing scheme, prepended to the hash preimage. Any change in the way how
+/// patch IDs are computed must bump it.
+pub const HASH_VERSION: u8 = 1;
+
+/// Domain separation tag prepended to messages signed by patch authors, so that signatures of
+/// patches can't be replayed as valid signatures in other protocols using the same keys (and vice
+/// versa). Any change in the way how patches are signed must bump its version suffix, together
+/// with [SIGNATURE_VERSION].
+pub const SIGNATURE_DOMAIN: &[u8] = b"storyteller-patch-v1";
+
+/// Version of the patch signing scheme, recorded by persistent stores, so that they can reject
+/// patches signed with other versions. Version 0 stands for patches signed before
+/// [SIGNATURE_DOMAIN] was introduced, which don't verify anymore.
+pub const SIGNATURE_VERSION: u32 = 1;
+
+/// Bit set in the [HASH_VERSION] tag of patches, which [ContentType] is not
+/// [ContentType::Opaque]. Such tag is followed by the content type byte, while opaque patches keep
+/// the original preimage, so that their IDs stay the same.
+const TYPED_HASH_FLAG: u8 = 0x80;
+
+/// Maximum number of dependencies, a patch read from an untrusted stream can declare.
+pub const MAX_DEPS_LEN: usize = 1024;
+
+/// Maximum length of the data payload (in bytes), a patch read from an untrusted stream can declare.
+pub const MAX_DATA_LEN: usize = 16 * 1024 * 1024;
+
+/// Maximum total length (in bytes) of patches read at once by [Patch::read_many].
+pub const MAX_BATCH_LEN: u64 = 64 * 1024 * 1024;
+
+/// Patch identifier: a hash of its content. IDs are ordered by their bytes, which is the same as
+/// the order of their hex representations.
 #[repr(transparent)]
-#[derive(Copy, Clone, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[derive(Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
 pub struct ID([u8; blake3::OUT_LEN]);
 
+impl ID {
+    /// Parses an ID from its 64-character hex representation (the same one used by `Display`).
+    pub fn from_hex<T: AsRef<[u8]>>(hex: T) -> std::result::Result<Self, FromHexError> {
+        let mut id = ID::default();
+        hex::decode_to_slice(hex, &mut id.0)?;
+        Ok(id)
+    }
+}
+
+impl FromStr for ID {
+    type Err = FromHexError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        ID::from_hex(s)
+    }
+}
+
 impl Deref for ID {
     type Target = [u8];
 
@@ -48,6 +107,7 @@ impl TryFrom<&[u8]> for ID {
     }
 }
 
+#[cfg(feature = "sqlite")]
 impl FromSql for ID {
     fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
         if let ValueRef::Blob(blob) = value {
@@ -59,6 +119,7 @@ impl FromSql for ID {
     }
 }
 
+#[cfg(feature = "sqlite")]
 impl ToSql for ID {
     fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
         Ok(ToSqlOutput::Borrowed(ValueRef::Blob(self.as_ref())))
@@ -67,23 +128,106 @@ impl ToSql for ID {
 
 impl Debug for ID {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", hex::encode(&self.0))
+        write!(f, "{}", hex::encode(self.0))
     }
 }
 
 impl Display for ID {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", hex::encode(&self.0))
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+/// Hashes given bytes with the same function, which the default [CryptoSuite] uses for patch
+/// IDs, so that content addresses computed by applications (eg. of external data referenced by
+/// patches) stay consistent with them.
+pub fn hash_bytes(data: &[u8]) -> ID {
+    hash_bytes_with::<Ed25519Blake3>(data)
+}
+
+/// Equivalent of [hash_bytes] for a given [CryptoSuite].
+pub fn hash_bytes_with<C: CryptoSuite>(data: &[u8]) -> ID {
+    C::hash([data])
+}
+
+/// Kind of patch data. Patches created before content types were introduced are opaque.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentType {
+    /// Application defined payload.
+    #[default]
+    Opaque = 0,
+    /// JSON-serialized [crate::op::Op].
+    Op = 1,
+}
+
+impl ContentType {
+    /// Content type of patches carrying given data, unless set explicitly: [ContentType::Op] if
+    /// it's a JSON-serialized [crate::op::Op], [ContentType::Opaque] otherwise. Only data tagged
+    /// with the name of an operation is parsed, so detecting opaque payloads is cheap.
+    pub(crate) fn detect(data: &[u8]) -> Self {
+        if has_op_tag(data) && serde_json::from_slice::<crate::op::Op>(data).is_ok() {
+            ContentType::Op
+        } else {
+            ContentType::Opaque
+        }
+    }
+}
+
+/// Checks if data starts like a JSON-serialized [crate::op::Op]: either a string or an object,
+/// which (first) key is one of [crate::op::VARIANTS].
+fn has_op_tag(data: &[u8]) -> bool {
+    let data = data.trim_ascii_start();
+    let tag = match data.split_first() {
+        Some((b'"', _)) => data,
+        Some((b'{', rest)) => rest.trim_ascii_start(),
+        _ => return false,
+    };
+    let Some(tag) = tag.strip_prefix(b"\"") else {
+        return false;
+    };
+    let Some(end) = tag.iter().position(|b| *b == b'"') else {
+        return false;
+    };
+    crate::op::VARIANTS
+        .iter()
+        .any(|variant| variant.as_bytes() == &tag[..end])
+}
+
+impl TryFrom<u8> for ContentType {
+    type Error = std::io::Error;
+
+    fn try_from(value: u8) -> std::result::Result<Self, Self::Error> {
+        Ok(codec::content_type(value)?)
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Eq)]
 pub struct Patch {
     id: ID,
     pub(crate) deps: Deps,
     author: PeerID,
-    sign: ed25519::Signature,
+    pub(crate) sign: ed25519::Signature,
     data: Bytes,
+    pub(crate) content_type: ContentType,
+}
+
+/// Patches are compared by their IDs first. Since ID is a hash of the patch content, patches with
+/// different IDs are never equal, which makes comparing distinct patches cheap regardless of their
+/// data size. Patches with equal IDs are compared by signature and then by content, so that
+/// equality doesn't rely on the hash alone. Data shared by both patches (eg. by clones of the same
+/// patch) is not compared byte by byte.
+impl PartialEq for Patch {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+            && self.sign == other.sign
+            && self.author == other.author
+            && self.content_type == other.content_type
+            && self.deps == other.deps
+            && (self.data.as_ptr() == other.data.as_ptr() && self.data.len() == other.data.len()
+                || self.data == other.data)
+    }
 }
 
 impl Patch {
@@ -92,18 +236,120 @@ impl Patch {
         D: IntoIterator<Item = ID>,
         B: Serialize,
     {
-        let data: Bytes = serde_json::to_vec(data)?.into();
-        let sign = key.sign(&data);
-        let author = key.verifying_key().to_bytes();
-        let deps = Deps::from_iter(deps);
+        Self::new_with::<Ed25519Blake3, D, B>(key, deps, data)
+    }
+
+    /// Creates a new patch, identified and signed using a given [CryptoSuite]. Data is serialized
+    /// using [canonical_json], so that equal values always produce patches with equal IDs. Data
+    /// which deserializes to [crate::op::Op] is tagged with [ContentType::Op], any other data is
+    /// [ContentType::Opaque].
+    pub fn new_with<C, D, B>(key: &C::SigningKey, deps: D, data: &B) -> Result<Self>
+    where
+        C: CryptoSuite,
+        D: IntoIterator<Item = ID>,
+        B: Serialize,
+    {
+        PatchBuilder::new()
+            .deps(deps)
+            .data(data)
+            .sign_with::<C>(key)
+    }
+
+    pub fn new_raw<D, B>(key: &SigningKey, deps: D, data: B) -> Self
+    where
+        D: IntoIterator<Item = ID>,
+        B: Into<Bytes>,
+    {
+        Self::new_raw_with::<Ed25519Blake3, D, B>(key, deps, data)
+    }
+
+    /// Creates a new patch carrying given bytes as its data as they are, without serializing them.
+    /// Like in [Patch::new_with], data which is a JSON-serialized [crate::op::Op] is tagged with
+    /// [ContentType::Op], any other data is [ContentType::Opaque].
+    pub fn new_raw_with<C, D, B>(key: &C::SigningKey, deps: D, data: B) -> Self
+    where
+        C: CryptoSuite,
+        D: IntoIterator<Item = ID>,
+        B: Into<Bytes>,
+    {
+        let data: Bytes = data.into();
+        let content_type = ContentType::detect(&data);
+        Self::build::<C>(key, Deps::from_iter(deps), data, content_type)
+    }
+
+    /// Returns a builder of a new patch, which (unlike [Patch::new]) allows to set its optional
+    /// attributes.
+    pub fn builder() -> PatchBuilder {
+        PatchBuilder::new()
+    }
+
+    fn build<C: CryptoSuite>(
+        key: &C::SigningKey,
+        deps: Deps,
+        data: Bytes,
+        content_type: ContentType,
+    ) -> Self {
         let mut record = Patch {
             id: ID::default(),
-            author,
-            sign,
+            author: C::peer_id(key),
+            sign: Signature::from_bytes(&[0; Signature::BYTE_SIZE]),
             deps,
             data,
+            content_type,
         };
-        record.id = record.hash();
+        record.sign = C::sign(key, &record.signed_message());
+        record.id = record.hash::<C>();
+        record
+    }
+
+    /// Creates an unsigned local patch, see [Patch::is_local].
+    pub(crate) fn local<C: CryptoSuite>(
+        deps: Deps,
+        data: Bytes,
+        content_type: ContentType,
+    ) -> Self {
+        let mut record = Patch {
+            id: ID::default(),
+            author: PeerID::default(),
+            sign: Signature::from_bytes(&[0; Signature::BYTE_SIZE]),
+            deps,
+            data,
+            content_type,
+        };
+        record.id = record.hash::<C>();
+        record
+    }
+
+    /// Creates a new patch with the same data as this one, but depending on `new_deps` instead,
+    /// eg. to cherry-pick it onto a different branch. The new patch is signed with a given key
+    /// and gets a new ID. Since only the author can sign its changes, it fails with
+    /// [Error::Unauthorized] if the key doesn't belong to the author of this patch.
+    pub fn rebase<D>(&self, key: &SigningKey, new_deps: D) -> Result<Self>
+    where
+        D: IntoIterator<Item = ID>,
+    {
+        self.rebase_with::<Ed25519Blake3, D>(key, new_deps)
+    }
+
+    /// Equivalent of [Patch::rebase] for patches created with a given [CryptoSuite].
+    pub fn rebase_with<C, D>(&self, key: &C::SigningKey, new_deps: D) -> Result<Self>
+    where
+        C: CryptoSuite,
+        D: IntoIterator<Item = ID>,
+    {
+        if C::peer_id(key) != self.author {
+            return Err(Error::Unauthorized(self.id));
+        }
+        let mut record = Patch {
+            id: ID::default(),
+            author: self.author,
+            sign: Signature::from_bytes(&[0; Signature::BYTE_SIZE]),
+            deps: Deps::from_iter(new_deps),
+            data: self.data.clone(),
+            content_type: self.content_type,
+        };
+        record.sign = C::sign(key, &record.signed_message());
+        record.id = record.hash::<C>();
         Ok(record)
     }
 
@@ -111,13 +357,21 @@ impl Patch {
     /// - 1: PeerID
     /// - 2: signature
     /// - 3: data blob
-    /// - 4: deps
+    /// - 4: deps (optional, may be NULL)
+    /// - 5: content type (optional)
+    #[cfg(feature = "sqlite")]
     pub fn from_sql_row(row: &Row) -> std::result::Result<Self, rusqlite::Error> {
         let id: ID = row.get(0)?;
         let author: PeerID = row.get(1)?;
         let sign = row.get_ref(2)?;
         let data = row.get_ref(3)?;
         let deps = row.get_ref(4);
+        let content_type = match row.get::<_, u8>(5) {
+            Ok(content_type) => {
+                ContentType::try_from(content_type).map_err(|e| FromSqlError::Other(Box::new(e)))?
+            }
+            Err(_) => ContentType::Opaque,
+        };
 
         let signature_bytes = sign.as_bytes()?;
         let signature = signature_bytes
@@ -127,10 +381,10 @@ impl Patch {
                 blob_size: signature_bytes.len(),
             })?;
         let deps: Deps = match deps {
+            Ok(ValueRef::Null) | Err(_) => Deps::default(),
             Ok(deps) => {
-                serde_json::from_slice(&deps.as_bytes()?).map_err(|_| FromSqlError::InvalidType)?
+                serde_json::from_slice(deps.as_bytes()?).map_err(|_| FromSqlError::InvalidType)?
             }
-            Err(_) => Deps::default(),
         };
         Ok(Patch {
             id,
@@ -138,6 +392,7 @@ impl Patch {
             sign: Signature::from_bytes(&signature),
             deps,
             data: Bytes::copy_from_slice(data.as_blob()?),
+            content_type,
         })
     }
 
@@ -157,41 +412,167 @@ impl Patch {
         &self.data
     }
 
+    /// Returns patch data as [Bytes] sharing the same buffer, without copying it.
+    pub fn data_bytes(&self) -> Bytes {
+        self.data.clone()
+    }
+
+    /// Consumes the patch, returning its data without copying it.
+    pub fn into_data(self) -> Bytes {
+        self.data
+    }
+
     pub fn sign(&self) -> &Signature {
         &self.sign
     }
 
-    fn hash(&self) -> ID {
-        let mut h = blake3::Hasher::new();
-        h.update(&self.author);
-        for parent in self.deps.iter() {
-            h.update(parent);
+    /// Returns true if it's a local patch, created by [crate::peer::Peer::commit_local]. Local
+    /// patches have a zero [PeerID] as their author and are not signed, since they never leave
+    /// the peer which created them. Their signature verification always fails.
+    pub fn is_local(&self) -> bool {
+        self.author == PeerID::default()
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        self.content_type
+    }
+
+    /// Computes patch ID from a [HASH_VERSION] tag, content type, author, deps and data.
+    ///
+    /// Signature is deliberately not a part of the hash preimage: ID identifies patch content,
+    /// while signature only authenticates it. This way the ID is known before signing and stays
+    /// the same even if the signature was produced differently (eg. by a non-deterministic
+    /// signature scheme). As a consequence two patches with the same author, deps and data but
+    /// different signatures share the same ID, so ID equality alone doesn't imply a valid
+    /// signature - use [Patch::verify] for that.
+    fn hash<C: CryptoSuite>(&self) -> ID {
+        C::hash(self.preimage())
+    }
+
+    /// Parts of the hash preimage: [HASH_VERSION] tag (followed by content type, unless opaque),
+    /// author, deps and data.
+    fn preimage(&self) -> impl Iterator<Item = &[u8]> {
+        let version: &[u8] = match self.content_type {
+            ContentType::Opaque => &[HASH_VERSION],
+            ContentType::Op => &[HASH_VERSION | TYPED_HASH_FLAG, ContentType::Op as u8],
+        };
+        let header: [&[u8]; 2] = [version, &self.author];
+        let deps = self.deps.iter().map(|id| &id[..]);
+        header.into_iter().chain(deps).chain([&self.data[..]])
+    }
+
+    /// Message covered by the patch signature: [SIGNATURE_DOMAIN] followed by the whole hash
+    /// preimage, so that signature authenticates not only patch data, but also its position in
+    /// the DAG.
+    pub(crate) fn signed_message(&self) -> Vec<u8> {
+        let len = self.preimage().map(<[u8]>::len).sum::<usize>() + SIGNATURE_DOMAIN.len();
+        let mut msg = Vec::with_capacity(len);
+        msg.extend_from_slice(SIGNATURE_DOMAIN);
+        for part in self.preimage() {
+            msg.extend_from_slice(part);
+        }
+        msg
+    }
+
+    /// Deserializes patch data as an [crate::op::Op]. Returns `None` for [ContentType::Opaque]
+    /// patches, which are never interpreted as operations, whatever their data.
+    pub fn op(&self) -> Option<serde_json::Result<crate::op::Op>> {
+        match self.content_type {
+            ContentType::Opaque => None,
+            ContentType::Op => Some(serde_json::from_slice(&self.data)),
+        }
+    }
+
+    /// Checks that patch ID matches the hash of its content, failing with [Error::IdMismatch]
+    /// otherwise. This detects patches which deps or data were altered after they were created.
+    pub fn verify_id(&self) -> Result<()> {
+        self.verify_id_with::<Ed25519Blake3>()
+    }
+
+    /// Equivalent of [Patch::verify_id] for patches created with a given [CryptoSuite].
+    pub fn verify_id_with<C: CryptoSuite>(&self) -> Result<()> {
+        if self.hash::<C>() == self.id {
+            Ok(())
+        } else {
+            Err(Error::IdMismatch(self.id))
         }
-        h.update(&self.data);
-        h.finalize().into()
     }
 
     pub fn verify(&self) -> std::result::Result<(), SignatureError> {
-        let verifier = VerifyingKey::from_bytes(&self.author)?;
-        verifier.verify(&self.data, &self.sign)
+        self.verify_with::<Ed25519Blake3>()
+    }
+
+    /// Equivalent of [Patch::verify] for patches created with a given [CryptoSuite].
+    pub fn verify_with<C: CryptoSuite>(&self) -> std::result::Result<(), SignatureError> {
+        if self.is_local() {
+            // local patches are unsigned, so they can never come from a remote peer
+            return Err(SignatureError::new());
+        }
+        C::verify(&self.author, &self.signed_message(), &self.sign)
+    }
+
+    /// Verifies signatures of all given patches at once, which is much faster than verifying
+    /// them one by one. If batch verification fails, patches are verified one by one, returning
+    /// error of the first invalid one.
+    pub fn verify_batch(patches: &[&Patch]) -> std::result::Result<(), SignatureError> {
+        Self::verify_batch_with::<Ed25519Blake3>(patches)
+    }
+
+    /// Equivalent of [Patch::verify_batch] for patches created with a given [CryptoSuite].
+    pub fn verify_batch_with<C: CryptoSuite>(
+        patches: &[&Patch],
+    ) -> std::result::Result<(), SignatureError> {
+        if patches.len() == 1 || patches.iter().any(|p| p.is_local()) {
+            return patches.iter().try_for_each(|p| p.verify_with::<C>());
+        }
+        let authors: Vec<&PeerID> = patches.iter().map(|p| &p.author).collect();
+        let msgs: Vec<Vec<u8>> = patches.iter().map(|p| p.signed_message()).collect();
+        let msgs: Vec<&[u8]> = msgs.iter().map(|msg| &msg[..]).collect();
+        let signs: Vec<Signature> = patches.iter().map(|p| p.sign).collect();
+        if C::verify_batch(&authors, &msgs, &signs).is_ok() {
+            return Ok(());
+        }
+        for patch in patches {
+            patch.verify_with::<C>()?;
+        }
+        // batch verification may reject signatures accepted individually (eg. non-canonical ones)
+        Ok(())
     }
 
     pub fn write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
-        w.write_u32_varint(self.deps.len() as u32)?;
-        w.write_u32_varint(self.data.len() as u32)?;
-        w.write_all(self.sign.r_bytes())?;
-        w.write_all(self.sign.s_bytes())?;
-        w.write_all(&self.author)?;
-        for parent in self.deps.iter() {
-            w.write_all(parent)?;
-        }
-        w.write_all(&self.data)?;
+        let (encoding, data) = encode_data(&self.data)?;
+        let mut header = Vec::new();
+        codec::encode_header(self, encoding, data.len(), &mut header);
+        w.write_all(&header)?;
+        w.write_all(&data)?;
         Ok(())
     }
 
     pub fn read<R: Read>(r: &mut R) -> Result<Self> {
+        Self::read_with::<Ed25519Blake3, R>(r)
+    }
+
+    /// Equivalent of [Patch::read] for patches created with a given [CryptoSuite], which is used
+    /// to recompute their IDs.
+    pub fn read_with<C: CryptoSuite, R: Read>(r: &mut R) -> Result<Self> {
+        Self::read_limited::<C, R>(r, MAX_DATA_LEN)
+    }
+
+    /// Equivalent of [Patch::read_with], which rejects patches declaring more than `max_data_len`
+    /// bytes of data, before reading them. Limit greater than [MAX_DATA_LEN] has no effect.
+    pub fn read_limited<C: CryptoSuite, R: Read>(r: &mut R, max_data_len: usize) -> Result<Self> {
         let deps_len = r.read_u32_varint()? as usize;
         let data_len = r.read_u32_varint()? as usize;
+        if deps_len > MAX_DEPS_LEN {
+            return Err(invalid_data("patch dependency count exceeds limit").into());
+        }
+        let max_data_len = max_data_len.min(MAX_DATA_LEN);
+        if data_len > max_data_len {
+            return Err(invalid_data("patch data length exceeds limit").into());
+        }
+        let mut encoding = [0u8];
+        r.read_exact(&mut encoding)?;
+        let content_type = ContentType::try_from(encoding[0] >> 4)?;
         let mut r_bytes = ComponentBytes::default();
         let mut s_bytes = ComponentBytes::default();
         r.read_exact(&mut r_bytes)?;
@@ -202,6 +583,7 @@ impl Patch {
             author: PeerID::default(),
             sign: ed25519::Signature::from_components(r_bytes, s_bytes),
             data: Bytes::default(),
+            content_type,
         };
         r.read_exact(&mut record.author)?;
         for _ in 0..deps_len {
@@ -209,17 +591,304 @@ impl Patch {
             r.read_exact(&mut parent)?;
             record.deps.insert(parent);
         }
-        let mut data = Vec::with_capacity(data_len);
-        unsafe { data.set_len(data_len) };
-        r.read_exact(&mut data)?;
-        record.data = Bytes::from(data);
-        record.id = record.hash();
+        // don't trust data_len for allocation: grow the buffer only as the bytes actually arrive
+        let mut data = Vec::new();
+        r.take(data_len as u64).read_to_end(&mut data)?;
+        if data.len() != data_len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        record.data = decode_data(encoding[0] & 0x0f, data, max_data_len)?;
+        record.id = record.hash::<C>();
         Ok(record)
     }
+
+    /// Writes a batch of patches prefixed with their count, to be read with [Patch::read_many].
+    pub fn write_many<W: Write>(patches: &[Patch], w: &mut W) -> std::io::Result<()> {
+        w.write_u32_varint(patches.len() as u32)?;
+        for patch in patches {
+            patch.write(w)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a batch of patches written with [Patch::write_many]. Fails if stream ends before all
+    /// declared patches were read, or if they take more than [MAX_BATCH_LEN] bytes in total.
+    pub fn read_many<R: Read>(r: &mut R) -> Result<Vec<Self>> {
+        Self::read_many_with::<Ed25519Blake3, R>(r)
+    }
+
+    /// Equivalent of [Patch::read_many] for patches created with a given [CryptoSuite].
+    pub fn read_many_with<C: CryptoSuite, R: Read>(r: &mut R) -> Result<Vec<Self>> {
+        let count = r.read_u32_varint()? as usize;
+        let mut r = r.take(MAX_BATCH_LEN);
+        // don't trust count for allocation either
+        let mut patches = Vec::with_capacity(count.min(1024));
+        for _ in 0..count {
+            patches.push(Patch::read_with::<C, _>(&mut r)?);
+        }
+        Ok(patches)
+    }
+}
+
+/// Builder of a [Patch], for patches which need more than [Patch::new] offers, eg. an explicit
+/// [ContentType]. Attributes are set one by one, then the patch is completed with
+/// [PatchBuilder::sign]. By default a patch has no dependencies, empty data and its content type
+/// is detected from its data (like in [Patch::new]).
+#[derive(Debug, Default)]
+pub struct PatchBuilder {
+    deps: Deps,
+    data: Bytes,
+    content_type: Option<ContentType>,
+    /// Failure to serialize data, reported once the patch is signed.
+    error: Option<Error>,
+}
+
+impl PatchBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets dependencies of the patch, replacing the previously set ones.
+    pub fn deps<D>(mut self, deps: D) -> Self
+    where
+        D: IntoIterator<Item = ID>,
+    {
+        self.deps = Deps::from_iter(deps);
+        self
+    }
+
+    /// Sets data of the patch, serialized using [canonical_json]. Serialization failure is
+    /// reported by [PatchBuilder::sign].
+    pub fn data<B: Serialize>(mut self, data: &B) -> Self {
+        match canonical_json(data) {
+            Ok(data) => {
+                self.data = data.into();
+                self.error = None;
+            }
+            Err(e) => self.error = Some(e),
+        }
+        self
+    }
+
+    /// Sets data of the patch to given bytes as they are, without serializing them.
+    pub fn raw_data<B: Into<Bytes>>(mut self, data: B) -> Self {
+        self.data = data.into();
+        self.error = None;
+        self
+    }
+
+    /// Sets content type of the patch explicitly, instead of detecting it from its data. Note
+    /// that [crate::doc::Document] fails to apply patches tagged with [ContentType::Op], which data
+    /// is not an [crate::op::Op].
+    pub fn content_type(mut self, content_type: ContentType) -> Self {
+        self.content_type = Some(content_type);
+        self
+    }
+
+    /// Signs the patch with a given key, which makes it complete. Fails if its data couldn't
+    /// be serialized.
+    pub fn sign(self, key: &SigningKey) -> Result<Patch> {
+        self.sign_with::<Ed25519Blake3>(key)
+    }
+
+    /// Equivalent of [PatchBuilder::sign] for patches created with a given [CryptoSuite].
+    pub fn sign_with<C: CryptoSuite>(self, key: &C::SigningKey) -> Result<Patch> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        let content_type = self
+            .content_type
+            .unwrap_or_else(|| ContentType::detect(&self.data));
+        Ok(Patch::build::<C>(key, self.deps, self.data, content_type))
+    }
+}
+
+/// Sorts patches topologically, so that every patch appears after all of its dependencies present
+/// in the same list. Concurrent patches are ordered by their IDs.
+pub(crate) fn topo_sort<P: Borrow<Patch>>(patches: Vec<P>) -> Vec<P> {
+    let ids: Vec<ID> = patches.iter().map(|p| *p.borrow().id()).collect();
+    let edges = patches
+        .iter()
+        .map(Borrow::borrow)
+        .flat_map(|p: &Patch| p.deps().iter().map(move |dep| (*p.id(), *dep)));
+    let order = topo_order(&ids, edges);
+    let mut slots: Vec<Option<P>> = patches.into_iter().map(Some).collect();
+    order.into_iter().filter_map(|i| slots[i].take()).collect()
+}
+
+/// Kahn's algorithm over a DAG of given `ids` and `(child, parent)` edges between them. Returns
+/// indexes of `ids` in topological order, breaking ties between concurrent nodes by their IDs.
+/// Edges referencing IDs outside of `ids` are ignored.
+pub(crate) fn topo_order<E>(ids: &[ID], edges: E) -> Vec<usize>
+where
+    E: IntoIterator<Item = (ID, ID)>,
+{
+    let index: HashMap<ID, usize> = ids.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+    let mut pending = vec![0usize; ids.len()];
+    let mut children: Vec<Vec<usize>> = vec![Vec::new(); ids.len()];
+    for (child, parent) in edges {
+        if let (Some(&child), Some(&parent)) = (index.get(&child), index.get(&parent)) {
+            pending[child] += 1;
+            children[parent].push(child);
+        }
+    }
+    let mut ready: BTreeSet<(ID, usize)> = (0..ids.len())
+        .filter(|i| pending[*i] == 0)
+        .map(|i| (ids[i], i))
+        .collect();
+    let mut order = Vec::with_capacity(ids.len());
+    while let Some((_, i)) = ready.pop_first() {
+        order.push(i);
+        for &child in children[i].iter() {
+            pending[child] -= 1;
+            if pending[child] == 0 {
+                ready.insert((ids[child], child));
+            }
+        }
+    }
+    order
+}
+
+/// Looks for a dependency cycle among given patches. Returns IDs of the patches forming a cycle, if
+/// there is one. Edges referencing patches outside of the list are ignored.
+pub(crate) fn find_cycle<P: Borrow<Patch>>(patches: &[P]) -> Option<Vec<ID>> {
+    let ids: Vec<ID> = patches.iter().map(|p| *p.borrow().id()).collect();
+    let edges = patches
+        .iter()
+        .map(Borrow::borrow)
+        .flat_map(|p: &Patch| p.deps().iter().map(move |dep| (*p.id(), *dep)));
+    let order = topo_order(&ids, edges);
+    if order.len() == ids.len() {
+        return None;
+    }
+    let mut sorted = vec![false; ids.len()];
+    for i in order {
+        sorted[i] = true;
+    }
+    let unsorted: HashMap<ID, &Patch> = patches
+        .iter()
+        .map(Borrow::borrow)
+        .enumerate()
+        .filter(|(i, _)| !sorted[*i])
+        .map(|(_, p)| (*p.id(), p))
+        .collect();
+    // every unsorted patch depends on another unsorted one, so following dependencies must end up
+    // in a loop at some point
+    let mut current = *unsorted.keys().min()?;
+    let mut path = Vec::new();
+    loop {
+        if let Some(pos) = path.iter().position(|id| *id == current) {
+            return Some(path.split_off(pos));
+        }
+        path.push(current);
+        current = *unsorted[&current]
+            .deps()
+            .iter()
+            .filter(|dep| unsorted.contains_key(*dep))
+            .min()?;
+    }
+}
+
+/// Patch data is written as is.
+const ENCODING_RAW: u8 = 0;
+/// Patch data is compressed with zstd.
+const ENCODING_ZSTD: u8 = 1;
+
+/// Patch data larger than this (in bytes) will be compressed by [Patch::write], when the
+/// `compression` feature is enabled.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Serializes a given value into a canonical JSON form, so that logically equal values are always
+/// serialized into the same bytes:
+/// - object keys are sorted by their UTF-8 bytes, regardless of the order in which they were
+///   serialized (eg. when coming from a `HashMap`),
+/// - floats with an integral value (up to 2^53) are written as integers, which also maps `-0.0`
+///   to `0`. Such numbers can still be deserialized into floating point fields.
+/// - no whitespace is emitted.
+pub fn canonical_json<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    fn normalize(value: serde_json::Value) -> serde_json::Value {
+        use serde_json::Value;
+        match value {
+            Value::Number(n) => match n.as_f64() {
+                Some(f) if n.is_f64() && f.fract() == 0.0 && f.abs() <= (1u64 << 53) as f64 => {
+                    Value::Number((f as i64).into())
+                }
+                _ => Value::Number(n),
+            },
+            Value::Array(items) => Value::Array(items.into_iter().map(normalize).collect()),
+            Value::Object(map) => {
+                // don't rely on map ordering: it's insertion order with `preserve_order` feature
+                let mut entries: Vec<_> = map.into_iter().collect();
+                entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+                Value::Object(
+                    entries
+                        .into_iter()
+                        .map(|(k, v)| (k, normalize(v)))
+                        .collect(),
+                )
+            }
+            other => other,
+        }
+    }
+    let value = normalize(serde_json::to_value(value)?);
+    Ok(serde_json::to_vec(&value)?)
+}
+
+/// Picks the wire encoding of patch data. Compressed form is used only if it's actually smaller.
+fn encode_data(data: &Bytes) -> std::io::Result<(u8, Bytes)> {
+    #[cfg(feature = "compression")]
+    if data.len() > COMPRESSION_THRESHOLD {
+        let compressed = zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+        if compressed.len() < data.len() {
+            return Ok((ENCODING_ZSTD, Bytes::from(compressed)));
+        }
+    }
+    Ok((ENCODING_RAW, data.clone()))
+}
+
+#[cfg_attr(not(feature = "compression"), allow(unused_variables))]
+fn decode_data(encoding: u8, data: Vec<u8>, max_len: usize) -> std::io::Result<Bytes> {
+    match encoding {
+        ENCODING_RAW => Ok(Bytes::from(data)),
+        #[cfg(feature = "compression")]
+        ENCODING_ZSTD => {
+            // bound decompressed size, so that a small payload can't expand into gigabytes
+            let decoder = zstd::stream::Decoder::new(data.as_slice())?;
+            let mut decompressed = Vec::new();
+            decoder
+                .take(max_len as u64 + 1)
+                .read_to_end(&mut decompressed)?;
+            if decompressed.len() > max_len {
+                return Err(invalid_data("patch data length exceeds limit"));
+            }
+            Ok(Bytes::from(decompressed))
+        }
+        #[cfg(not(feature = "compression"))]
+        ENCODING_ZSTD => Err(invalid_data(
+            "compressed patch data requires `compression` feature",
+        )),
+        _ => Err(invalid_data("unknown patch data encoding")),
+    }
+}
+
+pub(crate) fn invalid_data(msg: &'static str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
 }
 
+impl From<DecodeError> for std::io::Error {
+    fn from(e: DecodeError) -> Self {
+        match e {
+            DecodeError::UnexpectedEnd => std::io::Error::from(std::io::ErrorKind::UnexpectedEof),
+            DecodeError::Invalid(msg) => invalid_data(msg),
+        }
+    }
+}
+
+/// Set of patch dependencies. IDs are kept sorted by their bytes, so that lookups are binary
+/// searches and two sets with the same IDs are always stored (and hashed) in the same order.
 #[repr(transparent)]
-#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(from = "SmallVec<[ID; 1]>")]
 pub struct Deps(SmallVec<[ID; 1]>);
 
 impl Deref for Deps {
@@ -230,26 +899,15 @@ impl Deref for Deps {
     }
 }
 
-impl Eq for Deps {}
-impl PartialEq for Deps {
-    fn eq(&self, other: &Self) -> bool {
-        if self.0.len() != other.0.len() {
-            return false;
-        }
-        for id in other.iter() {
-            if !self.contains(id) {
-                return false;
-            }
-        }
-        return true;
+impl From<SmallVec<[ID; 1]>> for Deps {
+    fn from(value: SmallVec<[ID; 1]>) -> Self {
+        Deps::new(value)
     }
 }
 
 impl FromIterator<ID> for Deps {
     fn from_iter<T: IntoIterator<Item = ID>>(iter: T) -> Self {
-        let mut v = SmallVec::from_iter(iter);
-        v.dedup();
-        Deps(v)
+        Deps::new(iter.into_iter().collect())
     }
 }
 
@@ -263,36 +921,70 @@ impl IntoIterator for Deps {
 }
 
 impl Deps {
-    pub fn new(deps: SmallVec<[ID; 1]>) -> Self {
+    /// Creates a new set out of given IDs, sorting them and removing duplicates.
+    pub fn new(mut deps: SmallVec<[ID; 1]>) -> Self {
+        deps.sort_unstable();
+        deps.dedup();
         Deps(deps)
     }
+
     pub fn with_capacity(capacity: usize) -> Self {
         Deps(SmallVec::with_capacity(capacity))
     }
 
     pub fn insert(&mut self, value: ID) -> bool {
-        if self.contains(&value) {
-            false
-        } else {
-            self.0.push(value);
-            true
+        match self.search(&value) {
+            Ok(_) => false,
+            Err(i) => {
+                self.0.insert(i, value);
+                true
+            }
         }
     }
 
+    /// Removes a given ID from the set. Returns false if it was not there.
+    ///
+    /// Since patch IDs are computed from their dependencies, mutating methods are meant for
+    /// building a set for a new patch (eg. when grafting or rebasing a patch onto different
+    /// dependencies) before it gets hashed and signed. Dependencies of existing patches can't be
+    /// changed.
+    pub fn remove(&mut self, value: &ID) -> bool {
+        match self.search(value) {
+            Ok(i) => {
+                self.0.remove(i);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Removes all IDs from the set. See [Deps::remove] for when it's safe to use.
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
     pub fn contains(&self, value: &ID) -> bool {
-        self.0.iter().any(|id| id == value)
+        self.search(value).is_ok()
     }
 
     pub fn iter(&self) -> std::slice::Iter<'_, ID> {
         self.0.iter()
     }
+
+    fn search(&self, value: &ID) -> std::result::Result<usize, usize> {
+        self.0.binary_search(value)
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::patch::{Deps, Patch};
+    use crate::crypto::Ed25519Blake3;
+    use crate::op::Op;
+    use crate::patch::{ContentType, Deps, Patch, ID, SIGNATURE_DOMAIN};
     use ed25519_dalek::SigningKey;
+    use std::collections::{BTreeMap, HashMap};
     use std::io::Cursor;
+    use varint_rs::VarintWriter;
 
     #[test]
     fn serialize_record() {
@@ -308,4 +1000,437 @@ mod test {
         deserialized.verify().unwrap();
         assert_eq!(record, deserialized);
     }
+
+    #[test]
+    fn read_truncated_record() {
+        let key_pair = SigningKey::generate(&mut rand::rngs::OsRng);
+        let record = Patch::new(&key_pair, Deps::default(), &"hello world").unwrap();
+        let mut bytes = Vec::new();
+        record.write(&mut bytes).unwrap();
+
+        for len in 0..bytes.len() {
+            let mut cursor = Cursor::new(&bytes[..len]);
+            assert!(Patch::read(&mut cursor).is_err(), "truncated at {}", len);
+        }
+    }
+
+    #[test]
+    fn read_many() {
+        let key_pair = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key_pair, [], &"A").unwrap();
+        let b = Patch::new(&key_pair, [*a.id()], &"B").unwrap();
+        let c = Patch::new(&key_pair, [*a.id(), *b.id()], &"C").unwrap();
+        let patches = vec![a, b, c];
+        let mut bytes = Vec::new();
+        Patch::write_many(&patches, &mut bytes).unwrap();
+        assert_eq!(Patch::read_many(&mut bytes.as_slice()).unwrap(), patches);
+
+        // partial last patch
+        let res = Patch::read_many(&mut &bytes[..bytes.len() - 1]);
+        assert!(res.is_err());
+
+        // count declaring more patches than there are
+        let mut bytes = Vec::new();
+        bytes.write_u32_varint(u32::MAX).unwrap();
+        patches[0].write(&mut bytes).unwrap();
+        assert!(Patch::read_many(&mut bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn read_oversized_lengths() {
+        let mut bytes = Vec::new();
+        bytes.write_u32_varint(0).unwrap();
+        bytes.write_u32_varint(u32::MAX).unwrap();
+        let mut cursor = Cursor::new(bytes);
+        assert!(Patch::read(&mut cursor).is_err());
+
+        let mut bytes = Vec::new();
+        bytes.write_u32_varint(u32::MAX).unwrap();
+        bytes.write_u32_varint(0).unwrap();
+        let mut cursor = Cursor::new(bytes);
+        assert!(Patch::read(&mut cursor).is_err());
+    }
+
+    #[test]
+    fn read_limited() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let patch = Patch::new(&key, [], &"x".repeat(98)).unwrap();
+        assert_eq!(patch.data().len(), 100); // quoted JSON string
+        let mut bytes = Vec::new();
+        patch.write(&mut bytes).unwrap();
+
+        let read = Patch::read_limited::<Ed25519Blake3, _>(&mut bytes.as_slice(), 100).unwrap();
+        assert_eq!(read, patch);
+        let res = Patch::read_limited::<Ed25519Blake3, _>(&mut bytes.as_slice(), 99);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn content_type() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let op = Patch::new(&key, [], &Op::Prune).unwrap();
+        let opaque = Patch::new(&key, [], &"hello").unwrap();
+        assert_eq!(op.content_type(), ContentType::Op);
+        assert_eq!(opaque.content_type(), ContentType::Opaque);
+
+        // tag is a part of the ID: the same data with a different tag is a different patch
+        let mut retagged = op.clone();
+        retagged.content_type = ContentType::Opaque;
+        assert!(retagged.verify_id().is_err());
+        assert!(retagged.verify().is_err());
+
+        let mut bytes = Vec::new();
+        Patch::write_many(&[op.clone(), opaque.clone()], &mut bytes).unwrap();
+        let read = Patch::read_many(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read, vec![op, opaque]);
+        assert!(read.iter().all(|p| p.verify_id().is_ok()));
+    }
+
+    #[test]
+    fn data_bytes() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let patch = Patch::new(&key, [], &"hello").unwrap();
+        let bytes = patch.data_bytes();
+        assert_eq!(&bytes[..], patch.data());
+        assert_eq!(bytes.as_ptr(), patch.data().as_ptr());
+        let ptr = patch.data().as_ptr();
+        assert_eq!(patch.into_data().as_ptr(), ptr);
+    }
+
+    #[test]
+    fn hash_bytes() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let patch = Patch::new(&key, [], &"hello").unwrap();
+        let preimage = &patch.signed_message()[SIGNATURE_DOMAIN.len()..];
+        assert_eq!(super::hash_bytes(preimage), *patch.id());
+        assert_eq!(
+            super::hash_bytes(b"hello"),
+            ID::from(blake3::hash(b"hello"))
+        );
+    }
+
+    #[test]
+    fn id_hex_roundtrip() {
+        let id = ID::from(blake3::hash(b"hello world"));
+        let parsed: ID = id.to_string().parse().unwrap();
+        assert_eq!(parsed, id);
+        assert_eq!(ID::from_hex(id.to_string()).unwrap(), id);
+
+        let hex = id.to_string();
+        assert!(hex[..62].parse::<ID>().is_err());
+        assert!(format!("{}00", hex).parse::<ID>().is_err());
+        assert!(hex.replacen(&hex[..1], "z", 1).parse::<ID>().is_err());
+    }
+
+    #[test]
+    fn id_ordering() {
+        let mut ids: Vec<ID> = (0..100u32)
+            .map(|i| ID::from(blake3::hash(&i.to_le_bytes())))
+            .collect();
+        ids.sort();
+        assert!(ids.windows(2).all(|w| w[0][..] < w[1][..]));
+        let hex: Vec<String> = ids.iter().map(ID::to_string).collect();
+        let mut sorted_hex = hex.clone();
+        sorted_hex.sort();
+        assert_eq!(hex, sorted_hex);
+
+        // sorting is stable: equal IDs keep their relative order
+        let mut tagged: Vec<(ID, usize)> = ids.iter().rev().map(|id| (*id, 0)).collect();
+        tagged.extend(ids.iter().map(|id| (*id, 1)));
+        tagged.sort_by_key(|(id, _)| *id);
+        for pair in tagged.chunks(2) {
+            assert_eq!(pair[0].0, pair[1].0);
+            assert_eq!((pair[0].1, pair[1].1), (0, 1));
+        }
+        let map: BTreeMap<ID, usize> = ids.iter().rev().cloned().zip(0..).collect();
+        assert!(map.keys().eq(ids.iter()));
+    }
+
+    #[test]
+    fn unknown_data_encoding() {
+        let key_pair = SigningKey::generate(&mut rand::rngs::OsRng);
+        let record = Patch::new(&key_pair, Deps::default(), &"hello world").unwrap();
+        let mut bytes = Vec::new();
+        record.write(&mut bytes).unwrap();
+        bytes[2] = 0xff; // encoding flag follows two single-byte varints
+        assert!(Patch::read(&mut Cursor::new(bytes)).is_err());
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_record() {
+        let data = "hello world ".repeat(1000);
+        let key_pair = SigningKey::generate(&mut rand::rngs::OsRng);
+        let record = Patch::new(&key_pair, Deps::default(), &data).unwrap();
+
+        let mut bytes = Vec::new();
+        record.write(&mut bytes).unwrap();
+        assert!(bytes.len() < record.data().len());
+        let deserialized = Patch::read(&mut Cursor::new(bytes)).unwrap();
+        deserialized.verify().unwrap();
+        assert_eq!(deserialized.id(), record.id());
+        assert_eq!(record, deserialized);
+    }
+
+    #[test]
+    fn signature_covers_deps() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let b = Patch::new(&key, [], &"B").unwrap();
+        let c = Patch::new(&key, [*a.id()], &"C").unwrap();
+        assert!(c.verify().is_ok());
+
+        // re-parent C under B, keeping its data and signature
+        let mut bytes = Vec::new();
+        c.write(&mut bytes).unwrap();
+        let pos = bytes.len() - c.data().len() - blake3::OUT_LEN;
+        bytes[pos..pos + blake3::OUT_LEN].copy_from_slice(b.id());
+        let tampered = Patch::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(&tampered.deps()[..], &[*b.id()]);
+        assert_eq!(tampered.data(), c.data());
+        assert!(tampered.verify().is_err());
+        assert!(Patch::verify_batch(&[&a, &tampered]).is_err());
+    }
+
+    #[test]
+    fn signature_domain_separation() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let patch = Patch::new(&key, [], &"A").unwrap();
+        assert!(patch.signed_message().starts_with(SIGNATURE_DOMAIN));
+
+        // signature over the bare preimage, as produced before domain separation
+        let mut untagged = patch.clone();
+        let preimage = &patch.signed_message()[SIGNATURE_DOMAIN.len()..];
+        untagged.sign = ed25519_dalek::Signer::sign(&key, preimage);
+        assert_eq!(untagged.id(), patch.id());
+        assert!(untagged.verify().is_err());
+        assert!(Patch::verify_batch(&[&patch, &untagged]).is_err());
+        assert!(patch.verify().is_ok());
+    }
+
+    #[test]
+    fn id_excludes_signature() {
+        let key_pair = SigningKey::generate(&mut rand::rngs::OsRng);
+        let record = Patch::new(&key_pair, Deps::default(), &"hello world").unwrap();
+        let mut forged = record.clone();
+        forged.sign = ed25519::Signature::from_bytes(&[1; 64]);
+
+        assert_eq!(forged.hash::<Ed25519Blake3>(), *record.id());
+        assert!(record.verify().is_ok());
+        assert!(forged.verify().is_err());
+    }
+
+    #[test]
+    fn verify_batch() {
+        let alice = SigningKey::generate(&mut rand::rngs::OsRng);
+        let bob = SigningKey::generate(&mut rand::rngs::OsRng);
+        let mut patches: Vec<Patch> = (0..10)
+            .map(|i| Patch::new(if i % 2 == 0 { &alice } else { &bob }, [], &i).unwrap())
+            .collect();
+        assert!(Patch::verify_batch(&patches.iter().collect::<Vec<_>>()).is_ok());
+        assert!(Patch::verify_batch(&[]).is_ok());
+
+        patches[7].sign = ed25519::Signature::from_bytes(&[1; 64]);
+        assert!(Patch::verify_batch(&patches.iter().collect::<Vec<_>>()).is_err());
+        assert!(Patch::verify_batch(&[&patches[7]]).is_err());
+        assert!(Patch::verify_batch(&[&patches[6], &patches[8]]).is_ok());
+    }
+
+    #[test]
+    fn eq_by_id() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let b = Patch::new(&key, [], &"B").unwrap();
+        assert_eq!(a, a.clone());
+        assert_ne!(a, b);
+        // equal content read back into a separate buffer
+        let mut bytes = Vec::new();
+        a.write(&mut bytes).unwrap();
+        assert_eq!(Patch::read(&mut bytes.as_slice()).unwrap(), a);
+        // the same ID doesn't make patches with different signatures equal
+        let mut forged = a.clone();
+        forged.sign = ed25519::Signature::from_bytes(&[1; 64]);
+        assert_eq!(forged.id(), a.id());
+        assert_ne!(forged, a);
+    }
+
+    #[test]
+    fn canonical_data() {
+        use std::collections::HashMap;
+
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let keys: Vec<String> = (0..32).map(|i| format!("key{i}")).collect();
+        let forward: HashMap<&str, i32> = keys.iter().map(|k| (k.as_str(), 1)).collect();
+        let mut backward = HashMap::with_capacity(1);
+        for k in keys.iter().rev() {
+            backward.insert(k.as_str(), 1);
+        }
+        let a = Patch::new(&key, [], &forward).unwrap();
+        let b = Patch::new(&key, [], &backward).unwrap();
+        assert_eq!(a.id(), b.id());
+        assert!(a.data().starts_with(br#"{"key0":1,"key1":1,"key10":1"#));
+
+        let floats = Patch::new(&key, [], &(1.0f64, -0.0f64, 0.5f64, 1e300f64)).unwrap();
+        let ints = Patch::new(&key, [], &(1, 0, 0.5f64, 1e300f64)).unwrap();
+        assert_eq!(floats.id(), ints.id());
+        assert_eq!(floats.data(), br#"[1,0,0.5,1e300]"#);
+        let decoded: (f64, f64, f64, f64) = serde_json::from_slice(floats.data()).unwrap();
+        assert_eq!(decoded, (1.0, 0.0, 0.5, 1e300));
+    }
+
+    #[test]
+    fn id_independent_of_dep_order() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let ids: Vec<ID> = (0..3u8).map(|i| ID::from(blake3::hash(&[i]))).collect();
+        let a = Patch::new(&key, ids.iter().copied(), &"A").unwrap();
+        let b = Patch::new(&key, [ids[2], ids[0], ids[1], ids[0]], &"A").unwrap();
+        assert_eq!(a.id(), b.id());
+        assert_eq!(a, b);
+
+        // deps written in a different order are read back into the same patch
+        let mut bytes = Vec::new();
+        a.write(&mut bytes).unwrap();
+        let deps_at = bytes.len() - a.data().len() - 3 * blake3::OUT_LEN;
+        bytes[deps_at..deps_at + 2 * blake3::OUT_LEN].rotate_left(blake3::OUT_LEN);
+        let read = Patch::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read, a);
+        read.verify().unwrap();
+    }
+
+    #[test]
+    fn sorted_deps() {
+        let ids: Vec<ID> = (0..5u8).map(|i| ID::from(blake3::hash(&[i]))).collect();
+        let forward = Deps::from_iter(ids.iter().copied());
+        let mut backward = Deps::default();
+        for id in ids.iter().rev() {
+            assert!(backward.insert(*id));
+        }
+        assert!(!backward.insert(ids[0]));
+        assert_eq!(forward, backward);
+        assert_eq!(&forward[..], &backward[..]);
+        assert!(forward.windows(2).all(|w| w[0][..] < w[1][..]));
+        assert!(ids.iter().all(|id| forward.contains(id)));
+
+        let json = serde_json::to_vec(&ids).unwrap();
+        let deserialized: Deps = serde_json::from_slice(&json).unwrap();
+        assert_eq!(deserialized, forward);
+    }
+
+    #[test]
+    fn builder() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let op = Op::UpdateEntry("key".into(), crate::op::Value::Int(1));
+
+        let built = Patch::builder()
+            .deps([*a.id()])
+            .data(&op)
+            .sign(&key)
+            .unwrap();
+        let expected = Patch::new(&key, [*a.id()], &op).unwrap();
+        assert_eq!(built.id(), expected.id());
+        assert_eq!(built, expected);
+        assert_eq!(built.content_type(), ContentType::Op);
+
+        let built = Patch::builder().raw_data(&b"raw"[..]).sign(&key).unwrap();
+        assert_eq!(built.id(), Patch::new_raw(&key, [], &b"raw"[..]).id());
+        assert_eq!(Patch::builder().sign(&key).unwrap().data(), b"");
+
+        // explicit content type takes precedence over the detected one
+        let opaque = Patch::builder()
+            .deps([*a.id()])
+            .data(&op)
+            .content_type(ContentType::Opaque)
+            .sign(&key)
+            .unwrap();
+        assert_eq!(opaque.content_type(), ContentType::Opaque);
+        assert_ne!(opaque.id(), expected.id());
+        assert!(opaque.verify().is_ok());
+
+        let unserializable: HashMap<(u8, u8), u8> = [((1, 2), 3)].into();
+        let err = Patch::builder().data(&unserializable).sign(&key);
+        assert!(matches!(err, Err(crate::Error::Serde(_))));
+    }
+
+    #[test]
+    fn rebase() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let b = Patch::new(&key, [], &"B").unwrap();
+        let c = Patch::new(&key, [*a.id()], &Op::Prune).unwrap();
+
+        let rebased = c.rebase(&key, [*b.id()]).unwrap();
+        assert_ne!(rebased.id(), c.id());
+        assert_eq!(&rebased.deps()[..], &[*b.id()]);
+        assert_eq!(rebased.data(), c.data());
+        assert_eq!(rebased.content_type(), ContentType::Op);
+        rebased.verify_id().unwrap();
+        rebased.verify().unwrap();
+        // rebasing onto the same deps gives back the same patch
+        assert_eq!(rebased.rebase(&key, [*a.id()]).unwrap(), c);
+
+        let other = SigningKey::generate(&mut rand::rngs::OsRng);
+        let res = c.rebase(&other, [*b.id()]);
+        assert!(matches!(res, Err(crate::Error::Unauthorized(id)) if id == *c.id()));
+    }
+
+    #[test]
+    fn deps_mutation() {
+        let ids: Vec<ID> = (0..5u8).map(|i| ID::from(blake3::hash(&[i]))).collect();
+        let mut deps = Deps::from_iter(ids.iter().copied());
+        assert!(deps.remove(&ids[2]));
+        assert!(!deps.remove(&ids[2]));
+        assert!(!deps.contains(&ids[2]));
+        assert_eq!(deps.len(), 4);
+        assert!(deps.windows(2).all(|w| w[0][..] < w[1][..]));
+        assert!(deps.insert(ids[2]));
+        assert_eq!(deps, Deps::from_iter(ids.iter().copied()));
+
+        // patch built from mutated deps is hashed like one built from the same IDs directly
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        deps.remove(&ids[0]);
+        let a = Patch::new(&key, deps.clone(), &"A").unwrap();
+        let b = Patch::new(&key, ids[1..].iter().copied(), &"A").unwrap();
+        assert_eq!(a.id(), b.id());
+
+        deps.clear();
+        assert!(deps.is_empty());
+        assert_eq!(deps, Deps::default());
+    }
+
+    #[test]
+    fn detect_content_type() {
+        use crate::op::{Value, VARIANTS};
+        use crate::PeerID;
+
+        let peer = PeerID::new([1; 32]);
+        let ops = [
+            Op::Prune,
+            Op::TransferOwnership(peer),
+            Op::Revoke(peer),
+            Op::Grant(peer),
+            Op::UpdateEntry("a".into(), Value::Int(1)),
+            Op::InsertRange(0, vec![Value::Int(1)]),
+            Op::RemoveRange(0, 1),
+            Op::RotateKey(peer),
+        ];
+        assert_eq!(ops.len(), VARIANTS.len());
+        for op in ops.iter() {
+            let data = serde_json::to_vec(op).unwrap();
+            assert_eq!(ContentType::detect(&data), ContentType::Op, "{op:?}");
+        }
+        assert_eq!(
+            ContentType::detect(b" { \"Prune\" : null}"),
+            ContentType::Op
+        );
+        assert_eq!(ContentType::detect(b" \"Prune\""), ContentType::Op);
+        assert_eq!(
+            ContentType::detect(br#"{"Grant":"not a peer"}"#),
+            ContentType::Opaque
+        );
+        assert_eq!(ContentType::detect(br#"{"a":1}"#), ContentType::Opaque);
+        assert_eq!(ContentType::detect(b"\"Prun"), ContentType::Opaque);
+        assert_eq!(ContentType::detect(b"hello"), ContentType::Opaque);
+        assert_eq!(ContentType::detect(b""), ContentType::Opaque);
+    }
 }
@@ -0,0 +1,210 @@
+//! Slice-based encoding of patches, using the same wire format as [Patch::write] and [Patch::read].
+//!
+//! This module only depends on `core` and `alloc` (no `std::io`), so that patches can be encoded
+//! and decoded by sync agents running in `no_std` environments. Compressed patch data requires
+//! `std`: [encode] always writes data as is, while [decode] can only decompress data when the
+//! `compression` feature is enabled.
+
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+
+use bytes::Bytes;
+use ed25519::ComponentBytes;
+
+use crate::crypto::{CryptoSuite, Ed25519Blake3};
+use crate::patch::{
+    ContentType, Deps, Patch, ENCODING_RAW, ENCODING_ZSTD, ID, MAX_DATA_LEN, MAX_DEPS_LEN,
+};
+use crate::PeerID;
+
+/// Reason why a byte slice could not be decoded into a patch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Input ended before the whole patch was decoded.
+    UnexpectedEnd,
+    /// Input is not a valid patch encoding.
+    Invalid(&'static str),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => f.write_str("unexpected end of patch encoding"),
+            DecodeError::Invalid(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl core::error::Error for DecodeError {}
+
+/// Appends encoding of a given patch to a buffer. Patch data is never compressed.
+pub fn encode(patch: &Patch, buf: &mut Vec<u8>) {
+    encode_header(patch, ENCODING_RAW, patch.data.len(), buf);
+    buf.extend_from_slice(&patch.data);
+}
+
+/// Appends everything but the data of a given patch to a buffer. Data is expected to follow,
+/// encoded with a given encoding and taking `data_len` bytes.
+pub(crate) fn encode_header(patch: &Patch, encoding: u8, data_len: usize, buf: &mut Vec<u8>) {
+    write_varint(patch.deps.len() as u32, buf);
+    write_varint(data_len as u32, buf);
+    // content type takes the upper half of the encoding byte
+    buf.push(encoding | (patch.content_type as u8) << 4);
+    buf.extend_from_slice(patch.sign.r_bytes());
+    buf.extend_from_slice(patch.sign.s_bytes());
+    buf.extend_from_slice(patch.author.as_bytes());
+    for dep in patch.deps.iter() {
+        buf.extend_from_slice(dep);
+    }
+}
+
+/// Decodes a patch from the beginning of a given slice. Returns the patch and a number of bytes
+/// it took, so that consecutive patches can be decoded from the same buffer.
+pub fn decode(buf: &[u8]) -> Result<(Patch, usize), DecodeError> {
+    decode_with::<Ed25519Blake3>(buf, MAX_DATA_LEN)
+}
+
+/// Equivalent of [decode] for patches created with a given [CryptoSuite], which rejects patches
+/// with more than `max_data_len` bytes of data. Limit greater than [MAX_DATA_LEN] has no effect.
+pub fn decode_with<C: CryptoSuite>(
+    buf: &[u8],
+    max_data_len: usize,
+) -> Result<(Patch, usize), DecodeError> {
+    let mut r = SliceReader { buf, pos: 0 };
+    let deps_len = r.varint()? as usize;
+    let data_len = r.varint()? as usize;
+    if deps_len > MAX_DEPS_LEN {
+        return Err(DecodeError::Invalid("patch dependency count exceeds limit"));
+    }
+    let max_data_len = max_data_len.min(MAX_DATA_LEN);
+    if data_len > max_data_len {
+        return Err(DecodeError::Invalid("patch data length exceeds limit"));
+    }
+    let encoding = r.array::<1>()?[0];
+    let content_type = content_type(encoding >> 4)?;
+    let r_bytes: ComponentBytes = r.array()?;
+    let s_bytes: ComponentBytes = r.array()?;
+    let author = PeerID::new(r.array()?);
+    let mut deps = Deps::with_capacity(deps_len);
+    for _ in 0..deps_len {
+        deps.insert(ID(r.array()?));
+    }
+    let data = r.take(data_len)?;
+    let data = match encoding & 0x0f {
+        ENCODING_RAW => Bytes::copy_from_slice(data),
+        #[cfg(feature = "compression")]
+        ENCODING_ZSTD => super::decode_data(ENCODING_ZSTD, data.to_vec(), max_data_len)
+            .map_err(|_| DecodeError::Invalid("malformed compressed patch data"))?,
+        #[cfg(not(feature = "compression"))]
+        ENCODING_ZSTD => {
+            return Err(DecodeError::Invalid(
+                "compressed patch data requires `compression` feature",
+            ))
+        }
+        _ => return Err(DecodeError::Invalid("unknown patch data encoding")),
+    };
+    let mut patch = Patch {
+        id: ID::default(),
+        deps,
+        author,
+        sign: ed25519::Signature::from_components(r_bytes, s_bytes),
+        data,
+        content_type,
+    };
+    patch.id = patch.hash::<C>();
+    Ok((patch, r.pos))
+}
+
+pub(crate) fn content_type(bits: u8) -> Result<ContentType, DecodeError> {
+    match bits {
+        0 => Ok(ContentType::Opaque),
+        1 => Ok(ContentType::Op),
+        _ => Err(DecodeError::Invalid("unknown patch content type")),
+    }
+}
+
+/// Writes an unsigned LEB128 varint, the same way [varint_rs::VarintWriter] does.
+fn write_varint(mut value: u32, buf: &mut Vec<u8>) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+struct SliceReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SliceReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .ok_or(DecodeError::UnexpectedEnd)?;
+        let bytes = self
+            .buf
+            .get(self.pos..end)
+            .ok_or(DecodeError::UnexpectedEnd)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn varint(&mut self) -> Result<u32, DecodeError> {
+        let mut value = 0u32;
+        for shift in (0..32).step_by(7) {
+            let byte = self.array::<1>()?[0];
+            value |= ((byte & 0x7f) as u32) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::Invalid("varint is too long"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ed25519_dalek::SigningKey;
+
+    use crate::crypto::Ed25519Blake3;
+    use crate::patch::codec::{decode, decode_with, encode, DecodeError};
+    use crate::patch::Patch;
+
+    #[test]
+    fn slice_roundtrip() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let b = Patch::new(&key, [*a.id()], &vec![7u8; 300]).unwrap();
+
+        let mut buf = Vec::new();
+        encode(&a, &mut buf);
+        encode(&b, &mut buf);
+        let (decoded, len) = decode(&buf).unwrap();
+        assert_eq!(decoded, a);
+        let (decoded, rest) = decode(&buf[len..]).unwrap();
+        assert_eq!(decoded, b);
+        assert_eq!(len + rest, buf.len());
+
+        // the same wire format as the stream-based API
+        let mut written = Vec::new();
+        b.write(&mut written).unwrap();
+        assert_eq!(&written[..], &buf[len..]);
+        assert_eq!(Patch::read(&mut &buf[..len]).unwrap(), a);
+
+        for end in 0..len {
+            assert_eq!(decode(&buf[..end]), Err(DecodeError::UnexpectedEnd));
+        }
+        assert!(matches!(
+            decode_with::<Ed25519Blake3>(&buf[len..], 100),
+            Err(DecodeError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,74 @@
+//! Newline-delimited JSON form of patches, meant for scripting and producers written in other
+//! languages, which don't implement the binary wire format. Every line is a JSON object:
+//!
+//! ```json
+//! {"deps":["<hex>",...],"author":"<hex>","sign":"<hex>","data":<json>}
+//! ```
+//!
+//! `data` is embedded verbatim: its exact bytes are covered by the patch signature, so producers
+//! must sign exactly the JSON text they write (eg. produced with [crate::patch::canonical_json]).
+//! Optional `content_type` (`"opaque"` or `"op"`) is written only if it differs from the one
+//! detected from data, which is the default when it's missing. Patches which data is not JSON
+//! can't be represented in this form.
+
+use bytes::Bytes;
+use serde::de::Error as _;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use crate::crypto::CryptoSuite;
+use crate::patch::{ContentType, Deps, Patch, ID};
+use crate::{PeerID, Result};
+
+#[derive(Serialize, Deserialize)]
+struct Line<'a> {
+    deps: Vec<String>,
+    author: PeerID,
+    sign: String,
+    #[serde(borrow)]
+    data: &'a RawValue,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<ContentType>,
+}
+
+/// Serializes a given patch into a single line of JSON, without trailing newline. Fails if patch
+/// data is not JSON.
+pub fn to_line(patch: &Patch) -> Result<String> {
+    let data = std::str::from_utf8(patch.data()).map_err(serde_json::Error::custom)?;
+    let data: &RawValue = serde_json::from_str(data)?;
+    let content_type = Some(patch.content_type())
+        .filter(|content_type| *content_type != ContentType::detect(patch.data()));
+    let line = Line {
+        deps: patch.deps().iter().map(ID::to_string).collect(),
+        author: *patch.author(),
+        sign: hex::encode(patch.sign.to_bytes()),
+        data,
+        content_type,
+    };
+    Ok(serde_json::to_string(&line)?)
+}
+
+/// Reconstructs a patch created with a given [CryptoSuite] from its JSON line. Patch ID is
+/// computed from its content, but its signature is not verified.
+pub fn from_line_with<C: CryptoSuite>(line: &str) -> Result<Patch> {
+    let line: Line = serde_json::from_str(line)?;
+    let mut deps = Deps::with_capacity(line.deps.len());
+    for dep in line.deps.iter() {
+        deps.insert(ID::from_hex(dep).map_err(serde_json::Error::custom)?);
+    }
+    let mut sign = [0; 64];
+    hex::decode_to_slice(&line.sign, &mut sign).map_err(serde_json::Error::custom)?;
+    let data = Bytes::copy_from_slice(line.data.get().as_bytes());
+    let mut patch = Patch {
+        id: ID::default(),
+        deps,
+        author: line.author,
+        sign: ed25519::Signature::from_bytes(&sign),
+        content_type: line
+            .content_type
+            .unwrap_or_else(|| ContentType::detect(&data)),
+        data,
+    };
+    patch.id = patch.hash::<C>();
+    Ok(patch)
+}
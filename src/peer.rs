@@ -1,181 +1,2394 @@
+use bytes::Bytes;
 use ed25519_dalek::SigningKey;
 use serde::Serialize;
 
-use crate::patch::{Patch, ID};
-use crate::store::ObjectStore;
-use crate::{PeerID, Result};
+use std::borrow::{Borrow, Cow};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
 
+use varint_rs::{VarintReader, VarintWriter};
+
+use crate::bloom::BloomFilter;
+use crate::crypto::{CryptoSuite, Ed25519Blake3};
+use crate::doc::Document;
+use crate::op::{Op, Value};
+use crate::patch::{
+    canonical_json, find_cycle, ndjson, topo_sort, ContentType, Patch, ID, MAX_DATA_LEN,
+};
+use crate::peer::sink::PatchSink;
+use crate::peer::sync::Ack;
+use crate::permissions::Permissions;
+use crate::store::memory::MemoryStore;
+use crate::store::{AsyncObjectStore, Checkpoint, ObjectStore, StoreStats};
+use crate::{Error, PeerID, Result};
+
+pub mod gossip;
+pub mod shared;
+pub mod sink;
+pub mod sync;
+
+/// False-positive rate of bloom filters produced by [Peer::have_filter].
+pub const HAVE_FILTER_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+/// Number of patches loaded from the store at once by [Peer::export_snapshot].
+const SNAPSHOT_CHUNK_LEN: usize = 1024;
+
+/// Magic bytes opening every bundle written by [Peer::export_bundle].
+pub const BUNDLE_MAGIC: &[u8; 8] = b"STBUNDLE";
+
+/// Version of the bundle format written by [Peer::export_bundle]. Any change in the format must
+/// bump it. Version 2 was introduced together with [crate::patch::SIGNATURE_DOMAIN], since
+/// signatures of patches in version 1 bundles no longer verify.
+pub const BUNDLE_VERSION: u8 = 2;
+
+/// Stream wrapper computing a checksum of all bytes written or read through it.
+struct Checksummed<T> {
+    inner: T,
+    hasher: blake3::Hasher,
+}
+
+impl<T> Checksummed<T> {
+    fn new(inner: T) -> Self {
+        Checksummed {
+            inner,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Checksummed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Outcome of [Peer::integrate].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrateReport {
+    /// IDs of patches committed into the store, in commit order.
+    pub committed: Vec<ID>,
+    /// IDs of patches left stashed, waiting for their dependencies.
+    pub stashed: Vec<ID>,
+    /// IDs of dependencies not found in the store, which need to be requested from remote peers.
+    pub missing: Vec<ID>,
+    /// IDs of received patches, which were already known.
+    pub duplicates: Vec<ID>,
+    /// IDs of patches rejected by access control (see [Peer::with_access_control]), together with
+    /// patches depending on them.
+    pub rejected: Vec<ID>,
+}
+
+impl IntegrateReport {
+    /// IDs of dependencies not found in the store, which need to be requested from remote peers.
+    pub fn missing(&self) -> &[ID] {
+        &self.missing
+    }
+}
+
+/// Outcome of [Peer::integrate_one].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrateOutcome {
+    /// Patch has been committed. Contains IDs of all patches committed as a result, in commit
+    /// order: the patch itself, followed by previously stashed patches which were waiting for it.
+    Committed(Vec<ID>),
+    /// Patch has been stashed until its dependencies arrive. Contains dependencies, which need to
+    /// be requested from remote peers (see [IntegrateReport::missing]).
+    Stashed(Vec<ID>),
+    /// Patch was already known.
+    Duplicate,
+    /// Patch author is not allowed to perform its operation, so it has been rejected (see
+    /// [Peer::with_access_control]).
+    Unauthorized,
+}
+
+/// Peer working on a DAG of patches, which are identified and signed using a given [CryptoSuite].
+/// Underlying store must use the same suite.
 #[derive(Debug)]
-pub struct Peer<S> {
-    signing_key: SigningKey,
+pub struct Peer<S, C: CryptoSuite = Ed25519Blake3> {
+    signing_key: C::SigningKey,
     store: S,
     heads: Vec<ID>,
+    access_control: bool,
+    max_patch_bytes: usize,
+    checkpoint: Option<ID>,
+    /// Store generation, at which `heads` were last known to be up to date.
+    generation: u64,
+    listeners: Listeners,
+    fetcher: Option<Fetcher>,
 }
 
-impl<S: ObjectStore> Peer<S> {
-    pub fn new(signing_key: SigningKey, store: S) -> Result<Self> {
-        let heads = store.heads()?;
-        Ok(Peer {
+type Listener = Box<dyn FnMut(&Patch) + Send>;
+
+/// Callbacks registered with [Peer::on_commit].
+#[derive(Default)]
+struct Listeners(Vec<Listener>);
+
+impl Listeners {
+    fn notify(&mut self, patch: &Patch) {
+        for listener in self.0.iter_mut() {
+            listener(patch);
+        }
+    }
+}
+
+impl std::fmt::Debug for Listeners {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Listeners({})", self.0.len())
+    }
+}
+
+type FetchFn = dyn FnMut(&[ID]) -> Result<Vec<Patch>> + Send;
+
+/// Callback registered with [Peer::set_fetcher].
+struct Fetcher(Box<FetchFn>);
+
+impl std::fmt::Debug for Fetcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Fetcher")
+    }
+}
+
+impl<S, C: CryptoSuite> Peer<S, C> {
+    /// Creates a peer with default settings over a store with given heads and generation.
+    fn from_parts(signing_key: C::SigningKey, store: S, heads: Vec<ID>, generation: u64) -> Self {
+        Peer {
             signing_key,
             store,
             heads,
-        })
+            access_control: false,
+            max_patch_bytes: MAX_DATA_LEN,
+            checkpoint: None,
+            generation,
+            listeners: Listeners::default(),
+            fetcher: None,
+        }
     }
 
     pub fn peer_id(&self) -> PeerID {
-        self.signing_key.verifying_key().to_bytes()
+        C::peer_id(&self.signing_key)
     }
 
+    /// Returns current heads of the DAG. Heads are cached by the peer and only updated on its own
+    /// commits and integrations. If the underlying store has been modified by other means (e.g.
+    /// directly through [Peer::store] or by another peer sharing the same database), returned heads
+    /// may be stale until [Peer::refresh_heads] is called. Commits and integrations check the
+    /// [ObjectStore::generation] and refresh heads on their own before building on top of them.
     pub fn heads(&self) -> &[ID] {
         self.heads.as_slice()
     }
 
+    /// Returns the underlying store. Patches committed directly to it are not reflected in
+    /// [Peer::heads] until heads are refreshed.
     pub fn store(&self) -> &S {
         &self.store
     }
 
+    /// Returns true if this peer enforces [Permissions] of committed and integrated patches.
+    pub fn access_control(&self) -> bool {
+        self.access_control
+    }
+
+    /// ID of the most recent [Op::Prune] patch issued by the document owner, which has been
+    /// committed or integrated by this peer. All patches in its causal past are considered
+    /// stable: they form the sealed base of the [crate::doc::Document] state.
+    pub fn checkpoint(&self) -> Option<&ID> {
+        self.checkpoint.as_ref()
+    }
+
+    /// Registers a callback fired once for every patch committed by this peer, either locally or
+    /// by integrating it. Duplicated and stashed patches don't fire it until they are committed.
+    /// Patches committed at once are passed in topological order.
+    pub fn on_commit<F>(&mut self, f: F)
+    where
+        F: FnMut(&Patch) + Send + 'static,
+    {
+        self.listeners.0.push(Box::new(f));
+    }
+
+    /// Sets the maximum length (in bytes) of data of patches committed by this peer. Larger
+    /// payloads are rejected with [Error::PatchTooLarge] before reaching the store.
+    /// Default: [MAX_DATA_LEN].
+    pub fn with_max_patch_bytes(mut self, max_patch_bytes: usize) -> Self {
+        self.max_patch_bytes = max_patch_bytes;
+        self
+    }
+
+    /// Fails with [Error::PatchTooLarge] if data of a patch about to be committed by this peer
+    /// exceeds the limit set with [Peer::with_max_patch_bytes].
+    fn check_size(&self, patch: &Patch) -> Result<()> {
+        if patch.data().len() > self.max_patch_bytes {
+            return Err(Error::PatchTooLarge(*patch.id()));
+        }
+        Ok(())
+    }
+
+    /// Records a given [Op::Prune] patch as the current checkpoint, if it's issued by the owner
+    /// and descends from the previous checkpoint. `history` is the causal past of the patch.
+    fn update_checkpoint(&mut self, patch: &Patch, history: &[Patch]) {
+        let permissions = Permissions::resolve(history.iter());
+        if !permissions.is_owner(patch.author()) {
+            return;
+        }
+        let descends = match &self.checkpoint {
+            None => true,
+            Some(checkpoint) => history.iter().any(|p| p.id() == checkpoint),
+        };
+        if descends {
+            self.checkpoint = Some(*patch.id());
+        }
+    }
+}
+
+impl<S: ObjectStore> Peer<S> {
+    pub fn new(signing_key: SigningKey, store: S) -> Result<Self> {
+        Self::with_suite(signing_key, store)
+    }
+}
+
+impl<S: ObjectStore, C: CryptoSuite> Peer<S, C> {
+    /// Creates a peer, which identifies and signs its patches using a given [CryptoSuite].
+    pub fn with_suite(signing_key: C::SigningKey, store: S) -> Result<Self> {
+        let generation = store.generation()?;
+        let heads = store.heads()?;
+        Ok(Peer::from_parts(signing_key, store, heads, generation))
+    }
+
+    /// Enables or disables access control. When enabled, patches which authors lack the rights to
+    /// perform their operations (as defined by [Permissions] at the patch position in the DAG) are
+    /// rejected: local commits fail with [Error::Unauthorized], while received patches (and the
+    /// ones depending on them) are listed in [IntegrateReport::rejected].
+    pub fn with_access_control(mut self, enabled: bool) -> Self {
+        self.access_control = enabled;
+        self
+    }
+
+    /// Registers a callback used by [Peer::integrate] to request missing dependencies of
+    /// integrated patches, eg. from a remote peer over a request/response transport. Patches
+    /// returned by it are integrated right away, and dependencies they are missing in turn are
+    /// requested as well, until nothing is missing or the fetcher returns no patches.
+    ///
+    /// Every missing ID is requested at most once within a single [Peer::integrate] call, so a
+    /// fetcher unable to supply some dependencies can't make it loop forever. Such dependencies are
+    /// reported as [IntegrateReport::missing], like without a fetcher.
+    ///
+    /// Fetchers are only available to peers over an [ObjectStore]: [Peer::integrate_async] always
+    /// reports missing dependencies for the caller to request.
+    pub fn set_fetcher<F>(&mut self, f: F)
+    where
+        F: FnMut(&[ID]) -> Result<Vec<Patch>> + Send + 'static,
+    {
+        self.fetcher = Some(Fetcher(Box::new(f)));
+    }
+
+    /// Re-reads current heads from the store, discarding the cached ones.
+    pub fn refresh_heads(&mut self) -> Result<()> {
+        // generation is read first: if store changes in between, heads are refreshed again later
+        self.generation = self.store.generation()?;
+        self.heads = self.store.heads()?;
+        Ok(())
+    }
+
+    /// Refreshes cached heads if the store has been modified by other writers since they were
+    /// last read.
+    fn sync_heads(&mut self) -> Result<()> {
+        if self.store.generation()? != self.generation {
+            self.refresh_heads()?;
+        }
+        Ok(())
+    }
+
     pub fn commit<B>(&mut self, data: &B) -> Result<Patch>
     where
         B: Serialize,
     {
-        let patch = Patch::new(&self.signing_key, self.heads().iter().cloned(), data)?;
+        self.sync_heads()?;
+        let patch =
+            Patch::new_with::<C, _, _>(&self.signing_key, self.heads().iter().cloned(), data)?;
+        self.commit_patch(patch)
+    }
+
+    /// Commits given bytes on top of the current heads as they are, without serializing them
+    /// first. This way applications can encode patch data in formats other than JSON.
+    pub fn commit_raw<B>(&mut self, data: B) -> Result<Patch>
+    where
+        B: Into<Bytes>,
+    {
+        self.sync_heads()?;
+        let patch =
+            Patch::new_raw_with::<C, _, _>(&self.signing_key, self.heads().iter().cloned(), data);
+        self.commit_patch(patch)
+    }
+
+    /// Commits a local patch with given data, eg. UI state or drafts. Local patches are neither
+    /// signed nor synced: they're not a part of [Peer::heads], delta or any other sync results,
+    /// and patches integrated from remote peers are never local. They form a separate history,
+    /// each one depending on the previous [Peer::local_heads].
+    pub fn commit_local<B>(&self, data: &B) -> Result<Patch>
+    where
+        B: Serialize,
+    {
+        let data = canonical_json(data)?;
+        let content_type = ContentType::detect(&data);
+        let deps = self.local_heads()?.into_iter().collect();
+        let patch = Patch::local::<C>(deps, data.into(), content_type);
+        self.store.commit_local(&patch)?;
+        Ok(patch)
+    }
+
+    /// Returns all local patches in the order they were committed, see [Peer::commit_local].
+    pub fn local_patches(&self) -> Result<Vec<Patch>> {
+        self.store.local_patches()
+    }
+
+    /// Returns IDs of local patches, which no other local patch depends on.
+    pub fn local_heads(&self) -> Result<Vec<ID>> {
+        let patches = self.store.local_patches()?;
+        let parents: HashSet<&ID> = patches.iter().flat_map(|p| p.deps().iter()).collect();
+        Ok(patches
+            .iter()
+            .map(|p| *p.id())
+            .filter(|id| !parents.contains(id))
+            .collect())
+    }
+
+    /// Commits data on top of explicitly given dependencies instead of the current heads. This way
+    /// history can be grafted or replayed at any point of the DAG. Fails with
+    /// [Error::MissingDependency] if any of the dependencies has not been integrated. Duplicated
+    /// dependencies are ignored.
+    pub fn commit_on<B>(&mut self, deps: &[ID], data: &B) -> Result<Patch>
+    where
+        B: Serialize,
+    {
+        self.sync_heads()?;
+        for dep in deps {
+            if !self.store.is_integrated(dep)? {
+                return Err(Error::MissingDependency(*dep));
+            }
+        }
+        let patch = Patch::new_with::<C, _, _>(&self.signing_key, deps.iter().cloned(), data)?;
+        self.commit_patch(patch)
+    }
+
+    /// Undoes a given integrated patch by committing the compensating operation (see
+    /// [Op::invert]), instead of rewriting history. The state preceding the undone patch is
+    /// materialized from its causal past. The new patch depends only on the undone one, so that it
+    /// compensates exactly its changes, as seen by its author: changes made since then are kept,
+    /// and the remaining heads stay heads until the next commit merges them.
+    ///
+    /// Fails with [Error::MissingDependency] if the patch has not been integrated,
+    /// [Error::InvalidOp] if its data is not an [Op] and [Error::NotInvertible] if it's opaque or
+    /// its operation can't be undone.
+    pub fn undo(&mut self, patch_id: &ID) -> Result<Patch> {
+        let Some(patch) = self.store.patches(&[*patch_id])?.pop() else {
+            return Err(Error::MissingDependency(*patch_id));
+        };
+        let op: Op = match patch.op() {
+            Some(op) => op.map_err(|source| Error::InvalidOp {
+                patch: *patch_id,
+                source,
+            })?,
+            None => return Err(Error::NotInvertible(*patch_id)),
+        };
+        let prior = Document::from_patches(&self.causal_past(&patch.deps()[..])?)?;
+        let prior_state = match &op {
+            Op::UpdateEntry(key, _) => prior.get(key).cloned().unwrap_or(Value::Null),
+            Op::RemoveRange(..) => Value::List(prior.sequence()),
+            _ => Value::Null,
+        };
+        let inverse = op
+            .invert(&prior_state)
+            .ok_or(Error::NotInvertible(*patch_id))?;
+        self.commit_on(&[*patch_id], &inverse)
+    }
+
+    /// Commits an explicit merge of some of the current heads, with `resolution` (eg. describing
+    /// how their conflicts were resolved) as its data. Unlike [Peer::commit], the patch depends
+    /// only on the given heads, so the remaining ones stay heads. Fails with [Error::NotHead] if
+    /// any of the given IDs is not a current head.
+    pub fn merge<B>(&mut self, heads: &[ID], resolution: &B) -> Result<Patch>
+    where
+        B: Serialize,
+    {
+        self.sync_heads()?;
+        if let Some(id) = heads.iter().find(|id| !self.heads.contains(id)) {
+            return Err(Error::NotHead(*id));
+        }
+        let patch =
+            Patch::new_with::<C, _, _>(&self.signing_key, heads.iter().cloned(), resolution)?;
+        self.commit_patch(patch)
+    }
+
+    /// Commits [Op::Prune] on top of the current heads, revoking all moderator rights and
+    /// stabilizing the document state. Only the document owner is allowed to prune.
+    pub fn prune(&mut self) -> Result<Patch> {
+        self.sync_heads()?;
+        let patch = Patch::new_with::<C, _, _>(
+            &self.signing_key,
+            self.heads().iter().cloned(),
+            &Op::Prune,
+        )?;
+        if !self.permissions()?.is_owner(&self.peer_id()) {
+            return Err(Error::Unauthorized(*patch.id()));
+        }
+        self.commit_patch(patch)
+    }
+
+    /// Commits [Op::RotateKey] signed with the current key, which binds a given new key to the
+    /// identity of this peer, and switches to signing all following patches with the new key.
+    /// With access control enabled, patches signed with either key share the same rights.
+    pub fn rotate_key(&mut self, new_key: C::SigningKey) -> Result<Patch> {
+        self.sync_heads()?;
+        let op = Op::RotateKey(C::peer_id(&new_key));
+        let patch =
+            Patch::new_with::<C, _, _>(&self.signing_key, self.heads().iter().cloned(), &op)?;
+        let patch = self.commit_patch(patch)?;
+        self.signing_key = new_key;
+        Ok(patch)
+    }
+
+    /// Replaces a linear run of patches, starting at `from` and ending at `to`, with a single
+    /// patch, which depends on the dependencies of `from`. Data of the new patch is a JSON array
+    /// of data of the replaced patches, in causal order. Replaced patches are removed from the
+    /// store. Returns the new patch.
+    ///
+    /// Since the new patch has a different ID, the run must satisfy the following invariants,
+    /// otherwise squashing fails with [Error::NonLinearRun] without modifying the store:
+    /// - All patches of the run are integrated and authored by this peer.
+    /// - Every patch of the run, except for `from`, has exactly one dependency: its predecessor.
+    /// - Every patch of the run, except for `to`, has exactly one child: its successor. `to` has
+    ///   no children at all, so no other patch refers to the replaced IDs.
+    /// - The run doesn't contain the current [Peer::checkpoint].
+    ///
+    /// Fails with [Error::Pinned] if any patch of the run is pinned. If the run stops satisfying
+    /// these invariants while it's being removed (eg. because another writer sharing the store
+    /// added a child to it), squashing fails with [Error::NonLinearRun]. The removed run is
+    /// committed back, whenever squashing fails after removing it.
+    ///
+    /// Op-aware consumers, like [crate::doc::Document], don't recognize the squashed data as an
+    /// [Op], so runs meant for squashing are usually archival ones.
+    pub fn squash(&mut self, from: ID, to: ID) -> Result<Patch> {
+        self.sync_heads()?;
+        let pinned = self.store.pinned()?;
+        let mut run: Vec<Patch> = Vec::new();
+        let mut current = to;
+        loop {
+            let patch = match self.store.patches(&[current])?.pop() {
+                Some(patch) if patch.author() == &self.peer_id() => patch,
+                _ => return Err(Error::NonLinearRun(current)),
+            };
+            if self.checkpoint == Some(current) {
+                return Err(Error::NonLinearRun(current));
+            }
+            if pinned.contains(&current) {
+                return Err(Error::Pinned(current));
+            }
+            let children = self.store.children(&current)?;
+            let expected: &[ID] = match run.last() {
+                None => &[],
+                Some(next) => std::slice::from_ref(next.id()),
+            };
+            if children != expected {
+                return Err(Error::NonLinearRun(current));
+            }
+            if current == from {
+                run.push(patch);
+                break;
+            }
+            current = match &patch.deps()[..] {
+                [parent] => *parent,
+                _ => return Err(Error::NonLinearRun(current)),
+            };
+            run.push(patch);
+        }
+        run.reverse();
+
+        let data = run
+            .iter()
+            .map(|patch| serde_json::from_slice(patch.data()))
+            .collect::<std::result::Result<Vec<serde_json::Value>, _>>()?;
+        let deps = run[0].deps().iter().cloned();
+        let squashed = Patch::new_with::<C, _, _>(&self.signing_key, deps, &data)?;
+        self.check_size(&squashed)?;
+        self.authorize(&squashed)?;
+        // the run is removed at once, so that a child or a pin added meanwhile by another writer
+        // can't leave part of it behind
+        let removed = self.store.remove_cascade(&from)?;
+        let expected: Vec<ID> = run.iter().rev().map(|patch| *patch.id()).collect();
+        let result = if removed == expected {
+            self.commit_patch(squashed)
+        } else {
+            Err(Error::NonLinearRun(from))
+        };
+        if result.is_err() {
+            self.store.commit_batch(&run)?;
+        }
+        self.refresh_heads()?;
+        result
+    }
+
+    /// Pins an integrated patch, so that neither it nor any patch in its causal past can be
+    /// removed, eg. by [Peer::squash]. Returns false if patch is not integrated.
+    pub fn pin(&mut self, id: &ID) -> Result<bool> {
+        self.store.pin(id)
+    }
+
+    /// Unpins a patch pinned with [Peer::pin]. Returns false if patch was not pinned.
+    pub fn unpin(&mut self, id: &ID) -> Result<bool> {
+        self.store.unpin(id)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(
+                id = %patch.id(),
+                author = %patch.author(),
+                deps = patch.deps().len(),
+            )
+        )
+    )]
+    fn commit_patch(&mut self, patch: Patch) -> Result<Patch> {
+        timed!();
+        self.check_size(&patch)?;
+        self.authorize(&patch)?;
         self.store.commit(&patch)?;
-        self.heads = vec![*patch.id()];
-        return Ok(patch);
+        self.track_checkpoint(&patch)?;
+        if self.store.generation()? == self.generation + 1 {
+            // heads are never ancestors of other patches, so only the direct deps stop being heads
+            self.heads.retain(|id| !patch.deps().contains(id));
+            self.heads.push(*patch.id());
+            self.generation += 1;
+        } else {
+            // someone else has written to the store in the meantime
+            self.refresh_heads()?;
+        }
+        self.listeners.notify(&patch);
+        Ok(patch)
     }
 
-    pub fn integrate<I>(&mut self, patches: I) -> Result<Vec<ID>>
+    /// Retries integration of patches left in the stash, eg. by a previous session using the same
+    /// store, which dependencies may have been integrated in the meantime. Patches still missing
+    /// their dependencies are stashed again, so it's safe to call it on every startup. Stashed
+    /// patches already integrated by other means are reported as duplicates.
+    pub fn recover(&mut self) -> Result<IntegrateReport> {
+        let stashed = self.store.unstash()?;
+        self.integrate(stashed)
+    }
+
+    /// Integrates a single patch received from a remote peer and tells what happened to it. It
+    /// goes through the same verification and stashing as patches passed to [Peer::integrate].
+    pub fn integrate_one(&mut self, patch: Patch) -> Result<IntegrateOutcome> {
+        let id = *patch.id();
+        let report = self.integrate([patch])?;
+        Ok(if report.rejected.contains(&id) {
+            IntegrateOutcome::Unauthorized
+        } else if report.duplicates.contains(&id) {
+            IntegrateOutcome::Duplicate
+        } else if report.stashed.contains(&id) {
+            IntegrateOutcome::Stashed(report.missing)
+        } else {
+            IntegrateOutcome::Committed(report.committed)
+        })
+    }
+
+    /// Integrates patches received from a remote peer. Patches with dependencies not yet
+    /// integrated are stashed until these arrive. Returns a report of what happened to them.
+    ///
+    /// Patches don't need to come in causal order: they're sorted topologically (breaking ties
+    /// by their IDs) before being committed, so the commit order is the same for any order of
+    /// the input.
+    ///
+    /// Fails with [Error::CyclicDependency] if patches (including stashed ones) depend on each
+    /// other in a cycle, which can never be integrated. Patches rejected by access control don't
+    /// fail the call: they're skipped together with their descendants and listed in
+    /// [IntegrateReport::rejected].
+    ///
+    /// If a fetcher has been registered with [Peer::set_fetcher], missing dependencies are
+    /// fetched and integrated within the same call.
+    pub fn integrate<I>(&mut self, patches: I) -> Result<IntegrateReport>
     where
         I: IntoIterator<Item = Patch>,
     {
-        let mut changed = false;
-        let mut missing = Vec::new();
-        let mut patches: Box<dyn Iterator<Item = Patch>> = Box::new(patches.into_iter());
+        self.integrate_cow(patches.into_iter().map(Cow::Owned).collect())
+    }
+
+    /// Equivalent of [Peer::integrate], which doesn't take ownership of given patches, so that
+    /// the caller can keep using them afterwards (eg. to forward them to other peers). Patches
+    /// are only copied if they need to be stashed.
+    pub fn integrate_refs(&mut self, patches: &[Patch]) -> Result<IntegrateReport> {
+        self.integrate_cow(patches.iter().map(Cow::Borrowed).collect())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "integrate",
+            level = "debug",
+            skip_all,
+            fields(received = tracing::field::Empty)
+        )
+    )]
+    fn integrate_cow(&mut self, patches: Vec<Cow<'_, Patch>>) -> Result<IntegrateReport> {
+        timed!();
+        record!("received", patches.len());
+        let mut report = self.integrate_batch(patches)?;
+        if let Some(mut fetcher) = self.fetcher.take() {
+            let result = self.fetch_missing(&mut fetcher, &mut report);
+            self.fetcher = Some(fetcher);
+            result?;
+        }
+        debug_event!(
+            committed = report.committed.len(),
+            stashed = report.stashed.len(),
+            duplicates = report.duplicates.len(),
+            missing = report.missing.len(),
+            rejected = report.rejected.len(),
+            "integrated"
+        );
+        Ok(report)
+    }
+
+    /// Keeps fetching and integrating missing dependencies of patches integrated so far, merging
+    /// the outcome into a given report.
+    fn fetch_missing(&mut self, fetcher: &mut Fetcher, report: &mut IntegrateReport) -> Result<()> {
+        let mut requested = HashSet::new();
+        loop {
+            let wanted: Vec<ID> = report
+                .missing
+                .iter()
+                .filter(|id| !requested.contains(*id))
+                .cloned()
+                .collect();
+            if wanted.is_empty() {
+                return Ok(());
+            }
+            requested.extend(wanted.iter().cloned());
+            let fetched = (fetcher.0)(&wanted)?;
+            if fetched.is_empty() {
+                return Ok(());
+            }
+            let next = self.integrate_batch(fetched.into_iter().map(Cow::Owned).collect())?;
+            report.committed.extend(next.committed);
+            report.duplicates.extend(next.duplicates);
+            report.stashed.extend(next.stashed);
+            report.missing.extend(next.missing);
+            report.rejected.extend(next.rejected);
+            let mut dedup = HashSet::new();
+            let mut stashed = Vec::with_capacity(report.stashed.len());
+            for id in report.stashed.drain(..) {
+                if dedup.insert(id) && !self.store.is_integrated(&id)? {
+                    stashed.push(id);
+                }
+            }
+            report.stashed = stashed;
+            let mut missing = Vec::with_capacity(report.missing.len());
+            for id in report.missing.drain(..) {
+                if dedup.insert(id) && !self.store.contains(&id)? {
+                    missing.push(id);
+                }
+            }
+            report.missing = missing;
+        }
+    }
+
+    fn integrate_batch(&mut self, mut patches: Vec<Cow<'_, Patch>>) -> Result<IntegrateReport> {
+        self.sync_heads()?;
+        let mut report = IntegrateReport::default();
+        let mut unstashed = false;
+        let mut revisited = false;
+        // patches are already verified once before being stashed, don't repeat that on unstash
+        let mut verified = HashSet::new();
+        let mut rejected = HashSet::new();
         loop {
+            if let Some(cycle) = find_cycle(&patches) {
+                if unstashed {
+                    // keep patches, which are not a part of the cycle, waiting for their deps
+                    for patch in patches.iter().filter(|p| !cycle.contains(p.id())) {
+                        self.store.stash(patch)?;
+                    }
+                }
+                return Err(Error::CyclicDependency(cycle[0]));
+            }
+            // commit order doesn't depend on the input order, and patches arriving ahead of their
+            // dependencies within the same batch don't need to go through the stash
+            patches = topo_sort(patches);
+
+            // patches ready to be committed, together with their IDs, so that patches depending
+            // on them can join the same batch
+            let mut batch = Vec::new();
+            let mut batched = HashSet::new();
+            let mut changed = false;
+            // true if some patch is waiting for another stashed patch
+            let mut awaits_stashed = false;
+            report.stashed.clear();
+
+            // skip already known patches and verify signatures of the rest at once
+            let mut seen = HashSet::new();
+            let mut fresh = Vec::with_capacity(patches.len());
             for patch in patches {
-                patch.verify()?;
-                if !self.store.contains(patch.id())? {
-                    let mut stashed = false;
-                    for dep in patch.deps().iter() {
-                        if !self.store.is_integrated(dep)? {
-                            self.store.stash(&patch)?;
-                            if !missing.contains(dep) {
-                                missing.push(*dep);
-                            }
-                            stashed = true;
-                        }
+                if !seen.insert(*patch.id()) || self.store.contains(patch.id())? {
+                    if !unstashed {
+                        report.duplicates.push(*patch.id());
                     }
+                    continue;
+                }
+                fresh.push(patch);
+            }
+            let unverified: Vec<&Patch> = fresh
+                .iter()
+                .map(|p| p.as_ref())
+                .filter(|p| !verified.contains(p.id()))
+                .collect();
+            Patch::verify_batch_with::<C>(&unverified)?;
+            verified.extend(unverified.iter().map(|p| *p.id()));
 
-                    if !stashed {
-                        self.store.commit(&patch)?;
+            let deps: Vec<ID> = fresh
+                .iter()
+                .flat_map(|p| p.deps().iter().cloned())
+                .collect();
+            let mut integrated = self.store.integrated_set(&deps)?;
+            let mut pending = fresh.into_iter();
+            let placed = (|| -> Result<()> {
+                for patch in pending.by_ref() {
+                    if patch.deps().iter().any(|dep| rejected.contains(dep)) {
+                        rejected.insert(*patch.id());
+                        report.rejected.push(*patch.id());
+                        continue;
+                    }
+                    let missing: Vec<ID> = patch
+                        .deps()
+                        .iter()
+                        .filter(|dep| !batched.contains(*dep) && !integrated.contains(*dep))
+                        .cloned()
+                        .collect();
+                    for dep in missing.iter() {
+                        awaits_stashed |= self.store.contains(dep)?;
+                        if !report.missing.contains(dep) {
+                            report.missing.push(*dep);
+                        }
+                    }
+                    if !missing.is_empty() {
+                        self.store.stash(&patch)?;
+                        report.stashed.push(*patch.id());
+                    } else {
+                        // permissions are resolved from the store, so patches they depend on
+                        // must be committed first
+                        let depends_on_batch = patch.deps().is_empty()
+                            || patch.deps().iter().any(|dep| batched.contains(dep));
+                        if self.access_control && depends_on_batch && !batch.is_empty() {
+                            self.commit_batch(&batch)?;
+                            batch.clear();
+                            integrated.extend(batched.drain());
+                        }
+                        if !self.is_authorized(&patch)? {
+                            rejected.insert(*patch.id());
+                            report.rejected.push(*patch.id());
+                            continue;
+                        }
+                        batched.insert(*patch.id());
+                        report.committed.push(*patch.id());
+                        batch.push(patch);
                         changed = true;
                     }
                 }
+                Ok(())
+            })();
+            if let Err(e) = placed {
+                self.abort_round(&batch, pending, unstashed)?;
+                return Err(e);
             }
 
-            if changed {
-                changed = false;
-                self.heads = self.store.heads()?;
-                patches = Box::new(self.store.unstash()?.into_iter());
+            // stashed patches are revisited at least once, so that cycles spanning across multiple
+            // integrate calls are detected
+            if changed || (awaits_stashed && !revisited) {
+                revisited |= !changed;
+                if !batch.is_empty() {
+                    self.commit_batch(&batch)?;
+                }
+                patches = self.store.unstash()?.into_iter().map(Cow::Owned).collect();
+                unstashed = true;
             } else {
                 break;
             }
         }
 
-        Ok(missing)
+        // dependencies, which arrived later or are stashed themselves, are no longer missing
+        let mut missing = std::mem::take(&mut report.missing);
+        for dep in missing.drain(..) {
+            if !self.store.contains(&dep)? {
+                report.missing.push(dep);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Keeps the outcome of an integrate round, which failed midway: commits patches which already
+    /// joined the batch (as they're reported committed) and stashes again the unstashed patches,
+    /// which were not looked at yet, so that they're not lost.
+    fn abort_round<'a>(
+        &mut self,
+        batch: &[Cow<'a, Patch>],
+        pending: impl Iterator<Item = Cow<'a, Patch>>,
+        unstashed: bool,
+    ) -> Result<()> {
+        if !batch.is_empty() {
+            self.commit_batch(batch)?;
+        }
+        if unstashed {
+            for patch in pending {
+                self.store.stash(&patch)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Produces an [Ack] of those of given patches, which have been integrated by this peer.
+    pub fn make_ack(&self, ids: &[ID]) -> Result<Ack> {
+        let integrated = self.store.integrated_set(ids)?;
+        Ok(Ack::new::<C>(
+            &self.signing_key,
+            integrated.into_iter().collect(),
+        ))
     }
 
     pub fn missing(&self, heads: &[ID]) -> Result<Vec<ID>> {
+        let integrated = self.store.integrated_set(heads)?;
         let mut missing = Vec::with_capacity(heads.len());
         for id in heads.iter() {
-            if !self.store.contains(id)? {
+            if !integrated.contains(id) && !self.store.contains(id)? {
                 missing.push(*id);
             }
         }
         Ok(missing)
     }
 
-    pub fn patches(&self, ids: &[ID]) -> Result<Vec<Patch>> {
-        self.store.patches(ids)
+    /// Returns IDs of patches this peer wants: dependencies of stashed patches, which are neither
+    /// integrated nor stashed themselves. Unlike [Peer::missing], this reaches past the heads of
+    /// a remote peer, so that it can send these patches proactively.
+    pub fn frontier(&self) -> Result<Vec<ID>> {
+        let mut frontier = Vec::new();
+        let mut visited = HashSet::new();
+        for patch in self.store.stashed()? {
+            for dep in patch.deps().iter() {
+                if visited.insert(*dep) && !self.store.contains(dep)? {
+                    frontier.push(*dep);
+                }
+            }
+        }
+        Ok(frontier)
     }
-}
-
-#[cfg(test)]
-mod test {
-    use ed25519_dalek::SigningKey;
 
-    use crate::patch::Patch;
-    use crate::peer::Peer;
-    use crate::store::sqlite::SqliteStore;
+    /// Returns IDs of integrated patches, which a remote peer with given heads is missing: ones in
+    /// the causal past of the local heads (inclusive), but not in the causal past of the remote
+    /// heads. IDs are returned in topological order, so that they can be sent and integrated as is.
+    ///
+    /// Remote heads unknown to this peer are ignored, as nothing can be said about their history.
+    /// In the worst case (none of the remote heads are known) the whole history is returned.
+    pub fn delta(&self, remote_heads: &[ID]) -> Result<Vec<ID>> {
+        Ok(self
+            .delta_patches(remote_heads)?
+            .iter()
+            .map(|p| *p.id())
+            .collect())
+    }
 
-    fn create_peer() -> Peer<SqliteStore> {
-        let conn = rusqlite::Connection::open_in_memory().unwrap();
-        let store = SqliteStore::new(conn).unwrap();
-        let key_pair = SigningKey::generate(&mut rand::rngs::OsRng);
-        Peer::new(key_pair, store).unwrap()
+    /// Sends patches of [Peer::delta] to a given sink one by one, in topological order, waiting
+    /// for each of them to be accepted before sending the next one. Returns number of sent
+    /// patches.
+    pub async fn stream_delta<K>(&self, remote_heads: &[ID], sink: &mut K) -> Result<usize>
+    where
+        K: PatchSink,
+    {
+        let delta = self.delta_patches(remote_heads)?;
+        let len = delta.len();
+        for patch in delta {
+            sink.send(patch).await?;
+        }
+        Ok(len)
     }
 
-    /// ```no_compile
-    ///      / B - D
-    ///     A    \
-    ///      \ C - E - F
-    /// ```
-    pub fn init_patches(p: &Peer<SqliteStore>) -> Vec<Patch> {
-        let a = Patch::new(&p.signing_key, [], &"A").unwrap();
-        let b = Patch::new(&p.signing_key, [*a.id()], &"B").unwrap();
-        let c = Patch::new(&p.signing_key, [*a.id()], &"C").unwrap();
-        let d = Patch::new(&p.signing_key, [*b.id()], &"D").unwrap();
-        let e = Patch::new(&p.signing_key, [*b.id(), *c.id()], &"E").unwrap();
-        let f = Patch::new(&p.signing_key, [*e.id()], &"F").unwrap();
+    /// Equivalent of [Peer::delta] for a remote peer, which only cares about a part of the document
+    /// (eg. keys under some prefix): only patches carrying an [Op] accepted by a given predicate
+    /// are returned, together with permission operations (see [Op::is_permission]), which are
+    /// needed to authorize them. Causal past of these patches (within the delta) is returned as
+    /// well, even if filtered out, so that the remote DAG stays connected.
+    ///
+    /// Patches, which data is not an [Op], are only returned as a part of such causal past.
+    pub fn delta_filtered<F>(&self, remote_heads: &[ID], predicate: F) -> Result<Vec<ID>>
+    where
+        F: Fn(&Op) -> bool,
+    {
+        let delta = self.delta_patches(remote_heads)?;
+        let mut needed = HashSet::new();
+        let mut filtered = Vec::new();
+        // descendants come after their dependencies, so walking backwards visits them first
+        for patch in delta.iter().rev() {
+            let wanted = needed.contains(patch.id())
+                || patch
+                    .op()
+                    .and_then(std::result::Result::ok)
+                    .is_some_and(|op| op.is_permission() || predicate(&op));
+            if wanted {
+                needed.extend(patch.deps().iter().cloned());
+                filtered.push(*patch.id());
+            }
+        }
+        filtered.reverse();
+        Ok(filtered)
+    }
 
-        vec![a, b, c, d, e, f]
+    /// Returns patches of [Peer::delta], in topological order.
+    fn delta_patches(&self, remote_heads: &[ID]) -> Result<Vec<Patch>> {
+        let known: Vec<ID> = self
+            .store
+            .integrated_set(remote_heads)?
+            .into_iter()
+            .collect();
+        // walk back from local heads only, stopping at patches already in the remote causal past:
+        // their dependencies are known to the remote peer as well
+        let mut visited: HashSet<ID> = HashSet::new();
+        let mut delta = Vec::new();
+        let mut next: Vec<ID> = self
+            .heads
+            .iter()
+            .filter(|id| visited.insert(**id))
+            .cloned()
+            .collect();
+        while !next.is_empty() {
+            let patches = self.store.patches(&next)?;
+            next.clear();
+            for patch in patches {
+                if self.is_known_to(patch.id(), &known)? {
+                    continue;
+                }
+                for dep in patch.deps().iter() {
+                    if visited.insert(*dep) {
+                        next.push(*dep);
+                    }
+                }
+                delta.push(patch);
+            }
+        }
+        Ok(topo_sort(delta))
     }
 
-    fn run_reconcile(src: &Peer<SqliteStore>, dst: &mut Peer<SqliteStore>) {
-        let heads = src.heads();
-        let mut missing = dst.missing(heads).unwrap();
-        while !missing.is_empty() {
-            let patches = src.patches(&missing).unwrap();
-            missing = dst.integrate(patches).unwrap()
+    /// Checks if a given patch is in the causal past of any of the `remote_heads`.
+    fn is_known_to(&self, id: &ID, remote_heads: &[ID]) -> Result<bool> {
+        for head in remote_heads {
+            if self.store.is_ancestor(id, head)? {
+                return Ok(true);
+            }
         }
+        Ok(false)
     }
 
-    #[test]
-    fn reconcile() {
-        let mut p1 = create_peer();
-        let mut p2 = create_peer();
-        let patches = init_patches(&p1);
-        p1.integrate(patches.clone()).unwrap();
-        p2.integrate(patches.clone()).unwrap();
-        let g = p1.commit(&"G").unwrap();
-        let h = p2.commit(&"H").unwrap();
-        let i = p2.commit(&"I").unwrap();
+    pub fn patches(&self, ids: &[ID]) -> Result<Vec<Patch>> {
+        self.store.patches(ids)
+    }
 
-        // reconcile
-        run_reconcile(&p1, &mut p2);
-        run_reconcile(&p2, &mut p1);
+    /// Returns summary statistics of the underlying store, see [ObjectStore::stats].
+    pub fn stats(&self) -> Result<StoreStats> {
+        self.store.stats()
+    }
 
-        let mut ids: Vec<_> = patches.into_iter().map(|p| *p.id()).collect();
-        ids.push(*g.id());
-        ids.push(*h.id());
+    /// Durably writes all changes made so far to the underlying store, see [ObjectStore::flush].
+    pub fn flush(&self) -> Result<()> {
+        self.store.flush()
+    }
+
+    /// Checks integrity of all integrated patches: that their IDs match their content, their
+    /// signatures are valid, and their dependencies are all integrated and free of cycles. Patches
+    /// are checked in the order they were committed, failing on the first invalid one with
+    /// [Error::IdMismatch], [Error::InvalidSignature], [Error::MissingDependency] or
+    /// [Error::CyclicDependency].
+    ///
+    /// Unlike [Patch::verify], which checks a single patch received from a remote peer, it's meant
+    /// to validate consistency of the whole store, e.g. after a crash.
+    pub fn verify_history(&self) -> Result<()> {
+        const PAGE_SIZE: usize = 1024;
+        let mut patches: Vec<Patch> = Vec::new();
+        loop {
+            let page = self
+                .store
+                .patches_page(patches.last().map(|p| p.id()), PAGE_SIZE)?;
+            let done = page.len() < PAGE_SIZE;
+            patches.extend(page);
+            if done {
+                break;
+            }
+        }
+        let ids: HashSet<ID> = patches.iter().map(|p| *p.id()).collect();
+        for patch in patches.iter() {
+            patch.verify_id_with::<C>()?;
+            patch
+                .verify_with::<C>()
+                .map_err(|_| Error::InvalidSignature(*patch.id()))?;
+            if let Some(dep) = patch.deps().iter().find(|dep| !ids.contains(dep)) {
+                return Err(Error::MissingDependency(*dep));
+            }
+        }
+        if let Some(cycle) = find_cycle(&patches) {
+            return Err(Error::CyclicDependency(cycle[0]));
+        }
+        Ok(())
+    }
+
+    /// Materializes [Document] state of all integrated patches. Only patches committed after the
+    /// last [Peer::save_checkpoint] are applied on top of the checkpointed state, instead of
+    /// replaying the whole history. Fails with [Error::InvalidOp] like [Document::from_patches].
+    pub fn document(&self) -> Result<Document> {
+        Ok(self.fold_document()?.0)
+    }
+
+    /// Persists the current [Document] state in the store, so that subsequent [Peer::document]
+    /// calls (also after reopening the store) start from it. Saving a checkpoint replaces the
+    /// previous one, while removing integrated patches discards it.
+    pub fn save_checkpoint(&self) -> Result<()> {
+        let (doc, checkpoint) = self.fold_document()?;
+        if let Some(checkpoint) = checkpoint {
+            let state = serde_json::to_vec(&doc)?;
+            self.store.save_checkpoint(&Checkpoint {
+                state: state.into(),
+                ..checkpoint
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Folds integrated patches on top of the last checkpoint (if any). Returns the document state
+    /// together with the checkpoint of it, which state is left empty.
+    fn fold_document(&self) -> Result<(Document, Option<Checkpoint>)> {
+        let (mut doc, mut last, mut heads) = match self.store.last_checkpoint()? {
+            Some(checkpoint) => (
+                serde_json::from_slice(&checkpoint.state)?,
+                Some(checkpoint.last),
+                checkpoint.heads,
+            ),
+            None => (Document::default(), None, Vec::new()),
+        };
+        loop {
+            let page = self.store.patches_page(last.as_ref(), SNAPSHOT_CHUNK_LEN)?;
+            for patch in page.iter() {
+                doc.apply(patch)?;
+                heads.retain(|head| !patch.deps().contains(head));
+                heads.push(*patch.id());
+            }
+            match page.last() {
+                Some(patch) => last = Some(*patch.id()),
+                None => break,
+            }
+        }
+        let checkpoint = last.map(|last| Checkpoint {
+            last,
+            heads,
+            state: Bytes::new(),
+        });
+        Ok((doc, checkpoint))
+    }
+
+    /// Returns true if integrated patch `descendant` causally follows `ancestor` (or is the same
+    /// patch). See [ObjectStore::is_ancestor].
+    pub fn is_ancestor(&self, ancestor: &ID, descendant: &ID) -> Result<bool> {
+        self.store.is_ancestor(ancestor, descendant)
+    }
+
+    /// Returns IDs of integrated patches authored by a given peer, in the order they were committed.
+    pub fn authored_by(&self, author: &PeerID) -> Result<Vec<ID>> {
+        self.store.patches_by_author(author)
+    }
+
+    /// Returns the latest integrated patch of every author, eg. to show the most recent change of
+    /// each participant: the one with the greatest causal depth, committed last in case of ties.
+    pub fn author_heads(&self) -> Result<HashMap<PeerID, ID>> {
+        self.store.author_heads()
+    }
+
+    /// Returns a bloom filter of all patches integrated by this peer. It can be sent to a remote
+    /// peer, so that it can compute patches missing on this side via [Peer::missing_from_filter].
+    pub fn have_filter(&self) -> Result<BloomFilter> {
+        let history = self.causal_past(&self.heads)?;
+        let mut filter = BloomFilter::new(history.len(), HAVE_FILTER_FALSE_POSITIVE_RATE);
+        filter.extend(history.iter().map(|p| p.id()));
+        Ok(filter)
+    }
+
+    /// Walks back from given heads and returns IDs of integrated patches (in topological order)
+    /// which are not present in a remote peer's `filter`. Walk stops at patches reported by the
+    /// filter, as remote probably has them together with their causal past.
+    ///
+    /// Since bloom filter can give false positives, some patches may still be missed by this
+    /// method. In such case the remote will report them as missing dependencies on integration.
+    pub fn missing_from_filter(&self, filter: &BloomFilter, heads: &[ID]) -> Result<Vec<ID>> {
+        let mut visited: HashSet<ID> = HashSet::new();
+        let mut next: Vec<ID> = heads
+            .iter()
+            .filter(|id| !filter.contains(id) && visited.insert(**id))
+            .cloned()
+            .collect();
+        let mut missing = Vec::new();
+        while !next.is_empty() {
+            let patches = self.store.patches(&next)?;
+            next.clear();
+            for patch in patches {
+                for dep in patch.deps().iter() {
+                    if !filter.contains(dep) && visited.insert(*dep) {
+                        next.push(*dep);
+                    }
+                }
+                missing.push(patch);
+            }
+        }
+        Ok(topo_sort(missing).iter().map(|p| *p.id()).collect())
+    }
+
+    /// Writes all integrated patches into a given writer, in topological order, prefixed with their
+    /// count. Such snapshot can be then used by [Peer::import_snapshot] to bootstrap a new peer
+    /// much faster than by syncing patches one round-trip at a time. Returns number of exported
+    /// patches.
+    pub fn export_snapshot<W: Write>(&self, w: &mut W) -> Result<usize> {
+        let ids = self.store.topo_order()?;
+        w.write_u64_varint(ids.len() as u64)?;
+        for chunk in ids.chunks(SNAPSHOT_CHUNK_LEN) {
+            for patch in self.store.patches(chunk)? {
+                patch.write(w)?;
+            }
+        }
+        Ok(ids.len())
+    }
+
+    /// Imports patches written by [Peer::export_snapshot]. Since snapshot is topologically ordered,
+    /// patches are committed directly, without going through the stash. Snapshot is accepted
+    /// only as a whole: if any patch fails verification or depends on a patch that's neither
+    /// integrated nor earlier in the snapshot, nothing is imported. Returns number of imported
+    /// patches.
+    pub fn import_snapshot<R: Read>(&mut self, r: &mut R) -> Result<usize> {
+        let count = r.read_u64_varint()?;
+        let mut patches = Vec::new();
+        for _ in 0..count {
+            patches.push(Patch::read_with::<C, _>(r)?);
+        }
+        self.import_ordered(patches)
+    }
+
+    /// Writes all integrated patches into a given writer as a bundle: a stable, versioned file
+    /// format meant for backups and offline transfer, which can be read back by
+    /// [Peer::import_bundle]. Bundle consists of:
+    ///
+    /// - [BUNDLE_MAGIC] and [BUNDLE_VERSION] byte,
+    /// - table of patch authors: their count followed by their keys,
+    /// - patches in topological order: their count followed by patches encoded with [Patch::write],
+    /// - BLAKE3 checksum of everything above.
+    ///
+    /// Returns number of exported patches.
+    pub fn export_bundle<W: Write>(&self, w: &mut W) -> Result<usize> {
+        let ids = self.store.topo_order()?;
+        let mut authors = Vec::new();
+        let mut seen = HashSet::new();
+        for chunk in ids.chunks(SNAPSHOT_CHUNK_LEN) {
+            for patch in self.store.patches(chunk)? {
+                if seen.insert(*patch.author()) {
+                    authors.push(*patch.author());
+                }
+            }
+        }
+        let mut w = Checksummed::new(w);
+        w.write_all(BUNDLE_MAGIC)?;
+        w.write_all(&[BUNDLE_VERSION])?;
+        w.write_u32_varint(authors.len() as u32)?;
+        for author in authors.iter() {
+            w.write_all(author.as_bytes())?;
+        }
+        w.write_u64_varint(ids.len() as u64)?;
+        for chunk in ids.chunks(SNAPSHOT_CHUNK_LEN) {
+            for patch in self.store.patches(chunk)? {
+                patch.write(&mut w)?;
+            }
+        }
+        let checksum = w.hasher.finalize();
+        w.inner.write_all(checksum.as_bytes())?;
+        Ok(ids.len())
+    }
+
+    /// Imports patches from a bundle written by [Peer::export_bundle]. Whole bundle is read and
+    /// its checksum validated before anything is imported, failing with [Error::InvalidBundle]
+    /// if it's corrupted. Then patches are imported like by [Peer::import_snapshot], as a whole
+    /// or not at all. Returns number of imported patches.
+    pub fn import_bundle<R: Read>(&mut self, r: &mut R) -> Result<usize> {
+        let mut r = Checksummed::new(r);
+        let mut magic = [0; BUNDLE_MAGIC.len()];
+        let mut version = [0];
+        r.read_exact(&mut magic)?;
+        r.read_exact(&mut version)?;
+        if &magic != BUNDLE_MAGIC {
+            return Err(Error::InvalidBundle("not a bundle"));
+        }
+        if version[0] != BUNDLE_VERSION {
+            return Err(Error::InvalidBundle("unsupported bundle version"));
+        }
+        let count = r.read_u32_varint()? as usize;
+        let mut authors = HashSet::with_capacity(count.min(1024));
+        for _ in 0..count {
+            let mut author = PeerID::default();
+            r.read_exact(&mut author)?;
+            authors.insert(author);
+        }
+        let count = r.read_u64_varint()?;
+        let mut patches = Vec::with_capacity((count as usize).min(1024));
+        for _ in 0..count {
+            patches.push(Patch::read_with::<C, _>(&mut r)?);
+        }
+        let checksum = r.hasher.finalize();
+        let mut expected = [0; blake3::OUT_LEN];
+        r.inner.read_exact(&mut expected)?;
+        if checksum != blake3::Hash::from(expected) {
+            return Err(Error::InvalidBundle("checksum mismatch"));
+        }
+        if patches.iter().any(|p| !authors.contains(p.author())) {
+            return Err(Error::InvalidBundle(
+                "patch author missing from author table",
+            ));
+        }
+        self.import_ordered(patches)
+    }
+
+    /// Writes all integrated patches into a given writer in topological order, as newline-delimited
+    /// JSON described in [crate::patch::ndjson]. Fails if data of any patch is not JSON. Returns
+    /// number of exported patches.
+    pub fn export_ndjson<W: Write>(&self, w: &mut W) -> Result<usize> {
+        let ids = self.store.topo_order()?;
+        for chunk in ids.chunks(SNAPSHOT_CHUNK_LEN) {
+            for patch in self.store.patches(chunk)? {
+                writeln!(w, "{}", ndjson::to_line(&patch)?)?;
+            }
+        }
+        Ok(ids.len())
+    }
+
+    /// Integrates patches read from newline-delimited JSON described in [crate::patch::ndjson],
+    /// eg. produced by [Peer::export_ndjson] or by tools written in other languages. Lines can
+    /// come in any order and blank lines are skipped. All lines are parsed and their signatures
+    /// verified before anything is integrated, failing with [Error::InvalidLine] on the first
+    /// invalid one (counting lines from 1).
+    pub fn import_ndjson<R: Read>(&mut self, r: &mut R) -> Result<IntegrateReport> {
+        let mut patches = Vec::new();
+        for (i, line) in BufReader::new(r).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid = |source| Error::InvalidLine {
+                line: i + 1,
+                source: Box::new(source),
+            };
+            let patch = ndjson::from_line_with::<C>(&line).map_err(invalid)?;
+            patch
+                .verify_with::<C>()
+                .map_err(|_| invalid(Error::InvalidSignature(*patch.id())))?;
+            patches.push(patch);
+        }
+        self.integrate(patches)
+    }
+
+    /// Imports topologically ordered patches as a whole, failing if any of them doesn't pass
+    /// verification or depends on a patch that's neither integrated nor earlier in the list.
+    fn import_ordered(&mut self, patches: Vec<Patch>) -> Result<usize> {
+        let mut seen = HashSet::new();
+        for patch in patches.iter() {
+            for dep in patch.deps().iter() {
+                if !seen.contains(dep) && !self.store.is_integrated(dep)? {
+                    return Err(Error::MissingDependency(*dep));
+                }
+            }
+            seen.insert(*patch.id());
+        }
+        Patch::verify_batch_with::<C>(&patches.iter().collect::<Vec<_>>())?;
+        if self.access_control {
+            // authorization needs the causal past of each patch to be committed
+            self.integrate(patches)?;
+        } else {
+            self.commit_batch(&patches)?;
+        }
+        Ok(seen.len())
+    }
+
+    /// Forks this peer into an independent one, identified by a given key and backed by a fresh
+    /// [MemoryStore]. Both peers share the same history, but diverge on their future commits.
+    /// See [Peer::fork_into].
+    pub fn fork(&self, signing_key: C::SigningKey) -> Result<Peer<MemoryStore<C>, C>> {
+        self.fork_into(signing_key, MemoryStore::with_suite())
+    }
+
+    /// Forks this peer into an independent one, identified by a given key and backed by a given
+    /// store. All integrated patches are verified and copied in topological order. Stashed
+    /// patches are not copied.
+    pub fn fork_into<T: ObjectStore>(
+        &self,
+        signing_key: C::SigningKey,
+        store: T,
+    ) -> Result<Peer<T, C>> {
+        for chunk in self.store.topo_order()?.chunks(SNAPSHOT_CHUNK_LEN) {
+            let patches = self.store.patches(chunk)?;
+            Patch::verify_batch_with::<C>(&patches.iter().collect::<Vec<_>>())?;
+            store.commit_batch(&patches)?;
+        }
+        let mut fork = Peer::with_suite(signing_key, store)?;
+        fork.access_control = self.access_control;
+        fork.max_patch_bytes = self.max_patch_bytes;
+        fork.checkpoint = self.checkpoint;
+        Ok(fork)
+    }
+
+    pub fn permissions(&self) -> Result<Permissions> {
+        self.permissions_at(&self.heads)
+    }
+
+    /// Returns permissions resulting from the causal past of a given frontier (inclusive), with
+    /// conflicts between concurrent permission operations resolved as described in
+    /// [Permissions::resolve].
+    pub fn permissions_at(&self, frontier: &[ID]) -> Result<Permissions> {
+        let history = self.causal_past(frontier)?;
+        Ok(Permissions::resolve(history.iter()))
+    }
+
+    /// Returns all integrated patches reachable from a given frontier (inclusive), in topological
+    /// order.
+    fn causal_past(&self, frontier: &[ID]) -> Result<Vec<Patch>> {
+        let mut visited: HashSet<ID> = frontier.iter().cloned().collect();
+        let mut past = Vec::new();
+        let mut next: Vec<ID> = visited.iter().cloned().collect();
+        while !next.is_empty() {
+            let patches = self.store.patches(&next)?;
+            next.clear();
+            for patch in patches {
+                for dep in patch.deps().iter() {
+                    if visited.insert(*dep) {
+                        next.push(*dep);
+                    }
+                }
+                past.push(patch);
+            }
+        }
+        Ok(topo_sort(past))
+    }
+
+    /// Commits a batch of verified and authorized patches in one go and refreshes current heads.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(patches = batch.len()))
+    )]
+    fn commit_batch<P: Borrow<Patch>>(&mut self, batch: &[P]) -> Result<()> {
+        timed!();
+        self.store.commit_batch(batch)?;
+        for patch in batch {
+            self.track_checkpoint(patch.borrow())?;
+        }
+        for patch in batch {
+            self.listeners.notify(patch.borrow());
+        }
+        self.refresh_heads()
+    }
+
+    /// Records a given patch as the current checkpoint if it's a [Op::Prune] issued by the owner,
+    /// descending from the previous checkpoint.
+    fn track_checkpoint(&mut self, patch: &Patch) -> Result<()> {
+        if !matches!(patch.op(), Some(Ok(Op::Prune))) {
+            return Ok(());
+        }
+        let history = self.causal_past(patch.deps())?;
+        self.update_checkpoint(patch, &history);
+        Ok(())
+    }
+
+    /// Checks if patch is authorized at its position in the DAG, if access control is enabled.
+    /// Root patches are checked against the current heads, so that only the first one is accepted.
+    fn is_authorized(&self, patch: &Patch) -> Result<bool> {
+        if !self.access_control {
+            return Ok(true);
+        }
+        let frontier = if patch.deps().is_empty() {
+            self.heads.as_slice()
+        } else {
+            &patch.deps()[..]
+        };
+        Ok(self.permissions_at(frontier)?.is_authorized(patch))
+    }
+
+    /// Fails with [Error::Unauthorized] unless patch is authorized, see [Peer::is_authorized].
+    fn authorize(&self, patch: &Patch) -> Result<()> {
+        if self.is_authorized(patch)? {
+            Ok(())
+        } else {
+            Err(Error::Unauthorized(*patch.id()))
+        }
+    }
+}
+
+/// Async counterparts of the [Peer] API, used when the underlying store is an [AsyncObjectStore].
+impl<S: AsyncObjectStore> Peer<S> {
+    pub async fn new_async(signing_key: SigningKey, store: S) -> Result<Self> {
+        Self::with_suite_async(signing_key, store).await
+    }
+}
+
+impl<S: AsyncObjectStore, C: CryptoSuite> Peer<S, C> {
+    pub async fn with_suite_async(signing_key: C::SigningKey, store: S) -> Result<Self> {
+        let heads = store.heads().await?;
+        Ok(Peer::from_parts(signing_key, store, heads, 0))
+    }
+
+    /// Re-reads current heads from the store, discarding the cached ones.
+    pub async fn refresh_heads_async(&mut self) -> Result<()> {
+        self.heads = self.store.heads().await?;
+        Ok(())
+    }
+
+    pub async fn commit_async<B>(&mut self, data: &B) -> Result<Patch>
+    where
+        B: Serialize,
+    {
+        let patch =
+            Patch::new_with::<C, _, _>(&self.signing_key, self.heads.iter().cloned(), data)?;
+        self.check_size(&patch)?;
+        self.store.commit(&patch).await?;
+        self.track_checkpoint_async(&patch).await?;
+        self.heads = vec![*patch.id()];
+        self.listeners.notify(&patch);
+        Ok(patch)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(received = tracing::field::Empty))
+    )]
+    pub async fn integrate_async<I>(&mut self, patches: I) -> Result<IntegrateReport>
+    where
+        I: IntoIterator<Item = Patch>,
+    {
+        timed!();
+        let mut report = IntegrateReport::default();
+        let mut patches: Vec<Patch> = patches.into_iter().collect();
+        record!("received", patches.len());
+        let mut unstashed = false;
+        let mut revisited = false;
+        // patches are already verified once before being stashed, don't repeat that on unstash
+        let mut verified = HashSet::new();
+        loop {
+            if let Some(cycle) = find_cycle(&patches) {
+                if unstashed {
+                    // keep patches, which are not a part of the cycle, waiting for their deps
+                    for patch in patches.iter().filter(|p| !cycle.contains(p.id())) {
+                        self.store.stash(patch).await?;
+                    }
+                }
+                return Err(Error::CyclicDependency(cycle[0]));
+            }
+            patches = topo_sort(patches);
+
+            let mut changed = false;
+            let mut awaits_stashed = false;
+            report.stashed.clear();
+            for patch in patches {
+                if self.store.contains(patch.id()).await? {
+                    if !unstashed {
+                        report.duplicates.push(*patch.id());
+                    }
+                } else {
+                    if verified.insert(*patch.id()) {
+                        patch.verify_with::<C>()?;
+                    }
+                    let mut stashed = false;
+                    for dep in patch.deps().iter() {
+                        if !self.store.is_integrated(dep).await? {
+                            awaits_stashed |= self.store.contains(dep).await?;
+                            if !report.missing.contains(dep) {
+                                report.missing.push(*dep);
+                            }
+                            stashed = true;
+                        }
+                    }
+
+                    if stashed {
+                        self.store.stash(&patch).await?;
+                        report.stashed.push(*patch.id());
+                    } else {
+                        self.store.commit(&patch).await?;
+                        self.track_checkpoint_async(&patch).await?;
+                        self.listeners.notify(&patch);
+                        report.committed.push(*patch.id());
+                        changed = true;
+                    }
+                }
+            }
+
+            if changed || (awaits_stashed && !revisited) {
+                revisited |= !changed;
+                self.heads = self.store.heads().await?;
+                patches = self.store.unstash().await?;
+                unstashed = true;
+            } else {
+                break;
+            }
+        }
+
+        let mut missing = std::mem::take(&mut report.missing);
+        for dep in missing.drain(..) {
+            if !self.store.contains(&dep).await? {
+                report.missing.push(dep);
+            }
+        }
+        debug_event!(
+            committed = report.committed.len(),
+            stashed = report.stashed.len(),
+            duplicates = report.duplicates.len(),
+            missing = report.missing.len(),
+            "integrated"
+        );
+        Ok(report)
+    }
+
+    /// Async equivalent of [Peer::track_checkpoint].
+    async fn track_checkpoint_async(&mut self, patch: &Patch) -> Result<()> {
+        if !matches!(patch.op(), Some(Ok(Op::Prune))) {
+            return Ok(());
+        }
+        let mut visited: HashSet<ID> = patch.deps().iter().cloned().collect();
+        let mut history = Vec::new();
+        let mut next: Vec<ID> = visited.iter().cloned().collect();
+        while !next.is_empty() {
+            let patches = self.store.patches(&next).await?;
+            next.clear();
+            for patch in patches {
+                next.extend(patch.deps().iter().filter(|dep| visited.insert(**dep)));
+                history.push(patch);
+            }
+        }
+        self.update_checkpoint(patch, &topo_sort(history));
+        Ok(())
+    }
+
+    pub async fn missing_async(&self, heads: &[ID]) -> Result<Vec<ID>> {
+        let mut missing = Vec::with_capacity(heads.len());
+        for id in heads.iter() {
+            if !self.store.contains(id).await? {
+                missing.push(*id);
+            }
+        }
+        Ok(missing)
+    }
+
+    pub async fn patches_async(&self, ids: &[ID]) -> Result<Vec<Patch>> {
+        self.store.patches(ids).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use ed25519_dalek::SigningKey;
+
+    use crate::op::{Op, Value};
+    use crate::patch::{Patch, ID};
+    use crate::peer::{IntegrateOutcome, Peer};
+    use crate::store::blocking::{block_on, BlockingStore};
+    use crate::store::memory::MemoryStore;
+    use crate::store::{for_each_store, ObjectStore};
+    #[cfg(feature = "sqlite")]
+    use crate::{
+        doc::Document,
+        patch::{topo_sort, ContentType, Deps},
+        peer::{BUNDLE_MAGIC, BUNDLE_VERSION},
+        store::sqlite::{AsyncSqliteStore, SqliteStore},
+        store::StoreStats,
+    };
+    use crate::{Error, PeerID};
+
+    #[cfg(feature = "sqlite")]
+    pub fn create_peer() -> Peer<SqliteStore> {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let store = SqliteStore::new(conn).unwrap();
+        let key_pair = SigningKey::generate(&mut rand::rngs::OsRng);
+        Peer::new(key_pair, store).unwrap()
+    }
+
+    pub fn create_memory_peer() -> Peer<MemoryStore> {
+        create_peer_over(MemoryStore::new())
+    }
+
+    /// Creates a peer with a fresh key over a given store.
+    pub fn create_peer_over<S: ObjectStore>(store: S) -> Peer<S> {
+        let key_pair = SigningKey::generate(&mut rand::rngs::OsRng);
+        Peer::new(key_pair, store).unwrap()
+    }
+
+    /// ```no_compile
+    ///      / B - D
+    ///     A    \
+    ///      \ C - E - F
+    /// ```
+    pub fn init_patches<S: ObjectStore>(p: &Peer<S>) -> Vec<Patch> {
+        let a = Patch::new(&p.signing_key, [], &"A").unwrap();
+        let b = Patch::new(&p.signing_key, [*a.id()], &"B").unwrap();
+        let c = Patch::new(&p.signing_key, [*a.id()], &"C").unwrap();
+        let d = Patch::new(&p.signing_key, [*b.id()], &"D").unwrap();
+        let e = Patch::new(&p.signing_key, [*b.id(), *c.id()], &"E").unwrap();
+        let f = Patch::new(&p.signing_key, [*e.id()], &"F").unwrap();
+
+        vec![a, b, c, d, e, f]
+    }
+
+    fn run_reconcile<S1: ObjectStore, S2: ObjectStore>(src: &Peer<S1>, dst: &mut Peer<S2>) {
+        let heads = src.heads();
+        let mut missing = dst.missing(heads).unwrap();
+        while !missing.is_empty() {
+            let patches = src.patches(&missing).unwrap();
+            missing = dst.integrate(patches).unwrap().missing
+        }
+    }
+
+    #[test]
+    fn fetch_missing_deps() {
+        let src = Arc::new(Mutex::new(create_memory_peer()));
+        let patches = init_patches(&src.lock().unwrap());
+        src.lock().unwrap().integrate(patches.clone()).unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let mut dst = create_memory_peer();
+        let remote = src.clone();
+        let log = requests.clone();
+        dst.set_fetcher(move |ids| {
+            log.lock().unwrap().push(ids.to_vec());
+            remote.lock().unwrap().patches(ids)
+        });
+        // only F is given, its causal past (all but D) is pulled by the fetcher
+        let tip = patches.last().unwrap().clone();
+        let report = dst.integrate([tip.clone()]).unwrap();
+        assert!(report.missing.is_empty());
+        assert!(report.stashed.is_empty());
+        assert_eq!(report.committed.len(), 5);
+        assert_eq!(dst.heads(), &[*tip.id()]);
+        assert_eq!(requests.lock().unwrap().len(), 3); // [E], [B, C], [A]
+
+        // dependencies, which the fetcher can't supply, are requested once and reported missing
+        let mut dst = create_memory_peer();
+        let log = requests.clone();
+        requests.lock().unwrap().clear();
+        dst.set_fetcher(move |ids| {
+            log.lock().unwrap().push(ids.to_vec());
+            Ok(Vec::new())
+        });
+        let report = dst.integrate([tip.clone()]).unwrap();
+        assert_eq!(report.stashed, vec![*tip.id()]);
+        assert_eq!(report.missing, tip.deps().to_vec());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[cfg(feature = "tracing")]
+    #[test]
+    #[tracing_test::traced_test]
+    fn tracing_spans() {
+        let mut p1 = create_peer();
+        let mut p2 = create_peer();
+        let a = p1.commit(&"A").unwrap();
+        p1.commit(&"B").unwrap();
+        run_reconcile(&p1, &mut p2);
+
+        assert!(logs_contain(&format!("commit_patch{{id={}", a.id())));
+        assert!(logs_contain("integrate{received=1}"));
+        assert!(logs_contain("integrated committed=0 stashed=1"));
+        assert!(logs_contain("integrated committed=2 stashed=0"));
+        assert!(logs_contain(&format!("stash{{id={}", p1.heads()[0])));
+        assert!(logs_contain("finished duration_us="));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn reconcile() {
+        let mut p1 = create_peer();
+        let mut p2 = create_peer();
+        let patches = init_patches(&p1);
+        p1.integrate(patches.clone()).unwrap();
+        p2.integrate(patches.clone()).unwrap();
+        let g = p1.commit(&"G").unwrap();
+        let h = p2.commit(&"H").unwrap();
+        let i = p2.commit(&"I").unwrap();
+
+        // reconcile
+        run_reconcile(&p1, &mut p2);
+        run_reconcile(&p2, &mut p1);
+
+        let mut ids: Vec<_> = patches.into_iter().map(|p| *p.id()).collect();
+        ids.push(*g.id());
+        ids.push(*h.id());
         ids.push(*i.id());
-        let res1 = p1.patches(&*ids).unwrap();
-        let res2 = p1.patches(&*ids).unwrap();
+        let res1 = p1.patches(&ids).unwrap();
+        let res2 = p1.patches(&ids).unwrap();
         assert_eq!(res1, res2);
     }
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn recover() {
+        let path = std::env::temp_dir().join(format!("st-{}.db", rand::random::<u64>()));
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let b = Patch::new(&key, [*a.id()], &"B").unwrap();
+        let orphan = Patch::new(&key, [ID::from(blake3::hash(b"?"))], &"C").unwrap();
+
+        let mut peer = Peer::new(key.clone(), SqliteStore::open(&path).unwrap()).unwrap();
+        let report = peer.integrate([b.clone(), orphan.clone()]).unwrap();
+        assert_eq!(report.stashed.len(), 2);
+        drop(peer);
+
+        // dependency arrives by other means while the peer is down
+        SqliteStore::open(&path).unwrap().commit(&a).unwrap();
+
+        let mut peer = Peer::new(key.clone(), SqliteStore::open(&path).unwrap()).unwrap();
+        let report = peer.recover().unwrap();
+        assert_eq!(report.committed, vec![*b.id()]);
+        assert_eq!(report.stashed, vec![*orphan.id()]);
+        assert_eq!(peer.heads(), &[*b.id()]);
+        // genuine orphans stay stashed
+        let report = peer.recover().unwrap();
+        assert!(report.committed.is_empty());
+        assert_eq!(peer.store.stashed().unwrap(), vec![orphan]);
+        drop(peer);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn delta() {
+        let mut p1 = create_peer();
+        let mut p2 = create_peer();
+        let patches = init_patches(&p1);
+        p1.integrate(patches.clone()).unwrap();
+        p2.integrate(patches.clone()).unwrap();
+        let g = p1.commit(&"G").unwrap();
+        let h = p2.commit(&"H").unwrap();
+        let i = p2.commit(&"I").unwrap();
+
+        let shared = [*patches[3].id(), *patches[5].id()];
+        assert_eq!(p1.delta(&shared).unwrap(), vec![*g.id()]);
+        assert_eq!(p2.delta(&shared).unwrap(), vec![*h.id(), *i.id()]);
+        assert!(p1.delta(p1.heads()).unwrap().is_empty());
+
+        // unknown remote heads fall back to the whole history
+        let all = p1.delta(p2.heads()).unwrap();
+        assert_eq!(all, p1.store.topo_order().unwrap());
+
+        let delta = p2.delta(p1.heads()).unwrap();
+        assert_eq!(delta.len(), 8);
+        p1.integrate(p2.patches(&delta).unwrap()).unwrap();
+        let delta = p1.delta(&[*i.id()]).unwrap();
+        assert_eq!(delta, vec![*g.id()]);
+        p2.integrate(p1.patches(&delta).unwrap()).unwrap();
+        assert!(p1.delta(p2.heads()).unwrap().is_empty());
+        assert!(p2.delta(p1.heads()).unwrap().is_empty());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn delta_filtered() {
+        let mut p1 = create_peer();
+        let mut p2 = create_memory_peer();
+        let set = |key: &str, value: i64| Op::UpdateEntry(key.into(), Value::Int(value));
+        let users = |op: &Op| matches!(op, Op::UpdateEntry(key, _) if key.starts_with("users/42/"));
+        let a = p1.commit(&set("users/42/name", 1)).unwrap();
+        let b = p1.commit_on(&[*a.id()], &set("other", 1)).unwrap();
+        let c = p1.commit_on(&[*a.id()], &set("users/42/age", 2)).unwrap();
+        let grant = p1.commit_on(&[*c.id()], &Op::Grant(p2.peer_id())).unwrap();
+
+        let delta = p1.delta_filtered(&[], users).unwrap();
+        assert_eq!(delta, vec![*a.id(), *c.id(), *grant.id()]);
+        p2.integrate(p1.patches(&delta).unwrap()).unwrap();
+        let ids = p2.store().topo_order().unwrap();
+        let doc = Document::from_patches(&p2.patches(&ids).unwrap()).unwrap();
+        assert_eq!(doc.get("users/42/age"), Some(&Value::Int(2)));
+        assert_eq!(doc.get("other"), None);
+        assert_eq!(p2.stats().unwrap().patches, 3);
+
+        // filtered out patches are shipped if matching ones depend on them
+        let d = p1
+            .commit_on(&[*b.id(), *grant.id()], &set("users/42/name", 3))
+            .unwrap();
+        let delta = p1.delta_filtered(p2.heads(), users).unwrap();
+        assert_eq!(delta, vec![*b.id(), *d.id()]);
+        let report = p2.integrate(p1.patches(&delta).unwrap()).unwrap();
+        assert!(report.missing.is_empty());
+        assert_eq!(p2.heads(), &[*d.id()]);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn ndjson() {
+        let mut p1 = create_peer();
+        let patches = init_patches(&p1);
+        p1.integrate(patches.clone()).unwrap();
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        // operation data explicitly tagged as opaque
+        let opaque = Patch::builder()
+            .deps(p1.heads().to_vec())
+            .data(&Op::Prune)
+            .content_type(ContentType::Opaque)
+            .sign(&key)
+            .unwrap();
+        p1.integrate([opaque.clone()]).unwrap();
+        p1.commit(&Op::UpdateEntry("a".into(), Value::Int(1)))
+            .unwrap();
+        let mut out = Vec::new();
+        assert_eq!(p1.export_ndjson(&mut out).unwrap(), 8);
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(text.lines().count(), 8);
+        assert_eq!(text.matches("content_type").count(), 1);
+
+        // lines can come in any order
+        let reversed: String = text.lines().rev().map(|l| format!("{l}\n\n")).collect();
+        let mut p2 = create_memory_peer();
+        let report = p2.import_ndjson(&mut reversed.as_bytes()).unwrap();
+        assert!(report.missing().is_empty());
+        assert_eq!(report.committed.len(), 8);
+        let ids = p1.store().topo_order().unwrap();
+        assert_eq!(p2.store().topo_order().unwrap(), ids);
+        assert_eq!(p2.patches(&ids).unwrap(), p1.patches(&ids).unwrap());
+
+        let mut lines: Vec<String> = text.lines().map(String::from).collect();
+        lines[2] = lines[2].replace("\"sign\":\"", "\"sign\":\"zz");
+        lines[5] = lines[5].replace("\"data\":\"", "\"data\":\"Z");
+        let mut p3 = create_memory_peer();
+        let res = p3.import_ndjson(&mut lines[..].join("\n").as_bytes());
+        assert!(matches!(res, Err(Error::InvalidLine { line: 3, .. })));
+        let res = p3.import_ndjson(&mut lines[3..].join("\n").as_bytes());
+        assert!(matches!(
+            res,
+            Err(Error::InvalidLine { line: 3, source }) if matches!(*source, Error::InvalidSignature(_))
+        ));
+        assert!(p3.store().topo_order().unwrap().is_empty());
+
+        // only JSON data can be exported
+        p1.commit_raw(&[0xff, 0x00][..]).unwrap();
+        assert!(p1.export_ndjson(&mut Vec::new()).is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn local_patches() {
+        let mut p1 = create_peer();
+        let mut p2 = create_memory_peer();
+        let a = p1.commit(&"A").unwrap();
+        let x = p1.commit_local(&"cursor at 1").unwrap();
+        let y = p1.commit_local(&"cursor at 2").unwrap();
+        let b = p1.commit(&"B").unwrap();
+        assert!(x.is_local() && y.is_local() && !a.is_local());
+        assert!(x.deps().is_empty());
+        assert_eq!(y.deps()[..], [*x.id()]);
+        assert_eq!(p1.local_heads().unwrap(), vec![*y.id()]);
+        assert_eq!(p1.local_patches().unwrap(), vec![x.clone(), y.clone()]);
+        // committing the same local patch again is deterministic and has no effect
+        p1.store().commit_local(&y).unwrap();
+        assert_eq!(p1.local_patches().unwrap().len(), 2);
+
+        // local patches are invisible to sync
+        assert_eq!(p1.heads(), &[*b.id()]);
+        assert_eq!(b.deps()[..], [*a.id()]);
+        assert_eq!(p1.delta(&[]).unwrap(), vec![*a.id(), *b.id()]);
+        assert_eq!(p1.missing(&[*x.id(), *b.id()]).unwrap(), vec![*x.id()]);
+        assert!(p1.patches(&[*x.id(), *y.id()]).unwrap().is_empty());
+        assert_eq!(p1.stats().unwrap().patches, 2);
+        p2.integrate(p1.patches(&p1.delta(&[]).unwrap()).unwrap())
+            .unwrap();
+        assert_eq!(p2.heads(), p1.heads());
+        assert!(p2.local_patches().unwrap().is_empty());
+
+        // and can't be smuggled in from remote peers
+        assert!(p2.integrate([x.clone()]).is_err());
+        assert!(matches!(
+            p2.integrate([x.clone(), y.clone()]),
+            Err(Error::VerificationFailed(_))
+        ));
+        assert!(!p2.store().contains(x.id()).unwrap());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn undo() {
+        let mut p1 = create_peer().with_access_control(true);
+        let p2 = create_memory_peer();
+        let set = |key: &str, value: Value| Op::UpdateEntry(key.into(), value);
+        p1.commit(&set("title", Value::String("draft".into())))
+            .unwrap();
+        let rename = p1
+            .commit(&set("title", Value::String("final".into())))
+            .unwrap();
+        let added = p1.commit(&set("tags", Value::Int(1))).unwrap();
+        let grant = p1.commit(&Op::Grant(p2.peer_id())).unwrap();
+        assert!(p1.permissions().unwrap().is_moderator(&p2.peer_id()));
+
+        let undone = p1.undo(rename.id()).unwrap();
+        assert_eq!(undone.deps()[..], [*rename.id()]);
+        let doc = p1.document().unwrap();
+        assert_eq!(doc.get("title"), Some(&Value::String("draft".into())));
+        assert_eq!(doc.get("tags"), Some(&Value::Int(1)));
+        // entries which didn't exist before are removed
+        p1.undo(added.id()).unwrap();
+        assert_eq!(p1.document().unwrap().get("tags"), None);
+
+        p1.undo(grant.id()).unwrap();
+        p1.commit(&"merge").unwrap();
+        assert!(!p1.permissions().unwrap().is_moderator(&p2.peer_id()));
+        // undoing an undo restores the change
+        let undo_undo = p1.undo(undone.id()).unwrap();
+        let doc = p1.document().unwrap();
+        assert_eq!(doc.get("title"), Some(&Value::String("final".into())));
+        assert_eq!(undo_undo.deps()[..], [*undone.id()]);
+
+        let prune = p1.commit(&Op::Prune).unwrap();
+        assert!(matches!(p1.undo(prune.id()), Err(Error::NotInvertible(id)) if id == *prune.id()));
+        let opaque = p1.commit_raw(&b"opaque"[..]).unwrap();
+        assert!(
+            matches!(p1.undo(opaque.id()), Err(Error::NotInvertible(id)) if id == *opaque.id())
+        );
+        assert!(matches!(
+            p1.undo(&ID::default()),
+            Err(Error::MissingDependency(_))
+        ));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn checkpoint_document() {
+        let mut p1 = create_peer();
+        let mut p2 = create_memory_peer();
+        let set = |key: &str, value: i64| Op::UpdateEntry(key.into(), Value::Int(value));
+        let full_replay = |peer: &Peer<SqliteStore>| {
+            let ids = peer.store().topo_order().unwrap();
+            Document::from_patches(&peer.patches(&ids).unwrap()).unwrap()
+        };
+        assert_eq!(p1.document().unwrap(), Document::default());
+        p1.save_checkpoint().unwrap();
+        assert_eq!(p1.store().last_checkpoint().unwrap(), None);
+
+        p1.commit(&set("a", 1)).unwrap();
+        p1.commit(&Op::Grant(p2.peer_id())).unwrap();
+        p1.commit(&Op::InsertRange(0, vec![Value::Int(1), Value::Int(2)]))
+            .unwrap();
+        p2.integrate(p1.patches(&p1.store().topo_order().unwrap()).unwrap())
+            .unwrap();
+        p1.save_checkpoint().unwrap();
+        let checkpoint = p1.store().last_checkpoint().unwrap().unwrap();
+        assert_eq!(checkpoint.heads, p1.heads());
+        assert_eq!(p1.document().unwrap(), full_replay(&p1));
+
+        // concurrent patches integrated after the checkpoint
+        p2.commit(&set("a", 2)).unwrap();
+        p2.commit(&Op::RemoveRange(0, 1)).unwrap();
+        p1.commit(&set("b", 1)).unwrap();
+        p1.integrate(p2.patches(&p2.store().topo_order().unwrap()).unwrap())
+            .unwrap();
+        p1.commit(&Op::Prune).unwrap();
+        p1.commit(&Op::InsertRange(1, vec![Value::Int(3)])).unwrap();
+        let doc = p1.document().unwrap();
+        assert_eq!(doc, full_replay(&p1));
+        assert_eq!(doc.sequence(), vec![Value::Int(2), Value::Int(3)]);
+
+        p1.save_checkpoint().unwrap();
+        let checkpoint = p1.store().last_checkpoint().unwrap().unwrap();
+        assert_eq!(checkpoint.last, p1.heads()[0]);
+        assert_eq!(checkpoint.heads, p1.heads());
+        assert_eq!(p1.document().unwrap(), doc);
+
+        // removing patches covered by the checkpoint discards it
+        let head = p1.heads()[0];
+        assert!(p1.store().remove(&head).unwrap());
+        assert_eq!(p1.store().last_checkpoint().unwrap(), None);
+        p1.refresh_heads().unwrap();
+        assert_eq!(p1.document().unwrap(), full_replay(&p1));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn commit() {
+        let mut peer = create_peer();
+        let patches = init_patches(&peer);
+        let ids: Vec<_> = patches.iter().map(|p| *p.id()).collect();
+
+        let report = peer.integrate(patches.clone()).unwrap();
+        let in_store = peer.patches(&ids).unwrap();
+
+        assert_eq!(patches, in_store);
+        let sorted: Vec<ID> = topo_sort(patches).iter().map(|p| *p.id()).collect();
+        assert_eq!(report.committed, sorted);
+        assert!(report.missing().is_empty());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn integrate_order_independent() {
+        let mut forward = create_peer();
+        let mut backward = create_memory_peer();
+        let patches = init_patches(&forward);
+        let reversed: Vec<Patch> = patches.iter().rev().cloned().collect();
+
+        let report1 = forward.integrate(patches.clone()).unwrap();
+        let report2 = backward.integrate(reversed).unwrap();
+        assert_eq!(report1, report2);
+        assert!(report2.stashed.is_empty());
+        assert!(backward.store.stashed().unwrap().is_empty());
+
+        let mut heads1 = forward.heads().to_vec();
+        let mut heads2 = backward.heads().to_vec();
+        heads1.sort();
+        heads2.sort();
+        assert_eq!(heads1, heads2);
+        assert_eq!(
+            forward.store.topo_order().unwrap(),
+            backward.store.topo_order().unwrap()
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn redelivered_patch_is_not_verified() {
+        let mut peer = create_peer();
+        let patch = peer.commit(&"A").unwrap();
+        let mut buf = Vec::new();
+        patch.write(&mut buf).unwrap();
+        buf[3] ^= 0xff; // corrupt signature, which is not a part of the patch ID
+        let corrupted = Patch::read(&mut buf.as_slice()).unwrap();
+        assert_eq!(corrupted.id(), patch.id());
+        assert!(corrupted.verify().is_err());
+
+        let report = peer.integrate([corrupted]).unwrap();
+        assert_eq!(report.duplicates, vec![*patch.id()]);
+        assert!(report.committed.is_empty());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn commit_raw() {
+        let mut peer = create_peer();
+        let data: &[u8] = &[0xde, 0xad, 0xbe, 0xef, 0x00];
+        let a = peer.commit_raw(data.to_vec()).unwrap();
+        let b = peer.commit_raw(&b"{\"Grant\":null}"[..]).unwrap();
+        assert_eq!(a.data(), data);
+        assert_eq!(a.content_type(), ContentType::Opaque);
+        assert_eq!(b.deps()[..], [*a.id()]);
+        assert_eq!(b.content_type(), ContentType::Opaque);
+
+        // pre-encoded operations are recognized as such
+        let op = serde_json::to_vec(&Op::Prune).unwrap();
+        let c = peer.commit_raw(op.clone()).unwrap();
+        assert_eq!(c.content_type(), ContentType::Op);
+
+        let stored = peer.patches(&[*a.id(), *c.id()]).unwrap();
+        assert_eq!(stored[0].data(), data);
+        assert_eq!(stored[1].data(), &op[..]);
+        stored[0].verify().unwrap();
+        assert_eq!(peer.heads(), &[*c.id()]);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn commit_on() {
+        let mut peer = create_peer();
+        let patches = init_patches(&peer);
+        let ids: Vec<_> = patches.iter().map(|p| *p.id()).collect();
+        peer.integrate(patches).unwrap();
+        // heads of integrated patches follow their commit order, which depends on IDs
+        let sorted = |heads: &[ID]| {
+            let mut heads = heads.to_vec();
+            heads.sort();
+            heads
+        };
+        assert_eq!(sorted(peer.heads()), sorted(&[ids[3], ids[5]])); // D, F
+
+        // graft on top of B and C, keeping current heads
+        let g = peer.commit_on(&[ids[1], ids[2], ids[1]], &"G").unwrap();
+        assert_eq!(g.deps().len(), 2);
+        assert!(g.deps().contains(&ids[1]) && g.deps().contains(&ids[2]));
+        assert_eq!(sorted(peer.heads()), sorted(&[ids[3], ids[5], *g.id()]));
+
+        // merge of D and G
+        let h = peer.commit_on(&[ids[3], *g.id()], &"H").unwrap();
+        assert_eq!(sorted(peer.heads()), sorted(&[ids[5], *h.id()]));
+
+        let unknown = ID::from(blake3::hash(b"unknown"));
+        let res = peer.commit_on(&[ids[0], unknown], &"I");
+        assert!(matches!(res, Err(Error::MissingDependency(id)) if id == unknown));
+        assert_eq!(sorted(peer.heads()), sorted(&[ids[5], *h.id()]));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn snapshot() {
+        let mut p1 = create_peer();
+        let patches = init_patches(&p1);
+        p1.integrate(patches.clone()).unwrap();
+        let g = p1.commit(&"G").unwrap();
+        let mut snapshot = Vec::new();
+        assert_eq!(p1.export_snapshot(&mut snapshot).unwrap(), 7);
+
+        let mut p2 = create_memory_peer();
+        assert_eq!(p2.import_snapshot(&mut snapshot.as_slice()).unwrap(), 7);
+        assert_eq!(p2.heads(), p1.heads());
+        assert_eq!(
+            p2.store().topo_order().unwrap(),
+            p1.store().topo_order().unwrap()
+        );
+        assert_eq!(p2.patches(&[*g.id()]).unwrap(), vec![g]);
+
+        // corrupt the signature of the last patch
+        let mut p3 = create_peer();
+        let last = snapshot.len() - 1;
+        let sign_pos = last - "\"G\"".len() - 32 * 3 - 64 + 1;
+        snapshot[sign_pos] ^= 0xff;
+        let res = p3.import_snapshot(&mut snapshot.as_slice());
+        assert!(matches!(res, Err(Error::VerificationFailed(_))));
+        assert!(p3.heads().is_empty());
+        assert!(p3.store().topo_order().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn bundle() {
+        let mut p1 = create_peer();
+        let mut other = create_memory_peer();
+        let patches = init_patches(&p1);
+        p1.integrate(patches.clone()).unwrap();
+        other.integrate(patches.clone()).unwrap();
+        let g = other.commit(&"G").unwrap();
+        p1.integrate([g.clone()]).unwrap();
+        let mut bundle = Vec::new();
+        assert_eq!(p1.export_bundle(&mut bundle).unwrap(), 7);
+        assert!(bundle.starts_with(BUNDLE_MAGIC));
+        assert_eq!(bundle[BUNDLE_MAGIC.len()], BUNDLE_VERSION);
+
+        let mut p2 = create_memory_peer();
+        assert_eq!(p2.import_bundle(&mut bundle.as_slice()).unwrap(), 7);
+        assert_eq!(p2.heads(), p1.heads());
+        assert_eq!(
+            p2.store().topo_order().unwrap(),
+            p1.store().topo_order().unwrap()
+        );
+        assert_eq!(p2.patches(&[*g.id()]).unwrap(), vec![g]);
+        // importing the same bundle again changes nothing
+        assert_eq!(p2.import_bundle(&mut bundle.as_slice()).unwrap(), 7);
+        assert_eq!(p2.stats().unwrap().patches, 7);
+
+        // corrupted data of the first patch is caught by the checksum, before any verification
+        let mut corrupted = bundle.clone();
+        let pos = corrupted.windows(3).position(|w| w == b"\"A\"").unwrap();
+        corrupted[pos + 1] = b'Z';
+        let mut p3 = create_memory_peer();
+        let res = p3.import_bundle(&mut corrupted.as_slice());
+        assert!(matches!(
+            res,
+            Err(Error::InvalidBundle("checksum mismatch"))
+        ));
+        assert!(p3.heads().is_empty());
+        assert!(p3.store().topo_order().unwrap().is_empty());
+
+        let mut corrupted = bundle.clone();
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        let res = p3.import_bundle(&mut corrupted.as_slice());
+        assert!(matches!(
+            res,
+            Err(Error::InvalidBundle("checksum mismatch"))
+        ));
+        let res = p3.import_bundle(&mut &bundle[..bundle.len() - 1]);
+        assert!(matches!(res, Err(Error::IO(_))));
+        let mut snapshot = Vec::new();
+        p1.export_snapshot(&mut snapshot).unwrap();
+        let res = p3.import_bundle(&mut snapshot.as_slice());
+        assert!(matches!(res, Err(Error::InvalidBundle("not a bundle"))));
+        assert!(p3.store().topo_order().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "sqlite")]
     #[test]
-    fn commit() {
+    fn fork() {
+        let mut p1 = create_peer();
+        let patches = init_patches(&p1);
+        p1.integrate(patches).unwrap();
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let mut p2 = p1.fork(key).unwrap();
+        assert_ne!(p2.peer_id(), p1.peer_id());
+        let mut ids = p1.store().topo_order().unwrap();
+        let mut forked = p2.store().topo_order().unwrap();
+        ids.sort();
+        forked.sort();
+        assert_eq!(forked, ids);
+
+        let g = p1.commit(&"G").unwrap();
+        let h = p2.commit(&"H").unwrap();
+        assert_eq!(h.author(), &p2.peer_id());
+        assert!(!p1.store().contains(h.id()).unwrap());
+        assert!(!p2.store().contains(g.id()).unwrap());
+
+        run_reconcile(&p1, &mut p2);
+        run_reconcile(&p2, &mut p1);
+        let mut h1 = p1.heads().to_vec();
+        let mut h2 = p2.heads().to_vec();
+        h1.sort();
+        h2.sort();
+        assert_eq!(h1, h2);
+        assert!(h1.contains(g.id()) && h1.contains(h.id()));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn refresh_heads() {
         let mut peer = create_peer();
-        let patches = init_patches(&peer);
-        let ids: Vec<_> = patches.iter().map(|p| *p.id()).collect();
+        let a = peer.commit(&"A").unwrap();
+        let b = Patch::new(&peer.signing_key, [*a.id()], &"B").unwrap();
+        peer.store().commit(&b).unwrap();
+        assert_eq!(peer.heads(), &[*a.id()]);
 
-        let missing = peer.integrate(patches.clone()).unwrap();
-        let in_store = peer.patches(&ids).unwrap();
+        peer.refresh_heads().unwrap();
+        assert_eq!(peer.heads(), &[*b.id()]);
+        let c = peer.commit(&"C").unwrap();
+        assert_eq!(&c.deps()[..], &[*b.id()]);
+    }
 
-        assert_eq!(patches, in_store);
-        assert!(missing.is_empty());
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn shared_store() {
+        let path = std::env::temp_dir().join(format!("st-{}.db", rand::random::<u64>()));
+        let open = || {
+            let conn = rusqlite::Connection::open(&path).unwrap();
+            let store = SqliteStore::new(conn).unwrap();
+            Peer::new(SigningKey::generate(&mut rand::rngs::OsRng), store).unwrap()
+        };
+        let mut p1 = open();
+        let mut p2 = open();
+        let a = p1.commit(&"A").unwrap();
+        let b = p2.commit(&"B").unwrap();
+        assert_eq!(&b.deps()[..], &[*a.id()]);
+        let c = p1.commit(&"C").unwrap();
+        assert_eq!(&c.deps()[..], &[*b.id()]);
+        assert_eq!(p1.heads(), &[*c.id()]);
+
+        // patch committed directly through the store
+        let d = Patch::new(&p2.signing_key, [*c.id()], &"D").unwrap();
+        p2.store().commit(&d).unwrap();
+        let e = p1.commit(&"E").unwrap();
+        assert_eq!(&e.deps()[..], &[*d.id()]);
+        let f = p2.commit(&"F").unwrap();
+        assert_eq!(&f.deps()[..], &[*e.id()]);
+        assert_eq!(p1.store().generation().unwrap(), 6);
+
+        drop((p1, p2));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn frontier() {
+        let mut p1 = create_peer();
+        let a = p1.commit(&"A").unwrap();
+        let b = p1.commit(&"B").unwrap();
+        let c = p1.commit(&"C").unwrap();
+        let d = p1.commit(&"D").unwrap();
+
+        let mut p2 = create_peer();
+        assert!(p2.frontier().unwrap().is_empty());
+        p2.integrate([d.clone(), c.clone()]).unwrap();
+        assert_eq!(p2.frontier().unwrap(), vec![*b.id()]);
+        p2.integrate([b]).unwrap();
+        assert_eq!(p2.frontier().unwrap(), vec![*a.id()]);
+
+        let report = p2.integrate([a]).unwrap();
+        assert_eq!(report.committed.len(), 4);
+        assert!(p2.frontier().unwrap().is_empty());
+        assert_eq!(p2.heads(), &[*d.id()]);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn max_patch_bytes() {
+        let mut peer = create_peer().with_max_patch_bytes(10);
+        let a = peer.commit(&"12345678").unwrap();
+        assert_eq!(a.data().len(), 10);
+        let res = peer.commit(&"123456789");
+        assert!(matches!(res, Err(Error::PatchTooLarge(_))));
+        assert_eq!(peer.heads(), &[*a.id()]);
+        assert_eq!(peer.store.topo_order().unwrap().len(), 1);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn integrate_failure_keeps_batch() {
+        use crate::store::sqlite::Options;
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let store =
+            SqliteStore::with_options(conn, Options::default().max_patch_bytes(10)).unwrap();
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let mut peer = Peer::new(key.clone(), store).unwrap();
+        let a = Patch::new(&key, [], &"A").unwrap();
+        // waits for a missing dependency, but is too large to be stashed
+        let large = Patch::new(&key, [*a.id(), ID::default()], &"123456789").unwrap();
+
+        let res = peer.integrate([a.clone(), large]);
+        assert!(matches!(res, Err(Error::PatchTooLarge(_))));
+        assert!(peer.store.is_integrated(a.id()).unwrap());
+        assert_eq!(peer.heads(), &[*a.id()]);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn on_commit() {
+        let mut p1 = create_peer();
+        let mut p2 = create_peer();
+        let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = events.clone();
+        p2.on_commit(move |patch| sink.lock().unwrap().push((*patch.id(), *patch.author())));
+
+        let patches = init_patches(&p1);
+        let ids: Vec<_> = patches.iter().map(|p| *p.id()).collect();
+        p1.integrate(patches.clone()).unwrap();
+        // out of order: everything but A is stashed first, then flushed at once
+        p2.integrate(patches[1..].iter().rev().cloned()).unwrap();
+        assert!(events.lock().unwrap().is_empty());
+        p2.integrate(patches.clone()).unwrap();
+        let local = p2.commit(&"G").unwrap();
+
+        let events = events.lock().unwrap();
+        let committed: Vec<ID> = events.iter().map(|(id, _)| *id).collect();
+        assert_eq!(committed.len(), 7);
+        assert_eq!(committed[6], *local.id());
+        let pos = |id: &ID| committed.iter().position(|c| c == id).unwrap();
+        for patch in patches.iter() {
+            for dep in patch.deps().iter() {
+                assert!(pos(dep) < pos(patch.id()));
+            }
+        }
+        assert!(ids.iter().all(|id| committed.contains(id)));
+        assert_eq!(events[0].1, p1.peer_id());
+        assert_eq!(events[6].1, p2.peer_id());
     }
 
+    #[cfg(feature = "sqlite")]
     #[test]
     fn missing_dep() {
         let mut peer = create_peer();
@@ -183,7 +2396,99 @@ mod test {
         let ids: Vec<_> = patches.iter().map(|p| *p.id()).collect();
 
         let removed = patches.remove(4);
-        let missing = peer.integrate(patches.clone()).unwrap();
+        let report = peer.integrate(patches.clone()).unwrap();
+        assert_eq!(report.missing, vec![*removed.id()]);
+        assert_eq!(report.stashed, vec![ids[5]]);
+        let sorted: Vec<ID> = topo_sort(patches[..4].to_vec())
+            .iter()
+            .map(|p| *p.id())
+            .collect();
+        assert_eq!(report.committed, sorted);
+        assert!(report.duplicates.is_empty());
+
+        let in_store: Vec<_> = peer
+            .patches(&ids)
+            .unwrap()
+            .into_iter()
+            .map(|p| serde_json::from_slice::<String>(p.data()).unwrap())
+            .collect();
+        assert_eq!(in_store, vec!["A", "B", "C", "D"]);
+    }
+
+    /// Returns two patches depending on each other. Such patches can't be created honestly, since
+    /// ID of X no longer matches its content after its dependencies are swapped. They are signed
+    /// though, so that cycle is the only thing wrong with them until their IDs are checked.
+    #[cfg(feature = "sqlite")]
+    fn cyclic_patches(peer: &Peer<SqliteStore>) -> (Patch, Patch) {
+        let mut x = Patch::new(&peer.signing_key, [], &"X").unwrap();
+        let y = Patch::new(&peer.signing_key, [*x.id()], &"Y").unwrap();
+        let mut deps = Deps::with_capacity(1);
+        deps.insert(*y.id());
+        x.deps = deps;
+        x.sign = ed25519_dalek::Signer::sign(&peer.signing_key, &x.signed_message());
+        (x, y)
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn cyclic_dependency() {
+        let mut peer = create_peer();
+        let (x, y) = cyclic_patches(&peer);
+        let res = peer.integrate([x.clone(), y.clone()]);
+        assert!(matches!(res, Err(Error::CyclicDependency(id)) if id == *x.id() || id == *y.id()));
+        assert!(!peer.store().contains(x.id()).unwrap());
+        assert!(!peer.store().contains(y.id()).unwrap());
+
+        // cycle spanning across stashed and newly integrated patches
+        let mut peer = create_peer();
+        let (x, y) = cyclic_patches(&peer);
+        let a = peer.commit(&"A").unwrap();
+        let missing = peer.integrate([y.clone()]).unwrap().missing;
+        assert_eq!(missing, vec![*x.id()]);
+        let res = peer.integrate([x.clone()]);
+        assert!(matches!(res, Err(Error::CyclicDependency(_))));
+        assert!(!peer.store().contains(x.id()).unwrap());
+        assert_eq!(peer.heads(), &[*a.id()]);
+    }
+
+    #[test]
+    fn reconcile_memory() {
+        let mut p1 = create_memory_peer();
+        let mut p2 = create_memory_peer();
+        let patches = init_patches(&p1);
+        p1.integrate(patches.clone()).unwrap();
+        p2.integrate(patches.clone()).unwrap();
+        let g = p1.commit(&"G").unwrap();
+        let h = p2.commit(&"H").unwrap();
+        let i = p2.commit(&"I").unwrap();
+
+        run_reconcile(&p1, &mut p2);
+        run_reconcile(&p2, &mut p1);
+
+        let mut ids: Vec<_> = patches.into_iter().map(|p| *p.id()).collect();
+        ids.push(*g.id());
+        ids.push(*h.id());
+        ids.push(*i.id());
+        let res1 = p1.patches(&ids).unwrap();
+        let res2 = p2.patches(&ids).unwrap();
+        assert_eq!(res1.len(), ids.len());
+        assert_eq!(res1, res2);
+
+        let mut heads1 = p1.heads().to_vec();
+        let mut heads2 = p2.heads().to_vec();
+        heads1.sort();
+        heads2.sort();
+        assert_eq!(heads1, heads2);
+    }
+
+    #[test]
+    fn missing_dep_memory() {
+        let mut peer = create_memory_peer();
+        let mut patches = init_patches(&peer);
+        let ids: Vec<_> = patches.iter().map(|p| *p.id()).collect();
+
+        let removed = patches.remove(4);
+        let missing = peer.integrate(patches.clone()).unwrap().missing;
         assert_eq!(missing, vec![*removed.id()]);
 
         let in_store: Vec<_> = peer
@@ -193,5 +2498,655 @@ mod test {
             .map(|p| serde_json::from_slice::<String>(p.data()).unwrap())
             .collect();
         assert_eq!(in_store, vec!["A", "B", "C", "D"]);
+
+        let report = peer.integrate([removed]).unwrap();
+        assert!(report.missing().is_empty());
+        assert_eq!(peer.patches(&ids).unwrap().len(), ids.len());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn reconcile_async() {
+        block_on(async {
+            let conn = rusqlite::Connection::open_in_memory().unwrap();
+            let store: AsyncSqliteStore = BlockingStore::new(SqliteStore::new(conn).unwrap());
+            let key_pair = SigningKey::generate(&mut rand::rngs::OsRng);
+            let mut p1 = Peer::new_async(key_pair, store).await.unwrap();
+            let mut p2 = create_memory_peer();
+            p2.commit(&"A").unwrap();
+            p2.commit(&"B").unwrap();
+
+            let mut missing = p1.missing_async(p2.heads()).await.unwrap();
+            while !missing.is_empty() {
+                let patches = p2.patches(&missing).unwrap();
+                missing = p1.integrate_async(patches).await.unwrap().missing;
+            }
+            assert_eq!(p1.heads(), p2.heads());
+
+            let c = p1.commit_async(&"C").await.unwrap();
+            assert_eq!(c.deps().as_ref(), p2.heads());
+            let found = p1.patches_async(&[*c.id()]).await.unwrap();
+            assert_eq!(found, vec![c]);
+        });
+    }
+
+    #[test]
+    fn checkpoint_async() {
+        block_on(async {
+            let store = BlockingStore::new(MemoryStore::new());
+            let mut owner = create_memory_peer();
+            let key = SigningKey::generate(&mut rand::rngs::OsRng);
+            let mut other = Peer::new_async(key, store).await.unwrap();
+
+            let genesis = owner.commit(&"A").unwrap();
+            let prune = owner.prune().unwrap();
+            other
+                .integrate_async([genesis, prune.clone()])
+                .await
+                .unwrap();
+            assert_eq!(other.checkpoint(), Some(prune.id()));
+
+            // prune committed by a peer, which is not the owner, is not a checkpoint
+            other.commit_async(&Op::Prune).await.unwrap();
+            assert_eq!(other.checkpoint(), Some(prune.id()));
+
+            let key = SigningKey::generate(&mut rand::rngs::OsRng);
+            let mut owner = Peer::new_async(key, BlockingStore::new(MemoryStore::new()))
+                .await
+                .unwrap();
+            owner.commit_async(&"A").await.unwrap();
+            let prune = owner.commit_async(&Op::Prune).await.unwrap();
+            assert_eq!(owner.checkpoint(), Some(prune.id()));
+        });
+    }
+
+    #[test]
+    fn max_patch_bytes_async() {
+        block_on(async {
+            let key = SigningKey::generate(&mut rand::rngs::OsRng);
+            let mut peer = Peer::new_async(key, BlockingStore::new(MemoryStore::new()))
+                .await
+                .unwrap()
+                .with_max_patch_bytes(10);
+            let a = peer.commit_async(&"12345678").await.unwrap();
+            let res = peer.commit_async(&"123456789").await;
+            assert!(matches!(res, Err(Error::PatchTooLarge(_))));
+            assert_eq!(peer.heads(), &[*a.id()]);
+        });
+    }
+
+    #[test]
+    fn integrate_one() {
+        let mut owner = create_memory_peer().with_access_control(true);
+        let mut other = create_memory_peer().with_access_control(true);
+        let stranger = create_memory_peer();
+        let a = owner.commit(&"A").unwrap();
+        let b = owner.commit(&"B").unwrap();
+        let c = owner.commit(&"C").unwrap();
+
+        assert_eq!(
+            other.integrate_one(a.clone()).unwrap(),
+            IntegrateOutcome::Committed(vec![*a.id()])
+        );
+        assert_eq!(
+            other.integrate_one(a.clone()).unwrap(),
+            IntegrateOutcome::Duplicate
+        );
+        assert_eq!(
+            other.integrate_one(c.clone()).unwrap(),
+            IntegrateOutcome::Stashed(vec![*b.id()])
+        );
+        // stashed patch is committed as soon as its dependency arrives
+        assert_eq!(
+            other.integrate_one(b.clone()).unwrap(),
+            IntegrateOutcome::Committed(vec![*b.id(), *c.id()])
+        );
+
+        let forged = Patch::new(&stranger.signing_key, [*c.id()], &"D").unwrap();
+        assert_eq!(
+            other.integrate_one(forged).unwrap(),
+            IntegrateOutcome::Unauthorized
+        );
+        assert_eq!(other.heads(), &[*c.id()]);
+    }
+
+    #[test]
+    fn integrate_rejected_with_stashed_siblings() {
+        let mut owner = create_memory_peer().with_access_control(true);
+        let mut other = create_memory_peer().with_access_control(true);
+        let stranger = create_memory_peer();
+        let a = owner.commit(&"A").unwrap();
+        let b = owner.commit(&"B").unwrap();
+        let c = owner.commit(&"C").unwrap();
+        other.integrate([a]).unwrap();
+
+        let forged = Patch::new(&stranger.signing_key, [*b.id()], &"F").unwrap();
+        let descendant = Patch::new(&stranger.signing_key, [*forged.id()], &"G").unwrap();
+        let report = other
+            .integrate([c.clone(), forged.clone(), descendant.clone()])
+            .unwrap();
+        assert_eq!(report.stashed.len(), 3);
+
+        // unauthorized patch doesn't take its valid siblings down with it
+        let report = other.integrate([b.clone()]).unwrap();
+        assert_eq!(report.committed, vec![*b.id(), *c.id()]);
+        let mut rejected = vec![*forged.id(), *descendant.id()];
+        rejected.sort();
+        let mut reported = report.rejected.clone();
+        reported.sort();
+        assert_eq!(reported, rejected);
+        assert!(report.stashed.is_empty());
+        assert_eq!(other.heads(), &[*c.id()]);
+        assert!(other.store.stashed().unwrap().is_empty());
+    }
+
+    #[test]
+    fn integrate_refs() {
+        let mut a = create_memory_peer();
+        let mut b = create_memory_peer();
+        let mut c = create_memory_peer();
+        let p1 = a.commit(&"A").unwrap();
+        let p2 = a.commit(&"B").unwrap();
+        let p3 = a.commit(&"C").unwrap();
+
+        // patches are given out of order, and one of them waits for a missing dependency
+        let batch = vec![p3.clone(), p1.clone()];
+        let report = b.integrate_refs(&batch).unwrap();
+        assert_eq!(report.committed, vec![*p1.id()]);
+        assert_eq!(report.stashed, vec![*p3.id()]);
+        assert_eq!(report.missing, vec![*p2.id()]);
+        let report = b.integrate_refs(std::slice::from_ref(&p2)).unwrap();
+        assert_eq!(report.committed, vec![*p2.id(), *p3.id()]);
+        assert_eq!(b.heads(), a.heads());
+
+        // the same batch can still be passed on to another peer
+        let batch = vec![p1, p2, p3];
+        c.integrate_refs(&batch).unwrap();
+        let report = c.integrate(batch.clone()).unwrap();
+        assert_eq!(
+            report.duplicates,
+            batch.iter().map(|p| *p.id()).collect::<Vec<_>>()
+        );
+        assert_eq!(c.heads(), a.heads());
+    }
+
+    #[test]
+    fn access_control() {
+        let mut owner = create_memory_peer().with_access_control(true);
+        let mut stranger = create_memory_peer();
+        let set = Op::UpdateEntry("key".into(), Value::Int(1));
+
+        let genesis = owner.commit(&set).unwrap();
+        stranger.integrate([genesis.clone()]).unwrap();
+        let unauthorized = Patch::new(&stranger.signing_key, [*genesis.id()], &set).unwrap();
+        let fake_root = Patch::new(&stranger.signing_key, [], &set).unwrap();
+
+        let report = owner.integrate([unauthorized.clone()]).unwrap();
+        assert_eq!(report.rejected, vec![*unauthorized.id()]);
+        let report = owner.integrate([fake_root.clone()]).unwrap();
+        assert_eq!(report.rejected, vec![*fake_root.id()]);
+        assert_eq!(owner.heads(), &[*genesis.id()]);
+
+        let grant = owner.commit(&Op::Grant(stranger.peer_id())).unwrap();
+        stranger.integrate([grant]).unwrap();
+        let authorized = stranger.commit(&set).unwrap();
+        owner.integrate([authorized.clone()]).unwrap();
+        assert_eq!(owner.heads(), &[*authorized.id()]);
+        assert!(owner
+            .permissions()
+            .unwrap()
+            .is_moderator(&stranger.peer_id()));
+
+        // access control is disabled by default
+        let mut permissionless = create_memory_peer();
+        permissionless.integrate([genesis, unauthorized]).unwrap();
+        permissionless.integrate([fake_root]).unwrap();
+        assert_eq!(permissionless.heads().len(), 2);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn stats() {
+        let mut p1 = create_peer();
+        let mut p2 = create_memory_peer();
+        let patches = init_patches(&p1);
+        let f = &patches[5];
+        let data_bytes = patches.iter().map(|p| p.data().len() as u64).sum::<u64>();
+        for stats in [p1.stats().unwrap(), p2.stats().unwrap()] {
+            assert_eq!(stats, StoreStats::default());
+        }
+
+        let other = create_peer();
+        let g = Patch::new(&other.signing_key, [*f.id()], &"G").unwrap();
+        let h = Patch::new(&other.signing_key, [*g.id()], &"H").unwrap();
+        let i = Patch::new(&other.signing_key, [*h.id()], &"I").unwrap();
+        let received: Vec<_> = patches.iter().cloned().chain([g.clone(), i]).collect();
+        p1.integrate(received.clone()).unwrap();
+        p2.integrate(received).unwrap();
+
+        let expected = StoreStats {
+            patches: 7,
+            stashed: 1,
+            authors: 2,
+            data_bytes: data_bytes + g.data().len() as u64,
+        };
+        assert_eq!(p1.stats().unwrap(), expected);
+        assert_eq!(p2.stats().unwrap(), expected);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn permissions_at() {
+        let mut peer = create_peer();
+        let mod_id = PeerID::new([1; 32]);
+        let grant = peer.commit(&Op::Grant(mod_id)).unwrap();
+        let revoke = peer.commit(&Op::Revoke(mod_id)).unwrap();
+        assert!(peer
+            .permissions_at(&[*grant.id()])
+            .unwrap()
+            .is_moderator(&mod_id));
+        assert!(!peer
+            .permissions_at(&[*revoke.id()])
+            .unwrap()
+            .is_moderator(&mod_id));
+        assert_eq!(
+            peer.permissions().unwrap(),
+            peer.permissions_at(&[*revoke.id()]).unwrap()
+        );
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn merge() {
+        let mut peer = create_peer();
+        let a = peer.commit(&"A").unwrap();
+        let b = Patch::new(&peer.signing_key, [*a.id()], &"B").unwrap();
+        let c = Patch::new(&peer.signing_key, [*a.id()], &"C").unwrap();
+        let d = Patch::new(&peer.signing_key, [*a.id()], &"D").unwrap();
+        peer.integrate([b.clone(), c.clone(), d.clone()]).unwrap();
+        assert_eq!(peer.heads().len(), 3);
+
+        let res = peer.merge(&[*b.id(), *a.id()], &"B+A");
+        assert!(matches!(res, Err(Error::NotHead(id)) if id == *a.id()));
+
+        let m = peer.merge(&[*b.id(), *c.id()], &"B+C").unwrap();
+        assert_eq!(m.deps().len(), 2);
+        assert!(m.deps().contains(b.id()) && m.deps().contains(c.id()));
+        let mut heads = peer.heads().to_vec();
+        heads.sort();
+        let mut expected = vec![*d.id(), *m.id()];
+        expected.sort();
+        assert_eq!(heads, expected);
+        assert_eq!(peer.store.heads().unwrap().len(), 2);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn verify_history() {
+        let mut peer = create_peer();
+        let patches = init_patches(&peer);
+        peer.integrate(patches.clone()).unwrap();
+        peer.verify_history().unwrap();
+
+        // swap signatures of B and C
+        let (b, c) = (&patches[1], &patches[2]);
+        peer.store
+            .conn
+            .execute(
+                "UPDATE st_patches SET signature = ? WHERE hash = ?",
+                rusqlite::params![b.sign().to_bytes(), c.id()],
+            )
+            .unwrap();
+        let res = peer.verify_history();
+        assert!(matches!(res, Err(Error::InvalidSignature(id)) if id == *c.id()));
+
+        // stores require dependencies to be committed first
+        let peer = create_memory_peer();
+        let orphan = Patch::new(&peer.signing_key, [*patches[0].id()], &"B").unwrap();
+        let res = peer.store.commit(&orphan);
+        assert!(matches!(res, Err(Error::MissingDependency(id)) if id == *patches[0].id()));
+        peer.verify_history().unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn squash() {
+        let mut peer = create_peer();
+        let root = peer.commit(&"R").unwrap();
+        let a = peer.commit(&"A").unwrap();
+        let b = peer.commit(&"B").unwrap();
+        let c = peer.commit(&"C").unwrap();
+
+        let squashed = peer.squash(*a.id(), *c.id()).unwrap();
+        assert_eq!(squashed.deps().as_ref(), &[*root.id()]);
+        assert_eq!(squashed.data(), br#"["A","B","C"]"#);
+        assert_eq!(peer.heads(), &[*squashed.id()]);
+        assert_eq!(
+            peer.store.topo_order().unwrap(),
+            vec![*root.id(), *squashed.id()]
+        );
+        for removed in [&a, &b, &c] {
+            assert!(!peer.store.is_integrated(removed.id()).unwrap());
+        }
+
+        // runs with forks, foreign patches or dependents can't be squashed
+        let d = peer.commit(&"D").unwrap();
+        let e = peer.commit_on(&[*d.id()], &"E").unwrap();
+        let f = peer.commit_on(&[*d.id()], &"F").unwrap();
+        let res = peer.squash(*squashed.id(), *e.id());
+        assert!(matches!(res, Err(Error::NonLinearRun(id)) if id == *d.id()));
+        let res = peer.squash(*squashed.id(), *d.id());
+        assert!(matches!(res, Err(Error::NonLinearRun(id)) if id == *d.id()));
+        let other = create_peer();
+        let g = Patch::new(&other.signing_key, [*f.id()], &"G").unwrap();
+        peer.integrate([g.clone()]).unwrap();
+        let res = peer.squash(*f.id(), *g.id());
+        assert!(matches!(res, Err(Error::NonLinearRun(id)) if id == *g.id()));
+        assert_eq!(peer.store.topo_order().unwrap().len(), 6);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn children() {
+        let mut p1 = create_peer();
+        let mut p2 = create_memory_peer();
+        let patches = init_patches(&p1);
+        let (a, b, c, e, f) = (
+            &patches[0],
+            &patches[1],
+            &patches[2],
+            &patches[4],
+            &patches[5],
+        );
+        p1.integrate(patches.clone()).unwrap();
+        p2.integrate(patches.clone()).unwrap();
+
+        for children in [p1.store.children(a.id()), p2.store.children(a.id())] {
+            let children = children.unwrap();
+            assert_eq!(children.len(), 2);
+            assert!(children.contains(b.id()) && children.contains(c.id()));
+        }
+        assert_eq!(p1.store.children(c.id()).unwrap(), vec![*e.id()]);
+        assert_eq!(p2.store.children(c.id()).unwrap(), vec![*e.id()]);
+        assert!(p1.store.children(f.id()).unwrap().is_empty());
+
+        p2.store.remove(f.id()).unwrap();
+        assert!(p2.store.children(e.id()).unwrap().is_empty());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn stash_once() {
+        let mut p1 = create_peer();
+        let mut p2 = create_peer();
+        let a = p1.commit(&"A").unwrap();
+        let b = Patch::new(&p1.signing_key, [], &"B").unwrap();
+        let c = Patch::new(&p1.signing_key, [*a.id(), *b.id()], &"C").unwrap();
+
+        let report = p2.integrate([c.clone()]).unwrap();
+        assert_eq!(report.stashed, vec![*c.id()]);
+        let mut missing = report.missing.clone();
+        missing.sort();
+        let mut expected = vec![*a.id(), *b.id()];
+        expected.sort();
+        assert_eq!(missing, expected);
+        assert_eq!(p2.store.stashed().unwrap(), vec![c.clone()]);
+
+        let report = p2.integrate([a, b]).unwrap();
+        assert_eq!(report.committed.last(), Some(c.id()));
+        assert!(p2.store.stashed().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rotate_key() {
+        let mut owner = create_memory_peer().with_access_control(true);
+        let mut other = create_memory_peer().with_access_control(true);
+        let old_id = owner.peer_id();
+        let genesis = owner.commit(&"A").unwrap();
+
+        let new_key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let new_id: PeerID = new_key.verifying_key().into();
+        let rotation = owner.rotate_key(new_key).unwrap();
+        assert_eq!(rotation.author(), &old_id);
+        assert_eq!(owner.peer_id(), new_id);
+
+        let b = owner.commit(&"B").unwrap();
+        assert_eq!(b.author(), &new_id);
+        let prune = owner.prune().unwrap();
+        assert_eq!(owner.checkpoint(), Some(prune.id()));
+
+        let report = other
+            .integrate([genesis, rotation, b, prune.clone()])
+            .unwrap();
+        assert_eq!(report.committed.len(), 4);
+        assert_eq!(other.checkpoint(), Some(prune.id()));
+        let permissions = other.permissions().unwrap();
+        assert_eq!(permissions.owner(), Some(&old_id));
+        assert!(permissions.is_owner(&new_id));
+    }
+
+    #[test]
+    fn prune() {
+        let mut owner = create_memory_peer();
+        let mut other = create_memory_peer();
+        let genesis = owner.commit(&"A").unwrap();
+        other.integrate([genesis]).unwrap();
+
+        let err = other.prune().unwrap_err();
+        assert!(matches!(err, crate::Error::Unauthorized(_)));
+        assert_eq!(other.checkpoint(), None);
+
+        let prune = owner.prune().unwrap();
+        assert_eq!(owner.checkpoint(), Some(prune.id()));
+        other.integrate([prune.clone()]).unwrap();
+        assert_eq!(other.checkpoint(), Some(prune.id()));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn reconcile_with_filter() {
+        let mut p1 = create_peer();
+        let mut p2 = create_memory_peer();
+        let patches = init_patches(&p1);
+        p1.integrate(patches.clone()).unwrap();
+        p2.integrate(patches.clone()).unwrap();
+        let g = p1.commit(&"G").unwrap();
+        let h = p1.commit(&"H").unwrap();
+        let i = p2.commit(&"I").unwrap();
+
+        // p1 ships only the patches p2 doesn't have
+        let filter = p2.have_filter().unwrap();
+        let missing = p1.missing_from_filter(&filter, p1.heads()).unwrap();
+        assert!(missing.iter().all(|id| id == g.id() || id == h.id()));
+        p2.integrate(p1.patches(&missing).unwrap()).unwrap();
+
+        let filter = p1.have_filter().unwrap();
+        let missing = p2.missing_from_filter(&filter, p2.heads()).unwrap();
+        assert!(missing.iter().all(|id| id == i.id()));
+        p1.integrate(p2.patches(&missing).unwrap()).unwrap();
+
+        // false positives are caught up by the regular reconciliation
+        run_reconcile(&p1, &mut p2);
+        run_reconcile(&p2, &mut p1);
+
+        let mut heads1 = p1.heads().to_vec();
+        let mut heads2 = p2.heads().to_vec();
+        heads1.sort();
+        heads2.sort();
+        assert_eq!(heads1, heads2);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn topo_order() {
+        let p1 = create_peer();
+        let p2 = create_memory_peer();
+        let patches = init_patches(&p1);
+        // commit in an order, which is topological but not the canonical one
+        for i in [0, 2, 1, 4, 5, 3] {
+            p1.store().commit(&patches[i]).unwrap();
+            p2.store().commit(&patches[i]).unwrap();
+        }
+
+        let order1 = p1.store().topo_order().unwrap();
+        let order2 = p2.store().topo_order().unwrap();
+        assert_eq!(order1, order2);
+        assert_eq!(order1.len(), patches.len());
+        for (i, id) in order1.iter().enumerate() {
+            let patch = patches.iter().find(|p| p.id() == id).unwrap();
+            for dep in patch.deps().iter() {
+                assert!(order1[..i].contains(dep));
+            }
+        }
+    }
+
+    fn check_authored_by<S: ObjectStore>(mut p1: Peer<S>) {
+        let mut p2 = create_memory_peer();
+        let a = p1.commit(&"A").unwrap();
+        p2.integrate([a.clone()]).unwrap();
+        let b = p2.commit(&"B").unwrap();
+        p1.integrate([b.clone()]).unwrap();
+        let c = p1.commit(&"C").unwrap();
+        p2.integrate([c.clone()]).unwrap();
+        let d = p2.commit(&"D").unwrap();
+        p1.integrate([d.clone()]).unwrap();
+
+        assert_eq!(
+            p1.authored_by(&p1.peer_id()).unwrap(),
+            vec![*a.id(), *c.id()]
+        );
+        assert_eq!(
+            p1.authored_by(&p2.peer_id()).unwrap(),
+            vec![*b.id(), *d.id()]
+        );
+        assert!(p1.authored_by(&PeerID::default()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn authored_by() {
+        for_each_store!(|store| check_authored_by(create_peer_over(store)));
+    }
+
+    fn check_author_heads<S: ObjectStore>(mut p1: Peer<S>) {
+        let mut p2 = create_memory_peer();
+        assert!(p1.author_heads().unwrap().is_empty());
+        let a = p1.commit(&"A").unwrap();
+        p2.integrate([a.clone()]).unwrap();
+        let b = p2.commit(&"B").unwrap();
+        p1.integrate([b.clone()]).unwrap();
+        let c = p1.commit(&"C").unwrap();
+        p2.integrate([c.clone()]).unwrap();
+        let d = p2.commit(&"D").unwrap();
+        p1.integrate([d.clone()]).unwrap();
+        let heads = p1.author_heads().unwrap();
+        assert_eq!(heads.len(), 2);
+        assert_eq!(heads[&p1.peer_id()], *c.id());
+        assert_eq!(heads[&p2.peer_id()], *d.id());
+
+        // shallower patches committed later don't replace deeper ones
+        let g = p2.commit_on(&[*a.id()], &"G").unwrap();
+        p1.integrate([g]).unwrap();
+        // concurrent patches of the same depth are ordered by commit
+        p1.commit_on(&[*d.id()], &"E").unwrap();
+        let f = p1.commit_on(&[*d.id()], &"F").unwrap();
+        let heads = p1.author_heads().unwrap();
+        assert_eq!(heads[&p1.peer_id()], *f.id());
+        assert_eq!(heads[&p2.peer_id()], *d.id());
+    }
+
+    #[test]
+    fn author_heads() {
+        for_each_store!(|store| check_author_heads(create_peer_over(store)));
+    }
+
+    fn check_is_ancestor<S: ObjectStore>(mut p: Peer<S>) {
+        let patches = init_patches(&p);
+        let [a, b, c, d, e, f] = [0, 1, 2, 3, 4, 5].map(|i| *patches[i].id());
+        p.integrate(patches).unwrap();
+
+        assert!(p.is_ancestor(&a, &f).unwrap());
+        assert!(p.is_ancestor(&b, &f).unwrap());
+        assert!(p.is_ancestor(&c, &e).unwrap());
+        assert!(p.is_ancestor(&a, &d).unwrap());
+        // concurrent patches
+        assert!(!p.is_ancestor(&d, &e).unwrap());
+        assert!(!p.is_ancestor(&e, &d).unwrap());
+        assert!(!p.is_ancestor(&c, &d).unwrap());
+        // the relation is not symmetric
+        assert!(!p.is_ancestor(&f, &a).unwrap());
+        // every patch is its own ancestor
+        assert!(p.is_ancestor(&d, &d).unwrap());
+        // unknown patches are neither ancestors nor descendants
+        let unknown = ID::default();
+        assert!(!p.is_ancestor(&unknown, &f).unwrap());
+        assert!(!p.is_ancestor(&a, &unknown).unwrap());
+        assert!(!p.is_ancestor(&unknown, &unknown).unwrap());
+    }
+
+    #[test]
+    fn is_ancestor() {
+        for_each_store!(|store| check_is_ancestor(create_peer_over(store)));
+    }
+
+    fn check_pins<S: ObjectStore>(mut p: Peer<S>) {
+        let patches = init_patches(&p);
+        let [a, b, c, d, e, f] = [0, 1, 2, 3, 4, 5].map(|i| *patches[i].id());
+        p.integrate(patches).unwrap();
+        assert!(p.pin(&e).unwrap());
+        assert!(p.pin(&e).unwrap());
+        assert!(!p.pin(&ID::default()).unwrap());
+        assert_eq!(p.store().pinned().unwrap(), vec![e]);
+
+        // neither the pinned patch nor its ancestors can be removed
+        assert!(!p.store().remove(&e).unwrap());
+        for id in [a, b, c, e] {
+            let err = p.store().remove_cascade(&id).unwrap_err();
+            assert!(matches!(err, Error::Pinned(pinned) if pinned == e));
+        }
+        for id in [a, b, c, e] {
+            assert!(p.store().is_integrated(&id).unwrap());
+        }
+        // patches outside of its causal past are not protected
+        assert_eq!(p.store().remove_cascade(&d).unwrap(), vec![d]);
+        assert_eq!(p.store().heads().unwrap(), vec![f]);
+
+        // pinned patches can't be squashed away
+        let g = p.commit(&"G").unwrap();
+        let h = p.commit(&"H").unwrap();
+        p.pin(g.id()).unwrap();
+        let err = p.squash(*g.id(), *h.id()).unwrap_err();
+        assert!(matches!(err, Error::Pinned(pinned) if pinned == *g.id()));
+        assert_eq!(p.heads(), &[*h.id()]);
+
+        assert!(p.unpin(&e).unwrap());
+        assert!(!p.unpin(&e).unwrap());
+        assert!(p.unpin(g.id()).unwrap());
+        assert!(p.store().pinned().unwrap().is_empty());
+        assert_eq!(p.store().remove_cascade(&a).unwrap().len(), 7);
+    }
+
+    #[test]
+    fn pins() {
+        for_each_store!(|store| check_pins(create_peer_over(store)));
+    }
+
+    fn check_commit_missing_dependency<S: ObjectStore>(p: Peer<S>) {
+        let patches = init_patches(&p);
+        let (a, b) = (&patches[0], &patches[1]);
+        let res = p.store.commit(b);
+        assert!(matches!(res, Err(Error::MissingDependency(id)) if id == *a.id()));
+
+        p.store.commit(a).unwrap();
+        let missing = Patch::new(&p.signing_key, [*a.id(), ID::default()], &"X").unwrap();
+        let res = p.store.commit(&missing);
+        assert!(matches!(res, Err(Error::MissingDependency(id)) if id == ID::default()));
+        assert!(!p.store.is_integrated(missing.id()).unwrap());
+        assert_eq!(p.store.heads().unwrap(), vec![*a.id()]);
+        assert_eq!(p.store.topo_order().unwrap(), vec![*a.id()]);
+    }
+
+    #[test]
+    fn commit_missing_dependency() {
+        for_each_store!(|store| check_commit_missing_dependency(create_peer_over(store)));
     }
 }
@@ -0,0 +1,241 @@
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use rand::seq::index::sample;
+use rand::Rng;
+
+use crate::crypto::CryptoSuite;
+use crate::peer::sync::sync_stream;
+use crate::peer::{IntegrateReport, Peer};
+use crate::store::ObjectStore;
+use crate::Result;
+
+/// Connection to a neighbor of a [GossipEngine].
+pub trait Transport {
+    type Reader: Read;
+    type Writer: Write;
+
+    /// Opens a duplex byte stream to the neighbor, which is expected to run [sync_stream] on its
+    /// end of the stream.
+    fn connect(&mut self) -> Result<(Self::Reader, Self::Writer)>;
+}
+
+/// Outcome of synchronization with a single neighbor within a gossip round.
+#[derive(Debug)]
+pub struct Exchange {
+    /// Index of the neighbor, in the order they were given to the [GossipEngine].
+    pub neighbor: usize,
+    pub result: Result<IntegrateReport>,
+}
+
+/// Anti-entropy scheduler, which keeps a peer in sync with a set of neighbors. Every round it
+/// picks up to `fanout` random neighbors and runs [sync_stream] with each of them, so that
+/// patches eventually spread to all peers connected (even indirectly) to each other, without every
+/// pair of peers ever talking directly.
+///
+/// Rounds can be driven one by one with [GossipEngine::round], eg. from an existing event loop
+/// using [GossipEngine::next_delay] as a timer, or by a blocking [GossipEngine::run] loop.
+#[derive(Debug)]
+pub struct GossipEngine<T> {
+    neighbors: Vec<T>,
+    fanout: usize,
+    interval: Duration,
+    jitter: Duration,
+}
+
+impl<T: Transport> GossipEngine<T> {
+    pub fn new(neighbors: Vec<T>) -> Self {
+        GossipEngine {
+            neighbors,
+            fanout: 1,
+            interval: Duration::from_secs(1),
+            jitter: Duration::from_millis(250),
+        }
+    }
+
+    /// Sets a number of neighbors synchronized with in every round. Default: 1.
+    pub fn with_fanout(mut self, fanout: usize) -> Self {
+        self.fanout = fanout;
+        self
+    }
+
+    /// Sets an average time between consecutive rounds. Default: 1 second.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets a maximum random deviation from the interval between rounds, so that peers started
+    /// at the same time don't keep contacting each other in lockstep. Default: 250 milliseconds.
+    pub fn with_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn neighbors(&self) -> &[T] {
+        &self.neighbors
+    }
+
+    pub fn add_neighbor(&mut self, neighbor: T) {
+        self.neighbors.push(neighbor);
+    }
+
+    /// Returns a randomized time to wait before the next round: the interval shifted by a random
+    /// amount within the jitter, in either direction.
+    pub fn next_delay(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.interval;
+        }
+        let jitter = rand::thread_rng().gen_range(0..=2 * self.jitter.as_nanos() as u64);
+        (self.interval + Duration::from_nanos(jitter)).saturating_sub(self.jitter)
+    }
+
+    /// Runs a single round: synchronizes a given peer with up to `fanout` distinct neighbors,
+    /// picked at random. Failure to synchronize with one neighbor doesn't prevent synchronization
+    /// with the others, so it's reported within the returned exchanges.
+    pub fn round<S, C>(&mut self, peer: &mut Peer<S, C>) -> Vec<Exchange>
+    where
+        S: ObjectStore,
+        C: CryptoSuite,
+    {
+        let fanout = self.fanout.min(self.neighbors.len());
+        let picked = sample(&mut rand::thread_rng(), self.neighbors.len(), fanout);
+        picked
+            .into_iter()
+            .map(|neighbor| {
+                let result = self.neighbors[neighbor]
+                    .connect()
+                    .and_then(|(reader, writer)| sync_stream(peer, reader, writer));
+                Exchange { neighbor, result }
+            })
+            .collect()
+    }
+
+    /// Keeps running rounds, sleeping for [GossipEngine::next_delay] between them, for as long
+    /// as `proceed` returns true for the exchanges of the last round.
+    pub fn run<S, C, F>(&mut self, peer: &mut Peer<S, C>, mut proceed: F)
+    where
+        S: ObjectStore,
+        C: CryptoSuite,
+        F: FnMut(&[Exchange]) -> bool,
+    {
+        loop {
+            let exchanges = self.round(peer);
+            if !proceed(&exchanges) {
+                return;
+            }
+            std::thread::sleep(self.next_delay());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{PipeReader, PipeWriter};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use ed25519_dalek::SigningKey;
+
+    use crate::peer::gossip::{GossipEngine, Transport};
+    use crate::peer::sync::sync_stream;
+    use crate::peer::Peer;
+    use crate::store::memory::MemoryStore;
+    use crate::Result;
+
+    type SharedPeer = Arc<Mutex<Peer<MemoryStore>>>;
+
+    /// Connects to an in-process peer, which serves the connection on a separate thread.
+    struct InProcess(SharedPeer);
+
+    impl Transport for InProcess {
+        type Reader = PipeReader;
+        type Writer = PipeWriter;
+
+        fn connect(&mut self) -> Result<(PipeReader, PipeWriter)> {
+            let (local_reader, remote_writer) = std::io::pipe()?;
+            let (remote_reader, local_writer) = std::io::pipe()?;
+            let remote = self.0.clone();
+            std::thread::spawn(move || {
+                let mut remote = remote.lock().unwrap();
+                let _ = sync_stream(&mut remote, remote_reader, remote_writer);
+            });
+            Ok((local_reader, local_writer))
+        }
+    }
+
+    fn create_peer() -> SharedPeer {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        Arc::new(Mutex::new(Peer::new(key, MemoryStore::new()).unwrap()))
+    }
+
+    fn sorted_heads(peer: &SharedPeer) -> Vec<crate::patch::ID> {
+        let mut heads = peer.lock().unwrap().heads().to_vec();
+        heads.sort();
+        heads
+    }
+
+    #[test]
+    fn three_peers_converge() {
+        let peers: Vec<SharedPeer> = (0..3).map(|_| create_peer()).collect();
+        let mut engines: Vec<GossipEngine<InProcess>> = (0..3)
+            .map(|i| {
+                let neighbors = (0..3)
+                    .filter(|j| *j != i)
+                    .map(|j| InProcess(peers[j].clone()))
+                    .collect();
+                GossipEngine::new(neighbors).with_fanout(1)
+            })
+            .collect();
+        for (i, peer) in peers.iter().enumerate() {
+            let mut peer = peer.lock().unwrap();
+            peer.commit(&format!("root {i}")).unwrap();
+            peer.commit(&format!("child {i}")).unwrap();
+        }
+
+        let mut rounds = 0;
+        while !(sorted_heads(&peers[0]) == sorted_heads(&peers[1])
+            && sorted_heads(&peers[1]) == sorted_heads(&peers[2])
+            && sorted_heads(&peers[0]).len() == 3)
+        {
+            assert!(rounds < 100, "peers didn't converge");
+            let i = rounds % 3;
+            let mut peer = peers[i].lock().unwrap();
+            let exchanges = engines[i].round(&mut peer);
+            assert_eq!(exchanges.len(), 1);
+            assert!(exchanges.iter().all(|e| e.result.is_ok()));
+            rounds += 1;
+        }
+        for peer in peers.iter() {
+            assert_eq!(peer.lock().unwrap().stats().unwrap().patches, 6);
+        }
+    }
+
+    #[test]
+    fn fanout_and_jitter() {
+        let peers: Vec<SharedPeer> = (0..3).map(|_| create_peer()).collect();
+        let neighbors = peers[1..].iter().map(|p| InProcess(p.clone())).collect();
+        let mut engine = GossipEngine::new(neighbors)
+            .with_fanout(5)
+            .with_interval(Duration::from_millis(100))
+            .with_jitter(Duration::from_millis(20));
+        let committed = peers[0].lock().unwrap().commit(&"A").unwrap();
+
+        let mut exchanges = engine.round(&mut peers[0].lock().unwrap());
+        exchanges.sort_by_key(|e| e.neighbor);
+        assert_eq!(
+            exchanges.iter().map(|e| e.neighbor).collect::<Vec<_>>(),
+            [0, 1]
+        );
+        for peer in peers[1..].iter() {
+            assert_eq!(peer.lock().unwrap().heads(), &[*committed.id()]);
+        }
+
+        for _ in 0..100 {
+            let delay = engine.next_delay();
+            assert!(delay >= Duration::from_millis(80) && delay <= Duration::from_millis(120));
+        }
+        let engine = engine.with_jitter(Duration::ZERO);
+        assert_eq!(engine.next_delay(), Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,215 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock};
+
+use bytes::Bytes;
+use serde::Serialize;
+
+use crate::crypto::{CryptoSuite, Ed25519Blake3};
+use crate::patch::{Patch, ID};
+use crate::peer::{IntegrateOutcome, IntegrateReport, Peer};
+use crate::store::ObjectStore;
+use crate::{PeerID, Result};
+
+/// [Peer] which can be shared between threads (eg. behind an `Arc`), as all of its methods take
+/// `&self`. This way many concurrent sync sessions can work against the same peer.
+///
+/// Writes (commits and integrations) are serialized, since each of them has to build on top of the
+/// heads left by the previous one, so that no update is lost. Current heads, on the other hand,
+/// can be read at any time without waiting for writes in progress: they're published once a write
+/// completes, together with the store [ObjectStore::generation] they reflect.
+pub struct SharedPeer<S, C: CryptoSuite = Ed25519Blake3> {
+    peer: Mutex<Peer<S, C>>,
+    heads: RwLock<Vec<ID>>,
+    generation: AtomicU64,
+}
+
+impl<S, C: CryptoSuite> SharedPeer<S, C> {
+    pub fn new(peer: Peer<S, C>) -> Self {
+        SharedPeer {
+            heads: RwLock::new(peer.heads.clone()),
+            generation: AtomicU64::new(peer.generation),
+            peer: Mutex::new(peer),
+        }
+    }
+
+    pub fn peer_id(&self) -> PeerID {
+        self.lock().peer_id()
+    }
+
+    /// Returns heads published by the most recently completed write.
+    pub fn heads(&self) -> Vec<ID> {
+        self.heads
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Store generation, at which current [SharedPeer::heads] were published. Changes every time
+    /// new heads are published, so it can be used to cheaply detect updates.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Gives exclusive access to the underlying peer, eg. to call methods not exposed by the
+    /// shared peer. Writes are blocked until the guard is dropped, and heads are not published
+    /// until the next write made through the shared peer.
+    pub fn lock(&self) -> MutexGuard<'_, Peer<S, C>> {
+        // heads are refreshed from the store by the next write, if needed, so a panic in the
+        // middle of one doesn't leave the peer unusable
+        self.peer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn into_inner(self) -> Peer<S, C> {
+        self.peer
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Runs a given write on the underlying peer, then publishes its heads.
+    fn write<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Peer<S, C>) -> R,
+    {
+        let mut peer = self.lock();
+        let result = f(&mut peer);
+        let mut heads = self.heads.write().unwrap_or_else(PoisonError::into_inner);
+        heads.clone_from(&peer.heads);
+        self.generation.store(peer.generation, Ordering::Release);
+        result
+    }
+}
+
+impl<S: ObjectStore, C: CryptoSuite> SharedPeer<S, C> {
+    /// Equivalent of [Peer::commit].
+    pub fn commit<B>(&self, data: &B) -> Result<Patch>
+    where
+        B: Serialize,
+    {
+        self.write(|peer| peer.commit(data))
+    }
+
+    /// Equivalent of [Peer::commit_raw].
+    pub fn commit_raw<B>(&self, data: B) -> Result<Patch>
+    where
+        B: Into<Bytes>,
+    {
+        self.write(|peer| peer.commit_raw(data))
+    }
+
+    /// Equivalent of [Peer::integrate].
+    pub fn integrate<I>(&self, patches: I) -> Result<IntegrateReport>
+    where
+        I: IntoIterator<Item = Patch>,
+    {
+        self.write(|peer| peer.integrate(patches))
+    }
+
+    /// Equivalent of [Peer::integrate_refs].
+    pub fn integrate_refs(&self, patches: &[Patch]) -> Result<IntegrateReport> {
+        self.write(|peer| peer.integrate_refs(patches))
+    }
+
+    /// Equivalent of [Peer::integrate_one].
+    pub fn integrate_one(&self, patch: Patch) -> Result<IntegrateOutcome> {
+        self.write(|peer| peer.integrate_one(patch))
+    }
+
+    /// Equivalent of [Peer::refresh_heads], publishing heads changed by other means than this
+    /// shared peer (eg. by another process sharing the same database).
+    pub fn refresh_heads(&self) -> Result<()> {
+        self.write(|peer| peer.refresh_heads())
+    }
+
+    /// Equivalent of [Peer::missing].
+    pub fn missing(&self, heads: &[ID]) -> Result<Vec<ID>> {
+        self.lock().missing(heads)
+    }
+
+    /// Equivalent of [Peer::patches].
+    pub fn patches(&self, ids: &[ID]) -> Result<Vec<Patch>> {
+        self.lock().patches(ids)
+    }
+}
+
+impl<S, C: CryptoSuite> std::fmt::Debug for SharedPeer<S, C>
+where
+    Peer<S, C>: std::fmt::Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedPeer")
+            .field("peer", &self.peer)
+            .field("heads", &self.heads)
+            .field("generation", &self.generation)
+            .finish()
+    }
+}
+
+impl<S, C: CryptoSuite> From<Peer<S, C>> for SharedPeer<S, C> {
+    fn from(peer: Peer<S, C>) -> Self {
+        SharedPeer::new(peer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use ed25519_dalek::SigningKey;
+
+    use crate::peer::shared::SharedPeer;
+    use crate::peer::Peer;
+    use crate::store::memory::MemoryStore;
+    use crate::store::ObjectStore;
+
+    #[test]
+    fn concurrent_commits() {
+        const THREADS: usize = 8;
+        const COMMITS: usize = 25;
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let peer = Arc::new(SharedPeer::new(Peer::new(key, MemoryStore::new()).unwrap()));
+        let root = peer.commit(&"root").unwrap();
+
+        let writers: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let peer = peer.clone();
+                std::thread::spawn(move || {
+                    (0..COMMITS)
+                        .map(|i| peer.commit(&format!("{t}:{i}")).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let reader = {
+            let peer = peer.clone();
+            std::thread::spawn(move || {
+                let mut generation = peer.generation();
+                for _ in 0..1000 {
+                    // heads are never observed mid-update
+                    assert_eq!(peer.heads().len(), 1);
+                    assert!(peer.generation() >= generation);
+                    generation = peer.generation();
+                }
+            })
+        };
+        let committed: Vec<_> = writers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect();
+        reader.join().unwrap();
+
+        // every commit built on top of the previous one, so history is a single chain
+        let peer = Arc::into_inner(peer).unwrap().into_inner();
+        assert_eq!(
+            peer.stats().unwrap().patches,
+            (THREADS * COMMITS + 1) as u64
+        );
+        assert_eq!(peer.store().heads().unwrap(), peer.heads());
+        let mut deps: Vec<_> = committed.iter().map(|p| p.deps()[0]).collect();
+        deps.sort();
+        deps.dedup();
+        assert_eq!(deps.len(), THREADS * COMMITS);
+        assert!(committed.iter().all(|p| p.deps().len() == 1));
+        assert!(deps.contains(root.id()));
+        assert!(!deps.contains(&peer.heads()[0]));
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::VecDeque;
+use std::future::{poll_fn, Future};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Poll, Waker};
+
+use crate::patch::Patch;
+use crate::Result;
+
+/// Destination of patches sent by [crate::peer::Peer::stream_delta], eg. a framed network writer.
+/// Sink can apply backpressure by not completing [PatchSink::send] until it's ready to accept
+/// more patches, so that a slow receiver throttles the sender instead of making it buffer the
+/// whole delta.
+pub trait PatchSink {
+    /// Sends a given patch, completing once the sink has accepted it.
+    fn send(&mut self, patch: Patch) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Collects all sent patches, never applying backpressure.
+impl PatchSink for Vec<Patch> {
+    fn send(&mut self, patch: Patch) -> impl Future<Output = Result<()>> + Send {
+        self.push(patch);
+        std::future::ready(Ok(()))
+    }
+}
+
+/// Creates an in-memory channel, which buffers up to `capacity` (at least 1) patches. Once the
+/// buffer is full, [PatchSink::send] waits until [PatchReceiver] takes some patches out of it.
+pub fn channel(capacity: usize) -> (ChannelSink, PatchReceiver) {
+    let channel = Arc::new(Mutex::new(Channel {
+        queue: VecDeque::new(),
+        capacity: capacity.max(1),
+        sender: None,
+        receiver: None,
+        sink_closed: false,
+        receiver_closed: false,
+    }));
+    (ChannelSink(channel.clone()), PatchReceiver(channel))
+}
+
+struct Channel {
+    queue: VecDeque<Patch>,
+    capacity: usize,
+    /// Waker of the sink waiting for free space in the queue.
+    sender: Option<Waker>,
+    /// Waker of the receiver waiting for patches.
+    receiver: Option<Waker>,
+    sink_closed: bool,
+    receiver_closed: bool,
+}
+
+impl std::fmt::Debug for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Channel")
+            .field("queued", &self.queue.len())
+            .field("capacity", &self.capacity)
+            .field("sink_closed", &self.sink_closed)
+            .field("receiver_closed", &self.receiver_closed)
+            .finish()
+    }
+}
+
+fn lock(channel: &Mutex<Channel>) -> MutexGuard<'_, Channel> {
+    channel.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Sending half of a [channel]. Sending fails once the [PatchReceiver] is dropped.
+#[derive(Debug)]
+pub struct ChannelSink(Arc<Mutex<Channel>>);
+
+impl PatchSink for ChannelSink {
+    fn send(&mut self, patch: Patch) -> impl Future<Output = Result<()>> + Send {
+        let mut patch = Some(patch);
+        poll_fn(move |cx| {
+            let mut channel = lock(&self.0);
+            if channel.receiver_closed {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "patch receiver has been dropped",
+                )
+                .into()));
+            }
+            if channel.queue.len() < channel.capacity {
+                channel.queue.extend(patch.take());
+                if let Some(waker) = channel.receiver.take() {
+                    waker.wake();
+                }
+                Poll::Ready(Ok(()))
+            } else {
+                channel.sender = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl Drop for ChannelSink {
+    fn drop(&mut self) {
+        let mut channel = lock(&self.0);
+        channel.sink_closed = true;
+        if let Some(waker) = channel.receiver.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Receiving half of a [channel].
+#[derive(Debug)]
+pub struct PatchReceiver(Arc<Mutex<Channel>>);
+
+impl PatchReceiver {
+    /// Receives the next patch, waiting for one if none is buffered. Returns `None` once the
+    /// [ChannelSink] is dropped and all patches sent by it were received.
+    pub async fn recv(&mut self) -> Option<Patch> {
+        poll_fn(|cx| {
+            let mut channel = lock(&self.0);
+            match channel.queue.pop_front() {
+                Some(patch) => {
+                    if let Some(waker) = channel.sender.take() {
+                        waker.wake();
+                    }
+                    Poll::Ready(Some(patch))
+                }
+                None if channel.sink_closed => Poll::Ready(None),
+                None => {
+                    channel.receiver = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    /// Number of patches sent, but not received yet.
+    pub fn len(&self) -> usize {
+        lock(&self.0).queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for PatchReceiver {
+    fn drop(&mut self) {
+        let mut channel = lock(&self.0);
+        channel.receiver_closed = true;
+        if let Some(waker) = channel.sender.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use ed25519_dalek::SigningKey;
+
+    use crate::peer::sink::channel;
+    use crate::peer::Peer;
+    use crate::store::blocking::block_on;
+    use crate::store::memory::MemoryStore;
+
+    #[test]
+    fn slow_consumer_throttles_producer() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let mut peer = Peer::new(key, MemoryStore::new()).unwrap();
+        let committed: Vec<_> = (0..10).map(|i| *peer.commit(&i).unwrap().id()).collect();
+        let (mut sink, mut receiver) = channel(2);
+        let producer =
+            std::thread::spawn(move || block_on(peer.stream_delta(&[], &mut sink)).unwrap());
+
+        // nothing is received yet, so the producer waits for the buffer to free up
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(receiver.len(), 2);
+        assert!(!producer.is_finished());
+
+        let mut received = Vec::new();
+        while let Some(patch) = block_on(receiver.recv()) {
+            assert!(receiver.len() <= 2);
+            received.push(*patch.id());
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(producer.join().unwrap(), 10);
+        assert_eq!(received, committed);
+
+        // sending fails once the receiver is gone
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let mut peer = Peer::new(key, MemoryStore::new()).unwrap();
+        peer.commit(&"A").unwrap();
+        let (mut sink, receiver) = channel(1);
+        drop(receiver);
+        assert!(block_on(peer.stream_delta(&[], &mut sink)).is_err());
+    }
+}
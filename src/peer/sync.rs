@@ -0,0 +1,371 @@
+use std::io::{Read, Write};
+
+use ed25519::Signature;
+use ed25519_dalek::SignatureError;
+use varint_rs::{VarintReader, VarintWriter};
+
+use crate::crypto::{CryptoSuite, Ed25519Blake3};
+use crate::patch::{invalid_data, Patch, ID};
+use crate::peer::{IntegrateReport, Peer};
+use crate::store::ObjectStore;
+use crate::{PeerID, Result};
+
+/// Maximum length of a single frame. Large enough to fit a patch of maximum size.
+pub const MAX_FRAME_LEN: usize = 32 * 1024 * 1024;
+
+/// Domain separation tag prepended to messages signed by [Ack], distinct from
+/// [crate::patch::SIGNATURE_DOMAIN], so that acks and patches can't be passed off as one another.
+pub const ACK_SIGNATURE_DOMAIN: &[u8] = b"storyteller-ack-v1";
+
+/// Maximum number of IDs sent within a single frame. Longer lists are split across frames.
+const MAX_IDS_PER_FRAME: usize = 64 * 1024;
+
+const FRAME_HELLO: u8 = 0;
+const FRAME_HEADS: u8 = 1;
+const FRAME_WANT: u8 = 2;
+const FRAME_PATCH: u8 = 3;
+/// Terminates a list of IDs or patches.
+const FRAME_END: u8 = 4;
+
+const NONCE_LEN: usize = 32;
+
+/// Synchronizes a given peer with a remote one, reachable over a duplex byte stream. Both sides
+/// are expected to call this function. Returns once both peers have received all patches they
+/// were missing.
+///
+/// Protocol goes as follows:
+/// 1. Peers exchange random nonces. Side with a greater one becomes a leader: in every following
+///    exchange it writes its message first, while the other side reads first. This way peers
+///    never block on writing to each other at the same time.
+/// 2. Peers exchange their heads and determine which of them they are missing.
+/// 3. Peers exchange lists of wanted patch IDs and reply with requested patches, which are then
+///    integrated. Missing dependencies of these patches become wanted in the next round. This
+///    repeats until neither side wants anything.
+///
+/// Every message is sent as a sequence of frames, each one prefixed with its varint-encoded
+/// length and a frame type byte.
+pub fn sync_stream<S, C, R, W>(
+    peer: &mut Peer<S, C>,
+    reader: R,
+    writer: W,
+) -> Result<IntegrateReport>
+where
+    S: ObjectStore,
+    C: CryptoSuite,
+    R: Read,
+    W: Write,
+{
+    let mut channel = Channel {
+        reader,
+        writer,
+        leader: false,
+    };
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    write_frame(&mut channel.writer, FRAME_HELLO, &nonce)?;
+    channel.writer.flush()?;
+    let remote_nonce = read_frame(&mut channel.reader)?;
+    if remote_nonce.tag != FRAME_HELLO || remote_nonce.body.len() != NONCE_LEN {
+        return Err(invalid_data("expected hello frame").into());
+    }
+    if remote_nonce.body[..] == nonce[..] {
+        return Err(invalid_data("remote peer responded with the same nonce").into());
+    }
+    channel.leader = nonce[..] > remote_nonce.body[..];
+
+    let heads = peer.heads().to_vec();
+    let remote_heads = channel.exchange(
+        |w| write_ids(w, FRAME_HEADS, &heads),
+        |r| read_ids(r, FRAME_HEADS),
+    )?;
+
+    let mut report = IntegrateReport::default();
+    let mut missing = peer.missing(&remote_heads)?;
+    loop {
+        let wanted = channel.exchange(
+            |w| write_ids(w, FRAME_WANT, &missing),
+            |r| read_ids(r, FRAME_WANT),
+        )?;
+        if missing.is_empty() && wanted.is_empty() {
+            break;
+        }
+        let reply = peer.patches(&wanted)?;
+        let received = channel.exchange(|w| write_patches(w, &reply), read_patches::<C, _>)?;
+        let round = peer.integrate(received)?;
+        report.committed.extend(round.committed);
+        report.duplicates.extend(round.duplicates);
+        report.stashed = round.stashed;
+        missing = round.missing;
+    }
+    report.missing = missing;
+    Ok(report)
+}
+
+/// Acknowledgement of patches integrated by a peer, signed with its key. Sender of these patches
+/// can verify it to learn that they were committed (rather than stashed) and stop retransmitting
+/// them, while collected acks serve as an audit trail of which peer has which patches.
+///
+/// Ack signs [ACK_SIGNATURE_DOMAIN] followed by a concatenation of acknowledged IDs, sorted by
+/// their bytes. Produced by [Peer::make_ack].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ack {
+    peer: PeerID,
+    ids: Vec<ID>,
+    sign: Signature,
+}
+
+impl Ack {
+    pub(crate) fn new<C: CryptoSuite>(key: &C::SigningKey, mut ids: Vec<ID>) -> Self {
+        ids.sort();
+        ids.dedup();
+        let msg = Self::message(&ids);
+        Ack {
+            peer: C::peer_id(key),
+            sign: C::sign(key, &msg),
+            ids,
+        }
+    }
+
+    /// Peer, which has integrated acknowledged patches.
+    pub fn peer(&self) -> &PeerID {
+        &self.peer
+    }
+
+    /// IDs of acknowledged patches, sorted by their bytes.
+    pub fn ids(&self) -> &[ID] {
+        &self.ids
+    }
+
+    pub fn sign(&self) -> &Signature {
+        &self.sign
+    }
+
+    /// Verifies that the ack was signed by its peer.
+    pub fn verify(&self) -> std::result::Result<(), SignatureError> {
+        self.verify_with::<Ed25519Blake3>()
+    }
+
+    /// Equivalent of [Ack::verify] for peers using a given [CryptoSuite].
+    pub fn verify_with<C: CryptoSuite>(&self) -> std::result::Result<(), SignatureError> {
+        if !self.ids.is_sorted_by(|a, b| a[..] < b[..]) {
+            return Err(SignatureError::new());
+        }
+        C::verify(&self.peer, &Self::message(&self.ids), &self.sign)
+    }
+
+    fn message(ids: &[ID]) -> Vec<u8> {
+        let mut msg = Vec::with_capacity(ACK_SIGNATURE_DOMAIN.len() + ids.len() * blake3::OUT_LEN);
+        msg.extend_from_slice(ACK_SIGNATURE_DOMAIN);
+        for id in ids {
+            msg.extend_from_slice(id);
+        }
+        msg
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        w.write_all(&self.peer)?;
+        w.write_u32_varint(self.ids.len() as u32)?;
+        for id in self.ids.iter() {
+            w.write_all(id)?;
+        }
+        w.write_all(&self.sign.to_bytes())
+    }
+
+    /// Reads an ack written with [Ack::write]. Its signature is not verified.
+    pub fn read<R: Read>(r: &mut R) -> Result<Self> {
+        let mut peer = PeerID::default();
+        r.read_exact(&mut peer)?;
+        let len = r.read_u32_varint()? as usize;
+        if len > MAX_IDS_PER_FRAME {
+            return Err(invalid_data("ack ID count exceeds limit").into());
+        }
+        let mut ids = Vec::with_capacity(len);
+        for _ in 0..len {
+            let mut id = ID::default();
+            r.read_exact(&mut id)?;
+            ids.push(id);
+        }
+        let mut sign = [0u8; Signature::BYTE_SIZE];
+        r.read_exact(&mut sign)?;
+        Ok(Ack {
+            peer,
+            ids,
+            sign: Signature::from_bytes(&sign),
+        })
+    }
+}
+
+struct Channel<R, W> {
+    reader: R,
+    writer: W,
+    leader: bool,
+}
+
+impl<R: Read, W: Write> Channel<R, W> {
+    /// Sends a local message and receives a remote one, in order determined by the channel role.
+    fn exchange<T, F, G>(&mut self, send: F, recv: G) -> Result<T>
+    where
+        F: FnOnce(&mut W) -> std::io::Result<()>,
+        G: FnOnce(&mut R) -> Result<T>,
+    {
+        if self.leader {
+            send(&mut self.writer)?;
+            self.writer.flush()?;
+            recv(&mut self.reader)
+        } else {
+            let received = recv(&mut self.reader)?;
+            send(&mut self.writer)?;
+            self.writer.flush()?;
+            Ok(received)
+        }
+    }
+}
+
+struct Frame {
+    tag: u8,
+    body: Vec<u8>,
+}
+
+fn write_frame<W: Write>(w: &mut W, tag: u8, body: &[u8]) -> std::io::Result<()> {
+    w.write_u32_varint(body.len() as u32 + 1)?;
+    w.write_all(&[tag])?;
+    w.write_all(body)
+}
+
+fn read_frame<R: Read>(r: &mut R) -> std::io::Result<Frame> {
+    let len = r.read_u32_varint()? as usize;
+    if len == 0 || len > MAX_FRAME_LEN {
+        return Err(invalid_data("invalid frame length"));
+    }
+    let mut tag = [0u8; 1];
+    r.read_exact(&mut tag)?;
+    let mut body = Vec::new();
+    r.take(len as u64 - 1).read_to_end(&mut body)?;
+    if body.len() != len - 1 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Frame { tag: tag[0], body })
+}
+
+fn write_ids<W: Write>(w: &mut W, tag: u8, ids: &[ID]) -> std::io::Result<()> {
+    for chunk in ids.chunks(MAX_IDS_PER_FRAME) {
+        let body: Vec<u8> = chunk.iter().flat_map(|id| id.iter().copied()).collect();
+        write_frame(w, tag, &body)?;
+    }
+    write_frame(w, FRAME_END, &[])
+}
+
+fn read_ids<R: Read>(r: &mut R, tag: u8) -> Result<Vec<ID>> {
+    let mut ids = Vec::new();
+    loop {
+        let frame = read_frame(r)?;
+        if frame.tag == FRAME_END {
+            return Ok(ids);
+        }
+        if frame.tag != tag || frame.body.len() % blake3::OUT_LEN != 0 {
+            return Err(invalid_data("expected frame with a list of patch IDs").into());
+        }
+        for chunk in frame.body.chunks_exact(blake3::OUT_LEN) {
+            let mut id = ID::default();
+            id.copy_from_slice(chunk);
+            ids.push(id);
+        }
+    }
+}
+
+fn write_patches<W: Write>(w: &mut W, patches: &[Patch]) -> std::io::Result<()> {
+    let mut body = Vec::new();
+    for patch in patches {
+        body.clear();
+        patch.write(&mut body)?;
+        write_frame(w, FRAME_PATCH, &body)?;
+    }
+    write_frame(w, FRAME_END, &[])
+}
+
+fn read_patches<C: CryptoSuite, R: Read>(r: &mut R) -> Result<Vec<Patch>> {
+    let mut patches = Vec::new();
+    loop {
+        let frame = read_frame(r)?;
+        match frame.tag {
+            FRAME_END => return Ok(patches),
+            FRAME_PATCH => {
+                let mut body = frame.body.as_slice();
+                patches.push(Patch::read_with::<C, _>(&mut body)?);
+                if !body.is_empty() {
+                    return Err(invalid_data("unexpected trailing bytes in patch frame").into());
+                }
+            }
+            _ => return Err(invalid_data("expected patch frame").into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::peer::sync::{sync_stream, Ack};
+    use crate::peer::test::{create_memory_peer, init_patches};
+
+    #[test]
+    fn sync_over_pipes() {
+        let mut p1 = create_memory_peer();
+        let mut p2 = create_memory_peer();
+        let patches = init_patches(&p1);
+        let (a, b, c, d) = (&patches[0], &patches[1], &patches[2], &patches[3]);
+        p1.integrate([a.clone(), b.clone(), d.clone()]).unwrap();
+        p2.integrate([a.clone(), b.clone(), c.clone()]).unwrap();
+        let g = p1.commit(&"G").unwrap();
+        let h = p2.commit(&"H").unwrap();
+
+        let (r1, w1) = std::io::pipe().unwrap();
+        let (r2, w2) = std::io::pipe().unwrap();
+        let remote = std::thread::spawn(move || {
+            let report = sync_stream(&mut p2, r2, w1).unwrap();
+            (p2, report)
+        });
+        let report = sync_stream(&mut p1, r1, w2).unwrap();
+        let (p2, remote_report) = remote.join().unwrap();
+
+        assert_eq!(report.committed, vec![*c.id(), *h.id()]);
+        assert_eq!(remote_report.committed, vec![*d.id(), *g.id()]);
+        assert!(report.missing.is_empty());
+
+        let mut h1 = p1.heads().to_vec();
+        let mut h2 = p2.heads().to_vec();
+        h1.sort();
+        h2.sort();
+        assert_eq!(h1, h2);
+        assert!(h1.contains(g.id()) && h1.contains(h.id()));
+    }
+
+    #[test]
+    fn ack_roundtrip() {
+        let mut p1 = create_memory_peer();
+        let patches = init_patches(&p1);
+        let (a, b, d) = (&patches[0], &patches[1], &patches[3]);
+        p1.integrate([a.clone(), b.clone(), d.clone()]).unwrap();
+        let c = &patches[2];
+
+        // only integrated patches are acknowledged
+        let ack = p1.make_ack(&[*d.id(), *a.id(), *c.id(), *a.id()]).unwrap();
+        let mut expected = [*a.id(), *d.id()];
+        expected.sort();
+        assert_eq!(ack.ids(), &expected[..]);
+        assert_eq!(ack.peer(), &p1.peer_id());
+        ack.verify().unwrap();
+
+        let mut bytes = Vec::new();
+        ack.write(&mut bytes).unwrap();
+        let received = Ack::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(received, ack);
+        received.verify().unwrap();
+
+        // tampered ack fails verification
+        let mut tampered = received.clone();
+        tampered.ids.pop();
+        assert!(tampered.verify().is_err());
+        let mut tampered = received;
+        tampered.peer = create_memory_peer().peer_id();
+        assert!(tampered.verify().is_err());
+        assert!(Ack::read(&mut &bytes[..bytes.len() - 1]).is_err());
+    }
+}
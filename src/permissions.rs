@@ -0,0 +1,430 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::op::Op;
+use crate::patch::{Patch, ID};
+use crate::PeerID;
+
+/// Access control list derived from the permission operations found in patch history.
+///
+/// Rules are as follows:
+/// - Author of the first (root) patch becomes the document owner. Once the owner is known, no other
+///   root patches are accepted.
+/// - Owner can perform any operation. [Op::Prune] and [Op::TransferOwnership] can be performed
+///   only by the owner.
+/// - Moderators can [Op::Grant] and [Op::Revoke] moderator rights of other peers (except for the
+///   owner) and perform data operations.
+/// - Anyone else is not allowed to change the document.
+/// - Owner and moderators can [Op::RotateKey] to a key, which has not been used by any other
+///   identity known to the permissions. From then on, both keys identify the same logical author,
+///   sharing its rights. Logical identity of an author is the first key it was known by.
+///
+/// Opaque patches and patches which data is not a valid [Op] are treated as data operations.
+///
+/// [Permissions::resolve] additionally resolves conflicts between concurrent permission operations
+/// using the precedence, in which [Op] variants are declared. Patches are folded in causal order
+/// and concurrent ones are applied from the highest to the lowest precedence. [Op::Revoke] wins
+/// over concurrent operations of the revoked peer: [Op::Grant] of its rights is discarded, as well
+/// as the [Op::Grant] and [Op::Revoke] operations it performed itself, no matter in which order
+/// they would be folded otherwise.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Permissions {
+    owner: Option<PeerID>,
+    moderators: HashSet<PeerID>,
+    /// Rotated keys mapped to the logical identity they belong to.
+    identities: HashMap<PeerID, PeerID>,
+}
+
+impl Permissions {
+    /// Builds permissions by folding a topologically ordered list of patches. Patches which
+    /// authors were not authorized to perform their operations are skipped.
+    pub fn from_patches<'a, I>(patches: I) -> Self
+    where
+        I: IntoIterator<Item = &'a Patch>,
+    {
+        let mut permissions = Permissions::default();
+        for patch in patches {
+            permissions.apply(patch);
+        }
+        permissions
+    }
+
+    /// Builds permissions authoritative for a given causal history, which doesn't need to be
+    /// ordered. Unlike [Permissions::from_patches], conflicts between concurrent permission
+    /// operations are resolved by their precedence (see [Permissions]), so that all peers agree on
+    /// the outcome regardless of the IDs of conflicting patches.
+    pub fn resolve<'a, I>(patches: I) -> Self
+    where
+        I: IntoIterator<Item = &'a Patch>,
+    {
+        let patches: Vec<&Patch> = patches.into_iter().collect();
+        let ops: Vec<Option<Op>> = patches
+            .iter()
+            .map(|p| p.op().and_then(Result::ok))
+            .collect();
+        let order = precedence_order(&patches, &ops);
+        let ancestors = ancestors(&patches, &order);
+        let concurrent =
+            |i: usize, j: usize| !ancestors[i].contains(&j) && !ancestors[j].contains(&i);
+        // patches discarded by concurrent revokes, which may have been applied already, in which
+        // case the whole history is folded again without them
+        let mut discarded: HashSet<usize> = HashSet::new();
+        'fold: loop {
+            let mut permissions = Permissions::default();
+            for &i in order.iter() {
+                if discarded.contains(&i) || !permissions.apply_op(patches[i], ops[i].as_ref()) {
+                    continue;
+                }
+                let revoked = match &ops[i] {
+                    Some(Op::Revoke(peer)) => *permissions.identity(peer),
+                    _ => continue,
+                };
+                let mut refold = false;
+                for j in 0..patches.len() {
+                    if i == j || discarded.contains(&j) || !concurrent(i, j) {
+                        continue;
+                    }
+                    let author = permissions.identity(patches[j].author());
+                    let overridden = match &ops[j] {
+                        Some(Op::Grant(peer)) => {
+                            permissions.identity(peer) == &revoked || author == &revoked
+                        }
+                        Some(Op::Revoke(_)) => author == &revoked,
+                        _ => false,
+                    };
+                    if overridden {
+                        discarded.insert(j);
+                        refold = true;
+                    }
+                }
+                if refold {
+                    continue 'fold;
+                }
+            }
+            return permissions;
+        }
+    }
+
+    /// Logical identity of the current document owner. None if no patch has been applied yet.
+    pub fn owner(&self) -> Option<&PeerID> {
+        self.owner.as_ref()
+    }
+
+    /// Returns true if given key belongs to the document owner.
+    pub fn is_owner(&self, peer: &PeerID) -> bool {
+        self.owner.as_ref() == Some(self.identity(peer))
+    }
+
+    /// Returns true if given peer has been granted moderator rights.
+    pub fn is_moderator(&self, peer: &PeerID) -> bool {
+        self.moderators.contains(self.identity(peer))
+    }
+
+    /// Returns the logical identity, which a given key belongs to. Keys that have never been
+    /// rotated to are their own identities.
+    pub fn identity<'a>(&'a self, peer: &'a PeerID) -> &'a PeerID {
+        self.identities.get(peer).unwrap_or(peer)
+    }
+
+    /// Returns a set of logical identities of peers with granted moderator rights.
+    pub fn moderators(&self) -> &HashSet<PeerID> {
+        &self.moderators
+    }
+
+    /// Checks if the author of a given patch is allowed to perform its operation, given the current
+    /// permissions.
+    pub fn is_authorized(&self, patch: &Patch) -> bool {
+        let op = patch.op().and_then(Result::ok);
+        let author = self.identity(patch.author());
+        self.is_authorized_op(author, op.as_ref(), patch.deps().is_empty())
+    }
+
+    /// Applies permission changes of a given patch, if its author was authorized to perform them.
+    /// Returns false if patch was not authorized.
+    pub fn apply(&mut self, patch: &Patch) -> bool {
+        let op = patch.op().and_then(Result::ok);
+        self.apply_op(patch, op.as_ref())
+    }
+
+    fn apply_op(&mut self, patch: &Patch, op: Option<&Op>) -> bool {
+        let author = *self.identity(patch.author());
+        if !self.is_authorized_op(&author, op, patch.deps().is_empty()) {
+            return false;
+        }
+        if self.owner.is_none() {
+            self.owner = Some(author);
+        }
+        match op {
+            Some(Op::Prune) => self.moderators.clear(),
+            Some(Op::TransferOwnership(peer)) => {
+                let peer = *self.identity(peer);
+                self.moderators.remove(&peer);
+                self.owner = Some(peer);
+            }
+            Some(Op::Grant(peer)) => {
+                self.moderators.insert(*self.identity(peer));
+            }
+            Some(Op::Revoke(peer)) => {
+                let peer = *self.identity(peer);
+                self.moderators.remove(&peer);
+            }
+            Some(Op::RotateKey(key)) => {
+                self.identities.insert(*key, author);
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// Checks authorization of a given logical identity.
+    fn is_authorized_op(&self, author: &PeerID, op: Option<&Op>, is_root: bool) -> bool {
+        let owner = match &self.owner {
+            None => return is_root,
+            Some(_) if is_root => return false,
+            Some(owner) => owner,
+        };
+        if let Some(Op::RotateKey(key)) = op {
+            // rotating to a key of another identity would let the author take over its rights
+            if self.is_known(key) {
+                return false;
+            }
+        }
+        if author == owner {
+            return true;
+        }
+        if !self.moderators.contains(author) {
+            return false;
+        }
+        match op {
+            Some(Op::Prune) | Some(Op::TransferOwnership(_)) => false,
+            Some(Op::Grant(peer)) | Some(Op::Revoke(peer)) => self.identity(peer) != owner,
+            _ => true,
+        }
+    }
+
+    fn is_known(&self, key: &PeerID) -> bool {
+        self.identities.contains_key(key)
+            || self.owner.as_ref() == Some(key)
+            || self.moderators.contains(key)
+    }
+}
+
+/// Precedence of an operation, lower values first. Follows the order of [Op] variants, with
+/// [Op::Prune] sharing precedence with [Op::TransferOwnership] and all data operations (including
+/// patches which are not operations at all) sharing the lowest one.
+fn precedence(op: Option<&Op>) -> u8 {
+    match op {
+        Some(Op::Prune) | Some(Op::TransferOwnership(_)) => 0,
+        Some(Op::Revoke(_)) => 1,
+        Some(Op::Grant(_)) => 2,
+        Some(Op::RotateKey(_)) => 3,
+        _ => 4,
+    }
+}
+
+/// Returns indexes of given patches in topological order, breaking ties between concurrent
+/// patches by [precedence] of their operations and then by their IDs. Roots are ordered by their
+/// IDs only, so that the same root becomes the owner as in [Permissions::from_patches] folding
+/// patches ordered by [crate::store::ObjectStore::topo_order].
+fn precedence_order(patches: &[&Patch], ops: &[Option<Op>]) -> Vec<usize> {
+    let index: HashMap<&ID, usize> = patches
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (p.id(), i))
+        .collect();
+    let mut pending = vec![0usize; patches.len()];
+    let mut children: Vec<Vec<usize>> = vec![Vec::new(); patches.len()];
+    for (child, patch) in patches.iter().enumerate() {
+        for dep in patch.deps().iter() {
+            if let Some(&parent) = index.get(dep) {
+                pending[child] += 1;
+                children[parent].push(child);
+            }
+        }
+    }
+    let key = |i: usize| {
+        let rank = if patches[i].deps().is_empty() {
+            0
+        } else {
+            precedence(ops[i].as_ref())
+        };
+        (rank, &patches[i].id()[..], i)
+    };
+    let mut ready: BTreeSet<(u8, &[u8], usize)> = (0..patches.len())
+        .filter(|i| pending[*i] == 0)
+        .map(key)
+        .collect();
+    let mut order = Vec::with_capacity(patches.len());
+    while let Some((_, _, i)) = ready.pop_first() {
+        order.push(i);
+        for &child in children[i].iter() {
+            pending[child] -= 1;
+            if pending[child] == 0 {
+                ready.insert(key(child));
+            }
+        }
+    }
+    order
+}
+
+/// Returns transitive dependencies (as indexes of `patches`) of every patch, given their
+/// topological `order`.
+fn ancestors(patches: &[&Patch], order: &[usize]) -> Vec<HashSet<usize>> {
+    let index: HashMap<&ID, usize> = patches
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (p.id(), i))
+        .collect();
+    let mut ancestors: Vec<HashSet<usize>> = vec![HashSet::new(); patches.len()];
+    for &i in order {
+        let mut set = HashSet::new();
+        for dep in patches[i].deps().iter() {
+            if let Some(&dep) = index.get(dep) {
+                set.insert(dep);
+                set.extend(ancestors[dep].iter().cloned());
+            }
+        }
+        ancestors[i] = set;
+    }
+    ancestors
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use ed25519_dalek::SigningKey;
+
+    use crate::op::{Op, Value};
+    use crate::patch::Patch;
+    use crate::permissions::Permissions;
+    use crate::PeerID;
+
+    #[test]
+    fn fold_permissions() {
+        let owner = SigningKey::generate(&mut rand::rngs::OsRng);
+        let moderator = SigningKey::generate(&mut rand::rngs::OsRng);
+        let stranger = SigningKey::generate(&mut rand::rngs::OsRng);
+        let mod_id: PeerID = moderator.verifying_key().into();
+        let owner_id: PeerID = owner.verifying_key().into();
+        let set = Op::UpdateEntry("key".into(), Value::Int(1));
+
+        let a = Patch::new(&owner, [], &set).unwrap();
+        let b = Patch::new(&stranger, [*a.id()], &set).unwrap();
+        let c = Patch::new(&owner, [*a.id()], &Op::Grant(mod_id)).unwrap();
+        let d = Patch::new(&moderator, [*c.id()], &set).unwrap();
+        let e = Patch::new(&moderator, [*d.id()], &Op::Revoke(owner_id)).unwrap();
+        let f = Patch::new(&moderator, [*d.id()], &Op::Prune).unwrap();
+
+        let mut permissions = Permissions::default();
+        assert!(permissions.apply(&a));
+        assert_eq!(permissions.owner(), Some(&owner_id));
+        assert!(!permissions.apply(&b));
+        assert!(!permissions.is_authorized(&d));
+        assert!(permissions.apply(&c));
+        assert!(permissions.is_moderator(&mod_id));
+        assert!(permissions.apply(&d));
+        assert!(!permissions.apply(&e));
+        assert!(!permissions.apply(&f));
+
+        let folded = Permissions::from_patches([&a, &b, &c, &d, &e, &f]);
+        assert_eq!(folded, permissions);
+    }
+
+    #[test]
+    fn concurrent_grant_revoke() {
+        let owner = SigningKey::generate(&mut rand::rngs::OsRng);
+        let moderator = SigningKey::generate(&mut rand::rngs::OsRng);
+        let mod_id: PeerID = moderator.verifying_key().into();
+        let peer_id = PeerID::new([1; 32]);
+
+        let a = Patch::new(&owner, [], &Op::Grant(mod_id)).unwrap();
+        let b = Patch::new(&owner, [*a.id()], &Op::Grant(peer_id)).unwrap();
+        // owner revokes the peer, while moderator concurrently grants it again
+        let c = Patch::new(&owner, [*b.id()], &Op::Revoke(peer_id)).unwrap();
+        let d = Patch::new(&moderator, [*b.id()], &Op::Grant(peer_id)).unwrap();
+
+        for history in [[&a, &b, &c, &d], [&d, &c, &b, &a]] {
+            let permissions = Permissions::resolve(history);
+            assert!(!permissions.is_moderator(&peer_id));
+            assert!(permissions.is_moderator(&mod_id));
+        }
+
+        // grant which causally follows the revoke is not a conflict
+        let e = Patch::new(&moderator, [*c.id()], &Op::Grant(peer_id)).unwrap();
+        let permissions = Permissions::resolve([&a, &b, &c, &d, &e]);
+        assert!(permissions.is_moderator(&peer_id));
+    }
+
+    #[test]
+    fn concurrent_ops_of_revoked_moderator() {
+        let owner = SigningKey::generate(&mut rand::rngs::OsRng);
+        let moderator = SigningKey::generate(&mut rand::rngs::OsRng);
+        let mod_id: PeerID = moderator.verifying_key().into();
+        let peer_id = PeerID::new([1; 32]);
+        let set = Op::UpdateEntry("key".into(), Value::Int(1));
+
+        let a = Patch::new(&owner, [], &Op::Grant(mod_id)).unwrap();
+        let b = Patch::new(&owner, [*a.id()], &set).unwrap();
+        let c = Patch::new(&owner, [*b.id()], &Op::Revoke(mod_id)).unwrap();
+        // folded before the revoke, which is preceded by a data operation
+        let d = Patch::new(&moderator, [*a.id()], &Op::Grant(peer_id)).unwrap();
+
+        let folded = Permissions::from_patches([&a, &d, &b, &c]);
+        assert!(folded.is_moderator(&peer_id));
+        let permissions = Permissions::resolve([&a, &b, &c, &d]);
+        assert!(!permissions.is_moderator(&peer_id));
+        assert!(!permissions.is_moderator(&mod_id));
+    }
+
+    #[test]
+    fn concurrent_revoke_transfer_ownership() {
+        let owner = SigningKey::generate(&mut rand::rngs::OsRng);
+        let moderator = SigningKey::generate(&mut rand::rngs::OsRng);
+        let owner_id: PeerID = owner.verifying_key().into();
+        let mod_id: PeerID = moderator.verifying_key().into();
+        let peer_id = PeerID::new([1; 32]);
+
+        let a = Patch::new(&owner, [], &Op::Grant(mod_id)).unwrap();
+        let b = Patch::new(&owner, [*a.id()], &Op::Grant(peer_id)).unwrap();
+        // owner hands the document over to the peer, while moderator revokes its rights
+        let c = Patch::new(&owner, [*b.id()], &Op::TransferOwnership(peer_id)).unwrap();
+        let d = Patch::new(&moderator, [*b.id()], &Op::Revoke(peer_id)).unwrap();
+
+        for history in [[&a, &b, &c, &d], [&a, &b, &d, &c]] {
+            let permissions = Permissions::resolve(history);
+            assert_eq!(permissions.owner(), Some(&peer_id));
+            assert!(!permissions.is_owner(&owner_id));
+            assert_eq!(permissions.moderators(), &HashSet::from([mod_id]));
+        }
+    }
+
+    #[test]
+    fn rotated_keys() {
+        let owner = SigningKey::generate(&mut rand::rngs::OsRng);
+        let rotated = SigningKey::generate(&mut rand::rngs::OsRng);
+        let moderator = SigningKey::generate(&mut rand::rngs::OsRng);
+        let owner_id: PeerID = owner.verifying_key().into();
+        let rotated_id: PeerID = rotated.verifying_key().into();
+        let mod_id: PeerID = moderator.verifying_key().into();
+
+        let a = Patch::new(&owner, [], &Op::Grant(mod_id)).unwrap();
+        let b = Patch::new(&owner, [*a.id()], &Op::RotateKey(rotated_id)).unwrap();
+        // moderator can't take over the owner's keys
+        let c = Patch::new(&moderator, [*b.id()], &Op::RotateKey(rotated_id)).unwrap();
+        let d = Patch::new(&moderator, [*b.id()], &Op::RotateKey(owner_id)).unwrap();
+        let e = Patch::new(&rotated, [*b.id()], &Op::Revoke(mod_id)).unwrap();
+
+        let mut permissions = Permissions::default();
+        assert!(permissions.apply(&a));
+        assert!(permissions.apply(&b));
+        assert_eq!(permissions.owner(), Some(&owner_id));
+        assert!(permissions.is_owner(&rotated_id));
+        assert_eq!(permissions.identity(&rotated_id), &owner_id);
+        assert!(!permissions.apply(&c));
+        assert!(!permissions.apply(&d));
+        assert!(permissions.apply(&e));
+        assert!(!permissions.is_moderator(&mod_id));
+    }
+}
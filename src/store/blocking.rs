@@ -0,0 +1,214 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::JoinHandle;
+
+use crate::patch::{Patch, ID};
+use crate::store::{AsyncObjectStore, ObjectStore};
+use crate::Result;
+
+type Job<S> = Box<dyn FnOnce(&S) + Send>;
+
+/// Adapter exposing a synchronous [ObjectStore] as an [AsyncObjectStore]. Wrapped store is moved
+/// onto a dedicated worker thread, which executes requests one by one, so that blocking I/O never
+/// happens on the async executor threads.
+pub struct BlockingStore<S> {
+    jobs: Option<mpsc::Sender<Job<S>>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl<S> BlockingStore<S>
+where
+    S: ObjectStore + Send + 'static,
+{
+    pub fn new(store: S) -> Self {
+        let (tx, rx) = mpsc::channel::<Job<S>>();
+        let worker = std::thread::Builder::new()
+            .name("storyteller-store".into())
+            .spawn(move || {
+                for job in rx {
+                    job(&store);
+                }
+            })
+            .expect("failed to spawn object store worker thread");
+        BlockingStore {
+            jobs: Some(tx),
+            worker: Some(worker),
+        }
+    }
+
+    /// Schedules given function to be executed over the wrapped store on its worker thread.
+    fn spawn<F, T>(&self, f: F) -> Reply<T>
+    where
+        F: FnOnce(&S) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let shared = Arc::new(Mutex::new(Shared {
+            value: None,
+            waker: None,
+            closed: false,
+        }));
+        let sender = ReplySender(shared.clone());
+        let job: Job<S> = Box::new(move |store| sender.send(f(store)));
+        if let Some(jobs) = &self.jobs {
+            // if the worker is gone, the job is dropped together with its sender, closing the reply
+            let _ = jobs.send(job);
+        }
+        Reply(shared)
+    }
+}
+
+impl<S> Drop for BlockingStore<S> {
+    fn drop(&mut self) {
+        // closing the channel lets the worker drain remaining jobs and exit
+        self.jobs.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl<S> AsyncObjectStore for BlockingStore<S>
+where
+    S: ObjectStore + Send + 'static,
+{
+    fn heads(&self) -> impl Future<Output = Result<Vec<ID>>> + Send {
+        self.spawn(|store| store.heads())
+    }
+
+    fn patches(&self, ids: &[ID]) -> impl Future<Output = Result<Vec<Patch>>> + Send {
+        let ids = ids.to_vec();
+        self.spawn(move |store| store.patches(&ids))
+    }
+
+    fn is_integrated(&self, patch_id: &ID) -> impl Future<Output = Result<bool>> + Send {
+        let patch_id = *patch_id;
+        self.spawn(move |store| store.is_integrated(&patch_id))
+    }
+
+    fn contains(&self, patch_id: &ID) -> impl Future<Output = Result<bool>> + Send {
+        let patch_id = *patch_id;
+        self.spawn(move |store| store.contains(&patch_id))
+    }
+
+    fn commit(&self, patch: &Patch) -> impl Future<Output = Result<()>> + Send {
+        let patch = patch.clone();
+        self.spawn(move |store| store.commit(&patch))
+    }
+
+    fn stash(&self, patch: &Patch) -> impl Future<Output = Result<()>> + Send {
+        let patch = patch.clone();
+        self.spawn(move |store| store.stash(&patch))
+    }
+
+    fn unstash(&self) -> impl Future<Output = Result<Vec<Patch>>> + Send {
+        self.spawn(|store| store.unstash())
+    }
+}
+
+struct Shared<T> {
+    value: Option<Result<T>>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+/// Future resolved once the worker thread finishes processing a corresponding job.
+struct Reply<T>(Arc<Mutex<Shared<T>>>);
+
+impl<T> Future for Reply<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut shared = self.0.lock().unwrap();
+        if let Some(value) = shared.value.take() {
+            Poll::Ready(value)
+        } else if shared.closed {
+            Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "object store worker has stopped",
+            )
+            .into()))
+        } else {
+            shared.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+struct ReplySender<T>(Arc<Mutex<Shared<T>>>);
+
+impl<T> ReplySender<T> {
+    fn send(self, value: Result<T>) {
+        self.0.lock().unwrap().value = Some(value);
+    }
+}
+
+impl<T> Drop for ReplySender<T> {
+    fn drop(&mut self) {
+        let mut shared = self.0.lock().unwrap();
+        shared.closed = true;
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Minimal executor used to drive async store APIs in tests.
+#[cfg(test)]
+pub(crate) fn block_on<F: Future>(fut: F) -> F::Output {
+    use std::task::Wake;
+
+    struct ThreadWaker(std::thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let mut fut = std::pin::pin!(fut);
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match fut.as_mut().poll(&mut cx) {
+            Poll::Ready(value) => return value,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ed25519_dalek::SigningKey;
+
+    use crate::patch::Patch;
+    use crate::store::blocking::{block_on, BlockingStore};
+    use crate::store::{for_each_store, AsyncObjectStore, ObjectStore};
+
+    fn check_async_commit_and_read<S: ObjectStore + Send + 'static>(store: BlockingStore<S>) {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let b = Patch::new(&key, [*a.id()], &"B").unwrap();
+
+        block_on(async {
+            store.commit(&a).await.unwrap();
+            store.stash(&b).await.unwrap();
+            assert!(store.contains(b.id()).await.unwrap());
+            assert!(!store.is_integrated(b.id()).await.unwrap());
+
+            let stashed = store.unstash().await.unwrap();
+            assert_eq!(stashed, vec![b.clone()]);
+            store.commit(&b).await.unwrap();
+
+            assert_eq!(store.heads().await.unwrap(), vec![*b.id()]);
+            let patches = store.patches(&[*a.id(), *b.id()]).await.unwrap();
+            assert_eq!(patches, vec![a, b]);
+        });
+    }
+
+    #[test]
+    fn async_commit_and_read() {
+        for_each_store!(|store| check_async_commit_and_read(BlockingStore::new(store)));
+    }
+}
@@ -0,0 +1,522 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::crypto::{CryptoSuite, Ed25519Blake3};
+use crate::patch::{topo_order, Patch, ID};
+use crate::store::{cursor_not_found, Checkpoint, ObjectStore, StoreStats};
+use crate::{Error, PeerID, Result};
+
+/// Object store which keeps all of its state in memory. Useful for tests and ephemeral peers,
+/// which don't need to persist their history. Patch IDs are verified using a given [CryptoSuite].
+pub struct MemoryStore<C = Ed25519Blake3> {
+    inner: RefCell<Inner>,
+    clock: Arc<dyn Clock>,
+    suite: PhantomData<fn() -> C>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Integrated patches.
+    patches: HashMap<ID, Patch>,
+    /// IDs of integrated patches in the order they were committed.
+    seq: Vec<ID>,
+    /// Child → parents relation between integrated patches.
+    rel: HashMap<ID, Vec<ID>>,
+    /// Parent → children relation, reverse of `rel`.
+    children: HashMap<ID, Vec<ID>>,
+    /// Causal depths of integrated patches.
+    depths: HashMap<ID, u64>,
+    /// Patches waiting for their dependencies.
+    stash: Vec<Patch>,
+    /// Time when patches were first stashed. Kept after unstashing until the next unstash, so that
+    /// patches stashed again keep their original stash time.
+    stashed_at: HashMap<ID, u64>,
+    generation: u64,
+    checkpoint: Option<Checkpoint>,
+    /// Local patches in the order they were committed.
+    local: Vec<Patch>,
+    /// Integrated patches protected from removal.
+    pins: HashSet<ID>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<C: CryptoSuite> MemoryStore<C> {
+    /// Creates a store for patches created with a given [CryptoSuite].
+    pub fn with_suite() -> Self {
+        MemoryStore {
+            inner: RefCell::default(),
+            clock: Arc::new(SystemClock),
+            suite: PhantomData,
+        }
+    }
+
+    /// Replaces the [SystemClock] used to timestamp stashed patches.
+    pub fn with_clock<T: Clock + 'static>(mut self, clock: T) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::with_suite()
+    }
+}
+
+impl<C> Debug for MemoryStore<C> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryStore")
+            .field("inner", &self.inner)
+            .field("clock", &self.clock)
+            .finish()
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, patch_id: &ID) {
+        self.patches.remove(patch_id);
+        for parent in self.rel.remove(patch_id).unwrap_or_default() {
+            if let Some(children) = self.children.get_mut(&parent) {
+                children.retain(|id| id != patch_id);
+            }
+        }
+        self.children.remove(patch_id);
+        self.depths.remove(patch_id);
+        self.seq.retain(|id| id != patch_id);
+        self.generation += 1;
+        self.checkpoint = None;
+    }
+}
+
+impl<C: CryptoSuite> ObjectStore for MemoryStore<C> {
+    fn heads(&self) -> Result<Vec<ID>> {
+        let inner = self.inner.borrow();
+        Ok(inner
+            .seq
+            .iter()
+            .filter(|id| inner.children.get(id).is_none_or(|c| c.is_empty()))
+            .cloned()
+            .collect())
+    }
+
+    fn patches(&self, ids: &[ID]) -> Result<Vec<Patch>> {
+        let inner = self.inner.borrow();
+        Ok(ids
+            .iter()
+            .filter_map(|id| inner.patches.get(id).cloned())
+            .collect())
+    }
+
+    fn patches_page(&self, after: Option<&ID>, limit: usize) -> Result<Vec<Patch>> {
+        let inner = self.inner.borrow();
+        let start = match after {
+            None => 0,
+            Some(after) => match inner.seq.iter().position(|id| id == after) {
+                Some(pos) => pos + 1,
+                None => return Err(cursor_not_found()),
+            },
+        };
+        Ok(inner.seq[start..]
+            .iter()
+            .take(limit)
+            .map(|id| inner.patches[id].clone())
+            .collect())
+    }
+
+    fn is_integrated(&self, patch_id: &ID) -> Result<bool> {
+        Ok(self.inner.borrow().patches.contains_key(patch_id))
+    }
+
+    fn integrated_set(&self, ids: &[ID]) -> Result<HashSet<ID>> {
+        let inner = self.inner.borrow();
+        Ok(ids
+            .iter()
+            .filter(|id| inner.patches.contains_key(id))
+            .cloned()
+            .collect())
+    }
+
+    fn contains(&self, patch_id: &ID) -> Result<bool> {
+        let inner = self.inner.borrow();
+        Ok(inner.patches.contains_key(patch_id) || inner.stash.iter().any(|p| p.id() == patch_id))
+    }
+
+    fn commit(&self, patch: &Patch) -> Result<()> {
+        patch.verify_id_with::<C>()?;
+        let mut inner = self.inner.borrow_mut();
+        if inner.patches.contains_key(patch.id()) {
+            return Ok(());
+        }
+        if let Some(dep) = patch
+            .deps()
+            .iter()
+            .find(|id| !inner.patches.contains_key(id))
+        {
+            return Err(Error::MissingDependency(*dep));
+        }
+        let parents: Vec<ID> = patch.deps().iter().cloned().collect();
+        let depth = parents.iter().map(|id| inner.depths[id] + 1).max();
+        inner.depths.insert(*patch.id(), depth.unwrap_or(0));
+        for parent in parents.iter() {
+            inner.children.entry(*parent).or_default().push(*patch.id());
+        }
+        inner.rel.insert(*patch.id(), parents);
+        inner.seq.push(*patch.id());
+        inner.patches.insert(*patch.id(), patch.clone());
+        inner.stashed_at.remove(patch.id());
+        inner.generation += 1;
+        Ok(())
+    }
+
+    fn stash(&self, patch: &Patch) -> Result<()> {
+        let mut inner = self.inner.borrow_mut();
+        if !inner.stash.iter().any(|p| p.id() == patch.id()) {
+            inner.stash.push(patch.clone());
+        }
+        inner
+            .stashed_at
+            .entry(*patch.id())
+            .or_insert_with(|| self.clock.now());
+        Ok(())
+    }
+
+    fn unstash_iter(&self) -> Result<impl Iterator<Item = Result<Patch>> + '_> {
+        let inner = &mut *self.inner.borrow_mut();
+        // patches taken by the previous unstash, which were not stashed again, are gone for good
+        let stashed: HashSet<&ID> = inner.stash.iter().map(Patch::id).collect();
+        inner.stashed_at.retain(|id, _| stashed.contains(id));
+        // stashed patches are in memory already, so there's nothing to gain by taking them lazily
+        let stash = std::mem::take(&mut inner.stash);
+        Ok(stash.into_iter().map(Ok))
+    }
+
+    fn stashed(&self) -> Result<Vec<Patch>> {
+        Ok(self.inner.borrow().stash.clone())
+    }
+
+    fn remove(&self, patch_id: &ID) -> Result<bool> {
+        let inner = &mut *self.inner.borrow_mut();
+        if !inner.patches.contains_key(patch_id)
+            || inner.pins.contains(patch_id)
+            || inner.children.get(patch_id).is_some_and(|c| !c.is_empty())
+        {
+            return Ok(false);
+        }
+        inner.remove(patch_id);
+        Ok(true)
+    }
+
+    fn remove_cascade(&self, patch_id: &ID) -> Result<Vec<ID>> {
+        let inner = &mut *self.inner.borrow_mut();
+        if !inner.patches.contains_key(patch_id) {
+            return Ok(Vec::new());
+        }
+        let mut descendants = HashSet::from([*patch_id]);
+        // commit order is topological, so all descendants come after the patch itself
+        for id in inner.seq.iter() {
+            if inner.rel[id]
+                .iter()
+                .any(|parent| descendants.contains(parent))
+            {
+                descendants.insert(*id);
+            }
+        }
+        let removed: Vec<ID> = inner
+            .seq
+            .iter()
+            .rev()
+            .filter(|id| descendants.contains(id))
+            .cloned()
+            .collect();
+        if let Some(pinned) = removed.iter().find(|id| inner.pins.contains(*id)) {
+            return Err(Error::Pinned(*pinned));
+        }
+        for id in removed.iter() {
+            inner.remove(id);
+        }
+        Ok(removed)
+    }
+
+    fn pin(&self, patch_id: &ID) -> Result<bool> {
+        let inner = &mut *self.inner.borrow_mut();
+        if !inner.patches.contains_key(patch_id) {
+            return Ok(false);
+        }
+        inner.pins.insert(*patch_id);
+        Ok(true)
+    }
+
+    fn unpin(&self, patch_id: &ID) -> Result<bool> {
+        Ok(self.inner.borrow_mut().pins.remove(patch_id))
+    }
+
+    fn pinned(&self) -> Result<Vec<ID>> {
+        let inner = self.inner.borrow();
+        Ok(inner
+            .seq
+            .iter()
+            .filter(|id| inner.pins.contains(*id))
+            .cloned()
+            .collect())
+    }
+
+    fn prune_stash(&self, older_than: Duration) -> Result<usize> {
+        let cutoff = self
+            .clock
+            .now()
+            .saturating_sub(older_than.as_millis() as u64);
+        let inner = &mut *self.inner.borrow_mut();
+        let stashed_at = &mut inner.stashed_at;
+        let before = inner.stash.len();
+        inner.stash.retain(|p| {
+            let expired = stashed_at.get(p.id()).is_none_or(|at| *at < cutoff);
+            if expired {
+                stashed_at.remove(p.id());
+            }
+            !expired
+        });
+        Ok(before - inner.stash.len())
+    }
+
+    fn depth(&self, patch_id: &ID) -> Result<Option<u64>> {
+        Ok(self.inner.borrow().depths.get(patch_id).cloned())
+    }
+
+    fn children(&self, patch_id: &ID) -> Result<Vec<ID>> {
+        let inner = self.inner.borrow();
+        Ok(inner.children.get(patch_id).cloned().unwrap_or_default())
+    }
+
+    fn is_ancestor(&self, ancestor: &ID, descendant: &ID) -> Result<bool> {
+        let inner = self.inner.borrow();
+        let (Some(&min_depth), true) = (
+            inner.depths.get(ancestor),
+            inner.patches.contains_key(descendant),
+        ) else {
+            return Ok(false);
+        };
+        // ancestors are always shallower than their descendants, so deeper patches can be skipped
+        let mut visited = HashSet::new();
+        let mut queue = vec![*descendant];
+        while let Some(id) = queue.pop() {
+            if id == *ancestor {
+                return Ok(true);
+            }
+            if !visited.insert(id) {
+                continue;
+            }
+            for parent in inner.rel.get(&id).into_iter().flatten() {
+                if inner
+                    .depths
+                    .get(parent)
+                    .is_some_and(|depth| *depth >= min_depth)
+                {
+                    queue.push(*parent);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    fn topo_order(&self) -> Result<Vec<ID>> {
+        let inner = self.inner.borrow();
+        let edges = inner
+            .rel
+            .iter()
+            .flat_map(|(child, parents)| parents.iter().map(move |parent| (*child, *parent)));
+        let order = topo_order(&inner.seq, edges);
+        Ok(order.into_iter().map(|i| inner.seq[i]).collect())
+    }
+
+    fn patches_by_author(&self, author: &PeerID) -> Result<Vec<ID>> {
+        let inner = self.inner.borrow();
+        Ok(inner
+            .seq
+            .iter()
+            .filter(|id| inner.patches[*id].author() == author)
+            .cloned()
+            .collect())
+    }
+
+    fn author_heads(&self) -> Result<HashMap<PeerID, ID>> {
+        let inner = self.inner.borrow();
+        let mut latest: HashMap<PeerID, (u64, ID)> = HashMap::new();
+        // later commits replace earlier ones of the same depth
+        for id in inner.seq.iter() {
+            let author = *inner.patches[id].author();
+            let depth = inner.depths[id];
+            match latest.get(&author) {
+                Some((max, _)) if *max > depth => {}
+                _ => {
+                    latest.insert(author, (depth, *id));
+                }
+            }
+        }
+        Ok(latest
+            .into_iter()
+            .map(|(author, (_, id))| (author, id))
+            .collect())
+    }
+
+    fn generation(&self) -> Result<u64> {
+        Ok(self.inner.borrow().generation)
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        let inner = self.inner.borrow();
+        let authors: HashSet<&PeerID> = inner.patches.values().map(|p| p.author()).collect();
+        Ok(StoreStats {
+            patches: inner.patches.len() as u64,
+            stashed: inner.stash.len() as u64,
+            authors: authors.len() as u64,
+            data_bytes: inner.patches.values().map(|p| p.data().len() as u64).sum(),
+        })
+    }
+
+    fn save_checkpoint(&self, checkpoint: &Checkpoint) -> Result<()> {
+        self.inner.borrow_mut().checkpoint = Some(checkpoint.clone());
+        Ok(())
+    }
+
+    fn last_checkpoint(&self) -> Result<Option<Checkpoint>> {
+        Ok(self.inner.borrow().checkpoint.clone())
+    }
+
+    fn commit_local(&self, patch: &Patch) -> Result<()> {
+        let inner = &mut *self.inner.borrow_mut();
+        if !inner.local.iter().any(|p| p.id() == patch.id()) {
+            inner.local.push(patch.clone());
+        }
+        Ok(())
+    }
+
+    fn local_patches(&self) -> Result<Vec<Patch>> {
+        Ok(self.inner.borrow().local.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ed25519_dalek::SigningKey;
+
+    use std::time::Duration;
+
+    use crate::clock::MockClock;
+    use crate::patch::Patch;
+    use crate::peer::Peer;
+    use crate::store::memory::MemoryStore;
+    use crate::store::ObjectStore;
+
+    #[test]
+    fn heads_and_unstash() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let store = MemoryStore::new();
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let b = Patch::new(&key, [*a.id()], &"B").unwrap();
+        let c = Patch::new(&key, [*a.id()], &"C").unwrap();
+        store.commit(&a).unwrap();
+        store.commit(&b).unwrap();
+        store.stash(&c).unwrap();
+
+        assert_eq!(store.heads().unwrap(), vec![*b.id()]);
+        assert!(store.contains(c.id()).unwrap());
+        assert!(!store.is_integrated(c.id()).unwrap());
+
+        assert_eq!(store.unstash().unwrap(), vec![c.clone()]);
+        assert!(store.unstash().unwrap().is_empty());
+        assert!(!store.contains(c.id()).unwrap());
+
+        assert_eq!(store.depth(c.id()).unwrap(), None);
+        store.commit(&c).unwrap();
+        assert_eq!(store.heads().unwrap(), vec![*b.id(), *c.id()]);
+        assert_eq!(store.depth(a.id()).unwrap(), Some(0));
+        assert_eq!(store.depth(c.id()).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn remove_cascade() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let store = MemoryStore::new();
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let b = Patch::new(&key, [*a.id()], &"B").unwrap();
+        let c = Patch::new(&key, [*a.id()], &"C").unwrap();
+        let d = Patch::new(&key, [*b.id()], &"D").unwrap();
+        store
+            .commit_batch(&[a.clone(), b.clone(), c.clone(), d.clone()])
+            .unwrap();
+
+        assert!(!store.remove(b.id()).unwrap());
+        assert!(store.remove(c.id()).unwrap());
+        assert_eq!(
+            store.remove_cascade(b.id()).unwrap(),
+            vec![*d.id(), *b.id()]
+        );
+        assert_eq!(store.heads().unwrap(), vec![*a.id()]);
+    }
+
+    #[test]
+    fn prune_stash() {
+        let clock = MockClock::new(1_000_000);
+        let store = MemoryStore::new().with_clock(clock.clone());
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let orphan = Patch::new(&key, [*a.id()], &"B").unwrap();
+        let fresh = Patch::new(&key, [*a.id()], &"C").unwrap();
+        let hour = Duration::from_secs(3600);
+
+        store.stash(&orphan).unwrap();
+        clock.advance(hour);
+        store.stash(&fresh).unwrap();
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(store.prune_stash(hour).unwrap(), 1);
+        assert_eq!(store.stashed().unwrap(), vec![fresh.clone()]);
+
+        clock.advance(hour);
+        assert_eq!(store.prune_stash(hour).unwrap(), 1);
+        assert!(store.stashed().unwrap().is_empty());
+
+        // patches taken out of the stash are not evicted
+        store.stash(&orphan).unwrap();
+        assert_eq!(store.unstash().unwrap(), vec![orphan.clone()]);
+        clock.advance(2 * hour);
+        assert_eq!(store.prune_stash(hour).unwrap(), 0);
+    }
+
+    /// Peers backed by memory stores don't depend on the `sqlite` feature, so this test also runs
+    /// in builds without it, eg. on `wasm32-unknown-unknown`.
+    #[test]
+    fn peers_without_sqlite() {
+        let mut p1 = Peer::new(
+            SigningKey::generate(&mut rand::rngs::OsRng),
+            MemoryStore::new(),
+        )
+        .unwrap();
+        let mut p2 = Peer::new(
+            SigningKey::generate(&mut rand::rngs::OsRng),
+            MemoryStore::new(),
+        )
+        .unwrap();
+        p1.commit(&"A").unwrap();
+        let delta = p1.delta(p2.heads()).unwrap();
+        p2.integrate(p1.patches(&delta).unwrap()).unwrap();
+        let b = p2.commit(&"B").unwrap();
+        let delta = p2.delta(p1.heads()).unwrap();
+        p1.integrate(p2.patches(&delta).unwrap()).unwrap();
+
+        assert_eq!(p1.heads(), &[*b.id()]);
+        assert_eq!(
+            p1.store().topo_order().unwrap(),
+            p2.store().topo_order().unwrap()
+        );
+    }
+}
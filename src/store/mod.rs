@@ -1,5 +1,16 @@
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::time::Duration;
+
+use bytes::Bytes;
+
 use crate::patch::{Patch, ID};
+use crate::PeerID;
 
+pub mod blocking;
+pub mod memory;
+#[cfg(feature = "sqlite")]
 pub mod sqlite;
 
 pub trait ObjectStore: Sized {
@@ -10,18 +21,218 @@ pub trait ObjectStore: Sized {
     /// Returns list of patches identified by their IDs.
     fn patches(&self, ids: &[ID]) -> crate::Result<Vec<Patch>>;
 
+    /// Returns up to `limit` integrated patches in the order they were committed, starting right
+    /// after the patch identified by `after` (or from the first one, if `after` is None). ID of
+    /// the last returned patch serves as a cursor for the next page. Fails with
+    /// [std::io::ErrorKind::NotFound] if the cursor patch is not integrated.
+    fn patches_page(&self, after: Option<&ID>, limit: usize) -> crate::Result<Vec<Patch>>;
+
     /// Returns true if patch with a given ID has been successfully integrated into object store.
     fn is_integrated(&self, patch_id: &ID) -> crate::Result<bool>;
 
     /// Returns true if patch could be found in either object store or a list of stashed patches.
     fn contains(&self, patch_id: &ID) -> crate::Result<bool>;
 
-    /// Commits given patch, integrating it into object store.
+    /// Returns a subset of given IDs, which belong to integrated patches. This is a batched
+    /// equivalent of [ObjectStore::is_integrated].
+    fn integrated_set(&self, ids: &[ID]) -> crate::Result<HashSet<ID>> {
+        let mut set = HashSet::new();
+        for id in ids {
+            if self.is_integrated(id)? {
+                set.insert(*id);
+            }
+        }
+        Ok(set)
+    }
+
+    /// Commits given patch, integrating it into object store. Fails with
+    /// [crate::Error::MissingDependency] if any of its dependencies has not been integrated.
     fn commit(&self, patch: &Patch) -> crate::Result<()>;
 
+    /// Commits all given patches at once. Patches must be ordered so that each one comes after its
+    /// dependencies. If any of them fails to commit, none of them are integrated.
+    fn commit_batch<P: Borrow<Patch>>(&self, patches: &[P]) -> crate::Result<()> {
+        for patch in patches {
+            self.commit(patch.borrow())?;
+        }
+        Ok(())
+    }
+
     /// Stashes given patch.
     fn stash(&self, patch: &Patch) -> crate::Result<()>;
 
-    /// Returns iterator over stashed elements, removing them from stash space.
-    fn unstash(&self) -> crate::Result<Vec<Patch>>;
+    /// Returns iterator over stashed patches, taking each one out of the stash space as it's
+    /// yielded, so that large stashes don't need to be loaded into memory at once. Patches stashed
+    /// while iterating are not yielded. Patches which can't be read are yielded as errors and left
+    /// in the stash.
+    fn unstash_iter(&self) -> crate::Result<impl Iterator<Item = crate::Result<Patch>> + '_>;
+
+    /// Takes all patches out of the stash space at once.
+    fn unstash(&self) -> crate::Result<Vec<Patch>> {
+        self.unstash_iter()?.collect()
+    }
+
+    /// Returns stashed patches without removing them from stash space.
+    fn stashed(&self) -> crate::Result<Vec<Patch>>;
+
+    /// Removes an integrated patch, unless other integrated patches depend on it. Returns false if
+    /// patch was not removed, because it has dependents, is pinned or was not integrated in the
+    /// first place.
+    fn remove(&self, patch_id: &ID) -> crate::Result<bool>;
+
+    /// Removes an integrated patch together with all of its descendants. Returns IDs of removed
+    /// patches, descendants first. Fails with [crate::Error::Pinned] without removing anything,
+    /// if any of these patches is pinned.
+    fn remove_cascade(&self, patch_id: &ID) -> crate::Result<Vec<ID>>;
+
+    /// Pins an integrated patch, protecting it from removal. Since patches can't be removed while
+    /// others depend on them, this protects the whole causal past of the pinned patch as well.
+    /// Returns false if patch is not integrated. Pinning the same patch again has no effect.
+    fn pin(&self, patch_id: &ID) -> crate::Result<bool>;
+
+    /// Unpins a patch. Returns false if patch was not pinned.
+    fn unpin(&self, patch_id: &ID) -> crate::Result<bool>;
+
+    /// Returns IDs of pinned patches in the order they were committed.
+    fn pinned(&self) -> crate::Result<Vec<ID>>;
+
+    /// Evicts patches which have been waiting in the stash for their dependencies for longer than
+    /// `older_than`. Returns the number of evicted patches.
+    fn prune_stash(&self, older_than: Duration) -> crate::Result<usize>;
+
+    /// Returns causal depth of an integrated patch: 0 for root patches, otherwise 1 + maximum depth
+    /// of its dependencies. Returns None if patch was not integrated.
+    fn depth(&self, patch_id: &ID) -> crate::Result<Option<u64>>;
+
+    /// Returns IDs of integrated patches, which directly depend on a given one, in the order they
+    /// were committed. Together with [Patch::deps] it allows to walk the DAG in both directions.
+    fn children(&self, patch_id: &ID) -> crate::Result<Vec<ID>>;
+
+    /// Returns true if `descendant` depends on `ancestor`, either directly or transitively. Every
+    /// integrated patch is considered an ancestor of itself. Returns false if either of the patches
+    /// is not integrated.
+    fn is_ancestor(&self, ancestor: &ID, descendant: &ID) -> crate::Result<bool>;
+
+    /// Returns IDs of all integrated patches in topological order: every patch appears after all
+    /// of its dependencies. Concurrent patches are ordered by their ID bytes.
+    fn topo_order(&self) -> crate::Result<Vec<ID>>;
+
+    /// Returns IDs of integrated patches authored by a given peer, in the order they were committed.
+    fn patches_by_author(&self, author: &PeerID) -> crate::Result<Vec<ID>>;
+
+    /// Returns the latest integrated patch of every author: the one with the greatest causal depth,
+    /// committed last in case of ties.
+    fn author_heads(&self) -> crate::Result<HashMap<PeerID, ID>>;
+
+    /// Returns a counter, which increases every time integrated patches change: by one for every
+    /// newly committed patch, and by an unspecified amount when patches are removed. Peers compare
+    /// it with the last seen value to detect that the store has been modified by other writers.
+    fn generation(&self) -> crate::Result<u64>;
+
+    /// Returns summary statistics of the store contents.
+    fn stats(&self) -> crate::Result<StoreStats>;
+
+    /// Persists a local patch (see [Patch::is_local]). Local patches are kept apart from the
+    /// integrated ones: they're never returned by, nor affect results of the other methods.
+    /// Committing the same local patch again has no effect.
+    fn commit_local(&self, patch: &Patch) -> crate::Result<()>;
+
+    /// Returns all local patches in the order they were committed.
+    fn local_patches(&self) -> crate::Result<Vec<Patch>>;
+
+    /// Makes sure that all committed changes are durably written to the underlying storage, eg.
+    /// before shutdown. Stores without separate durable storage don't need to do anything.
+    fn flush(&self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    /// Persists a given checkpoint, replacing the previously saved one.
+    fn save_checkpoint(&self, checkpoint: &Checkpoint) -> crate::Result<()>;
+
+    /// Returns the most recently saved checkpoint. Removing any integrated patch discards the
+    /// checkpoint, since its state may include the effects of removed patches.
+    fn last_checkpoint(&self) -> crate::Result<Option<Checkpoint>>;
+}
+
+/// Materialized state of integrated patches, see [crate::peer::Peer::save_checkpoint].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Last patch (in the commit order) included in the state. All patches committed before it
+    /// are included as well.
+    pub last: ID,
+    /// Heads of the patches included in the state.
+    pub heads: Vec<ID>,
+    /// Serialized state.
+    pub state: Bytes,
+}
+
+/// Summary of the [ObjectStore] contents, see [ObjectStore::stats].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Number of integrated patches.
+    pub patches: u64,
+    /// Number of patches waiting in the stash for their dependencies.
+    pub stashed: u64,
+    /// Number of distinct authors of integrated patches.
+    pub authors: u64,
+    /// Total length of data of integrated patches (in bytes), before any deduplication or
+    /// compression applied by the store.
+    pub data_bytes: u64,
+}
+
+pub(crate) fn cursor_not_found() -> crate::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, "page cursor patch not found").into()
 }
+
+/// Asynchronous equivalent of [ObjectStore], which can be used from within async runtimes without
+/// blocking executor threads. See [blocking::BlockingStore] for an adapter over synchronous stores.
+pub trait AsyncObjectStore: Sized {
+    /// Returns current heads - IDs of the most recent patches that will serve as future dependencies
+    /// for newly committed patches.
+    fn heads(&self) -> impl Future<Output = crate::Result<Vec<ID>>> + Send;
+
+    /// Returns list of patches identified by their IDs.
+    fn patches(&self, ids: &[ID]) -> impl Future<Output = crate::Result<Vec<Patch>>> + Send;
+
+    /// Returns true if patch with a given ID has been successfully integrated into object store.
+    fn is_integrated(&self, patch_id: &ID) -> impl Future<Output = crate::Result<bool>> + Send;
+
+    /// Returns true if patch could be found in either object store or a list of stashed patches.
+    fn contains(&self, patch_id: &ID) -> impl Future<Output = crate::Result<bool>> + Send;
+
+    /// Commits given patch, integrating it into object store.
+    fn commit(&self, patch: &Patch) -> impl Future<Output = crate::Result<()>> + Send;
+
+    /// Stashes given patch.
+    fn stash(&self, patch: &Patch) -> impl Future<Output = crate::Result<()>> + Send;
+
+    /// Returns stashed elements, removing them from stash space.
+    fn unstash(&self) -> impl Future<Output = crate::Result<Vec<Patch>>> + Send;
+}
+
+/// Runs a given test body once for every store implementation, with `$store` bound to a fresh
+/// store identifying patches with a given [crate::crypto::CryptoSuite] (by default
+/// [crate::crypto::Ed25519Blake3]).
+#[cfg(test)]
+macro_rules! for_each_store {
+    (|$store:ident| $body:expr) => {
+        $crate::store::for_each_store!($crate::crypto::Ed25519Blake3, |$store| $body)
+    };
+    ($suite:ty, |$store:ident| $body:expr) => {{
+        {
+            let $store = $crate::store::memory::MemoryStore::<$suite>::with_suite();
+            $body;
+        }
+        #[cfg(feature = "sqlite")]
+        {
+            let conn = rusqlite::Connection::open_in_memory().unwrap();
+            let options = $crate::store::sqlite::Options::default();
+            let $store =
+                $crate::store::sqlite::SqliteStore::<$suite>::with_suite(conn, options).unwrap();
+            $body;
+        }
+    }};
+}
+
+#[cfg(test)]
+pub(crate) use for_each_store;
@@ -1,26 +1,35 @@
-use crate::patch::{Deps, Patch, ID};
-use crate::store::ObjectStore;
-use crate::Result;
+use crate::clock::{Clock, SystemClock};
+use crate::crypto::{CryptoSuite, Ed25519Blake3};
+use crate::patch::{topo_order, Deps, Patch, ID, MAX_DATA_LEN, SIGNATURE_VERSION};
+use crate::store::blocking::BlockingStore;
+use crate::store::{cursor_not_found, Checkpoint, ObjectStore, StoreStats};
+use crate::{Error, PeerID, Result};
 use rusqlite::params;
+use rusqlite::types::Value as SqlValue;
+use rusqlite::vtab::array::Array;
 use smallvec::SmallVec;
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
 
-pub struct SqliteStore {
-    conn: rusqlite::Connection,
-}
-
-impl SqliteStore {
-    pub fn new(conn: rusqlite::Connection) -> Result<Self> {
-        Self::with_options(conn, Options::default())
-    }
+/// Version of the database schema, stored in its `user_version` pragma, created by this version of
+/// the library.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
 
-    pub fn with_options(conn: rusqlite::Connection, options: Options) -> Result<Self> {
-        Self::init_schema(&conn)?;
-        Ok(SqliteStore { conn })
-    }
+/// Schema migrations in order: the one at index `i` upgrades a database from version `i` to
+/// `i + 1`. Once released, a migration must never change, new ones are only appended.
+const MIGRATIONS: &[fn(&rusqlite::Connection) -> Result<()>] =
+    &[migrate_v1, migrate_v2, migrate_v3, migrate_v4];
 
-    fn init_schema(conn: &rusqlite::Connection) -> Result<()> {
-        conn.execute_batch(
-            r#"
+/// Creates the initial schema. Databases created before schema versioning was introduced are at
+/// version 0 as well, so this also brings their tables up to date.
+fn migrate_v1(conn: &rusqlite::Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
         CREATE TABLE IF NOT EXISTS st_authors(
             author_id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
             verification_key BLOB NOT NULL UNIQUE
@@ -28,39 +37,545 @@ impl SqliteStore {
         CREATE TABLE IF NOT EXISTS st_patches(
             seq_no INTEGER PRIMARY KEY AUTOINCREMENT,
             hash BLOB NOT NULL UNIQUE CHECK(LENGTH(hash) = 32),
-            author_id BLOB NOT NULL,
+            author_id INTEGER NOT NULL,
             signature BLOB NOT NULL CHECK(LENGTH(signature) = 64),
             data JSONB,
+            depth INTEGER NOT NULL DEFAULT 0,
+            blob_hash BLOB REFERENCES st_blobs(blob_hash),
+            content_type INTEGER NOT NULL DEFAULT 0,
             FOREIGN KEY (author_id) REFERENCES st_authors(author_id)
         );
+        CREATE TABLE IF NOT EXISTS st_blobs(
+            blob_hash BLOB NOT NULL PRIMARY KEY CHECK(LENGTH(blob_hash) = 32),
+            bytes BLOB NOT NULL
+        );
         CREATE TABLE IF NOT EXISTS st_stash(
             seq_no INTEGER PRIMARY KEY AUTOINCREMENT,
             deps JSONB NOT NULL,
             hash BLOB NOT NULL UNIQUE CHECK(LENGTH(hash) = 32),
             author BLOB NOT NULL CHECK(LENGTH(author) = 32),
             signature BLOB NOT NULL CHECK(LENGTH(signature) = 64),
-            data JSONB
+            data JSONB,
+            stashed_at INTEGER NOT NULL,
+            taken INTEGER NOT NULL DEFAULT 0,
+            content_type INTEGER NOT NULL DEFAULT 0
         );
         CREATE UNIQUE INDEX IF NOT EXISTS uq_st_stash_hash ON st_stash(hash);
         CREATE TABLE IF NOT EXISTS st_rel(
-            child INTEGER,
-            parent INTEGER,
+            child INTEGER NOT NULL,
+            parent INTEGER NOT NULL,
             PRIMARY KEY (child, parent),
             FOREIGN KEY (child) REFERENCES st_patches(seq_no),
             FOREIGN KEY (parent) REFERENCES st_patches(seq_no)
+        );
+        CREATE TABLE IF NOT EXISTS st_heads(
+            seq_no INTEGER NOT NULL PRIMARY KEY REFERENCES st_patches(seq_no)
+        );
+        CREATE TABLE IF NOT EXISTS st_meta(
+            key TEXT NOT NULL PRIMARY KEY,
+            value INTEGER NOT NULL
+        );
+        INSERT INTO st_meta(key, value) VALUES ('generation', 0) ON CONFLICT DO NOTHING;
+        -- older versions recorded edges to missing dependencies with NULL parents
+        DELETE FROM st_rel WHERE child IS NULL OR parent IS NULL;
+        -- st_heads added to an already existing database. Non-empty DAG always has heads.
+        INSERT INTO st_heads(seq_no)
+        SELECT seq_no FROM st_patches
+        WHERE seq_no NOT IN (SELECT parent FROM st_rel)
+          AND NOT EXISTS (SELECT 1 FROM st_heads)"#,
+    )?;
+    // databases created before patch depths, stash expiration, blob deduplication and content
+    // types were introduced lack their columns
+    if add_column(conn, "st_patches", "depth", "INTEGER NOT NULL DEFAULT 0")? {
+        // commit order is topological, so depths of parents are always known by then
+        let mut seq_nos = conn.prepare("SELECT seq_no FROM st_patches ORDER BY seq_no")?;
+        let mut update = conn.prepare(
+            r#"
+            UPDATE st_patches SET depth = COALESCE((
+                SELECT MAX(p.depth) + 1
+                FROM st_rel r
+                JOIN st_patches p ON p.seq_no = r.parent
+                WHERE r.child = ?1), 0)
+            WHERE seq_no = ?1"#,
+        )?;
+        for seq_no in seq_nos.query_map((), |row| row.get::<_, i64>(0))? {
+            update.execute(params![seq_no?])?;
+        }
+    }
+    if add_column(conn, "st_stash", "stashed_at", "INTEGER NOT NULL DEFAULT 0")? {
+        // time of stashing is unknown, so already stashed patches start waiting from now on
+        conn.execute(
+            "UPDATE st_stash SET stashed_at = ?",
+            params![SystemClock.now()],
+        )?;
+    }
+    add_column(conn, "st_stash", "taken", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column(
+        conn,
+        "st_patches",
+        "blob_hash",
+        "BLOB REFERENCES st_blobs(blob_hash)",
+    )?;
+    add_column(
+        conn,
+        "st_patches",
+        "content_type",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column(
+        conn,
+        "st_stash",
+        "content_type",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    conn.execute_batch(
+        "CREATE INDEX IF NOT EXISTS idx_st_patches_blob_hash ON st_patches(blob_hash)",
+    )?;
+    Ok(())
+}
+
+/// Adds a table for checkpoints of materialized state.
+fn migrate_v2(conn: &rusqlite::Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS st_checkpoints(
+            seq_no INTEGER PRIMARY KEY AUTOINCREMENT,
+            last BLOB NOT NULL CHECK(LENGTH(last) = 32),
+            heads JSONB NOT NULL,
+            state BLOB NOT NULL
+        )"#,
+    )?;
+    Ok(())
+}
+
+/// Adds a table for local patches, which are unsigned and have no author.
+fn migrate_v3(conn: &rusqlite::Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS st_local(
+            seq_no INTEGER PRIMARY KEY AUTOINCREMENT,
+            hash BLOB NOT NULL UNIQUE CHECK(LENGTH(hash) = 32),
+            deps JSONB NOT NULL,
+            data JSONB,
+            content_type INTEGER NOT NULL DEFAULT 0
+        )"#,
+    )?;
+    Ok(())
+}
+
+/// Adds a table for patches pinned against removal.
+fn migrate_v4(conn: &rusqlite::Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS st_pins(
+            seq_no INTEGER NOT NULL PRIMARY KEY REFERENCES st_patches(seq_no)
         )"#,
+    )?;
+    Ok(())
+}
+
+/// Adds a column to an existing table, unless it's already there. Returns true if column was
+/// added.
+fn add_column(
+    conn: &rusqlite::Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<bool> {
+    let exists = conn
+        .prepare("SELECT 1 FROM pragma_table_info(?) WHERE name = ?")?
+        .exists(params![table, column])?;
+    if !exists {
+        conn.execute_batch(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, definition
+        ))?;
+    }
+    Ok(!exists)
+}
+
+/// [SqliteStore] running on its own worker thread, usable via [crate::store::AsyncObjectStore].
+pub type AsyncSqliteStore = BlockingStore<SqliteStore>;
+
+/// Object store persisting patches in a SQLite database. Patch IDs are verified using a given
+/// [CryptoSuite].
+pub struct SqliteStore<C = Ed25519Blake3> {
+    pub(crate) conn: rusqlite::Connection,
+    dedup_blobs: bool,
+    max_patch_bytes: usize,
+    verify_on_commit: bool,
+    clock: Arc<dyn Clock>,
+    suite: PhantomData<fn() -> C>,
+}
+
+impl SqliteStore {
+    pub fn new(conn: rusqlite::Connection) -> Result<Self> {
+        Self::with_options(conn, Options::default())
+    }
+
+    pub fn with_options(conn: rusqlite::Connection, options: Options) -> Result<Self> {
+        Self::with_suite(conn, options)
+    }
+
+    /// Opens (creating if necessary) a database file at a given path, using default [Options].
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::new(rusqlite::Connection::open(path)?)
+    }
+
+    /// Opens a new in-memory database, using default [Options].
+    pub fn open_in_memory() -> Result<Self> {
+        Self::new(rusqlite::Connection::open_in_memory()?)
+    }
+}
+
+impl<C: CryptoSuite> SqliteStore<C> {
+    /// Creates a store for patches created with a given [CryptoSuite]. Fails with
+    /// [Error::UnsupportedSignatureVersion] if the database holds patches signed with a different
+    /// [SIGNATURE_VERSION].
+    pub fn with_suite(conn: rusqlite::Connection, options: Options) -> Result<Self> {
+        Self::init_schema(&conn, &options)?;
+        let store = SqliteStore {
+            conn,
+            dedup_blobs: options.dedup_blobs,
+            max_patch_bytes: options.max_patch_bytes,
+            verify_on_commit: options.verify_on_commit,
+            clock: options.clock.clone(),
+            suite: PhantomData,
+        };
+        if store.dedup_blobs {
+            store.atomic(|| store.intern_blobs())?;
+        }
+        store.check_signature_version()?;
+        Ok(store)
+    }
+
+    /// Fails with [Error::UnsupportedSignatureVersion] unless patches in the database are signed
+    /// with the current [SIGNATURE_VERSION]. Databases which don't record it yet have their
+    /// patches (stashed ones included) verified once: if any of them doesn't verify, they're
+    /// recorded as signed with version 0.
+    fn check_signature_version(&self) -> Result<()> {
+        let recorded: Option<u32> = self
+            .conn
+            .query_row(
+                r#"SELECT value FROM st_meta WHERE key = 'signature_version'"#,
+                (),
+                |row| row.get(0),
+            )
+            .found()?;
+        let version = match recorded {
+            Some(version) => version,
+            None => {
+                let version = if self.verify_all()? {
+                    SIGNATURE_VERSION
+                } else {
+                    0
+                };
+                self.conn.execute(
+                    r#"INSERT INTO st_meta(key, value) VALUES ('signature_version', ?)"#,
+                    params![version],
+                )?;
+                version
+            }
+        };
+        if version != SIGNATURE_VERSION {
+            return Err(Error::UnsupportedSignatureVersion {
+                found: version,
+                supported: SIGNATURE_VERSION,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks if signatures of all integrated and stashed patches verify.
+    fn verify_all(&self) -> Result<bool> {
+        const PAGE_SIZE: usize = 1024;
+        let mut after = None;
+        loop {
+            let page = self.patches_page(after.as_ref(), PAGE_SIZE)?;
+            if !page.is_empty()
+                && Patch::verify_batch_with::<C>(&page.iter().collect::<Vec<_>>()).is_err()
+            {
+                return Ok(false);
+            }
+            match page.last() {
+                Some(last) if page.len() == PAGE_SIZE => after = Some(*last.id()),
+                _ => break,
+            }
+        }
+        let stashed = self.stashed()?;
+        Ok(stashed.is_empty()
+            || Patch::verify_batch_with::<C>(&stashed.iter().collect::<Vec<_>>()).is_ok())
+    }
+
+    fn init_schema(conn: &rusqlite::Connection, options: &Options) -> Result<()> {
+        rusqlite::vtab::array::load_module(conn)?;
+        // journal_mode returns the resulting mode, which is not always the requested one
+        // (eg. in-memory databases can only use MEMORY mode)
+        conn.pragma_update_and_check(None, "journal_mode", options.journal_mode.as_str(), |_| {
+            Ok(())
+        })?;
+        conn.pragma_update(None, "synchronous", options.synchronous.as_str())?;
+        conn.pragma_update(None, "foreign_keys", options.foreign_keys)?;
+        if let Some(cache_size) = options.cache_size {
+            conn.pragma_update(None, "cache_size", cache_size)?;
+        }
+        conn.busy_timeout(options.busy_timeout)?;
+        // bring the schema up to date, one migration at a time, each applied atomically
+        let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version > SCHEMA_VERSION {
+            return Err(Error::UnsupportedSchema {
+                found: version,
+                supported: SCHEMA_VERSION,
+            });
+        }
+        for (i, migrate) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let tx = conn.unchecked_transaction()?;
+            migrate(&tx)?;
+            tx.pragma_update(None, "user_version", i as u32 + 1)?;
+            tx.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Moves data of patches, which were committed with blob deduplication disabled, into
+    /// `st_blobs`.
+    fn intern_blobs(&self) -> Result<()> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT seq_no, data FROM st_patches WHERE data IS NOT NULL")?;
+        let rows = stmt
+            .query_map((), |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for (seq_no, data) in rows {
+            let blob_hash = self.insert_blob(&data)?;
+            self.conn.execute(
+                "UPDATE st_patches SET data = NULL, blob_hash = ? WHERE seq_no = ?",
+                params![blob_hash, seq_no],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Stores data in `st_blobs` unless it's already there, returning its blake3 hash.
+    fn insert_blob(&self, data: &[u8]) -> Result<ID> {
+        let blob_hash = ID::from(blake3::hash(data));
+        self.conn.execute(
+            "INSERT INTO st_blobs(blob_hash, bytes) VALUES (?, ?) ON CONFLICT DO NOTHING",
+            params![blob_hash, data],
+        )?;
+        Ok(blob_hash)
+    }
+
+    /// Runs a given function within a savepoint, rolling back all of its changes if it fails.
+    /// Unlike transactions, savepoints can be nested.
+    fn atomic<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        self.conn.execute_batch("SAVEPOINT st_atomic")?;
+        match f() {
+            Ok(value) => {
+                self.conn.execute_batch("RELEASE st_atomic")?;
+                Ok(value)
+            }
+            Err(e) => {
+                self.conn
+                    .execute_batch("ROLLBACK TO st_atomic; RELEASE st_atomic")?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Increments the generation counter, see [ObjectStore::generation].
+    fn bump_generation(&self) -> Result<()> {
+        self.conn.execute(
+            r#"UPDATE st_meta SET value = value + 1 WHERE key = 'generation'"#,
+            (),
+        )?;
+        Ok(())
+    }
+
+    /// Deletes patches with given sequence numbers together with edges to their parents.
+    fn delete_patches(&self, seq_nos: Array) -> Result<()> {
+        let parents: Vec<SqlValue> = self
+            .conn
+            .prepare(r#"SELECT DISTINCT parent FROM st_rel WHERE child IN rarray(?)"#)?
+            .query_map(params![seq_nos], |row| row.get(0))?
+            .collect::<std::result::Result<_, _>>()?;
+        self.conn.execute(
+            r#"DELETE FROM st_rel WHERE child IN rarray(?)"#,
+            params![seq_nos],
+        )?;
+        self.conn.execute(
+            r#"DELETE FROM st_heads WHERE seq_no IN rarray(?)"#,
+            params![seq_nos],
+        )?;
+        self.conn.execute(
+            r#"DELETE FROM st_patches WHERE seq_no IN rarray(?)"#,
+            params![seq_nos],
         )?;
+        self.conn.execute(r#"DELETE FROM st_checkpoints"#, ())?;
+        // parents, which are left with no children, become heads again
+        self.conn.execute(
+            r#"
+        INSERT INTO st_heads(seq_no)
+        SELECT p.value FROM rarray(?) p
+        WHERE p.value IN (SELECT seq_no FROM st_patches)
+          AND p.value NOT IN (SELECT parent FROM st_rel)
+        ON CONFLICT DO NOTHING"#,
+            params![Rc::new(parents)],
+        )?;
+        // blobs no longer referenced by any patch
+        self.conn.execute(
+            r#"
+        DELETE FROM st_blobs
+        WHERE NOT EXISTS (SELECT 1 FROM st_patches p WHERE p.blob_hash = st_blobs.blob_hash)"#,
+            (),
+        )?;
+        self.bump_generation()
+    }
+
+    /// Takes the first stashed patch with a sequence number in range `(after, last]` out of the
+    /// stash. Returns its sequence number together with the patch, or an error if its row is
+    /// corrupted, in which case it stays in the stash.
+    fn take_stashed(&self, after: i64, last: i64) -> Result<Option<(i64, Result<Patch>)>> {
+        let next = self
+            .conn
+            .query_row(
+                r#"
+            SELECT hash, author, signature, data, deps, content_type, seq_no
+            FROM st_stash
+            WHERE taken = 0 AND seq_no > ? AND seq_no <= ?
+            ORDER BY seq_no
+            LIMIT 1"#,
+                params![after, last],
+                // stashed patches can come from untrusted peers: fail on corrupted rows instead of
+                // panicking
+                |row| Ok((row.get::<_, i64>(6)?, Patch::from_sql_row(row))),
+            )
+            .found()?;
+        match next {
+            None => Ok(None),
+            Some((seq_no, Ok(patch))) => {
+                self.conn.execute(
+                    r#"UPDATE st_stash SET taken = 1 WHERE seq_no = ?"#,
+                    [seq_no],
+                )?;
+                Ok(Some((seq_no, Ok(patch))))
+            }
+            Some((seq_no, Err(e))) => Ok(Some((seq_no, Err(e.into())))),
+        }
+    }
+
+    fn check_size(&self, patch: &Patch) -> Result<()> {
+        if patch.data().len() > self.max_patch_bytes {
+            return Err(Error::PatchTooLarge(*patch.id()));
+        }
+        Ok(())
+    }
+
+    /// Verifies signatures of patches about to be committed, unless disabled with
+    /// [Options::verify_on_commit]. IDs are checked first, so that tampered patches are reported
+    /// as [Error::IdMismatch], just like when verification is disabled.
+    fn verify_signatures(&self, patches: &[&Patch]) -> Result<()> {
+        if self.verify_on_commit {
+            for patch in patches {
+                patch.verify_id_with::<C>()?;
+            }
+            Patch::verify_batch_with::<C>(patches)?;
+        }
+        Ok(())
+    }
+
+    fn insert_patch(&self, patch: &Patch) -> Result<()> {
+        self.check_size(patch)?;
+        patch.verify_id_with::<C>()?;
+        let mut parents = Vec::with_capacity(patch.deps().len());
+        for dep in patch.deps().iter() {
+            let parent = self
+                .conn
+                .query_row(
+                    r#"SELECT seq_no FROM st_patches WHERE hash = ?"#,
+                    params![dep],
+                    |row| row.get::<_, u64>(0),
+                )
+                .found()?
+                .ok_or(Error::MissingDependency(*dep))?;
+            parents.push(parent);
+        }
+        let hash = patch.id();
+        let author = patch.author();
+        let sign = patch.sign().to_bytes();
+        let data = patch.data();
+        let author_id =
+            self.conn.query_row(r#"SElECT author_id FROM st_authors WHERE verification_key = ?"#, params![author], |row| row.get::<_, u32>(0)).or_else(|_|
+            self.conn.query_row(r#"INSERT INTO st_authors(verification_key) VALUES(?) ON CONFLICT (verification_key) DO NOTHING RETURNING author_id"#, params![author], |row| row.get::<_, u32>(0)))?;
+        // committing an already integrated patch is a no-op, except for filling missing st_rel edges
+        let (data, blob_hash) = if self.dedup_blobs {
+            (None, Some(self.insert_blob(data)?))
+        } else {
+            (Some(data), None)
+        };
+        let inserted = self.conn.execute(
+            r#"INSERT INTO st_patches(hash, author_id, signature, data, blob_hash, content_type) VALUES (?, ?, ?, ?, ?, ?) ON CONFLICT (hash) DO NOTHING"#,
+            params![hash, author_id, sign, data, blob_hash, patch.content_type() as u8],
+        )?;
+        if inserted != 0 {
+            self.bump_generation()?;
+        }
+        let patch_id = self.conn.query_row(
+            r#"SELECT seq_no FROM st_patches WHERE hash = ?"#,
+            params![hash],
+            |row| row.get::<_, u64>(0),
+        )?;
+        for parent in parents {
+            self.conn.execute(
+                r#"INSERT INTO st_rel(parent, child) VALUES(?, ?) ON CONFLICT DO NOTHING"#,
+                params![parent, patch_id],
+            )?;
+        }
+        if !patch.deps().is_empty() {
+            self.conn.execute(
+                r#"
+            UPDATE st_patches SET depth = (
+                SELECT MAX(p.depth) + 1
+                FROM st_rel r
+                JOIN st_patches p ON p.seq_no = r.parent
+                WHERE r.child = ?1)
+            WHERE seq_no = ?1"#,
+                params![patch_id],
+            )?;
+        }
+        // patch replaces its parents as a head, unless it's re-committed and already has children
+        self.conn.execute(
+            r#"DELETE FROM st_heads WHERE seq_no IN (SELECT parent FROM st_rel WHERE child = ?)"#,
+            params![patch_id],
+        )?;
+        self.conn.execute(
+            r#"
+        INSERT INTO st_heads(seq_no)
+        SELECT ?1 WHERE NOT EXISTS (SELECT 1 FROM st_rel WHERE parent = ?1)
+        ON CONFLICT DO NOTHING"#,
+            params![patch_id],
+        )?;
+        self.conn
+            .execute(r#"DELETE FROM st_stash WHERE hash = ?"#, params![hash])?;
         Ok(())
     }
 }
 
-impl ObjectStore for SqliteStore {
+impl<C: CryptoSuite> ObjectStore for SqliteStore<C> {
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
     fn heads(&self) -> Result<Vec<ID>> {
+        timed!();
         let mut stmt = self.conn.prepare(
             r#"
-        SELECT hash
-        FROM st_patches
-        WHERE seq_no NOT IN (SELECT child FROM st_rel)"#,
+        SELECT p.hash
+        FROM st_heads h
+        JOIN st_patches p ON p.seq_no = h.seq_no
+        ORDER BY h.seq_no"#,
         )?;
         let mut heads = Vec::new();
         for head in stmt.query_map((), |row| row.get(0))? {
@@ -69,135 +584,800 @@ impl ObjectStore for SqliteStore {
         Ok(heads)
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(ids = ids.len()))
+    )]
     fn patches(&self, ids: &[ID]) -> Result<Vec<Patch>> {
-        let mut patches = Vec::with_capacity(ids.len());
+        timed!();
+        let hashes = id_array(ids);
         let mut patch_stmt = self.conn.prepare(
             r#"
-            SELECT p.hash, a.verification_key as author, p.signature, p.data
-            FROM st_patches p
+            SELECT p.hash, a.verification_key as author, p.signature, COALESCE(p.data, b.bytes),
+                NULL, p.content_type
+            FROM rarray(?) ids
+            JOIN st_patches p ON p.hash = ids.value
             JOIN st_authors a ON p.author_id = a.author_id
-            WHERE hash = ?"#,
+            LEFT JOIN st_blobs b ON p.blob_hash = b.blob_hash"#,
         )?;
+        let mut found = HashMap::with_capacity(ids.len());
+        for patch in patch_stmt.query_map(params![hashes], Patch::from_sql_row)? {
+            let patch = patch?;
+            found.insert(*patch.id(), (patch, SmallVec::new()));
+        }
+
         let mut deps_stmt = self.conn.prepare(
             r#"
-        SELECT parent.hash
-        FROM st_patches parent
-        JOIN st_rel r ON parent.seq_no = r.parent
-        JOIN st_patches child ON child.seq_no = r.child
-        WHERE child.hash = ?"#,
+        SELECT child.hash, parent.hash
+        FROM rarray(?) ids
+        JOIN st_patches child ON child.hash = ids.value
+        JOIN st_rel r ON child.seq_no = r.child
+        JOIN st_patches parent ON parent.seq_no = r.parent"#,
         )?;
+        let rels = deps_stmt.query_map(params![hashes], |row| {
+            Ok((row.get::<_, ID>(0)?, row.get::<_, ID>(1)?))
+        })?;
+        for rel in rels {
+            let (child, parent) = rel?;
+            if let Some((_, deps)) = found.get_mut(&child) {
+                deps.push(parent);
+            }
+        }
+
+        let mut patches = Vec::with_capacity(found.len());
         for id in ids.iter() {
-            if let Some(mut patch) = patch_stmt
-                .query_row(params![id], Patch::from_sql_row)
-                .found()?
-            {
-                let parents = deps_stmt.query_map(params![id], |row| row.get::<_, ID>(0))?;
-                let mut deps = SmallVec::default();
-                for parent in parents {
-                    deps.push(parent?);
-                }
+            if let Some((mut patch, deps)) = found.remove(id) {
                 patch.deps = Deps::new(deps);
+                // detects corrupted st_rel
+                patch.verify_id_with::<C>()?;
                 patches.push(patch);
             }
         }
         Ok(patches)
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(limit = limit))
+    )]
+    fn patches_page(&self, after: Option<&ID>, limit: usize) -> Result<Vec<Patch>> {
+        timed!();
+        let after = match after {
+            None => 0,
+            Some(after) => self
+                .conn
+                .query_row(
+                    r#"SELECT seq_no FROM st_patches WHERE hash = ?"#,
+                    params![after],
+                    |row| row.get::<_, i64>(0),
+                )
+                .found()?
+                .ok_or_else(cursor_not_found)?,
+        };
+        let mut stmt = self
+            .conn
+            .prepare(r#"SELECT hash FROM st_patches WHERE seq_no > ? ORDER BY seq_no LIMIT ?"#)?;
+        let ids = stmt
+            .query_map(params![after, limit as i64], |row| row.get::<_, ID>(0))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        self.patches(&ids)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(id = %patch_id))
+    )]
     fn is_integrated(&self, patch_id: &ID) -> Result<bool> {
+        timed!();
         let mut stmt = self.conn.prepare(
             r#"
         SELECT 1
         FROM st_patches
         WHERE hash = ?"#,
         )?;
-        let res = stmt.query_row(params![patch_id], |_| Ok(()));
-        match res {
-            Ok(_) => Ok(true),
-            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
-            Err(e) => Err(e.into()),
-        }
+        let res = stmt.query_row(params![patch_id], |_| Ok(())).found()?;
+        Ok(res.is_some())
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(ids = ids.len()))
+    )]
+    fn integrated_set(&self, ids: &[ID]) -> Result<HashSet<ID>> {
+        timed!();
+        let mut stmt = self
+            .conn
+            .prepare(r#"SELECT hash FROM st_patches WHERE hash IN rarray(?)"#)?;
+        let set = stmt
+            .query_map(params![id_array(ids)], |row| row.get::<_, ID>(0))?
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(set)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(id = %patch_id))
+    )]
     fn contains(&self, patch_id: &ID) -> Result<bool> {
+        timed!();
         let mut stmt = self.conn.prepare(
             r#"
         SELECT 1 FROM st_patches WHERE hash = ?
         UNION
-        SELECT 1 FROM st_stash WHERE hash = ?"#,
+        SELECT 1 FROM st_stash WHERE hash = ? AND taken = 0"#,
         )?;
-        let res = stmt.query_row(params![patch_id, patch_id], |_| Ok(()));
-        match res {
-            Ok(_) => Ok(true),
-            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
-            Err(e) => Err(e.into()),
-        }
+        let res = stmt
+            .query_row(params![patch_id, patch_id], |_| Ok(()))
+            .found()?;
+        Ok(res.is_some())
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "trace",
+            skip_all,
+            fields(
+                id = %patch.id(),
+                author = %patch.author(),
+                deps = patch.deps().len(),
+            )
+        )
+    )]
     fn commit(&self, patch: &Patch) -> Result<()> {
-        let hash = patch.id();
-        let author = patch.author();
-        let sign = patch.sign().to_bytes();
-        let data = patch.data();
-        let author_id =
-            self.conn.query_row(r#"SElECT author_id FROM st_authors WHERE verification_key = ?"#, params![author], |row| row.get::<_, u32>(0)).or_else(|_|
-            self.conn.query_row(r#"INSERT INTO st_authors(verification_key) VALUES(?) ON CONFLICT (verification_key) DO NOTHING RETURNING author_id"#, params![author], |row| row.get::<_, u32>(0)))?;
-        let patch_id = self.conn.query_row(
-            r#"INSERT INTO st_patches(hash, author_id, signature, data) VALUES (?, ?, ?, ?) RETURNING seq_no"#,
-            params![hash, author_id, sign, data],
-            |row| row.get::<_, u64>(0)
-        )?;
-        for parent in patch.deps().iter() {
-            self.conn.execute(
-                r#"
-            INSERT INTO st_rel(parent, child)
-            VALUES((SELECT seq_no FROM st_patches WHERE hash = ?), ?)
-            "#,
-                params![parent, patch_id],
-            )?;
-        }
-        Ok(())
+        timed!();
+        self.verify_signatures(&[patch])?;
+        self.atomic(|| self.insert_patch(patch))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(patches = patches.len()))
+    )]
+    fn commit_batch<P: Borrow<Patch>>(&self, patches: &[P]) -> Result<()> {
+        timed!();
+        let patches: Vec<&Patch> = patches.iter().map(Borrow::borrow).collect();
+        self.verify_signatures(&patches)?;
+        self.atomic(|| {
+            for patch in patches.iter() {
+                self.insert_patch(patch)?;
+            }
+            Ok(())
+        })
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(id = %patch.id()))
+    )]
     fn stash(&self, patch: &Patch) -> Result<()> {
+        timed!();
+        self.check_size(patch)?;
         let hash = patch.id();
         let author = patch.author();
         let sign = patch.sign().to_bytes();
         let deps = serde_json::to_vec(patch.deps())?;
         let data = patch.data();
+        // patches taken out of the stash and stashed again keep their original stash time
         self.conn.execute(
             r#"
-        INSERT INTO st_stash(hash, signature, deps, data, author)
-        VALUES (?, ?, ?, ?, ?)"#,
-            params![hash, sign, deps, data, author],
+        INSERT INTO st_stash(hash, signature, deps, data, author, stashed_at, content_type)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT (hash) DO UPDATE SET taken = 0"#,
+            params![
+                hash,
+                sign,
+                deps,
+                data,
+                author,
+                self.clock.now(),
+                patch.content_type() as u8
+            ],
         )?;
         Ok(())
     }
 
+    /// Stashed patches are only marked as taken, so that their stash time survives being stashed
+    /// again. Taken patches are removed once committed or by the next unstash, if they were not
+    /// stashed again in the meantime.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
+    fn unstash_iter(&self) -> Result<impl Iterator<Item = Result<Patch>> + '_> {
+        timed!();
+        self.conn
+            .execute(r#"DELETE FROM st_stash WHERE taken = 1"#, ())?;
+        // patches stashed after this point (including the ones yielded and stashed again) are
+        // beyond the last position
+        let last: i64 = self.conn.query_row(
+            r#"SELECT COALESCE(MAX(seq_no), 0) FROM st_stash"#,
+            (),
+            |row| row.get(0),
+        )?;
+        let mut cursor = 0i64;
+        Ok(std::iter::from_fn(move || {
+            if cursor >= last {
+                return None;
+            }
+            match self.take_stashed(cursor, last) {
+                Ok(Some((seq_no, patch))) => {
+                    cursor = seq_no;
+                    Some(patch)
+                }
+                Ok(None) => {
+                    cursor = last;
+                    None
+                }
+                Err(e) => {
+                    cursor = last;
+                    Some(Err(e))
+                }
+            }
+        }))
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
     fn unstash(&self) -> Result<Vec<Patch>> {
+        timed!();
+        // failed unstash doesn't take anything out of the stash
+        self.atomic(|| self.unstash_iter()?.collect())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
+    fn stashed(&self) -> Result<Vec<Patch>> {
+        timed!();
+        let mut stmt = self.conn.prepare(
+            r#"SELECT hash, author, signature, data, deps, content_type FROM st_stash WHERE taken = 0"#,
+        )?;
+        let patches = stmt
+            .query_map((), Patch::from_sql_row)?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(patches)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(older_than = ?older_than))
+    )]
+    fn prune_stash(&self, older_than: Duration) -> Result<usize> {
+        timed!();
+        let cutoff = self
+            .clock
+            .now()
+            .saturating_sub(older_than.as_millis() as u64);
+        let evicted = self.conn.execute(
+            r#"DELETE FROM st_stash WHERE stashed_at < ? AND taken = 0"#,
+            params![cutoff],
+        )?;
+        Ok(evicted)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(id = %patch_id))
+    )]
+    fn depth(&self, patch_id: &ID) -> Result<Option<u64>> {
+        timed!();
+        let depth = self
+            .conn
+            .query_row(
+                r#"SELECT depth FROM st_patches WHERE hash = ?"#,
+                params![patch_id],
+                |row| row.get(0),
+            )
+            .found()?;
+        Ok(depth)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
+    fn topo_order(&self) -> Result<Vec<ID>> {
+        timed!();
         let mut stmt = self
             .conn
-            .prepare(r#"SELECT hash, author, signature, data, deps FROM st_stash"#)?;
-        let patches: Vec<_> = stmt
-            .query_map((), |row| match Patch::from_sql_row(row) {
-                Ok(patch) => Ok(patch),
-                Err(e) => Err(rusqlite::Error::ToSqlConversionFailure(e.into())),
+            .prepare(r#"SELECT hash FROM st_patches ORDER BY seq_no"#)?;
+        let ids = stmt
+            .query_map((), |row| row.get::<_, ID>(0))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let mut stmt = self.conn.prepare(
+            r#"
+        SELECT child.hash, parent.hash
+        FROM st_rel r
+        JOIN st_patches child ON child.seq_no = r.child
+        JOIN st_patches parent ON parent.seq_no = r.parent"#,
+        )?;
+        let edges = stmt
+            .query_map((), |row| Ok((row.get::<_, ID>(0)?, row.get::<_, ID>(1)?)))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let order = topo_order(&ids, edges);
+        Ok(order.into_iter().map(|i| ids[i]).collect())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(id = %patch_id))
+    )]
+    fn remove(&self, patch_id: &ID) -> Result<bool> {
+        timed!();
+        self.atomic(|| {
+            let seq_no = self
+                .conn
+                .query_row(
+                    r#"
+                SELECT seq_no FROM st_patches p
+                WHERE hash = ?
+                  AND NOT EXISTS (SELECT 1 FROM st_rel WHERE parent = p.seq_no)
+                  AND NOT EXISTS (SELECT 1 FROM st_pins WHERE seq_no = p.seq_no)"#,
+                    params![patch_id],
+                    |row| row.get::<_, i64>(0),
+                )
+                .found()?;
+            match seq_no {
+                None => Ok(false),
+                Some(seq_no) => {
+                    self.delete_patches(Rc::new(vec![SqlValue::Integer(seq_no)]))?;
+                    Ok(true)
+                }
+            }
+        })
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(id = %patch_id))
+    )]
+    fn remove_cascade(&self, patch_id: &ID) -> Result<Vec<ID>> {
+        timed!();
+        self.atomic(|| {
+            let mut stmt = self.conn.prepare(
+                r#"
+            WITH RECURSIVE descendants(seq_no) AS (
+                SELECT seq_no FROM st_patches WHERE hash = ?
+                UNION
+                SELECT r.child FROM st_rel r JOIN descendants d ON r.parent = d.seq_no
+            )
+            SELECT p.seq_no, p.hash, EXISTS (SELECT 1 FROM st_pins WHERE seq_no = p.seq_no)
+            FROM st_patches p
+            JOIN descendants d ON p.seq_no = d.seq_no
+            ORDER BY p.seq_no DESC"#,
+            )?;
+            let mut seq_nos = Vec::new();
+            let mut ids = Vec::new();
+            for row in stmt.query_map(params![patch_id], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, ID>(1)?,
+                    row.get::<_, bool>(2)?,
+                ))
+            })? {
+                let (seq_no, id, pinned) = row?;
+                if pinned {
+                    return Err(Error::Pinned(id));
+                }
+                seq_nos.push(SqlValue::Integer(seq_no));
+                ids.push(id);
+            }
+            self.delete_patches(Rc::new(seq_nos))?;
+            Ok(ids)
+        })
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(id = %patch_id))
+    )]
+    fn children(&self, patch_id: &ID) -> Result<Vec<ID>> {
+        timed!();
+        let mut stmt = self.conn.prepare(
+            r#"
+        SELECT child.hash
+        FROM st_patches parent
+        JOIN st_rel r ON r.parent = parent.seq_no
+        JOIN st_patches child ON child.seq_no = r.child
+        WHERE parent.hash = ?
+        ORDER BY child.seq_no"#,
+        )?;
+        let ids = stmt
+            .query_map(params![patch_id], |row| row.get::<_, ID>(0))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(ids)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(id = %patch_id))
+    )]
+    fn pin(&self, patch_id: &ID) -> Result<bool> {
+        timed!();
+        self.conn.execute(
+            r#"
+        INSERT INTO st_pins(seq_no)
+        SELECT seq_no FROM st_patches WHERE hash = ?
+        ON CONFLICT DO NOTHING"#,
+            params![patch_id],
+        )?;
+        self.is_integrated(patch_id)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(id = %patch_id))
+    )]
+    fn unpin(&self, patch_id: &ID) -> Result<bool> {
+        timed!();
+        let removed = self.conn.execute(
+            r#"
+        DELETE FROM st_pins
+        WHERE seq_no = (SELECT seq_no FROM st_patches WHERE hash = ?)"#,
+            params![patch_id],
+        )?;
+        Ok(removed > 0)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
+    fn pinned(&self) -> Result<Vec<ID>> {
+        timed!();
+        let mut stmt = self.conn.prepare(
+            r#"
+        SELECT p.hash
+        FROM st_pins pin
+        JOIN st_patches p ON p.seq_no = pin.seq_no
+        ORDER BY p.seq_no"#,
+        )?;
+        let ids = stmt
+            .query_map((), |row| row.get::<_, ID>(0))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(ids)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(ancestor = %ancestor, descendant = %descendant))
+    )]
+    fn is_ancestor(&self, ancestor: &ID, descendant: &ID) -> Result<bool> {
+        timed!();
+        // ancestors are always shallower than their descendants, so deeper patches are not
+        // followed, and UNION skips already visited ones
+        let found = self.conn.query_row(
+            r#"
+        WITH RECURSIVE target(seq_no, depth) AS (
+            SELECT seq_no, depth FROM st_patches WHERE hash = ?1
+        ),
+        ancestors(seq_no) AS (
+            SELECT seq_no FROM st_patches WHERE hash = ?2
+            UNION
+            SELECT r.parent
+            FROM st_rel r
+            JOIN ancestors a ON r.child = a.seq_no
+            JOIN st_patches p ON p.seq_no = r.parent
+            WHERE p.depth >= (SELECT depth FROM target)
+        )
+        SELECT EXISTS (SELECT 1 FROM ancestors a JOIN target t ON a.seq_no = t.seq_no)"#,
+            params![ancestor, descendant],
+            |row| row.get::<_, bool>(0),
+        )?;
+        Ok(found)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(author = %author))
+    )]
+    fn patches_by_author(&self, author: &PeerID) -> Result<Vec<ID>> {
+        timed!();
+        let mut stmt = self.conn.prepare(
+            r#"
+        SELECT p.hash
+        FROM st_patches p
+        JOIN st_authors a ON p.author_id = a.author_id
+        WHERE a.verification_key = ?
+        ORDER BY p.seq_no"#,
+        )?;
+        let ids = stmt
+            .query_map(params![author], |row| row.get::<_, ID>(0))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(ids)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
+    fn author_heads(&self) -> Result<HashMap<PeerID, ID>> {
+        timed!();
+        let mut stmt = self.conn.prepare(
+            r#"
+        SELECT verification_key, hash FROM (
+            SELECT a.verification_key, p.hash, ROW_NUMBER() OVER (
+                PARTITION BY p.author_id ORDER BY p.depth DESC, p.seq_no DESC
+            ) AS rank
+            FROM st_patches p
+            JOIN st_authors a ON p.author_id = a.author_id
+        )
+        WHERE rank = 1"#,
+        )?;
+        let heads = stmt
+            .query_map((), |row| {
+                Ok((row.get::<_, PeerID>(0)?, row.get::<_, ID>(1)?))
             })?
-            .map(|patch| patch.unwrap())
-            .collect();
-        self.conn.execute("DELETE FROM st_stash", ())?;
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(heads)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
+    fn stats(&self) -> Result<StoreStats> {
+        timed!();
+        let (patches, authors, data_bytes) = self.conn.query_row(
+            r#"
+        SELECT COUNT(*), COUNT(DISTINCT p.author_id), COALESCE(SUM(LENGTH(COALESCE(p.data, b.bytes))), 0)
+        FROM st_patches p
+        LEFT JOIN st_blobs b ON p.blob_hash = b.blob_hash"#,
+            (),
+            |row| Ok((row.get::<_, u64>(0)?, row.get::<_, u64>(1)?, row.get::<_, u64>(2)?)),
+        )?;
+        let stashed = self.conn.query_row(
+            r#"SELECT COUNT(*) FROM st_stash WHERE taken = 0"#,
+            (),
+            |row| row.get::<_, u64>(0),
+        )?;
+        Ok(StoreStats {
+            patches,
+            stashed,
+            authors,
+            data_bytes,
+        })
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
+    fn generation(&self) -> Result<u64> {
+        timed!();
+        let generation = self.conn.query_row(
+            r#"SELECT value FROM st_meta WHERE key = 'generation'"#,
+            (),
+            |row| row.get::<_, u64>(0),
+        )?;
+        Ok(generation)
+    }
+
+    /// Moves all pages from the write-ahead log into the database file and truncates the log. Fails
+    /// with [std::io::ErrorKind::WouldBlock] if concurrent readers or writers prevented the log
+    /// from being fully checkpointed. Does nothing in journal modes other than [JournalMode::Wal].
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
+    fn flush(&self) -> Result<()> {
+        timed!();
+        let busy = self
+            .conn
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", (), |row| {
+                row.get::<_, i64>(0)
+            })?;
+        if busy != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::WouldBlock,
+                "WAL checkpoint blocked by concurrent database connections",
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(id = %patch.id()))
+    )]
+    fn commit_local(&self, patch: &Patch) -> Result<()> {
+        timed!();
+        self.check_size(patch)?;
+        let deps = serde_json::to_vec(patch.deps())?;
+        self.conn.execute(
+            r#"
+        INSERT INTO st_local(hash, deps, data, content_type) VALUES (?, ?, ?, ?)
+        ON CONFLICT (hash) DO NOTHING"#,
+            params![patch.id(), deps, patch.data(), patch.content_type() as u8],
+        )?;
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
+    fn local_patches(&self) -> Result<Vec<Patch>> {
+        timed!();
+        // local patches have no author nor signature, which are zeroed instead
+        let mut stmt = self.conn.prepare(
+            r#"
+        SELECT hash, zeroblob(32), zeroblob(64), data, deps, content_type
+        FROM st_local
+        ORDER BY seq_no"#,
+        )?;
+        let patches = stmt
+            .query_map((), Patch::from_sql_row)?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
         Ok(patches)
     }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(last = %checkpoint.last))
+    )]
+    fn save_checkpoint(&self, checkpoint: &Checkpoint) -> Result<()> {
+        timed!();
+        let heads = serde_json::to_vec(&checkpoint.heads)?;
+        self.atomic(|| {
+            self.conn.execute(r#"DELETE FROM st_checkpoints"#, ())?;
+            self.conn.execute(
+                r#"INSERT INTO st_checkpoints(last, heads, state) VALUES (?, ?, ?)"#,
+                params![checkpoint.last, heads, &checkpoint.state[..]],
+            )?;
+            Ok(())
+        })
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
+    fn last_checkpoint(&self) -> Result<Option<Checkpoint>> {
+        timed!();
+        let checkpoint = self
+            .conn
+            .query_row(
+                r#"SELECT last, heads, state FROM st_checkpoints ORDER BY seq_no DESC LIMIT 1"#,
+                (),
+                |row| {
+                    Ok((
+                        row.get::<_, ID>(0)?,
+                        row.get::<_, Vec<u8>>(1)?,
+                        row.get::<_, Vec<u8>>(2)?,
+                    ))
+                },
+            )
+            .found()?;
+        match checkpoint {
+            None => Ok(None),
+            Some((last, heads, state)) => Ok(Some(Checkpoint {
+                last,
+                heads: serde_json::from_slice(&heads)?,
+                state: state.into(),
+            })),
+        }
+    }
 }
 
+/// Configuration of the SQLite connection used by [SqliteStore], applied when the store is being
+/// created.
 #[derive(Debug, Clone)]
-pub struct Options {}
+pub struct Options {
+    journal_mode: JournalMode,
+    synchronous: Synchronous,
+    busy_timeout: Duration,
+    cache_size: Option<i64>,
+    foreign_keys: bool,
+    dedup_blobs: bool,
+    max_patch_bytes: usize,
+    verify_on_commit: bool,
+    clock: Arc<dyn Clock>,
+}
+
+impl Options {
+    /// Sets `PRAGMA journal_mode`. Default: [JournalMode::Wal].
+    pub fn journal_mode(mut self, journal_mode: JournalMode) -> Self {
+        self.journal_mode = journal_mode;
+        self
+    }
+
+    /// Sets `PRAGMA synchronous`. Default: [Synchronous::Normal].
+    pub fn synchronous(mut self, synchronous: Synchronous) -> Self {
+        self.synchronous = synchronous;
+        self
+    }
+
+    /// Sets how long a connection waits for a lock held by another connection before failing with
+    /// `SQLITE_BUSY`. Default: 5 seconds.
+    pub fn busy_timeout(mut self, busy_timeout: Duration) -> Self {
+        self.busy_timeout = busy_timeout;
+        self
+    }
+
+    /// Sets `PRAGMA cache_size`: a positive value is a number of pages, while a negative one is
+    /// a limit in KiB. Default: SQLite's own default.
+    pub fn cache_size(mut self, cache_size: i64) -> Self {
+        self.cache_size = Some(cache_size);
+        self
+    }
+
+    /// Enables or disables foreign key constraints enforcement. Default: enabled.
+    pub fn foreign_keys(mut self, enabled: bool) -> Self {
+        self.foreign_keys = enabled;
+        self
+    }
+
+    /// Enables or disables deduplication of patch data. When enabled, data of committed patches
+    /// is stored once per unique content in a separate `st_blobs` table, and data of patches
+    /// committed before is moved there when the store is opened. Disabling it again is safe:
+    /// already deduplicated data remain readable. Default: disabled.
+    pub fn dedup_blobs(mut self, enabled: bool) -> Self {
+        self.dedup_blobs = enabled;
+        self
+    }
+
+    /// Sets the maximum length (in bytes) of patch data. Larger patches are rejected by
+    /// [ObjectStore::commit] and [ObjectStore::stash] with [Error::PatchTooLarge].
+    /// Default: [MAX_DATA_LEN].
+    pub fn max_patch_bytes(mut self, max_patch_bytes: usize) -> Self {
+        self.max_patch_bytes = max_patch_bytes;
+        self
+    }
+
+    /// Enables or disables verification of patch signatures in [ObjectStore::commit] and
+    /// [ObjectStore::commit_batch], so that patches committed directly through the store (rather
+    /// than via [crate::peer::Peer], which verifies them on its own) can't put unverifiable data
+    /// into the database. Default: enabled.
+    pub fn verify_on_commit(mut self, enabled: bool) -> Self {
+        self.verify_on_commit = enabled;
+        self
+    }
+
+    /// Sets the [Clock] used to timestamp stashed patches. Default: [SystemClock].
+    pub fn clock<T: Clock + 'static>(mut self, clock: T) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+}
 
 impl Default for Options {
     fn default() -> Self {
-        Options {}
+        Options {
+            journal_mode: JournalMode::Wal,
+            synchronous: Synchronous::Normal,
+            busy_timeout: Duration::from_secs(5),
+            cache_size: None,
+            foreign_keys: true,
+            dedup_blobs: false,
+            max_patch_bytes: MAX_DATA_LEN,
+            verify_on_commit: true,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+/// SQLite journal mode, see <https://www.sqlite.org/pragma.html#pragma_journal_mode>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+impl JournalMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JournalMode::Delete => "DELETE",
+            JournalMode::Truncate => "TRUNCATE",
+            JournalMode::Persist => "PERSIST",
+            JournalMode::Memory => "MEMORY",
+            JournalMode::Wal => "WAL",
+            JournalMode::Off => "OFF",
+        }
     }
 }
 
+/// SQLite synchronous level, see <https://www.sqlite.org/pragma.html#pragma_synchronous>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        }
+    }
+}
+
+/// Wraps a list of patch IDs into an array value that can be passed to `rarray(?)` table function.
+fn id_array(ids: &[ID]) -> Array {
+    Rc::new(ids.iter().map(|id| SqlValue::Blob(id.to_vec())).collect())
+}
+
 trait Found {
     type Item;
     type Error;
@@ -217,3 +1397,797 @@ impl<T> Found for std::result::Result<T, rusqlite::Error> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use ed25519_dalek::SigningKey;
+    use rusqlite::{params, ErrorCode};
+
+    use crate::clock::MockClock;
+    use crate::op::Op;
+    use crate::patch::{ContentType, Deps, Patch, ID, SIGNATURE_DOMAIN, SIGNATURE_VERSION};
+    use crate::store::sqlite::{Options, SqliteStore, Synchronous, SCHEMA_VERSION};
+    use crate::store::{Checkpoint, ObjectStore};
+    use crate::{Error, PeerID};
+    use std::time::Duration;
+
+    fn create_store() -> SqliteStore {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        SqliteStore::new(conn).unwrap()
+    }
+
+    #[test]
+    fn patches_batch_lookup() {
+        let store = create_store();
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let b = Patch::new(&key, [*a.id()], &"B").unwrap();
+        let c = Patch::new(&key, [*a.id()], &"C").unwrap();
+        let d = Patch::new(&key, [*b.id(), *c.id()], &"D").unwrap();
+        for patch in [&a, &b, &c, &d] {
+            store.commit(patch).unwrap();
+        }
+
+        let unknown = ID::from(blake3::hash(b"unknown"));
+        let found = store
+            .patches(&[*d.id(), unknown, *a.id(), *c.id()])
+            .unwrap();
+        assert_eq!(found, vec![d.clone(), a.clone(), c]);
+
+        assert_eq!(store.depth(a.id()).unwrap(), Some(0));
+        assert_eq!(store.depth(b.id()).unwrap(), Some(1));
+        assert_eq!(store.depth(d.id()).unwrap(), Some(2));
+        assert_eq!(store.depth(&unknown).unwrap(), None);
+    }
+
+    #[test]
+    fn integrated_set() {
+        let store = create_store();
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let b = Patch::new(&key, [*a.id()], &"B").unwrap();
+        let c = Patch::new(&key, [*b.id()], &"C").unwrap();
+        store.commit_batch(&[a.clone(), b.clone()]).unwrap();
+        store.stash(&c).unwrap();
+
+        let unknown = ID::from(blake3::hash(b"unknown"));
+        let ids = [*c.id(), *a.id(), unknown, *b.id(), *a.id()];
+        let set = store.integrated_set(&ids).unwrap();
+        for id in ids.iter() {
+            assert_eq!(set.contains(id), store.is_integrated(id).unwrap());
+        }
+        assert_eq!(set.len(), 2);
+        assert!(store.integrated_set(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn patches_page() {
+        let store = create_store();
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let mut patches: Vec<Patch> = Vec::new();
+        for i in 0..10 {
+            let deps = patches.last().map(|p| *p.id());
+            patches.push(Patch::new(&key, deps, &i).unwrap());
+        }
+        store.commit_batch(&patches).unwrap();
+
+        let mut paged = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = store.patches_page(cursor.as_ref(), 3).unwrap();
+            assert!(page.len() <= 3);
+            match page.last() {
+                None => break,
+                Some(last) => cursor = Some(*last.id()),
+            }
+            paged.extend(page);
+        }
+        assert_eq!(paged, patches);
+        assert_eq!(store.patches_page(None, 0).unwrap(), vec![]);
+        let last = patches[9].id();
+        assert!(store.patches_page(Some(last), 3).unwrap().is_empty());
+
+        let unknown = ID::from(blake3::hash(b"unknown"));
+        let res = store.patches_page(Some(&unknown), 3);
+        assert!(matches!(res, Err(Error::IO(e)) if e.kind() == std::io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn commit_twice() {
+        let store = create_store();
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let b = Patch::new(&key, [*a.id()], &"B").unwrap();
+        store.commit(&a).unwrap();
+        store.commit(&b).unwrap();
+
+        // lost edge is restored on re-commit
+        store.conn.execute("DELETE FROM st_rel", ()).unwrap();
+        store.commit(&b).unwrap();
+        store.commit(&a).unwrap();
+
+        assert_eq!(store.heads().unwrap(), vec![*b.id()]);
+        assert_eq!(store.topo_order().unwrap(), vec![*a.id(), *b.id()]);
+        assert_eq!(store.patches(&[*b.id()]).unwrap(), vec![b]);
+    }
+
+    #[test]
+    fn commit_batch_rolls_back() {
+        let store = create_store();
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let b = Patch::new(&key, [*a.id()], &"B").unwrap();
+        let c = Patch::new(&key, [*b.id()], &"C").unwrap();
+        let orphan = Patch::new(&key, [blake3::hash(b"unknown").into()], &"X").unwrap();
+
+        // patch with a missing dependency trips a constraint
+        let res = store.commit_batch(&[a.clone(), b.clone(), c.clone(), orphan]);
+        assert!(res.is_err());
+        assert!(!store.is_integrated(a.id()).unwrap());
+        assert!(!store.is_integrated(b.id()).unwrap());
+
+        store.commit_batch(&[a.clone(), b, c.clone()]).unwrap();
+        assert_eq!(store.heads().unwrap(), vec![*c.id()]);
+    }
+
+    #[test]
+    fn dedup_blobs() {
+        let path = std::env::temp_dir().join(format!("st-{}.db", rand::random::<u64>()));
+        let open = |dedup| {
+            let conn = rusqlite::Connection::open(&path).unwrap();
+            SqliteStore::with_options(conn, Options::default().dedup_blobs(dedup)).unwrap()
+        };
+        let alice = SigningKey::generate(&mut rand::rngs::OsRng);
+        let bob = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&alice, [], &"same").unwrap();
+        let b = Patch::new(&bob, [*a.id()], &"same").unwrap();
+        let c = Patch::new(&bob, [*b.id()], &"other").unwrap();
+        let d = Patch::new(&alice, [*c.id()], &"same").unwrap();
+        let count = |store: &SqliteStore, sql: &str| {
+            store
+                .conn
+                .query_row(sql, (), |row| row.get::<_, i64>(0))
+                .unwrap()
+        };
+
+        let store = open(false);
+        store.commit_batch(&[a.clone(), b.clone()]).unwrap();
+        assert_eq!(count(&store, "SELECT COUNT(*) FROM st_blobs"), 0);
+        drop(store);
+
+        // existing data is moved into st_blobs
+        let store = open(true);
+        store.commit_batch(&[c.clone(), d.clone()]).unwrap();
+        assert_eq!(count(&store, "SELECT COUNT(*) FROM st_blobs"), 2);
+        let inline = "SELECT COUNT(*) FROM st_patches WHERE data IS NOT NULL";
+        assert_eq!(count(&store, inline), 0);
+        let all = [*a.id(), *b.id(), *c.id(), *d.id()];
+        assert_eq!(
+            store.patches(&all).unwrap(),
+            vec![a, b, c.clone(), d.clone()]
+        );
+
+        assert_eq!(store.remove_cascade(c.id()).unwrap().len(), 2);
+        assert_eq!(count(&store, "SELECT COUNT(*) FROM st_blobs"), 1);
+        drop(store);
+
+        let store = open(false);
+        assert_eq!(store.heads().unwrap().len(), 1);
+        store.commit(&c).unwrap();
+        assert_eq!(store.patches(&[*c.id()]).unwrap(), vec![c]);
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn incremental_heads() {
+        use rand::seq::SliceRandom;
+        use rand::Rng;
+
+        let store = create_store();
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let mut rng = rand::thread_rng();
+        let mut committed: Vec<ID> = Vec::new();
+        let antijoin = |store: &SqliteStore| -> Vec<ID> {
+            let mut stmt = store
+                .conn
+                .prepare(
+                    r#"SELECT hash FROM st_patches
+                    WHERE seq_no NOT IN (SELECT parent FROM st_rel) ORDER BY seq_no"#,
+                )
+                .unwrap();
+            let heads = stmt.query_map((), |row| row.get(0)).unwrap();
+            heads.map(|id| id.unwrap()).collect()
+        };
+        for i in 0..300u32 {
+            let deps_len = rng.gen_range(0..=3).min(committed.len());
+            let deps: Vec<ID> = committed
+                .choose_multiple(&mut rng, deps_len)
+                .cloned()
+                .collect();
+            let patch = Patch::new(&key, deps, &i).unwrap();
+            store.commit(&patch).unwrap();
+            committed.push(*patch.id());
+            if i % 10 == 0 {
+                store.commit(&patch).unwrap(); // re-commit
+            }
+            if i % 7 == 0 {
+                let heads = store.heads().unwrap();
+                let head = heads.choose(&mut rng).unwrap();
+                assert!(store.remove(head).unwrap());
+                committed.retain(|id| id != head);
+            }
+            if i % 31 == 0 && !committed.is_empty() {
+                let id = *committed.choose(&mut rng).unwrap();
+                let removed = store.remove_cascade(&id).unwrap();
+                committed.retain(|id| !removed.contains(id));
+            }
+            assert_eq!(store.heads().unwrap(), antijoin(&store));
+        }
+    }
+
+    #[test]
+    fn remove_patches() {
+        let store = create_store();
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let b = Patch::new(&key, [*a.id()], &"B").unwrap();
+        let c = Patch::new(&key, [*a.id()], &"C").unwrap();
+        let d = Patch::new(&key, [*b.id(), *c.id()], &"D").unwrap();
+        let e = Patch::new(&key, [*a.id()], &"E").unwrap();
+        store
+            .commit_batch(&[a.clone(), b.clone(), c.clone(), d.clone(), e.clone()])
+            .unwrap();
+
+        // patches with dependents are not removed
+        assert!(!store.remove(b.id()).unwrap());
+        assert!(store.is_integrated(b.id()).unwrap());
+
+        assert!(store.remove(e.id()).unwrap());
+        assert!(!store.is_integrated(e.id()).unwrap());
+        assert!(!store.remove(e.id()).unwrap());
+        assert_eq!(store.heads().unwrap(), vec![*d.id()]);
+
+        let removed = store.remove_cascade(b.id()).unwrap();
+        assert_eq!(removed, vec![*d.id(), *b.id()]);
+        assert_eq!(store.heads().unwrap(), vec![*c.id()]);
+        assert_eq!(store.patches(&[*c.id()]).unwrap(), vec![c]);
+        assert_eq!(store.patches_by_author(a.author()).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn unstash_corrupted_row() {
+        let store = create_store();
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        store.stash(&a).unwrap();
+        store
+            .conn
+            .execute("UPDATE st_stash SET deps = x'deadbeef'", ())
+            .unwrap();
+
+        assert!(matches!(store.unstash(), Err(Error::Sqlite(_))));
+        // failed unstash doesn't take anything out of the stash
+        assert!(store.contains(a.id()).unwrap());
+    }
+
+    #[test]
+    fn unstash_iter() {
+        let store = create_store();
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let stashed: Vec<Patch> = ["B", "C", "D", "E"]
+            .iter()
+            .map(|data| Patch::new(&key, [*a.id()], data).unwrap())
+            .collect();
+        for patch in stashed.iter() {
+            store.stash(patch).unwrap();
+        }
+        store
+            .conn
+            .execute(
+                "UPDATE st_stash SET deps = x'deadbeef' WHERE hash = ?",
+                params![stashed[2].id()],
+            )
+            .unwrap();
+
+        let mut iter = store.unstash_iter().unwrap();
+        assert_eq!(iter.next().unwrap().unwrap(), stashed[0]);
+        // patches are taken out of the stash one by one
+        let count: u64 = store
+            .conn
+            .query_row("SELECT COUNT(*) FROM st_stash WHERE taken = 0", (), |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(count, 3);
+        let late = Patch::new(&key, [*a.id()], &"F").unwrap();
+        store.stash(&late).unwrap();
+        store.stash(&stashed[0]).unwrap();
+        assert_eq!(iter.next().unwrap().unwrap(), stashed[1]);
+        assert!(matches!(iter.next(), Some(Err(Error::Sqlite(_)))));
+        assert_eq!(iter.next().unwrap().unwrap(), stashed[3]);
+        assert!(iter.next().is_none());
+
+        // corrupted row, as well as patches stashed during iteration, stay in the stash
+        let remaining: Vec<ID> = store
+            .conn
+            .prepare("SELECT hash FROM st_stash WHERE taken = 0 ORDER BY seq_no")
+            .unwrap()
+            .query_map((), |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            remaining,
+            vec![*stashed[0].id(), *stashed[2].id(), *late.id()]
+        );
+    }
+
+    #[test]
+    fn prune_stash() {
+        let clock = MockClock::new(1_000_000);
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let store =
+            SqliteStore::with_options(conn, Options::default().clock(clock.clone())).unwrap();
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let orphan = Patch::new(&key, [*a.id()], &"B").unwrap();
+        let fresh = Patch::new(&key, [*a.id()], &"C").unwrap();
+        store.stash(&orphan).unwrap();
+
+        let hour = Duration::from_secs(3600);
+        clock.advance(2 * hour);
+        store.stash(&fresh).unwrap();
+
+        // stashing patch again doesn't reset its stash time
+        assert_eq!(store.unstash().unwrap().len(), 2);
+        store.stash(&orphan).unwrap();
+        store.stash(&fresh).unwrap();
+
+        assert_eq!(store.prune_stash(hour).unwrap(), 1);
+        assert!(!store.contains(orphan.id()).unwrap());
+        assert_eq!(store.unstash().unwrap(), vec![fresh]);
+
+        // patches taken out of the stash are not evicted, but dropped by the next unstash
+        clock.advance(2 * hour);
+        assert_eq!(store.prune_stash(hour).unwrap(), 0);
+        assert!(store.unstash().unwrap().is_empty());
+        let rows: u64 = store
+            .conn
+            .query_row("SELECT COUNT(*) FROM st_stash", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 0);
+    }
+
+    #[test]
+    fn max_patch_bytes() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let store =
+            SqliteStore::with_options(conn, Options::default().max_patch_bytes(10)).unwrap();
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let max = Patch::new(&key, [], &"12345678").unwrap();
+        let over = Patch::new(&key, [], &"123456789").unwrap();
+        assert_eq!(max.data().len(), 10);
+
+        store.commit(&max).unwrap();
+        assert!(matches!(store.commit(&over), Err(Error::PatchTooLarge(id)) if id == *over.id()));
+        assert!(matches!(store.stash(&over), Err(Error::PatchTooLarge(id)) if id == *over.id()));
+        assert!(!store.contains(over.id()).unwrap());
+        store.stash(&max).unwrap();
+    }
+
+    #[test]
+    fn open() {
+        let path = std::env::temp_dir().join(format!("st-{}.db", rand::random::<u64>()));
+        let store = SqliteStore::open(&path).unwrap();
+        assert!(path.exists());
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        store.commit(&a).unwrap();
+        drop(store);
+
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.heads().unwrap(), vec![*a.id()]);
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+
+        let store = SqliteStore::open_in_memory().unwrap();
+        assert!(store.heads().unwrap().is_empty());
+        store.commit(&a).unwrap();
+        assert!(store.is_integrated(a.id()).unwrap());
+    }
+
+    #[test]
+    fn content_type() {
+        let path = std::env::temp_dir().join(format!("st-{}.db", rand::random::<u64>()));
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let legacy = Patch::new(&key, [], &"A").unwrap();
+        let store = SqliteStore::open(&path).unwrap();
+        store.commit(&legacy).unwrap();
+        store
+            .conn
+            .execute_batch(
+                "ALTER TABLE st_patches DROP COLUMN content_type;
+                 ALTER TABLE st_stash DROP COLUMN content_type;
+                 PRAGMA user_version = 0;",
+            )
+            .unwrap();
+        drop(store);
+
+        // columns are restored with opaque content type for existing patches
+        let store = SqliteStore::open(&path).unwrap();
+        let op = Patch::new(&key, [*legacy.id()], &Op::Prune).unwrap();
+        let stashed =
+            Patch::new(&key, [*op.id(), *op.id()], &Op::Revoke(PeerID::default())).unwrap();
+        store.commit(&op).unwrap();
+        store.stash(&stashed).unwrap();
+        let found = store.patches(&[*legacy.id(), *op.id()]).unwrap();
+        assert_eq!(found, vec![legacy, op]);
+        assert_eq!(found[1].content_type(), ContentType::Op);
+        assert_eq!(store.stashed().unwrap(), vec![stashed]);
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn schema_migrations() {
+        let path = std::env::temp_dir().join(format!("st-{}.db", rand::random::<u64>()));
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let b = Patch::new(&key, [*a.id()], &"B").unwrap();
+        let stashed = Patch::new(&key, [ID::default()], &"S").unwrap();
+        // schema and rows written by versions from before schema versioning was introduced
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS st_authors(
+                author_id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+                verification_key BLOB NOT NULL UNIQUE
+            );
+            CREATE TABLE IF NOT EXISTS st_patches(
+                seq_no INTEGER PRIMARY KEY AUTOINCREMENT,
+                hash BLOB NOT NULL UNIQUE CHECK(LENGTH(hash) = 32),
+                author_id BLOB NOT NULL,
+                signature BLOB NOT NULL CHECK(LENGTH(signature) = 64),
+                data JSONB,
+                FOREIGN KEY (author_id) REFERENCES st_authors(author_id)
+            );
+            CREATE TABLE IF NOT EXISTS st_stash(
+                seq_no INTEGER PRIMARY KEY AUTOINCREMENT,
+                deps JSONB NOT NULL,
+                hash BLOB NOT NULL UNIQUE CHECK(LENGTH(hash) = 32),
+                author BLOB NOT NULL CHECK(LENGTH(author) = 32),
+                signature BLOB NOT NULL CHECK(LENGTH(signature) = 64),
+                data JSONB
+            );
+            CREATE UNIQUE INDEX IF NOT EXISTS uq_st_stash_hash ON st_stash(hash);
+            CREATE TABLE IF NOT EXISTS st_rel(
+                child INTEGER,
+                parent INTEGER,
+                PRIMARY KEY (child, parent),
+                FOREIGN KEY (child) REFERENCES st_patches(seq_no),
+                FOREIGN KEY (parent) REFERENCES st_patches(seq_no)
+            )"#,
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO st_authors(verification_key) VALUES (?)",
+            params![a.author()],
+        )
+        .unwrap();
+        for patch in [&a, &b] {
+            conn.execute(
+                "INSERT INTO st_patches(hash, author_id, signature, data) VALUES (?, 1, ?, ?)",
+                params![patch.id(), patch.sign().to_bytes(), patch.data()],
+            )
+            .unwrap();
+        }
+        conn.execute("INSERT INTO st_rel(parent, child) VALUES (1, 2)", ())
+            .unwrap();
+        conn.execute(
+            "INSERT INTO st_stash(hash, signature, deps, data, author) VALUES (?, ?, ?, ?, ?)",
+            params![
+                stashed.id(),
+                stashed.sign().to_bytes(),
+                serde_json::to_vec(stashed.deps()).unwrap(),
+                stashed.data(),
+                stashed.author()
+            ],
+        )
+        .unwrap();
+        drop(conn);
+
+        let store = SqliteStore::open(&path).unwrap();
+        let version = |store: &SqliteStore| -> u32 {
+            store
+                .conn
+                .pragma_query_value(None, "user_version", |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(version(&store), SCHEMA_VERSION);
+        assert_eq!(store.heads().unwrap(), vec![*b.id()]);
+        assert_eq!(
+            store.patches(&[*a.id(), *b.id()]).unwrap(),
+            vec![a.clone(), b.clone()]
+        );
+        assert_eq!(store.depth(b.id()).unwrap(), Some(1));
+        // patches stashed before the upgrade don't expire right away
+        assert_eq!(store.prune_stash(Duration::from_secs(60)).unwrap(), 0);
+        assert_eq!(store.stashed().unwrap(), vec![stashed]);
+
+        // migrated database keeps working as a fresh one
+        let c = Patch::new(&key, [*b.id()], &"C").unwrap();
+        let orphan = Patch::new(&key, [ID::default()], &"O").unwrap();
+        store.commit(&c).unwrap();
+        store.stash(&orphan).unwrap();
+        assert_eq!(store.depth(c.id()).unwrap(), Some(2));
+        assert_eq!(store.heads().unwrap(), vec![*c.id()]);
+        assert!(store.contains(orphan.id()).unwrap());
+        assert_eq!(store.unstash().unwrap().len(), 2);
+        store
+            .conn
+            .pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        drop(store);
+
+        // databases created by a newer version are not touched
+        match SqliteStore::open(&path) {
+            Err(Error::UnsupportedSchema { found, supported }) => {
+                assert_eq!(found, SCHEMA_VERSION + 1);
+                assert_eq!(supported, SCHEMA_VERSION);
+            }
+            other => panic!("expected UnsupportedSchema, got {:?}", other.map(|_| ())),
+        }
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn legacy_signatures() {
+        let path = std::env::temp_dir().join(format!("st-{}.db", rand::random::<u64>()));
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let b = Patch::new(&key, [*a.id()], &"B").unwrap();
+        let store = SqliteStore::open(&path).unwrap();
+        store.commit_batch(&[&a, &b]).unwrap();
+        // pretend the database was created before signature version was recorded
+        let forget_version = |store: &SqliteStore| {
+            store
+                .conn
+                .execute("DELETE FROM st_meta WHERE key = 'signature_version'", ())
+                .unwrap();
+        };
+        forget_version(&store);
+        drop(store);
+
+        // patches signed with the current scheme are verified and the version is recorded
+        let store = SqliteStore::open(&path).unwrap();
+        let recorded: u32 = store
+            .conn
+            .query_row(
+                "SELECT value FROM st_meta WHERE key = 'signature_version'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(recorded, SIGNATURE_VERSION);
+
+        // signature over the bare preimage, as produced before domain separation
+        let preimage = &b.signed_message()[SIGNATURE_DOMAIN.len()..];
+        let legacy: ed25519::Signature = ed25519_dalek::Signer::sign(&key, preimage);
+        store
+            .conn
+            .execute(
+                "UPDATE st_patches SET signature = ? WHERE hash = ?",
+                params![legacy.to_bytes(), b.id()],
+            )
+            .unwrap();
+        forget_version(&store);
+        drop(store);
+
+        for _ in 0..2 {
+            match SqliteStore::open(&path) {
+                Err(Error::UnsupportedSignatureVersion { found, supported }) => {
+                    assert_eq!(found, 0);
+                    assert_eq!(supported, SIGNATURE_VERSION);
+                }
+                other => panic!(
+                    "expected UnsupportedSignatureVersion, got {:?}",
+                    other.map(|_| ())
+                ),
+            }
+        }
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn checkpoint_persistence() {
+        let path = std::env::temp_dir().join(format!("st-{}.db", rand::random::<u64>()));
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let b = Patch::new(&key, [*a.id()], &"B").unwrap();
+        let store = SqliteStore::open(&path).unwrap();
+        store.commit(&a).unwrap();
+        store.commit(&b).unwrap();
+        assert_eq!(store.last_checkpoint().unwrap(), None);
+        let first = Checkpoint {
+            last: *a.id(),
+            heads: vec![*a.id()],
+            state: Bytes::from_static(b"first"),
+        };
+        let second = Checkpoint {
+            last: *b.id(),
+            heads: vec![*b.id()],
+            state: Bytes::from_static(b"second"),
+        };
+        store.save_checkpoint(&first).unwrap();
+        store.save_checkpoint(&second).unwrap();
+        drop(store);
+
+        // only the latest checkpoint is kept
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.last_checkpoint().unwrap(), Some(second));
+        let count: u64 = store
+            .conn
+            .query_row("SELECT COUNT(*) FROM st_checkpoints", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+
+        assert_eq!(store.remove_cascade(b.id()).unwrap(), vec![*b.id()]);
+        assert_eq!(store.last_checkpoint().unwrap(), None);
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn flush() {
+        let path = std::env::temp_dir().join(format!("st-{}.db", rand::random::<u64>()));
+        let copy = path.with_extension("copy.db");
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let b = Patch::new(&key, [*a.id()], &"B").unwrap();
+        let store = SqliteStore::open(&path).unwrap();
+        store.commit(&a).unwrap();
+        store.commit(&b).unwrap();
+        let wal = std::path::PathBuf::from(format!("{}-wal", path.display()));
+        assert!(std::fs::metadata(&wal).unwrap().len() > 0);
+        store.flush().unwrap();
+        assert_eq!(std::fs::metadata(&wal).unwrap().len(), 0);
+
+        // simulate a crash: connection is never closed and the log is lost, so the database file
+        // alone must contain all flushed patches
+        std::mem::forget(store);
+        std::fs::copy(&path, &copy).unwrap();
+        let store = SqliteStore::open(&copy).unwrap();
+        assert_eq!(store.heads().unwrap(), vec![*b.id()]);
+        assert_eq!(store.patches(&[*a.id(), *b.id()]).unwrap(), vec![a, b]);
+        drop(store);
+        for path in [path, copy] {
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+            }
+        }
+
+        // journal modes other than WAL have nothing to flush
+        create_store().flush().unwrap();
+    }
+
+    #[test]
+    fn options_pragmas() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let options = Options::default()
+            .busy_timeout(Duration::from_millis(1234))
+            .synchronous(Synchronous::Full)
+            .cache_size(-4096);
+        let store = SqliteStore::with_options(conn, options).unwrap();
+        let pragma = |name: &str| {
+            store
+                .conn
+                .pragma_query_value(None, name, |row| row.get::<_, i64>(0))
+                .unwrap()
+        };
+        assert_eq!(pragma("busy_timeout"), 1234);
+        assert_eq!(pragma("synchronous"), 2);
+        assert_eq!(pragma("cache_size"), -4096);
+        assert_eq!(pragma("foreign_keys"), 1);
+
+        // WAL is not available for in-memory databases
+        let path = std::env::temp_dir().join(format!("st-{}.db", rand::random::<u64>()));
+        let store = SqliteStore::new(rusqlite::Connection::open(&path).unwrap()).unwrap();
+        let mode: String = store
+            .conn
+            .pragma_query_value(None, "journal_mode", |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn foreign_keys_enforced() {
+        let store = create_store();
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let res = store.conn.execute(
+            "INSERT INTO st_patches(hash, author_id, signature, data) VALUES (?, ?, ?, ?)",
+            params![a.id(), 999, a.sign().to_bytes(), a.data()],
+        );
+        assert!(matches!(
+            res,
+            Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::ConstraintViolation
+        ));
+
+        // dependency, which was never committed
+        let b = Patch::new(&key, [*a.id()], &"B").unwrap();
+        assert!(store.commit(&b).is_err());
+        assert!(!store.is_integrated(b.id()).unwrap());
+    }
+
+    #[test]
+    fn verify_on_commit() {
+        let store = create_store();
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let b = Patch::new(&key, [*a.id()], &"B").unwrap();
+        // signature doesn't cover content of the patch, but its ID is valid
+        let mut forged = Patch::new(&key, [*a.id()], &"C").unwrap();
+        forged.sign = b.sign;
+
+        let res = store.commit(&forged);
+        assert!(matches!(res, Err(Error::VerificationFailed(_))));
+        let res = store.commit_batch(&[a.clone(), forged.clone()]);
+        assert!(matches!(res, Err(Error::VerificationFailed(_))));
+        assert!(!store.contains(a.id()).unwrap());
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let store =
+            SqliteStore::with_options(conn, Options::default().verify_on_commit(false)).unwrap();
+        store.commit_batch(&[a, forged.clone()]).unwrap();
+        assert!(store.contains(forged.id()).unwrap());
+    }
+
+    #[test]
+    fn detect_tampered_deps() {
+        let store = create_store();
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let a = Patch::new(&key, [], &"A").unwrap();
+        let b = Patch::new(&key, [*a.id()], &"B").unwrap();
+        let c = Patch::new(&key, [*b.id()], &"C").unwrap();
+        store
+            .commit_batch(&[a.clone(), b.clone(), c.clone()])
+            .unwrap();
+
+        // patch which content doesn't match its ID
+        let mut forged = Patch::new(&key, [*a.id()], &"D").unwrap();
+        forged.deps = Deps::default();
+        assert!(matches!(store.commit(&forged), Err(Error::IdMismatch(_))));
+
+        // make C depend on A instead of B
+        store
+            .conn
+            .execute(
+                r#"UPDATE st_rel SET parent = (SELECT seq_no FROM st_patches WHERE hash = ?)
+                WHERE child = (SELECT seq_no FROM st_patches WHERE hash = ?)"#,
+                params![a.id(), c.id()],
+            )
+            .unwrap();
+        assert_eq!(store.patches(&[*b.id()]).unwrap(), vec![b]);
+        let res = store.patches(&[*c.id()]);
+        assert!(matches!(res, Err(Error::IdMismatch(id)) if id == *c.id()));
+    }
+}
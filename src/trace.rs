@@ -0,0 +1,38 @@
+//! Helpers for instrumentation with the `tracing` crate, enabled by the `tracing` feature. With the
+//! feature disabled, they expand to nothing, so instrumented code doesn't pay for it.
+
+/// Emits a debug-level event, within the current span.
+macro_rules! debug_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
+
+/// Records a value of a field declared (as empty) by the current span.
+macro_rules! record {
+    ($field:literal, $value:expr) => {
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record($field, $value);
+    };
+}
+
+/// Emits a trace-level event with the time spent (in microseconds), once the enclosing block
+/// finishes, either successfully or not.
+macro_rules! timed {
+    () => {
+        #[cfg(feature = "tracing")]
+        let _timed = crate::trace::Timed(std::time::Instant::now());
+    };
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) struct Timed(pub std::time::Instant);
+
+#[cfg(feature = "tracing")]
+impl Drop for Timed {
+    fn drop(&mut self) {
+        let duration_us = self.0.elapsed().as_micros() as u64;
+        tracing::trace!(duration_us, "finished");
+    }
+}